@@ -28,6 +28,7 @@ enum Mru {
     MslLatest(msl::msllatest::MslLatest),
     MslLocation(msl::msllocation::MslLocation),
     MslRunOn(msl::mslrunon::MslRunOn),
+    MslEcamAssemble(msl::ecamassemble::MslEcamAssemble),
     MslWeather(msl::mslweather::MslWeather),
 
     M20Fetch(m20::m20fetch::M20Fetch),
@@ -47,6 +48,7 @@ enum Mru {
 
     Calibrate(calibrate::Calibrate),
     Anaglyph(anaglyph::Anaglyph),
+    Assemble(assemble::Assemble),
     Composite(composite::Composite),
     Crop(crop::Crop),
     Debayer(debayer::Debayer),
@@ -61,6 +63,10 @@ enum Mru {
     Info(info::Info),
     Xeye(xeye::CrossEye),
     Profile(profile::Profile),
+    WhiteBalance(whitebalance::WhiteBalance),
+    SpectralCube(spectralcube::SpectralCube),
+    Spectrum(spectrum::Spectrum),
+    PruneVersions(pruneversions::PruneVersions),
     Decorr(decorr::DecorrelationStretch),
     UpdateCalData(caldata::UpdateCalData),
 
@@ -94,6 +100,7 @@ async fn main() -> Result<(), anyhow::Error> {
         Mru::MslLatest(args) => args.run().await,
         Mru::MslLocation(args) => args.run().await,
         Mru::MslRunOn(args) => args.run().await,
+        Mru::MslEcamAssemble(args) => args.run().await,
         Mru::MslWeather(args) => args.run().await,
         Mru::M20Date(args) => args.run().await,
         Mru::M20Latest(args) => args.run().await,
@@ -106,6 +113,7 @@ async fn main() -> Result<(), anyhow::Error> {
         Mru::NsytLatest(args) => args.run().await,
         Mru::MerDate(args) => args.run().await,
         Mru::Anaglyph(args) => args.run().await,
+        Mru::Assemble(args) => args.run().await,
         Mru::Composite(args) => args.run().await,
         Mru::Crop(args) => args.run().await,
         Mru::Debayer(args) => args.run().await,
@@ -118,6 +126,10 @@ async fn main() -> Result<(), anyhow::Error> {
         Mru::Info(args) => args.run().await,
         Mru::Xeye(args) => args.run().await,
         Mru::Profile(args) => args.run().await,
+        Mru::WhiteBalance(args) => args.run().await,
+        Mru::SpectralCube(args) => args.run().await,
+        Mru::Spectrum(args) => args.run().await,
+        Mru::PruneVersions(args) => args.run().await,
         Mru::Decorr(args) => args.run().await,
         Mru::UpdateCalData(args) => args.run().await,
         Mru::Pds2Png(args) => args.run().await,
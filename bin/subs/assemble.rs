@@ -0,0 +1,135 @@
+use mars_raw_utils::prelude::*;
+
+use crate::subs::runnable::RunnableSubcommand;
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use mars_raw_utils::assemble::{Assembly, AssemblyOptions, BlendMode, Tile};
+use sciimg::path;
+use std::env;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Assemble subframed tiles of any camera onto the full frame", long_about = None)]
+pub struct Assemble {
+    #[arg(long, short, help = "Input raw images", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Output image")]
+    output: std::path::PathBuf,
+
+    #[arg(long, short, help = "Do not perform brightness matching")]
+    nobright: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Scale factor of the output, the finest of the tiles by default"
+    )]
+    scale: Option<u32>,
+
+    #[arg(long, short, help = "Crop to the area covered by the tiles")]
+    crop: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Blending of overlapping tiles (hard, feather, multiband)",
+        default_value = "hard"
+    )]
+    blend: BlendMode,
+
+    #[arg(
+        long,
+        short = 'I',
+        help = "Instrument, when it can't be determined from the tiles"
+    )]
+    instrument: Option<String>,
+}
+
+/// Opens, lays out, level matches and saves a set of tiles. `instrument_of` gives the
+/// instrument each input is opened as.
+pub fn assemble_files<F>(
+    input_files: &[std::path::PathBuf],
+    output: &std::path::Path,
+    nobright: bool,
+    options: &AssemblyOptions,
+    instrument_of: F,
+) -> Result<()>
+where
+    F: Fn(&str) -> Instrument,
+{
+    let output = output.as_os_str().to_str().unwrap();
+
+    let mut tiles: Vec<Tile> = vec![];
+    for in_file in input_files.iter() {
+        let in_file = in_file.as_os_str().to_str().unwrap();
+        if !path::file_exists(in_file) {
+            return Err(anyhow!("File not found: {}", in_file));
+        }
+        tiles.push(Tile::new_from_file(in_file, instrument_of(in_file))?);
+    }
+
+    let instrument = tiles
+        .iter()
+        .map(|t| t.image.instrument)
+        .find(|instrument| *instrument != Instrument::None)
+        .unwrap_or(Instrument::None);
+
+    let (mut assembly, discarded) = Assembly::new(tiles, instrument, options)?;
+    discarded.iter().for_each(|t| {
+        warn!(
+            "Discarding image that can't be placed on the composite: {:?}",
+            t.image.file_path
+        )
+    });
+
+    // Runs each tile through the level matching
+    if !nobright {
+        vprintln!("Solving for tile gains and offsets across all overlaps");
+        assembly.match_levels();
+    } else {
+        vprintln!("Skipping the computation of relative brightnesses");
+    }
+
+    vprintln!(
+        "Saving composite of {} tiles to {}",
+        assembly.tiles.len(),
+        output
+    );
+    assembly
+        .metadata
+        .history
+        .push(env::args().collect::<Vec<String>>().join(" "));
+    assembly.finalize_and_save(output)
+}
+
+impl RunnableSubcommand for Assemble {
+    async fn run(&self) -> Result<()> {
+        pb_set_print!();
+
+        let options = AssemblyOptions {
+            scale: self.scale,
+            crop_to_tiles: self.crop,
+            blend: self.blend,
+        };
+        let result = assemble_files(
+            &self.input_files,
+            &self.output,
+            self.nobright,
+            &options,
+            |in_file| {
+                identify::identify_instrument(in_file, &self.instrument)
+                    .map(|(instrument, _)| instrument)
+                    .unwrap_or(Instrument::None)
+            },
+        );
+
+        if result.is_ok() {
+            pb_done!();
+        } else {
+            pb_done_with_error!();
+        }
+        result
+    }
+}
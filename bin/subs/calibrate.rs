@@ -1,19 +1,20 @@
-use mars_raw_utils::calprofile::load_calibration_profile;
+use mars_raw_utils::calprofile::{ProfileLayers, DEFAULT_SOURCE};
+use mars_raw_utils::calreport::{self, CalReport};
 use mars_raw_utils::prelude::*;
+use mars_raw_utils::profileselect::ProfileSelector;
+use mars_raw_utils::radiometry::RadiometricUnits;
 use sciimg::debayer::DebayerMethod;
 use sciimg::path;
+use stump::CompleteStatus;
 
 use crate::subs::runnable::RunnableSubcommand;
 
-use backtrace::Backtrace;
 use rayon::prelude::*;
-use std::panic;
-use std::process;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, Result};
 use clap::Parser;
-use stump;
 
 pb_create!();
 
@@ -53,144 +54,332 @@ pub struct Calibrate {
     #[arg(long, short = 'P', help = "Calibration profile", num_args = 1..)]
     profile: Option<Vec<String>>,
 
+    #[arg(
+        long,
+        help = "Choose a profile for each image by its instrument and filter, from the given profiles or all installed profiles",
+        num_args = 0..,
+        conflicts_with = "profile"
+    )]
+    auto_profile: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        help = "Profile for images that match no profile during automatic selection",
+        requires = "auto_profile"
+    )]
+    fallback_profile: Option<String>,
+
+    #[arg(
+        long,
+        help = "Override a profile value, e.g. red_scalar=1.2 (repeatable)",
+        num_args = 1..
+    )]
+    set: Option<Vec<String>>,
+
     #[arg(long, short = 'D', help = "Debayer method (malvar, amaze)")]
     debayer: Option<String>,
 
     #[arg(long, short = 'C', help = "Apply sRGB color correction")]
     srgb_color_correction: bool,
 
+    #[arg(long, help = "Apply the instrument's color correction matrix")]
+    color_matrix: bool,
+
     #[arg(
         long,
         short = 'S',
         help = "Skip auto subframing (cropping) of output images"
     )]
     no_subframing: bool,
+
+    #[arg(
+        long,
+        help = "Write a calibration report to a JSON file (or JSON Lines for .jsonl)"
+    )]
+    report: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        help = "Print the calibration plan for each image without processing it"
+    )]
+    plan: bool,
+
+    #[arg(
+        long,
+        help = "Write the image after each calibration stage for debugging"
+    )]
+    dump_stages: bool,
+
+    #[arg(
+        long,
+        help = "Reject profiles with unknown keys or invalid values instead of ignoring them"
+    )]
+    strict: bool,
+
+    #[arg(
+        long,
+        help = "Convert to physical units (radiance, iof) and write floating point TIFF output"
+    )]
+    radiometric: Option<RadiometricUnits>,
 }
 
 impl Calibrate {
-    fn get_calibrator_for_file(
-        input_file: &str,
-        default_instrument: &Option<String>,
-    ) -> Option<&'static CalContainer> {
-        let metadata_file = util::replace_image_extension(input_file, "-metadata.json");
-        info!("Checking for metadata file at {}", metadata_file);
-        if path::file_exists(metadata_file.as_str()) {
-            vprintln!("Metadata file exists for loaded image: {}", metadata_file);
-            match metadata::load_image_metadata(&metadata_file) {
-                Err(_) => {
-                    warn!("Could not load metadata file!");
-                    None
-                } // Error loading the metadata file
-                Ok(md) => calibrator_for_instrument_from_str(&md.instrument),
+    /// Resolves the final profile, validating it first when `--strict` is given
+    fn resolve_profile(&self, layers: &ProfileLayers) -> Result<CalProfile> {
+        match self.strict {
+            true => Ok(layers.resolve_strict()?.profile),
+            false => Ok(layers.resolve()?.profile),
+        }
+    }
+
+    /// Applies `--set` assignments, in the order given
+    fn apply_set_overrides(&self, layers: &mut ProfileLayers) -> Result<()> {
+        if let Some(assignments) = &self.set {
+            for assignment in assignments.iter() {
+                layers.set_override_from_str(assignment)?;
             }
-        } else {
-            // metadata file is missing
+        }
+        Ok(())
+    }
 
-            // If a default instrument was passed in, try and use that
-            if let Some(instrument) = default_instrument {
-                calibrator_for_instrument_from_str(instrument)
-            } else {
-                warn!("We don't know what instrument was used!");
-                None // Otherwise, we don't know the instrument.
+    /// Applies command line overrides on top of the loaded profile layers. `--set` assignments
+    /// are applied first, followed by the dedicated options.
+    fn apply_profile_overrides(
+        &self,
+        layers: &mut ProfileLayers,
+        debayer_method: Option<DebayerMethod>,
+    ) -> Result<()> {
+        self.apply_set_overrides(layers)?;
+
+        if self.raw {
+            layers.set_override("apply_ilt", true)?;
+        }
+
+        if let Some(red_scalar) = self.red_weight {
+            layers.set_override("red_scalar", red_scalar)?;
+        }
+
+        if let Some(green_scalar) = self.green_weight {
+            layers.set_override("green_scalar", green_scalar)?;
+        }
+
+        if let Some(blue_scalar) = self.blue_weight {
+            layers.set_override("blue_scalar", blue_scalar)?;
+        }
+
+        if let Some(color_noise_reduction_amount) = self.color_noise_reduction_amount {
+            layers.set_override("color_noise_reduction", true)?;
+            layers.set_override("color_noise_reduction_amount", color_noise_reduction_amount)?;
+        }
+
+        if let Some(hpc_threshold) = self.hpc_threshold {
+            layers.set_override("hot_pixel_detection_threshold", hpc_threshold)?;
+        }
+
+        if let Some(hpc_window) = self.hpc_window {
+            layers.set_override("hot_pixel_window_size", hpc_window)?;
+        }
+
+        if self.decorrelate {
+            layers.set_override("decorrelate_color", true)?;
+        }
+
+        if let Some(method) = debayer_method {
+            layers.set_override("debayer_method", method)?;
+        }
+
+        if self.dump_stages {
+            layers.set_override("dump_stages", true)?;
+        }
+
+        if self.color_matrix {
+            layers.set_override("apply_color_matrix", true)?;
+        }
+
+        if let Some(units) = self.radiometric {
+            layers.set_override("radiometric_units", units)?;
+        }
+        Ok(())
+    }
+
+    /// The profiles to calibrate a file with, each with a description of how it was chosen
+    /// when automatic selection is enabled. Without a match, the fixed profiles are used.
+    fn profiles_for_file(
+        &self,
+        instrument: Instrument,
+        input_file: &str,
+        selector: &Option<ProfileSelector>,
+        fixed_profiles: &[CalProfile],
+        debayer_method: Option<DebayerMethod>,
+    ) -> Result<Vec<(CalProfile, Option<String>)>> {
+        let selector = match selector {
+            Some(selector) => selector,
+            None => return Ok(fixed_profiles.iter().map(|p| (p.clone(), None)).collect()),
+        };
+
+        let metadata = MarsImage::load_image_metadata(input_file)?;
+        match selector.select(instrument, metadata.filter_name.as_deref()) {
+            Some(selection) => {
+                if !selection.tied_with.is_empty() {
+                    warn!(
+                        "Profile {} selected for {} over equally ranked {}",
+                        selection.name,
+                        path::basename(input_file),
+                        selection.tied_with.join(", ")
+                    );
+                }
+                let mut layers = ProfileLayers::from_spec(&selection.spec)?;
+                self.apply_profile_overrides(&mut layers, debayer_method)?;
+                Ok(vec![(
+                    self.resolve_profile(&layers)?,
+                    Some(format!("{} ({})", selection.name, selection.reason)),
+                )])
             }
+            None => Ok(fixed_profiles
+                .iter()
+                .map(|p| (p.clone(), Some(format!("{} (no match)", DEFAULT_SOURCE))))
+                .collect()),
+        }
+    }
+}
+
+fn print_plan(plan: &CalPlan) {
+    let status = match plan.is_complete() {
+        true => CompleteStatus::OK,
+        false => CompleteStatus::FAIL,
+    };
+    let mut lines = vec![
+        format_complete(
+            &format!(
+                "{} ({})",
+                path::basename(&plan.input_file),
+                plan.profile.filename_suffix
+            ),
+            status,
+        ),
+        format!(
+            "    Instrument:   {} ({})",
+            plan.instrument,
+            plan.instrument_source.as_deref().unwrap_or("unknown")
+        ),
+        format!("    Output:       {}", plan.output_file),
+    ];
+
+    if let Some(selection) = &plan.profile_selection {
+        lines.push(format!("    Profile:      {}", selection));
+    }
+
+    lines.push(format!("    Stages:       {}", plan.stages.join(" -> ")));
+
+    let files = [
+        ("LUT:", &plan.lut),
+        ("Flat:", &plan.flat),
+        ("Inpaint mask:", &plan.inpaint_mask),
+        ("Mask:", &plan.mask),
+        ("Bias:", &plan.bias),
+        ("Dark:", &plan.dark),
+    ];
+    files.iter().for_each(|(name, file)| {
+        if let Some(file) = file {
+            lines.push(format!("    {:<13} {}", name, file));
         }
+    });
+
+    if let Some(rect) = &plan.subframe_rect {
+        lines.push(format!(
+            "    Subframe:     {},{} {}x{} (scale factor {})",
+            rect[0], rect[1], rect[2], rect[3], plan.scale_factor
+        ));
     }
+    plan.crops.iter().for_each(|c| {
+        lines.push(format!(
+            "    Crop:         {},{} {}x{}",
+            c[0], c[1], c[2], c[3]
+        ));
+    });
+    plan.notes.iter().for_each(|n| {
+        lines.push(format!("    Note:         {}", n));
+    });
+    plan.missing.iter().for_each(|m| {
+        lines.push(format!("    Missing:      {}", m));
+    });
+
+    pb_println!(lines.join("\n"));
 }
 
 impl RunnableSubcommand for Calibrate {
     async fn run(&self) -> Result<()> {
+        let debayer_method = match &self.debayer {
+            Some(d) => match DebayerMethod::from_str(d) {
+                Ok(m) => Some(m),
+                Err(why) => return Err(anyhow!("Invalid debayer method: {}", why)),
+            },
+            None => None,
+        };
+
         let profiles: Vec<CalProfile> = match &self.profile {
             Some(profile_list) => {
                 let mut v: Vec<CalProfile> = Vec::new();
-                let profile_results: Vec<Result<CalProfile, Error>> = profile_list
-                    .iter()
-                    .map(|profile_name| {
-                        match load_calibration_profile(profile_name) {
-                            Ok(profile) => {
-                                let mut profile_mut = profile;
-
-                                // Overrides
-                                if self.raw {
-                                    profile_mut.apply_ilt = true;
-                                }
-
-                                if let Some(red_scalar) = self.red_weight {
-                                    profile_mut.red_scalar = red_scalar;
-                                }
-
-                                if let Some(green_scalar) = self.green_weight {
-                                    profile_mut.green_scalar = green_scalar;
-                                }
-
-                                if let Some(color_noise_reduction_amount) =
-                                    self.color_noise_reduction_amount
-                                {
-                                    profile_mut.color_noise_reduction = true;
-                                    profile_mut.color_noise_reduction_amount =
-                                        color_noise_reduction_amount;
-                                }
-
-                                if let Some(hpc_threshold) = self.hpc_threshold {
-                                    profile_mut.hot_pixel_detection_threshold = hpc_threshold;
-                                }
-
-                                if let Some(hpc_window) = self.hpc_window {
-                                    profile_mut.hot_pixel_window_size = hpc_window;
-                                }
-
-                                if self.decorrelate {
-                                    profile_mut.decorrelate_color = true;
-                                }
-
-                                if let Some(debayer) = &self.debayer {
-                                    profile_mut.debayer_method =
-                                        match DebayerMethod::from_str(debayer) {
-                                            Ok(m) => m,
-                                            Err(why) => panic!("Error: {}", why),
-                                        };
-                                }
-                                Ok(profile_mut)
-                            }
-                            Err(why) => Err(anyhow!("Error loading calibration profile: {}", why)),
+                for profile_spec in profile_list.iter() {
+                    let mut layers = match ProfileLayers::from_spec(profile_spec) {
+                        Ok(layers) => layers,
+                        Err(why) => {
+                            return Err(anyhow!("Error loading calibration profile: {}", why))
                         }
-
-                        //v.push(
-                    })
-                    .collect();
-                for f in profile_results {
-                    match f {
-                        Ok(cp) => v.push(cp.clone()),
-                        Err(why) => return Err(anyhow!(why)),
                     };
+                    self.apply_profile_overrides(&mut layers, debayer_method)?;
+                    v.push(self.resolve_profile(&layers)?);
                 }
                 v
             }
-            None => vec![CalProfile {
-                calfiletype: "profile".to_string(),
-                apply_ilt: !self.raw,
-                red_scalar: self.red_weight.unwrap_or(1.0),
-                green_scalar: self.green_weight.unwrap_or(1.0),
-                blue_scalar: self.blue_weight.unwrap_or(1.0),
-                color_noise_reduction: self.color_noise_reduction_amount.is_some(),
-                color_noise_reduction_amount: self.color_noise_reduction_amount.unwrap_or(0),
-                hot_pixel_detection_threshold: self.hpc_threshold.unwrap_or(0.0),
-                hot_pixel_window_size: self.hpc_window.unwrap_or(3),
-                filename_suffix: String::from(constants::OUTPUT_FILENAME_APPEND),
-                decorrelate_color: self.decorrelate,
-                mission: None,
-                instrument: None,
-                description: None,
-                debayer_method: if let Some(d) = &self.debayer {
-                    match DebayerMethod::from_str(d) {
-                        Ok(m) => m,
-                        Err(why) => panic!("Error: {}", why),
-                    }
-                } else {
-                    DebayerMethod::Malvar
-                },
-                srgb_color_correction: self.srgb_color_correction,
-                auto_subframing: !self.no_subframing,
-            }],
+            None => {
+                let mut layers = ProfileLayers::from_profile(
+                    &CalProfile {
+                        calfiletype: "profile".to_string(),
+                        apply_ilt: !self.raw,
+                        red_scalar: self.red_weight.unwrap_or(1.0),
+                        green_scalar: self.green_weight.unwrap_or(1.0),
+                        blue_scalar: self.blue_weight.unwrap_or(1.0),
+                        color_noise_reduction: self.color_noise_reduction_amount.is_some(),
+                        color_noise_reduction_amount: self
+                            .color_noise_reduction_amount
+                            .unwrap_or(0),
+                        hot_pixel_detection_threshold: self.hpc_threshold.unwrap_or(0.0),
+                        hot_pixel_window_size: self.hpc_window.unwrap_or(3),
+                        filename_suffix: String::from(constants::OUTPUT_FILENAME_APPEND),
+                        decorrelate_color: self.decorrelate,
+                        mission: None,
+                        instrument: None,
+                        description: None,
+                        debayer_method: debayer_method.unwrap_or(DebayerMethod::Malvar),
+                        srgb_color_correction: self.srgb_color_correction,
+                        auto_subframing: !self.no_subframing,
+                        pipeline: None,
+                        dump_stages: self.dump_stages,
+                        filter_name: None,
+                        auto_priority: 0,
+                        radiometric_units: self.radiometric,
+                        apply_color_matrix: self.color_matrix,
+                        color_matrix: None,
+                        color_offset: None,
+                    },
+                    DEFAULT_SOURCE,
+                )?;
+                self.apply_set_overrides(&mut layers)?;
+                vec![self.resolve_profile(&layers)?]
+            }
+        };
+
+        let selector = match &self.auto_profile {
+            Some(profile_names) if profile_names.is_empty() => Some(
+                ProfileSelector::from_installed_profiles()
+                    .with_fallback(self.fallback_profile.clone()),
+            ),
+            Some(profile_names) => Some(
+                ProfileSelector::from_profiles(profile_names)?
+                    .with_fallback(self.fallback_profile.clone()),
+            ),
+            None => None,
         };
 
         let in_files: Vec<String> = self
@@ -201,32 +390,93 @@ impl RunnableSubcommand for Calibrate {
 
         pb_set_print_and_length!(in_files.len() * profiles.len());
 
-        panic::set_hook(Box::new(|_info| {
-            if stump::is_verbose() {
-                println!("{:?}", Backtrace::new());
-            }
-            print_fail("Internal Error!");
-
-            // If the user has exported MRU_EXIT_ON_PANIC=1, then we should exit here.
-            // This will prevent situations where errors fly by on the screen and
-            // aren't noticed when testing.
-            if let Some(v) = option_env!("MRU_EXIT_ON_PANIC") {
-                if v == "1" {
-                    process::exit(1);
-                }
-            };
-        }));
+        let reports: Arc<Mutex<Vec<CalReport>>> = Arc::new(Mutex::new(vec![]));
+        let add_report = |report: CalReport| {
+            reports
+                .lock()
+                .expect("`reports` cannot be locked")
+                .push(report);
+        };
+        let plans: Arc<Mutex<Vec<CalPlan>>> = Arc::new(Mutex::new(vec![]));
+        let add_failed_reports = |input_file: &str, why: &str| {
+            profiles.iter().for_each(|p| {
+                let mut report = CalReport::new(input_file, p);
+                report.set_error(why);
+                add_report(report);
+            });
+        };
 
         in_files.par_iter().for_each(|input_file| {
             if !path::file_exists(input_file) {
-                print_fail(&format!("Error: File not found: {}", input_file));
-                process::exit(1);
+                let why = CalError::MissingFile(input_file.to_owned()).to_string();
+                pb_println!(format_fail(&format!(
+                    "{} - Error: {}",
+                    path::basename(input_file),
+                    why
+                )));
+                add_failed_reports(input_file, &why);
+                pb_inc_by!(profiles.len() as u64);
+                return;
             }
 
-            if let Some(cal) = Calibrate::get_calibrator_for_file(input_file, &self.instrument) {
-                profiles.par_iter().for_each(|p| {
-                    match cal.calibrator.process_with_profile(input_file, false, p) {
-                        Ok(res) => {
+            if let Some((cal, instrument, instrument_source)) =
+                calibrator_for_file(input_file, &self.instrument)
+            {
+                let file_profiles = match self.profiles_for_file(
+                    instrument,
+                    input_file,
+                    &selector,
+                    &profiles,
+                    debayer_method,
+                ) {
+                    Ok(file_profiles) => file_profiles,
+                    Err(why) => {
+                        pb_println!(format_fail(&format!(
+                            "{} - Error selecting profile: {}",
+                            path::basename(input_file),
+                            why
+                        )));
+                        add_failed_reports(input_file, &why.to_string());
+                        pb_inc_by!(profiles.len() as u64);
+                        return;
+                    }
+                };
+
+                // The bar counts every fixed profile for each file, of which automatic
+                // selection may run fewer
+                pb_inc_by!(profiles.len().saturating_sub(file_profiles.len()) as u64);
+
+                if self.plan {
+                    file_profiles.iter().for_each(|(p, selection)| {
+                        match cal.calibrator.plan_file(input_file, instrument, p) {
+                            Ok(mut plan) => {
+                                plan.instrument_source = Some(instrument_source.to_string());
+                                plan.profile_selection = selection.clone();
+                                print_plan(&plan);
+                                plans.lock().expect("`plans` cannot be locked").push(plan);
+                            }
+                            Err(why) => {
+                                pb_println!(format_fail(&format!(
+                                    "{} ({}) - Error: {}",
+                                    path::basename(input_file),
+                                    p.filename_suffix,
+                                    why
+                                )));
+                            }
+                        }
+                        pb_inc!();
+                    });
+                    return;
+                }
+
+                file_profiles.par_iter().for_each(|(p, selection)| {
+                    match cal
+                        .calibrator
+                        .process_with_profile(input_file, instrument, false, p)
+                    {
+                        Ok(mut res) => {
+                            res.report.profile_selection = selection.clone();
+                            res.report.instrument_source = Some(instrument_source.to_string());
                             pb_println!(format_complete(
                                 &format!(
                                     "{} ({})",
@@ -235,10 +485,20 @@ impl RunnableSubcommand for Calibrate {
                                 ),
                                 res.status,
                             ));
+                            add_report(res.report);
                         }
-                        Err(res) => {
-                            pb_println!(format!("Error: {:?}", res));
-                            pb_println!(format_fail(input_file));
+                        Err(why) => {
+                            pb_println!(format_fail(&format!(
+                                "{} ({}) - Error: {}",
+                                path::basename(input_file),
+                                p.filename_suffix,
+                                why
+                            )));
+                            let mut report = CalReport::new(input_file, p);
+                            report.profile_selection = selection.clone();
+                            report.instrument_source = Some(instrument_source.to_string());
+                            report.set_error(&why.to_string());
+                            add_report(report);
                         }
                     };
                     pb_inc!();
@@ -248,9 +508,22 @@ impl RunnableSubcommand for Calibrate {
                     "{} - Error: Instrument Unknown!",
                     path::basename(input_file)
                 ));
+                add_failed_reports(input_file, "Instrument Unknown");
+                pb_inc_by!(profiles.len() as u64);
             }
         });
 
+        if let Some(report_file) = &self.report {
+            let report_file = report_file.as_os_str().to_str().unwrap();
+            if self.plan {
+                info!("Writing calibration plan to {}", report_file);
+                calreport::save_reports(report_file, &plans.lock().unwrap())?;
+            } else {
+                info!("Writing calibration report to {}", report_file);
+                calreport::save_reports(report_file, &reports.lock().unwrap())?;
+            }
+        }
+
         Ok(())
     }
 }
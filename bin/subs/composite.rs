@@ -1,8 +1,12 @@
 use crate::subs::runnable::RunnableSubcommand;
 use anyhow::Result;
 use clap::Parser;
-use mars_raw_utils::{composite, prelude::*};
-use sciimg::{drawable::*, prelude::*, quaternion::Quaternion};
+use mars_raw_utils::composite::{
+    self, CompositeOptions, Interpolation, MapOptions, MosaicAccumulator, Projection,
+};
+use mars_raw_utils::metadata::Metadata;
+use mars_raw_utils::prelude::*;
+use sciimg::{drawable::*, prelude::*};
 use std::process;
 use stump;
 
@@ -22,6 +26,50 @@ pub struct Composite {
 
     #[arg(long, short = 'r', help = "Azimuth rotation")]
     azimuth: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Sampling of source pixels (nearest, bilinear, bicubic)",
+        default_value = "bilinear"
+    )]
+    interpolation: Interpolation,
+
+    #[arg(
+        long,
+        short,
+        help = "Width in pixels over which overlapping images are blended, 0 for hard seams",
+        default_value = "32"
+    )]
+    feather: usize,
+
+    #[arg(
+        long,
+        short,
+        help = "Output projection (equirectangular, cylindrical, mercator, perspective, polar)",
+        default_value = "equirectangular"
+    )]
+    projection: Projection,
+
+    #[arg(
+        long,
+        num_args = 2,
+        allow_negative_numbers = true,
+        value_names = ["MIN", "MAX"],
+        help = "Azimuth bounds in degrees clockwise from north"
+    )]
+    azimuth_bounds: Option<Vec<f64>>,
+
+    #[arg(
+        long,
+        num_args = 2,
+        allow_negative_numbers = true,
+        value_names = ["MIN", "MAX"],
+        help = "Elevation bounds in degrees above the horizon"
+    )]
+    elevation_bounds: Option<Vec<f64>>,
+
+    #[arg(long, short, help = "Output scale in degrees per pixel")]
+    degrees_per_pixel: Option<f64>,
 }
 
 impl RunnableSubcommand for Composite {
@@ -38,19 +86,23 @@ impl RunnableSubcommand for Composite {
 
         let output = self.output.as_os_str().to_str().unwrap();
 
-        let azimuth_rotation: f64 = self.azimuth.unwrap_or(0.0);
-
-        let quat = Quaternion::from_pitch_roll_yaw(0.0, 0.0, azimuth_rotation.to_radians());
+        let map_options = MapOptions {
+            projection: self.projection,
+            azimuth_rotation: self.azimuth.unwrap_or(0.0),
+            azimuth_bounds: self.azimuth_bounds.as_ref().map(|b| [b[0], b[1]]),
+            elevation_bounds: self.elevation_bounds.as_ref().map(|b| [b[0], b[1]]),
+            degrees_per_pixel: self.degrees_per_pixel,
+        };
 
-        let map_context = composite::determine_map_context(&in_files, &quat);
+        let map_context = composite::determine_map_context(&in_files, &map_options);
         debug!("Map Context: {:?}", map_context);
         debug!(
             "FOV Vertical: {}",
-            map_context.top_lat - map_context.bottom_lat
+            map_context.max_elevation - map_context.min_elevation
         );
         debug!(
             "FOV Horizontal: {}",
-            map_context.right_lon - map_context.left_lon
+            map_context.max_azimuth - map_context.min_azimuth
         );
 
         if map_context.width == 0 {
@@ -65,26 +117,21 @@ impl RunnableSubcommand for Composite {
 
         let mut map = Image::create_masked(map_context.width, map_context.height, true);
 
-        let first_image = MarsImage::open(&in_files[0], Instrument::M20MastcamZLeft);
-        let initial_origin = if let Some(model) = composite::get_cahvor(&first_image) {
-            model.c()
-        } else {
-            error!("Cannot determine initial camera origin");
-            pb_done_with_error!();
-            process::exit(2);
+        let options = CompositeOptions {
+            interpolation: self.interpolation,
+            feather: self.feather,
+            anaglyph: self.anaglyph,
         };
+        let mut accumulator = MosaicAccumulator::new(&map_context, map.num_bands(), &options);
 
         for in_file in in_files.iter() {
             if path::file_exists(in_file) {
                 info!("Processing File: {}", in_file);
-                composite::process_file(
-                    in_file,
-                    &map_context,
-                    &mut map,
-                    self.anaglyph,
-                    &quat,
-                    &initial_origin,
-                );
+                if let Err(why) =
+                    composite::process_file(in_file, &map_context, &mut accumulator, &options)
+                {
+                    error!("Error processing {}: {}", in_file, why);
+                }
             } else {
                 error!("File not found: {}", in_file);
                 pb_done_with_error!();
@@ -92,8 +139,19 @@ impl RunnableSubcommand for Composite {
             }
         }
 
+        accumulator.paint(&mut map);
         map.save(output).expect("Failed to save image");
 
+        // The perspective projection is a camera of its own, described by a linear model
+        if let Some(model) = map_context.output_model() {
+            let metadata = Metadata {
+                camera_model_component_list: model,
+                history: vec![std::env::args().collect::<Vec<String>>().join(" ")],
+                ..Default::default()
+            };
+            util::save_image_json(output, &metadata, None)?;
+        }
+
         pb_done!();
         Ok(())
     }
@@ -41,7 +41,7 @@ impl RunnableSubcommand for Crop {
                 info!("Processing File: {:?}", in_file);
 
                 let mut raw =
-                    MarsImage::open(in_file.as_os_str().to_str().unwrap(), Instrument::None);
+                    MarsImage::open(in_file.as_os_str().to_str().unwrap(), Instrument::None)?;
 
                 if x >= raw.image.width {
                     error!(
@@ -130,10 +130,16 @@ fn cross_file_decorrelation(input_files: &[PathBuf], ignore_black: bool) {
     info!("Computing value ranges...");
     input_files.par_iter().for_each(|in_file| {
         if in_file.exists() {
-            let image = MarsImage::open(
+            let image = match MarsImage::open(
                 &String::from(in_file.as_os_str().to_str().unwrap()),
                 Instrument::None,
-            );
+            ) {
+                Ok(image) => image,
+                Err(why) => {
+                    error!("Error opening {:?}: {}", in_file, why);
+                    return;
+                }
+            };
 
             let prepped = color_range_determine_prep(&image.image);
 
@@ -165,10 +171,16 @@ fn cross_file_decorrelation(input_files: &[PathBuf], ignore_black: bool) {
         if in_file.exists() {
             info!("Processing File: {:?}", in_file);
 
-            let mut image = MarsImage::open(
+            let mut image = match MarsImage::open(
                 &String::from(in_file.as_os_str().to_str().unwrap()),
                 Instrument::None,
-            );
+            ) {
+                Ok(image) => image,
+                Err(why) => {
+                    error!("Error opening {:?}: {}", in_file, why);
+                    return;
+                }
+            };
 
             for b in 0..image.image.num_bands() {
                 image.image.normalize_band_to_with_min_max(
@@ -204,10 +216,16 @@ fn individual_file_decorrelation(input_files: &Vec<PathBuf>, ignore_black: bool)
         if in_file.exists() {
             info!("Processing File: {:?}", in_file);
 
-            let mut image = MarsImage::open(
+            let mut image = match MarsImage::open(
                 &String::from(in_file.as_os_str().to_str().unwrap()),
                 Instrument::None,
-            );
+            ) {
+                Ok(image) => image,
+                Err(why) => {
+                    error!("Error opening {:?}: {}", in_file, why);
+                    return;
+                }
+            };
 
             let prepped = color_range_determine_prep(&image.image);
             for b in 0..3 {
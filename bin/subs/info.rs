@@ -29,7 +29,7 @@ impl RunnableSubcommand for Info {
         for in_file in self.input_files.iter() {
             if in_file.exists() {
                 println!("Image: {:?}", in_file);
-                let img = MarsImage::open(in_file.as_os_str().to_str().unwrap(), Instrument::None);
+                let img = MarsImage::open(in_file.as_os_str().to_str().unwrap(), Instrument::None)?;
 
                 println!("Sol:                         {}", img.metadata.sol);
                 println!("Instrument:                  {}", img.metadata.instrument);
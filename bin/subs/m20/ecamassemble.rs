@@ -4,6 +4,7 @@ use crate::subs::runnable::RunnableSubcommand;
 use anyhow::Result;
 use clap::Parser;
 use colored::{self, Colorize};
+use mars_raw_utils::assemble::BlendMode;
 use mars_raw_utils::m20::assemble::{Composite, NavcamTile};
 use mars_raw_utils::m20::ncamlevels;
 use mars_raw_utils::util;
@@ -24,6 +25,21 @@ pub struct M20EcamAssemble {
 
     #[arg(long, short, help = "Do not perform brightness matching")]
     nobright: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Blending of overlapping tiles (hard, feather, multiband)",
+        default_value = "hard"
+    )]
+    blend: BlendMode,
+
+    #[arg(
+        long,
+        short,
+        help = "Match brightness with a gain and offset per tile solved over all overlaps at once"
+    )]
+    global_levels: bool,
 }
 
 impl RunnableSubcommand for M20EcamAssemble {
@@ -45,7 +61,7 @@ impl RunnableSubcommand for M20EcamAssemble {
                 process::exit(1);
             }
             let image =
-                NavcamTile::new_from_file(&String::from(in_file), Instrument::M20NavcamRight);
+                NavcamTile::new_from_file(&String::from(in_file), Instrument::M20NavcamRight)?;
 
             // Disabling destretch for now as the tool will just restretch it anyway
             // when the image is reencoded for 16 bit.
@@ -73,21 +89,31 @@ impl RunnableSubcommand for M20EcamAssemble {
             process::exit(1);
         }
 
+        // Build a composite canvas. This will be the output image
+        vprintln!("Creating composite structure");
+        let mut composite = Composite::new(&tiles);
+
         // Runs each tile through the level matching algorithms
-        if !self.nobright {
+        if self.nobright {
+            vprintln!("Skipping the computation of relative brightnesses");
+        } else if self.global_levels {
+            vprintln!("Solving for tile gains and offsets across all overlaps");
+            ncamlevels::match_levels_global(&mut tiles, &composite);
+        } else {
             vprintln!("Computing relative brightnesses");
             ncamlevels::match_levels(&mut tiles);
-        } else {
-            vprintln!("Skipping the computation of relative brightnesses");
         }
 
-        // Build a composite canvas. This will be the output image
-        vprintln!("Creating composite structure");
-        let mut composite = Composite::new(&tiles);
-
-        // Pastes the tiles into the canvas
-        vprintln!("Adding {} tiles to composite", tiles.len());
-        composite.paste_tiles(&tiles);
+        // Places the tiles onto the canvas
+        vprintln!(
+            "Adding {} tiles to composite with {:?} blending",
+            tiles.len(),
+            self.blend
+        );
+        if let Err(why) = composite.blend_tiles(&tiles, self.blend) {
+            pb_done_with_error!();
+            return Err(why);
+        }
 
         // Stretches image to 16 bit and saves to disk
         vprintln!("Saving composite to {}", output);
@@ -55,6 +55,18 @@ pub struct M20Fetch {
 
     #[arg(long, short = 'P', help = "Product type codes (ECM, EBY, etc)", num_args = 1..)]
     product_types: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        help = "Keep all versions and thumbnails rather than only the newest full frame"
+    )]
+    all_versions: bool,
+
+    #[arg(
+        long,
+        help = "Only download newer versions or full frames of images already downloaded"
+    )]
+    update: bool,
 }
 
 impl RunnableSubcommand for M20Fetch {
@@ -151,6 +163,8 @@ impl RunnableSubcommand for M20Fetch {
                 only_new: self.new,
                 product_types,
                 output_path: output,
+                all_versions: self.all_versions,
+                updates_only: self.update,
             },
             |total| pb_set_length!(total),
             |_| pb_inc!(),
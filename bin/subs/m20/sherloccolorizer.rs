@@ -37,12 +37,12 @@ impl RunnableSubcommand for M20SherlocColorizer {
         let red = MarsImage::open(
             self.red.as_os_str().to_string_lossy().as_ref(),
             Instrument::M20SherlocAci,
-        );
+        )?;
 
         let blue = MarsImage::open(
             self.blue.as_os_str().to_string_lossy().as_ref(),
             Instrument::M20SherlocAci,
-        );
+        )?;
 
         if red.image.width != blue.image.width || red.image.height != blue.image.height {
             eprintln!("Error: Input image dimension mismatch.");
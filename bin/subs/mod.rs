@@ -135,6 +135,7 @@ pub mod nsyt;
 
 // Multimission subcommands:
 pub mod anaglyph;
+pub mod assemble;
 pub mod caldata;
 pub mod calibrate;
 pub mod composite;
@@ -151,4 +152,8 @@ pub mod meanstack;
 pub mod passes;
 pub mod pds2png;
 pub mod profile;
+pub mod pruneversions;
+pub mod spectralcube;
+pub mod spectrum;
+pub mod whitebalance;
 pub mod xeye;
@@ -0,0 +1,52 @@
+use mars_raw_utils::prelude::*;
+
+use crate::subs::assemble;
+use crate::subs::runnable::RunnableSubcommand;
+use anyhow::Result;
+use clap::Parser;
+use mars_raw_utils::assemble::AssemblyOptions;
+use mars_raw_utils::productid::ProductId;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Reassemble MSL ECAM subframes", long_about = None)]
+pub struct MslEcamAssemble {
+    #[arg(long, short, help = "Input raw images", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Output image")]
+    output: std::path::PathBuf,
+
+    #[arg(long, short, help = "Do not perform brightness matching")]
+    nobright: bool,
+}
+
+impl RunnableSubcommand for MslEcamAssemble {
+    async fn run(&self) -> Result<()> {
+        pb_set_print!();
+
+        // The camera only matters for the sensor size, which is the same for all of the
+        // Navcams and Hazcams
+        let result = assemble::assemble_files(
+            &self.input_files,
+            &self.output,
+            self.nobright,
+            &AssemblyOptions::default(),
+            |in_file| {
+                ProductId::from_file_name(in_file)
+                    .map(|product_id| product_id.instrument())
+                    .ok()
+                    .filter(|instrument| *instrument != Instrument::None)
+                    .unwrap_or(Instrument::MslNavCamLeft)
+            },
+        );
+
+        if result.is_ok() {
+            pb_done!();
+        } else {
+            pb_done_with_error!();
+        }
+        result
+    }
+}
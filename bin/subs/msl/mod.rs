@@ -1,3 +1,4 @@
+pub mod ecamassemble;
 pub mod msldate;
 pub mod mslfetch;
 pub mod msllatest;
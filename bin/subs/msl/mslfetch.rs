@@ -49,6 +49,18 @@ pub struct MslFetch {
 
     #[arg(long, short = 'n', help = "Only new images. Skipped processed images.")]
     new: bool,
+
+    #[arg(
+        long,
+        help = "Keep all versions and thumbnails rather than only the newest full frame"
+    )]
+    all_versions: bool,
+
+    #[arg(
+        long,
+        help = "Only download newer versions or full frames of images already downloaded"
+    )]
+    update: bool,
 }
 
 impl RunnableSubcommand for MslFetch {
@@ -143,6 +155,8 @@ impl RunnableSubcommand for MslFetch {
                 only_new: self.new,
                 product_types: vec![],
                 output_path: output,
+                all_versions: self.all_versions,
+                updates_only: self.update,
             },
             |total| pb_set_length!(total),
             |_| pb_inc!(),
@@ -5,6 +5,7 @@ use itertools::Itertools;
 use mars_raw_utils::metadata::Metadata;
 use mars_raw_utils::msl::fetch::MslFetch as MslFetchClient;
 use mars_raw_utils::prelude::*;
+use mars_raw_utils::productid::ProductId;
 use std::collections::HashMap;
 use std::process;
 
@@ -85,6 +86,8 @@ impl RunnableSubcommand for MslRunOn {
             only_new: false,
             product_types: vec![],
             output_path: "".to_string(),
+            all_versions: false,
+            updates_only: false,
         };
 
         let mut sequences: HashMap<String, SequenceStats> = HashMap::new();
@@ -92,10 +95,12 @@ impl RunnableSubcommand for MslRunOn {
 
         available
             .into_iter()
-            .filter(|md| md.imageid.len() >= 36)
-            .for_each(|md| {
-                let seqid = md.imageid[25..34].to_string();
-
+            .filter_map(|md| {
+                ProductId::parse(&md.imageid)
+                    .ok()
+                    .map(|product_id| (product_id.sequence_id(), md))
+            })
+            .for_each(|(seqid, md)| {
                 if let Some(ss) = sequences.get_mut(&seqid) {
                     ss.add(&md)
                 } else {
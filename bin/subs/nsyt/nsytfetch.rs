@@ -50,6 +50,18 @@ pub struct NsytFetch {
 
     #[arg(long, short = 'n', help = "Only new images. Skipped processed images.")]
     new: bool,
+
+    #[arg(
+        long,
+        help = "Keep all versions and thumbnails rather than only the newest full frame"
+    )]
+    all_versions: bool,
+
+    #[arg(
+        long,
+        help = "Only download newer versions or full frames of images already downloaded"
+    )]
+    update: bool,
 }
 
 impl RunnableSubcommand for NsytFetch {
@@ -152,6 +164,8 @@ impl RunnableSubcommand for NsytFetch {
                 only_new: self.new,
                 product_types: vec![],
                 output_path: output,
+                all_versions: self.all_versions,
+                updates_only: self.update,
             },
             cb_on_total_known,
             cb_on_image_downloaded,
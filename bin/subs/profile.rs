@@ -1,10 +1,11 @@
+use mars_raw_utils::calibfile;
 use mars_raw_utils::calprofile::*;
 
 use crate::subs::runnable::RunnableSubcommand;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use glob::glob;
-use std::env;
 use std::path::Path;
+use stump::{print_complete, CompleteStatus};
 
 use clap::Parser;
 
@@ -16,6 +17,75 @@ pub struct Profile {
 
     #[arg(long, short, help = "List available profiles")]
     list: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Show the effective profile and where each value came from"
+    )]
+    resolve: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Check profiles for unknown keys and invalid values. Checks every installed profile unless -p is given"
+    )]
+    validate: bool,
+
+    #[arg(
+        long,
+        help = "Override a profile value, e.g. red_scalar=1.2 (repeatable)",
+        num_args = 1..
+    )]
+    set: Option<Vec<String>>,
+}
+
+/// Validates one profile specification, printing its status and any problems found. Returns
+/// true if the profile is valid.
+fn validate_profile_spec(spec: &str, set: &Option<Vec<String>>) -> bool {
+    let issues = match ProfileLayers::from_spec(spec) {
+        Ok(mut layers) => {
+            let mut issues = vec![];
+            if let Some(assignments) = set {
+                for assignment in assignments.iter() {
+                    if let Err(why) = layers.set_override_from_str(assignment) {
+                        issues.push(why.to_string());
+                    }
+                }
+            }
+            issues.extend(layers.validate());
+            issues
+        }
+        Err(why) => vec![why.to_string()],
+    };
+
+    if issues.is_empty() {
+        print_complete(spec, CompleteStatus::OK);
+        true
+    } else {
+        print_complete(spec, CompleteStatus::FAIL);
+        issues.iter().for_each(|issue| println!("    {}", issue));
+        false
+    }
+}
+
+/// Validates every profile found in the profile directories, returning the number that failed
+fn validate_installed_profiles() -> usize {
+    let mut failed = 0;
+    for directory in calibfile::calibration_search_paths().iter() {
+        for file_path in glob(&format!("{}/*.toml", directory))
+            .expect("Failed to read glob pattern")
+            .flatten()
+        {
+            let file_path_str = String::from(file_path.to_str().unwrap());
+            if is_calibration_profile_file(&file_path_str)
+                && !validate_profile_spec(&file_path_str, &None)
+            {
+                failed += 1;
+            }
+        }
+    }
+    failed
 }
 
 fn print_list_header() {
@@ -46,27 +116,49 @@ fn list_profiles_in_directory(path: &str) {
     }
 }
 
+fn print_resolved_profile(spec: &str, set: &Option<Vec<String>>) -> Result<()> {
+    let mut layers = ProfileLayers::from_spec(spec)?;
+    if let Some(assignments) = set {
+        for assignment in assignments.iter() {
+            layers.set_override_from_str(assignment)?;
+        }
+    }
+    let resolved = layers.resolve()?;
+
+    println!("# Effective calibration profile for {}", spec);
+    for (field, value) in toml::Table::try_from(&resolved.profile)?.iter() {
+        let assignment = format!("{} = {}", field, value);
+        println!("{:50} # {}", assignment, resolved.source_of(field));
+    }
+    Ok(())
+}
+
 impl RunnableSubcommand for Profile {
     async fn run(&self) -> Result<()> {
         if self.list && self.profile.is_some() {
             error!("Error: Two actions specified, please only select one at a time");
         } else if self.list {
             print_list_header();
-
-            //list_profiles_in_directory("mars-raw-utils-data/caldata");
-            list_profiles_in_directory("/usr/share/mars_raw_utils/data");
-
-            if let Some(v) = option_env!("MARSDATAROOT") {
-                list_profiles_in_directory(v);
-            }
-
-            if let Some(dir) = dirs::home_dir() {
-                let homedatadir = format!("{}/.marsdata", dir.to_str().unwrap());
-                list_profiles_in_directory(homedatadir.as_str());
+            calibfile::calibration_search_paths()
+                .iter()
+                .for_each(|dir| list_profiles_in_directory(dir));
+        } else if self.validate {
+            match &self.profile {
+                Some(profile) => {
+                    if !validate_profile_spec(profile, &self.set) {
+                        return Err(anyhow!("Profile {} is invalid", profile));
+                    }
+                }
+                None => {
+                    let failed = validate_installed_profiles();
+                    if failed > 0 {
+                        return Err(anyhow!("{} invalid profile(s) found", failed));
+                    }
+                }
             }
-
-            if let Ok(dir) = env::var("MARS_RAW_DATA") {
-                list_profiles_in_directory(dir.as_str());
+        } else if let (Some(profile), true) = (&self.profile, self.resolve) {
+            if let Err(why) = print_resolved_profile(profile, &self.set) {
+                error!("Error: {}", why);
             }
         } else if let Some(profile) = self.profile.clone() {
             match load_calibration_profile(&profile) {
@@ -109,6 +201,16 @@ impl RunnableSubcommand for Profile {
                     }
                     println!("Decorrelated Color Stretch: {}", profile.decorrelate_color);
                     println!("Output Filename Suffix: {}", profile.filename_suffix);
+                    if let Some(pipeline) = profile.pipeline {
+                        println!(
+                            "Pipeline: {}",
+                            pipeline
+                                .iter()
+                                .map(|s| s.name())
+                                .collect::<Vec<&str>>()
+                                .join(" -> ")
+                        );
+                    }
                 }
                 Err(why) => {
                     error!("Error: {}", why);
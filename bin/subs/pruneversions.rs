@@ -0,0 +1,91 @@
+use crate::subs::runnable::RunnableSubcommand;
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::productid::{self, ProductId};
+use sciimg::path;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(author, version, about = "Remove images superseded by newer versions or full frames", long_about = None)]
+pub struct PruneVersions {
+    #[arg(long, short, help = "Input images", required(true), num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short = 'n', help = "List the images that would be removed")]
+    dry_run: bool,
+
+    #[arg(long, help = "Keep thumbnails even when the full frame is present")]
+    keep_thumbnails: bool,
+}
+
+/// Groups a file with the other products of its directory sharing the same processing
+/// suffix, so that a calibrated version is only compared with other calibrated versions.
+fn product_of(input_file: &str, keep_thumbnails: bool) -> Option<(String, ProductId)> {
+    let file_name = path::basename(input_file);
+    let product_id = ProductId::parse(&file_name).ok()?;
+    let suffix = file_name.get(product_id.to_string().len()..)?;
+    let dir = Path::new(input_file)
+        .parent()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let group = match keep_thumbnails {
+        true => format!("{}|{}|{}", dir, suffix, product_id.is_thumbnail()),
+        false => format!("{}|{}", dir, suffix),
+    };
+    Some((group, product_id))
+}
+
+impl PruneVersions {
+    fn remove(&self, file: &str) -> Result<()> {
+        if self.dry_run {
+            println!("Would remove {}", file);
+        } else {
+            vprintln!("Removing {}", file);
+            fs::remove_file(file)?;
+        }
+        Ok(())
+    }
+}
+
+impl RunnableSubcommand for PruneVersions {
+    async fn run(&self) -> Result<()> {
+        // Sidecars go with their images rather than being compared on their own
+        let input_files: Vec<String> = self
+            .input_files
+            .iter()
+            .map(|f| f.to_string_lossy().into_owned())
+            .filter(|f| !f.ends_with("-metadata.json"))
+            .filter(|f| {
+                let exists = path::file_exists(f);
+                if !exists {
+                    warn!("File not found: {}", f);
+                }
+                exists
+            })
+            .collect();
+
+        let (kept, superseded) =
+            productid::partition_superseded(input_files, |f| product_of(f, self.keep_thumbnails));
+
+        for file in superseded.iter() {
+            self.remove(file)?;
+
+            let metadata_file = util::replace_image_extension(file, "-metadata.json");
+            if metadata_file != *file && path::file_exists(&metadata_file) {
+                self.remove(&metadata_file)?;
+            }
+        }
+
+        info!(
+            "{} {} superseded images, kept {}",
+            if self.dry_run { "Found" } else { "Removed" },
+            superseded.len(),
+            kept.len()
+        );
+        Ok(())
+    }
+}
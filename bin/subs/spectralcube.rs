@@ -0,0 +1,91 @@
+use crate::subs::runnable::RunnableSubcommand;
+use mars_raw_utils::spectral::{self, CubeFormat, FrameInfo};
+use sciimg::path;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(author, version, about = "Assemble multispectral cubes from filter frames of a sequence", long_about = None)]
+pub struct SpectralCube {
+    #[arg(long, short, help = "Calibrated input images", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Output directory", default_value = ".")]
+    output_dir: std::path::PathBuf,
+
+    #[arg(
+        long,
+        short,
+        help = "Cube file format (envi, tiff)",
+        default_value = "envi"
+    )]
+    format: CubeFormat,
+
+    #[arg(
+        long,
+        short,
+        help = "Register frames against the first by searching up to this many pixels"
+    )]
+    register: Option<usize>,
+}
+
+impl RunnableSubcommand for SpectralCube {
+    async fn run(&self) -> Result<()> {
+        let output_dir = self.output_dir.as_os_str().to_str().unwrap();
+        if !path::file_exists(output_dir) {
+            return Err(anyhow!("Output directory not found: {}", output_dir));
+        }
+
+        let mut frames = vec![];
+        for input_file in self.input_files.iter() {
+            let input_file = input_file.as_os_str().to_str().unwrap();
+            if path::file_exists(input_file) {
+                frames.push(FrameInfo::load(input_file)?);
+            } else {
+                error!("File not found: {}", input_file);
+            }
+        }
+
+        for group in spectral::group_frames(frames).iter() {
+            vprintln!(
+                "Assembling {} from {} frames",
+                group.name,
+                group.frames.len()
+            );
+
+            let mut loaded = vec![];
+            for frame in group.frames.iter() {
+                loaded.push((frame.clone(), frame.open()?));
+            }
+
+            let cube = match spectral::assemble_cube(&group.name, &loaded, self.register) {
+                Ok(cube) => cube,
+                Err(why) => {
+                    error!("Unable to assemble {}: {}", group.name, why);
+                    continue;
+                }
+            };
+
+            let output = format!(
+                "{}/{}-cube.{}",
+                output_dir,
+                group.name,
+                self.format.extension()
+            );
+            spectral::save_cube(&cube, &output, self.format)?;
+            println!(
+                "Wrote {} ({}x{}, bands {})",
+                output,
+                cube.image.width,
+                cube.image.height,
+                cube.bands
+                    .iter()
+                    .map(|b| b.name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use crate::subs::runnable::RunnableSubcommand;
+use mars_raw_utils::spectral;
+use sciimg::path;
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(author, version, about = "Extract the mean spectrum of a region of a multispectral cube", long_about = None)]
+pub struct Spectrum {
+    #[arg(long, short, help = "Input cube (.hdr or .tif)")]
+    input_file: std::path::PathBuf,
+
+    #[arg(long, short, help = "Region as x,y,width,height")]
+    region: String,
+
+    #[arg(
+        long,
+        short,
+        help = "Output CSV file (prints to the console if omitted)"
+    )]
+    output: Option<std::path::PathBuf>,
+}
+
+fn parse_region(spec: &str) -> Result<[usize; 4]> {
+    let values: Vec<usize> = spec
+        .split(',')
+        .map(|s| s.trim().parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|why| anyhow!("Invalid region '{}': {}", spec, why))?;
+
+    match values[..] {
+        [x, y, width, height] => Ok([x, y, width, height]),
+        _ => Err(anyhow!(
+            "Invalid region '{}', expected x,y,width,height",
+            spec
+        )),
+    }
+}
+
+impl RunnableSubcommand for Spectrum {
+    async fn run(&self) -> Result<()> {
+        let input_file = self.input_file.as_os_str().to_str().unwrap();
+        if !path::file_exists(input_file) {
+            return Err(anyhow!("File not found: {}", input_file));
+        }
+
+        let cube = spectral::open_cube(input_file)?;
+        let samples = spectral::extract_spectrum(&cube, parse_region(&self.region)?)?;
+        let csv = spectral::spectrum_to_csv(&samples);
+
+        match &self.output {
+            Some(output) => {
+                let output = output.as_os_str().to_str().unwrap();
+                fs::write(output, csv)?;
+                println!("Wrote spectrum to {}", output);
+            }
+            None => print!("{}", csv),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use crate::subs::runnable::RunnableSubcommand;
+use mars_raw_utils::calprofile::load_calibration_profile;
+use mars_raw_utils::floattiff;
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::whitebalance::{self, CalTarget, DerivedProfile, Patch, PatchMeasurement};
+use sciimg::path;
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(author, version, about = "Derive a white balance profile from a calibration target image", long_about = None)]
+pub struct WhiteBalance {
+    #[arg(long, short, help = "Calibrated image of the calibration target")]
+    input_file: std::path::PathBuf,
+
+    #[arg(
+        long,
+        short,
+        help = "Calibration target patch layout, by name or path",
+        required_unless_present = "patch"
+    )]
+    target: Option<String>,
+
+    #[arg(
+        long,
+        short = 'r',
+        help = "Bounding box of the target in the image as x,y,width,height, for relative layouts"
+    )]
+    target_rect: Option<String>,
+
+    #[arg(
+        long,
+        short,
+        help = "Gray patch as x,y,width,height[,reflectance] (repeatable)",
+        num_args = 1..
+    )]
+    patch: Option<Vec<String>>,
+
+    #[arg(long, short, help = "Output profile TOML file")]
+    output: std::path::PathBuf,
+
+    #[arg(long, short, help = "Profile the derived profile extends")]
+    extends: Option<String>,
+
+    #[arg(
+        long,
+        short = 'P',
+        help = "Profile the input was calibrated with, whose color scalars the weights are composed with. Defaults to --extends"
+    )]
+    source_profile: Option<String>,
+
+    #[arg(
+        long,
+        short,
+        help = "Filename suffix for images calibrated with the profile"
+    )]
+    suffix: Option<String>,
+
+    #[arg(
+        long,
+        short = 'f',
+        help = "Restrict the profile to images taken through this filter"
+    )]
+    filter_name: Option<String>,
+
+    #[arg(
+        long,
+        help = "Also fit a color correction matrix from the target's colored patches"
+    )]
+    ccm: bool,
+}
+
+/// Parses a `--patch` value. Reflectance defaults to 1.0 since only the relative response
+/// of gray patches determines the weights.
+fn parse_patch(index: usize, spec: &str) -> Result<Patch> {
+    let values: Vec<f32> = spec
+        .split(',')
+        .map(|s| s.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|why| anyhow!("Invalid patch '{}': {}", spec, why))?;
+
+    if values.len() != 4 && values.len() != 5 {
+        return Err(anyhow!(
+            "Invalid patch '{}', expected x,y,width,height[,reflectance]",
+            spec
+        ));
+    }
+
+    Ok(Patch::gray(
+        &format!("patch{}", index + 1),
+        [
+            values[0] as usize,
+            values[1] as usize,
+            values[2] as usize,
+            values[3] as usize,
+        ],
+        values.get(4).copied().unwrap_or(1.0),
+    ))
+}
+
+/// Parses a `--target-rect` value
+fn parse_rect(spec: &str) -> Result<[usize; 4]> {
+    let values: Vec<usize> = spec
+        .split(',')
+        .map(|s| s.trim().parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|why| anyhow!("Invalid target rect '{}': {}", spec, why))?;
+    match values[..] {
+        [x, y, width, height] => Ok([x, y, width, height]),
+        _ => Err(anyhow!(
+            "Invalid target rect '{}', expected x,y,width,height",
+            spec
+        )),
+    }
+}
+
+/// Opens a 16 bit calibrated image or a floating point radiometric product
+fn open_image(input_file: &str) -> Result<MarsImage> {
+    if input_file.to_lowercase().ends_with(".tif") {
+        let image = floattiff::open_image(input_file)?;
+        let mut raw = MarsImage::from_image(&image, Instrument::None);
+        raw.metadata = MarsImage::load_image_metadata(input_file)?;
+        Ok(raw)
+    } else {
+        Ok(MarsImage::open(input_file, Instrument::None)?)
+    }
+}
+
+impl RunnableSubcommand for WhiteBalance {
+    async fn run(&self) -> Result<()> {
+        let input_file = self.input_file.as_os_str().to_str().unwrap();
+        if !path::file_exists(input_file) {
+            return Err(anyhow!("File not found: {}", input_file));
+        }
+
+        let target_rect = match &self.target_rect {
+            Some(spec) => Some(parse_rect(spec)?),
+            None => None,
+        };
+        let mut patches = match &self.target {
+            Some(target) => CalTarget::load(target)?.place(target_rect)?,
+            None => vec![],
+        };
+        if let Some(specs) = &self.patch {
+            for (index, spec) in specs.iter().enumerate() {
+                patches.push(parse_patch(index, spec)?);
+            }
+        }
+
+        let raw = open_image(input_file)?;
+
+        let measurements = patches
+            .iter()
+            .map(|patch| whitebalance::measure_patch(&raw.image, patch))
+            .collect::<Result<Vec<PatchMeasurement>>>()?;
+
+        for m in measurements.iter() {
+            println!(
+                "{:<12} R: {:>10.3}  G: {:>10.3}  B: {:>10.3}  ({} pixels{})",
+                m.patch.name,
+                m.mean[0],
+                m.mean[1],
+                m.mean[2],
+                m.pixels,
+                if m.patch.is_gray() { ", gray" } else { "" }
+            );
+        }
+
+        let weights = whitebalance::compute_weights(&measurements)?;
+        println!(
+            "Weights      R: {:.4}  G: {:.4}  B: {:.4}",
+            weights[0], weights[1], weights[2]
+        );
+
+        // The input already carries the scalars of the profile it was calibrated with
+        let profile_scalars = match self.source_profile.as_ref().or(self.extends.as_ref()) {
+            Some(source) => {
+                let source = load_calibration_profile(source)?;
+                let composed = whitebalance::compose_weights(&weights, &source);
+                println!(
+                    "Scalars      R: {:.4}  G: {:.4}  B: {:.4}",
+                    composed[0], composed[1], composed[2]
+                );
+                composed
+            }
+            None => weights,
+        };
+
+        let color_matrix = match self.ccm {
+            true => {
+                let color_matrix = whitebalance::compute_color_matrix(&measurements, &weights)?;
+                println!("Color matrix {:?}", color_matrix.matrix);
+                Some(color_matrix)
+            }
+            false => None,
+        };
+
+        // Tie the profile to the instrument that took the target image, when known
+        let instrument = Instrument::from_str(&raw.metadata.instrument).unwrap_or_default();
+        let (mission, instrument) = match instrument.mission() {
+            Some(mission) if instrument != Instrument::None => (
+                Some(format!("{:?}", mission)),
+                Some(raw.metadata.instrument.clone()),
+            ),
+            _ => (None, None),
+        };
+
+        let profile = DerivedProfile {
+            description: format!(
+                "White balance from {} ({} patches{})",
+                path::basename(input_file),
+                measurements.len(),
+                match raw.metadata.date_taken_utc.is_empty() {
+                    true => String::default(),
+                    false => format!(", taken {}", raw.metadata.date_taken_utc),
+                }
+            ),
+            extends: self.extends.clone(),
+            mission,
+            instrument,
+            filter_name: self.filter_name.clone(),
+            filename_suffix: self.suffix.clone(),
+            weights: profile_scalars,
+            color_matrix,
+        };
+
+        let output = self.output.as_os_str().to_str().unwrap();
+        fs::write(output, profile.to_toml_string()?)?;
+        println!("Wrote profile to {}", output);
+        Ok(())
+    }
+}
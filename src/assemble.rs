@@ -0,0 +1,1018 @@
+use crate::prelude::*;
+use crate::{metadata::Metadata, serializers::as_cahvore};
+
+use anyhow::{anyhow, Result};
+use sciimg::{enums::ImageMode, image::Image, prelude::CameraModel};
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+/// Width of the strips compared across the edge between tiles that abut without overlapping
+const SEAM_STRIP_WIDTH: usize = 12;
+
+/// Weight of the pull of each gain toward 1 and each offset toward 0 when solving levels
+/// globally, relative to the average seam. Keeps the solution from drifting in overall
+/// brightness and leaves tiles that touch no others unchanged.
+const GAIN_OFFSET_PRIOR: f64 = 0.001;
+
+/// Number of pyramid levels used in multi-band blending, reduced for small canvases
+const MULTIBAND_LEVELS: usize = 6;
+
+/// 5 tap binomial kernel approximating a gaussian for pyramid reduction
+const BINOMIAL: [f32; 5] = [0.0625, 0.25, 0.375, 0.25, 0.0625];
+
+/// A rectangle of pixels on the composite canvas
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TileRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl TileRect {
+    pub fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    /// The smallest rectangle covering both
+    pub fn union(&self, other: &TileRect) -> TileRect {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        TileRect {
+            x,
+            y,
+            width: self.right().max(other.right()) - x,
+            height: self.bottom().max(other.bottom()) - y,
+        }
+    }
+
+    /// The area covered by both, None if they don't overlap
+    pub fn intersection(&self, other: &TileRect) -> Option<TileRect> {
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let (right, bottom) = (
+            self.right().min(other.right()),
+            self.bottom().min(other.bottom()),
+        );
+        match right > x && bottom > y {
+            true => Some(TileRect {
+                x,
+                y,
+                width: right - x,
+                height: bottom - y,
+            }),
+            false => None,
+        }
+    }
+}
+
+/// Corresponding regions of two tiles that are compared when matching their levels, each as
+/// `[x, y, width, height]` within its own tile
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Seam {
+    pub a: [usize; 4],
+    pub b: [usize; 4],
+}
+
+/// Finds the regions of two tiles that meet. Overlapping tiles are compared over their
+/// overlap, and tiles that abut are compared over strips of up to `strip` pixels on either
+/// side of the shared edge. None for tiles that don't touch.
+pub fn seam_between(a: &TileRect, b: &TileRect, strip: usize) -> Option<Seam> {
+    if let Some(overlap) = a.intersection(b) {
+        let (width, height) = (overlap.width, overlap.height);
+        return Some(Seam {
+            a: [overlap.x - a.x, overlap.y - a.y, width, height],
+            b: [overlap.x - b.x, overlap.y - b.y, width, height],
+        });
+    }
+
+    let (x0, x1) = (a.x.max(b.x), a.right().min(b.right()));
+    let (y0, y1) = (a.y.max(b.y), a.bottom().min(b.bottom()));
+
+    // Side by side
+    if y1 > y0 && (a.right() == b.x || b.right() == a.x) {
+        let (left, right) = if a.right() == b.x { (a, b) } else { (b, a) };
+        let width = strip.min(left.width).min(right.width);
+        if width == 0 {
+            return None;
+        }
+        let left_region = [left.width - width, y0 - left.y, width, y1 - y0];
+        let right_region = [0, y0 - right.y, width, y1 - y0];
+        return Some(match a.right() == b.x {
+            true => Seam {
+                a: left_region,
+                b: right_region,
+            },
+            false => Seam {
+                a: right_region,
+                b: left_region,
+            },
+        });
+    }
+
+    // One above the other
+    if x1 > x0 && (a.bottom() == b.y || b.bottom() == a.y) {
+        let (top, bottom) = if a.bottom() == b.y { (a, b) } else { (b, a) };
+        let height = strip.min(top.height).min(bottom.height);
+        if height == 0 {
+            return None;
+        }
+        let top_region = [x0 - top.x, top.height - height, x1 - x0, height];
+        let bottom_region = [x0 - bottom.x, 0, x1 - x0, height];
+        return Some(match a.bottom() == b.y {
+            true => Seam {
+                a: top_region,
+                b: bottom_region,
+            },
+            false => Seam {
+                a: bottom_region,
+                b: top_region,
+            },
+        });
+    }
+
+    None
+}
+
+/// Maps a camera model from a tile's pixel grid to the composite's, where tile pixel (x, y)
+/// lands at (x * scale + offset[0], y * scale + offset[1]). A CAHV family model projects to
+/// x = (P - C)·H / (P - C)·A, so this is H' = scale * H + offset[0] * A, and likewise for V.
+/// The distortion terms are unaffected.
+pub fn transform_camera_model(model: &CameraModel, scale: f64, offset: [f64; 2]) -> CameraModel {
+    if !model.is_valid() {
+        return model.clone();
+    }
+
+    let mut parts = as_cahvore::components(&model.serialize());
+    if parts.len() < 4 || parts[1..4].iter().any(|p| p.len() != 3) {
+        warn!("Unable to adjust camera model for the composite, leaving it unchanged");
+        return model.clone();
+    }
+
+    let a = parts[1].clone();
+    for (i, offset) in [(2, offset[0]), (3, offset[1])] {
+        parts[i] = parts[i]
+            .iter()
+            .zip(a.iter())
+            .map(|(h, a)| h * scale + a * offset)
+            .collect();
+    }
+    as_cahvore::from_components(&parts)
+}
+
+/// A subframed image, placed on the sensor by its metadata `subframe_rect` and `scale_factor`
+#[derive(Clone)]
+pub struct Tile {
+    pub image: MarsImage,
+}
+
+impl Tile {
+    /// Constructs a new `Tile` by opening the image and assigning an instrument.
+    pub fn new_from_file(file_path: &str, instrument: Instrument) -> Result<Self, CalError> {
+        Ok(Tile {
+            image: MarsImage::open(file_path, instrument)?,
+        })
+    }
+
+    /// Constructs a new `Tile` with an existing instance of `MarsImage`.
+    pub fn new_with_image(image: &MarsImage) -> Self {
+        Tile {
+            image: image.clone(),
+        }
+    }
+
+    /// Returns the scale factor in the metadata `scale_factor` field
+    pub fn get_scale_factor(&self) -> u32 {
+        if self.image.metadata.scale_factor >= 1 {
+            self.image.metadata.scale_factor
+        } else {
+            1
+        }
+    }
+
+    /// Returns the subframe coordinates from the metadata `subframe_rect` field
+    pub fn get_subframe_region(&self) -> Option<Vec<f64>> {
+        match &self.image.metadata.subframe_rect {
+            Some(sf) if sf.len() == 4 => Some(sf.clone()),
+            _ => None,
+        }
+    }
+
+    /// The tile's top left corner on the sensor in full resolution pixels, from its 1-based
+    /// subframe
+    pub fn get_sensor_origin(&self) -> Option<[f64; 2]> {
+        let sf = self.get_subframe_region()?;
+        Some([(sf[0] - 1.0).max(0.0), (sf[1] - 1.0).max(0.0)])
+    }
+
+    /// How much the tile is enlarged by when brought to a composite scale factor
+    pub fn resample_factor(&self, scale: u32) -> f64 {
+        self.get_scale_factor() as f64 / scale as f64
+    }
+
+    /// The tile's position on the sensor at a composite scale factor, with its size once
+    /// resampled to that scale factor
+    pub fn get_tile_rect(&self, scale: u32) -> Option<TileRect> {
+        let [x, y] = self.get_sensor_origin()?;
+        let k = self.resample_factor(scale);
+        Some(TileRect {
+            x: (x / scale as f64).round() as usize,
+            y: (y / scale as f64).round() as usize,
+            width: (self.image.image.width as f64 * k).round() as usize,
+            height: (self.image.image.height as f64 * k).round() as usize,
+        })
+    }
+}
+
+/// How a set of tiles is assembled
+#[derive(Debug, Clone, Default)]
+pub struct AssemblyOptions {
+    /// Scale factor of the composite, the finest among the tiles when not given
+    pub scale: Option<u32>,
+
+    /// Crop the canvas to the area covered by the tiles rather than the full sensor
+    pub crop_to_tiles: bool,
+
+    /// How overlapping tiles are combined
+    pub blend: BlendMode,
+}
+
+/// A set of tiles laid out on a common canvas
+pub struct Assembly {
+    pub instrument: Instrument,
+
+    /// Scale factor of the composite
+    pub scale: u32,
+
+    /// The canvas, at the composite's scale factor, relative to the sensor's top left
+    pub canvas: TileRect,
+
+    /// The tiles resampled to the composite's scale factor and trimmed to the canvas, in the
+    /// order they are pasted. Coarser tiles come first so that finer ones cover them.
+    pub tiles: Vec<Tile>,
+
+    /// Where each tile sits on the canvas
+    pub rects: Vec<TileRect>,
+
+    /// How overlapping tiles are combined
+    pub blend: BlendMode,
+
+    /// Metadata for the composite
+    pub metadata: Metadata,
+}
+
+impl Assembly {
+    /// Lays out tiles on a canvas covering the instrument's sensor at the composite's scale
+    /// factor, or just the area the tiles cover when the sensor size isn't known or
+    /// `crop_to_tiles` is set. Tiles at other scale factors are resampled to the composite's.
+    /// Returns the assembly along with the tiles that couldn't be placed: those without a
+    /// subframe, of a different number of bands, or entirely off the canvas.
+    pub fn new(
+        tiles: Vec<Tile>,
+        instrument: Instrument,
+        options: &AssemblyOptions,
+    ) -> Result<(Self, Vec<Tile>)> {
+        let (mut placeable, mut discarded): (Vec<Tile>, Vec<Tile>) = tiles
+            .into_iter()
+            .partition(|t| t.get_sensor_origin().is_some());
+        if placeable.is_empty() {
+            return Err(anyhow!("No tiles with a subframe to assemble"));
+        }
+
+        let scale = match options.scale {
+            Some(0) => return Err(anyhow!("Invalid composite scale factor of 0")),
+            Some(scale) => scale,
+            None => placeable
+                .iter()
+                .map(|t| t.get_scale_factor())
+                .min()
+                .unwrap_or(1),
+        };
+
+        // The finest, largest tile provides the composite's metadata and band count
+        let reference = placeable
+            .iter()
+            .enumerate()
+            .min_by_key(|(i, t)| {
+                (
+                    t.get_scale_factor(),
+                    Reverse(t.image.image.width * t.image.image.height),
+                    *i,
+                )
+            })
+            .map(|(_, t)| t.clone())
+            .ok_or_else(|| anyhow!("No tiles to assemble"))?;
+        let num_bands = reference.image.image.num_bands();
+
+        let (same_bands, other_bands): (Vec<Tile>, Vec<Tile>) = placeable
+            .into_iter()
+            .partition(|t| t.image.image.num_bands() == num_bands);
+        placeable = same_bands;
+        discarded.extend(other_bands);
+
+        // Coarser tiles are pasted first. The sort is stable, so tiles of the same scale
+        // factor keep their input order.
+        placeable.sort_by_key(|t| Reverse(t.get_scale_factor()));
+
+        let placed = placeable
+            .iter()
+            .map(|t| t.get_tile_rect(scale))
+            .collect::<Option<Vec<TileRect>>>()
+            .ok_or_else(|| anyhow!("Tile without a subframe"))?;
+        let bounds = placed
+            .iter()
+            .skip(1)
+            .fold(placed[0], |bounds, rect| bounds.union(rect));
+
+        let sensor = instrument.sensor_size().map(|(width, height)| TileRect {
+            x: 0,
+            y: 0,
+            width: (width + scale as usize - 1) / scale as usize,
+            height: (height + scale as usize - 1) / scale as usize,
+        });
+        let canvas = match sensor {
+            Some(sensor) if options.crop_to_tiles => sensor
+                .intersection(&bounds)
+                .ok_or_else(|| anyhow!("Tiles fall outside of the sensor frame"))?,
+            Some(sensor) => sensor,
+            None => {
+                vprintln!(
+                    "Sensor size of {:?} is unknown, sizing the canvas to the tiles",
+                    instrument
+                );
+                bounds
+            }
+        };
+
+        let mut tiles = vec![];
+        let mut rects = vec![];
+        for (mut tile, rect) in placeable.into_iter().zip(placed.into_iter()) {
+            let visible = match rect.intersection(&canvas) {
+                Some(visible) => visible,
+                None => {
+                    discarded.push(tile);
+                    continue;
+                }
+            };
+
+            if rect.width != tile.image.image.width || rect.height != tile.image.image.height {
+                vprintln!(
+                    "Resampling {:?} from scale factor {} to {}",
+                    tile.image.file_path,
+                    tile.get_scale_factor(),
+                    scale
+                );
+                tile.image.resize_to(rect.width, rect.height);
+            }
+            if visible != rect {
+                tile.image.image.crop(
+                    visible.x - rect.x,
+                    visible.y - rect.y,
+                    visible.width,
+                    visible.height,
+                );
+            }
+
+            tiles.push(tile);
+            rects.push(TileRect {
+                x: visible.x - canvas.x,
+                y: visible.y - canvas.y,
+                width: visible.width,
+                height: visible.height,
+            });
+        }
+
+        let metadata = Assembly::composite_metadata(&reference, scale, &canvas);
+
+        Ok((
+            Assembly {
+                instrument,
+                scale,
+                canvas,
+                tiles,
+                rects,
+                blend: options.blend,
+                metadata,
+            },
+            discarded,
+        ))
+    }
+
+    /// The reference tile's metadata with its subframe, scale factor and camera model
+    /// describing the composite. Tile camera models are taken to describe each tile's own
+    /// pixel grid, as they do in the raw image metadata.
+    fn composite_metadata(reference: &Tile, scale: u32, canvas: &TileRect) -> Metadata {
+        let mut metadata = reference.image.metadata.clone();
+        let s = scale as f64;
+
+        metadata.subframe_rect = Some(vec![
+            canvas.x as f64 * s + 1.0,
+            canvas.y as f64 * s + 1.0,
+            canvas.width as f64 * s,
+            canvas.height as f64 * s,
+        ]);
+        metadata.scale_factor = scale;
+
+        if let Some([x, y]) = reference.get_sensor_origin() {
+            metadata.camera_model_component_list = transform_camera_model(
+                &metadata.camera_model_component_list,
+                reference.resample_factor(scale),
+                [x / s - canvas.x as f64, y / s - canvas.y as f64],
+            );
+        }
+        metadata
+    }
+
+    /// Where each tile's pixels sit on the canvas, in pasting order
+    fn placements(&self) -> Vec<(&Image, TileRect)> {
+        self.tiles
+            .iter()
+            .zip(self.rects.iter())
+            .map(|(tile, rect)| (&tile.image.image, *rect))
+            .collect()
+    }
+
+    /// Matches the levels of tiles that overlap or abut with a gain and offset on each band,
+    /// solved over every seam at once. See `solve_gain_offset`.
+    pub fn match_levels(&mut self) {
+        let adjustments = solve_gain_offset(&self.placements());
+        for (tile, adjustment) in self.tiles.iter_mut().zip(adjustments.iter()) {
+            vprintln!(
+                "Adjusting tile {:?} by {:?}",
+                tile.image.file_path,
+                adjustment
+            );
+            apply_adjustments(&mut tile.image.image, adjustment);
+        }
+    }
+
+    /// Combines the tiles on the canvas with the assembly's blend mode
+    pub fn composite(&self) -> Result<Image> {
+        vprintln!(
+            "Composite has width {}px, height {}px, and scale factor of {}, with {:?} blending",
+            self.canvas.width,
+            self.canvas.height,
+            self.scale,
+            self.blend
+        );
+        blend_tiles(
+            &self.placements(),
+            self.canvas.width,
+            self.canvas.height,
+            self.blend,
+        )
+    }
+
+    /// Composites the tiles, normalizes the result and saves it to disk along with its
+    /// metadata
+    pub fn finalize_and_save(&self, output_path: &str) -> Result<()> {
+        let mut image = self.composite()?;
+        image.normalize_to_8bit();
+        image.save(output_path)?;
+        util::save_image_json(output_path, &self.metadata, None)
+    }
+}
+
+/// How tiles are combined where they overlap
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum BlendMode {
+    /// Each tile is pasted over those before it
+    #[default]
+    Hard,
+
+    /// Overlapping tiles are averaged, weighted by the distance to each tile's edge
+    Feather,
+
+    /// Overlapping tiles are blended over a transition that widens with spatial scale, so
+    /// broad gradients are carried across seams without blurring fine detail
+    MultiBand,
+}
+
+impl FromStr for BlendMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "hard" => Ok(BlendMode::Hard),
+            "feather" => Ok(BlendMode::Feather),
+            "multiband" | "multi-band" => Ok(BlendMode::MultiBand),
+            _ => Err(anyhow!(
+                "Invalid blend mode '{}', expected 'hard', 'feather' or 'multiband'",
+                s
+            )),
+        }
+    }
+}
+
+/// A linear adjustment of one band of a tile, applied as `value * gain + offset`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LevelAdjustment {
+    pub gain: f32,
+    pub offset: f32,
+}
+
+impl Default for LevelAdjustment {
+    fn default() -> Self {
+        LevelAdjustment {
+            gain: 1.0,
+            offset: 0.0,
+        }
+    }
+}
+
+/// Mean, standard deviation and pixel count of a `[x, y, width, height]` region of a band
+fn region_stats(image: &Image, band: usize, region: &[usize; 4]) -> (f64, f64, usize) {
+    let buffer = image.get_band(band.min(image.num_bands() - 1));
+    let mut values = vec![];
+    for y in region[1]..(region[1] + region[3]).min(image.height) {
+        for x in region[0]..(region[0] + region[2]).min(image.width) {
+            values.push(buffer.get(x, y) as f64);
+        }
+    }
+    if values.is_empty() {
+        return (0.0, 0.0, 0);
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    (mean, variance.sqrt(), values.len())
+}
+
+/// Adds a weighted residual `Σ coefficient * x[index] - target` to the normal equations
+fn add_residual(
+    normal: &mut [Vec<f64>],
+    rhs: &mut [f64],
+    terms: &[(usize, f64)],
+    target: f64,
+    weight: f64,
+) {
+    for (p, vp) in terms.iter() {
+        for (q, vq) in terms.iter() {
+            normal[*p][*q] += weight * vp * vq;
+        }
+        rhs[*p] += weight * vp * target;
+    }
+}
+
+/// Solves `a·x = b` by gaussian elimination with partial pivoting. None if `a` is singular.
+fn solve_linear(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let (pivot_rows, rows) = a.split_at_mut(col + 1);
+        let (pivot_b, rest_b) = b.split_at_mut(col + 1);
+        let pivot_row = &pivot_rows[col];
+        for (row, rhs) in rows.iter_mut().zip(rest_b.iter_mut()) {
+            let factor = row[col] / pivot_row[col];
+            if factor == 0.0 {
+                continue;
+            }
+            row.iter_mut()
+                .zip(pivot_row.iter())
+                .skip(col)
+                .for_each(|(v, p)| *v -= factor * p);
+            *rhs -= factor * pivot_b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum = ((row + 1)..n).map(|k| a[row][k] * x[k]).sum::<f64>();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Solves for a gain and offset on each band of every tile that together bring the mean
+/// and spread of every pair of overlapping or abutting tiles into agreement. All seams are
+/// weighed at once by least squares, weighted by their size, rather than matching tiles
+/// pairwise along a chain, so differences aren't accumulated from one tile to the next.
+/// Tiles are given as their pixels and where they sit on the canvas. Returns the
+/// adjustments for each tile, one per band.
+pub fn solve_gain_offset(tiles: &[(&Image, TileRect)]) -> Vec<Vec<LevelAdjustment>> {
+    let n = tiles.len();
+    let num_bands = tiles
+        .iter()
+        .map(|(image, _)| image.num_bands())
+        .max()
+        .unwrap_or(0);
+    let mut adjustments = vec![vec![LevelAdjustment::default(); num_bands]; n];
+
+    let mut seams = vec![];
+    for (i, (_, a)) in tiles.iter().enumerate() {
+        for (j, (_, b)) in tiles.iter().enumerate().skip(i + 1) {
+            if let Some(seam) = seam_between(a, b, SEAM_STRIP_WIDTH) {
+                seams.push((i, j, seam));
+            }
+        }
+    }
+    if seams.is_empty() {
+        vprintln!("No tiles overlap or abut, levels left unmatched");
+        return adjustments;
+    }
+
+    for band in 0..num_bands {
+        let stats = seams
+            .iter()
+            .map(|(i, j, seam)| {
+                (
+                    region_stats(tiles[*i].0, band, &seam.a),
+                    region_stats(tiles[*j].0, band, &seam.b),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Values are normalized by the average seam level so that gains and offsets are
+        // of a similar magnitude in the solve
+        let level = stats.iter().map(|(a, b)| a.0 + b.0).sum::<f64>() / (2 * stats.len()) as f64;
+        if level <= 0.0 {
+            continue;
+        }
+
+        // Unknowns are interleaved as [gain_0, offset_0, gain_1, offset_1, ...]
+        let mut normal = vec![vec![0.0; 2 * n]; 2 * n];
+        let mut rhs = vec![0.0; 2 * n];
+        let mut total_weight = 0.0;
+        for ((i, j, _), (a, b)) in seams.iter().zip(stats.iter()) {
+            let weight = a.2.min(b.2) as f64;
+            total_weight += weight;
+            add_residual(
+                &mut normal,
+                &mut rhs,
+                &[
+                    (2 * i, a.0 / level),
+                    (2 * i + 1, 1.0),
+                    (2 * j, -b.0 / level),
+                    (2 * j + 1, -1.0),
+                ],
+                0.0,
+                weight,
+            );
+            add_residual(
+                &mut normal,
+                &mut rhs,
+                &[(2 * i, a.1 / level), (2 * j, -b.1 / level)],
+                0.0,
+                weight,
+            );
+        }
+
+        let prior = GAIN_OFFSET_PRIOR * total_weight / n as f64;
+        for i in 0..n {
+            add_residual(&mut normal, &mut rhs, &[(2 * i, 1.0)], 1.0, prior);
+            add_residual(&mut normal, &mut rhs, &[(2 * i + 1, 1.0)], 0.0, prior);
+        }
+
+        match solve_linear(normal, rhs) {
+            Some(x) => (0..n).for_each(|i| {
+                adjustments[i][band] = LevelAdjustment {
+                    gain: x[2 * i] as f32,
+                    offset: (x[2 * i + 1] * level) as f32,
+                }
+            }),
+            None => warn!(
+                "Unable to solve levels for band {}, leaving it unmatched",
+                band
+            ),
+        }
+    }
+    adjustments
+}
+
+/// Applies a gain and offset to each band of an image, clamping at zero
+pub fn apply_adjustments(image: &mut Image, adjustments: &[LevelAdjustment]) {
+    adjustments
+        .iter()
+        .enumerate()
+        .take(image.num_bands())
+        .for_each(|(b, adjustment)| {
+            if *adjustment == LevelAdjustment::default() {
+                return;
+            }
+            for y in 0..image.height {
+                for x in 0..image.width {
+                    let value = image.get_band(b).get(x, y);
+                    image.put(
+                        x,
+                        y,
+                        (value * adjustment.gain + adjustment.offset).max(0.0),
+                        b,
+                    );
+                }
+            }
+        });
+}
+
+/// A single band working buffer for blending
+#[derive(Clone)]
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        Plane {
+            width,
+            height,
+            data: vec![0.0; width * height],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    /// Blurs with the binomial kernel and keeps every other row and column
+    fn reduce(&self) -> Plane {
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let clamp = |v: isize, size: usize| v.clamp(0, size as isize - 1) as usize;
+
+        let mut rows = Plane::new(width, self.height);
+        for y in 0..self.height {
+            for x in 0..width {
+                rows.data[y * width + x] = BINOMIAL
+                    .iter()
+                    .enumerate()
+                    .map(|(i, k)| {
+                        k * self.get(clamp(2 * x as isize + i as isize - 2, self.width), y)
+                    })
+                    .sum();
+            }
+        }
+
+        let mut reduced = Plane::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                reduced.data[y * width + x] = BINOMIAL
+                    .iter()
+                    .enumerate()
+                    .map(|(i, k)| {
+                        k * rows.get(x, clamp(2 * y as isize + i as isize - 2, self.height))
+                    })
+                    .sum();
+            }
+        }
+        reduced
+    }
+
+    /// Enlarges to the given size, pixel (x, y) interpolating this plane at (x / 2, y / 2)
+    fn expand(&self, width: usize, height: usize) -> Plane {
+        let mut expanded = Plane::new(width, height);
+        for y in 0..height {
+            let (y0, fy) = (y / 2, (y % 2) as f32 * 0.5);
+            let (y0, y1) = (y0.min(self.height - 1), (y0 + 1).min(self.height - 1));
+            for x in 0..width {
+                let (x0, fx) = (x / 2, (x % 2) as f32 * 0.5);
+                let (x0, x1) = (x0.min(self.width - 1), (x0 + 1).min(self.width - 1));
+                let top = self.get(x0, y0) * (1.0 - fx) + self.get(x1, y0) * fx;
+                let bottom = self.get(x0, y1) * (1.0 - fx) + self.get(x1, y1) * fx;
+                expanded.data[y * width + x] = top * (1.0 - fy) + bottom * fy;
+            }
+        }
+        expanded
+    }
+
+    /// The plane followed by `levels` successive reductions
+    fn gaussian_pyramid(self, levels: usize) -> Vec<Plane> {
+        let mut pyramid = vec![self];
+        for _ in 0..levels {
+            let next = pyramid[pyramid.len() - 1].reduce();
+            pyramid.push(next);
+        }
+        pyramid
+    }
+
+    /// The detail lost at each level of a gaussian pyramid, ending with its coarsest level
+    fn laplacian_pyramid(self, levels: usize) -> Vec<Plane> {
+        let gaussian = self.gaussian_pyramid(levels);
+        (0..=levels)
+            .map(|k| match k < levels {
+                true => {
+                    let mut detail = gaussian[k].clone();
+                    let coarse = gaussian[k + 1].expand(detail.width, detail.height);
+                    detail
+                        .data
+                        .iter_mut()
+                        .zip(coarse.data.iter())
+                        .for_each(|(d, c)| *d -= c);
+                    detail
+                }
+                false => gaussian[k].clone(),
+            })
+            .collect()
+    }
+}
+
+/// Distance of a tile pixel to the nearest edge of the tile, counting the edge pixels as 1
+fn feather_weight(x: usize, y: usize, width: usize, height: usize) -> f32 {
+    (x + 1).min(width - x).min(y + 1).min(height - y) as f32
+}
+
+/// Combines tiles onto a canvas of the given size. Tiles are given as their pixels and
+/// where they sit on the canvas, and are expected to lie within it. Pixels that no tile
+/// covers are left at zero.
+pub fn blend_tiles(
+    tiles: &[(&Image, TileRect)],
+    width: usize,
+    height: usize,
+    mode: BlendMode,
+) -> Result<Image> {
+    let num_bands = tiles
+        .iter()
+        .map(|(image, _)| image.num_bands())
+        .max()
+        .ok_or_else(|| anyhow!("No tiles to blend"))?;
+    let mut image = Image::new_with_bands(width, height, num_bands, ImageMode::U16BIT)?;
+
+    match mode {
+        BlendMode::Hard => tiles
+            .iter()
+            .for_each(|(tile, rect)| image.paste(tile, rect.x, rect.y)),
+        BlendMode::Feather => feather_blend(&mut image, tiles),
+        BlendMode::MultiBand => multiband_blend(&mut image, tiles),
+    }
+    Ok(image)
+}
+
+/// Each tile's pixels clipped to a canvas of the given size, as `(canvas x, canvas y, tile
+/// x, tile y)`
+fn covered_pixels(
+    rect: &TileRect,
+    width: usize,
+    height: usize,
+) -> impl Iterator<Item = (usize, usize, usize, usize)> {
+    let rect = *rect;
+    (rect.y..rect.bottom().min(height)).flat_map(move |y| {
+        (rect.x..rect.right().min(width)).map(move |x| (x, y, x - rect.x, y - rect.y))
+    })
+}
+
+fn feather_blend(image: &mut Image, tiles: &[(&Image, TileRect)]) {
+    let (width, height, num_bands) = (image.width, image.height, image.num_bands());
+    let mut sums = vec![Plane::new(width, height); num_bands];
+    let mut weights = Plane::new(width, height);
+
+    for (tile, rect) in tiles.iter() {
+        for (x, y, tx, ty) in covered_pixels(rect, width, height) {
+            let weight = feather_weight(tx, ty, rect.width, rect.height);
+            weights.data[y * width + x] += weight;
+            for (b, sum) in sums.iter_mut().enumerate() {
+                let band = b.min(tile.num_bands() - 1);
+                sum.data[y * width + x] += weight * tile.get_band(band).get(tx, ty);
+            }
+        }
+    }
+
+    for y in 0..height {
+        for x in 0..width {
+            let weight = weights.get(x, y);
+            if weight > 0.0 {
+                for (b, sum) in sums.iter().enumerate() {
+                    image.put(x, y, sum.get(x, y) / weight, b);
+                }
+            }
+        }
+    }
+}
+
+/// Blends each level of the tiles' laplacian pyramids with a correspondingly smoothed mask
+/// of which tile is nearest to each pixel, then collapses the blended pyramid. Tiles are
+/// extended past their edges by repeating their edge pixels, so the coarse levels, whose
+/// masks reach further, have values to blend.
+fn multiband_blend(image: &mut Image, tiles: &[(&Image, TileRect)]) {
+    let (width, height, num_bands) = (image.width, image.height, image.num_bands());
+
+    let mut levels = MULTIBAND_LEVELS;
+    while levels > 0 && (width.min(height) >> levels) < 8 {
+        levels -= 1;
+    }
+
+    // Each pixel goes to the tile it's deepest within. Ties go to the later tile, as they
+    // do when pasting.
+    let mut owner: Vec<Option<usize>> = vec![None; width * height];
+    let mut depth = Plane::new(width, height);
+    for (i, (_, rect)) in tiles.iter().enumerate() {
+        for (x, y, tx, ty) in covered_pixels(rect, width, height) {
+            let weight = feather_weight(tx, ty, rect.width, rect.height);
+            if weight >= depth.data[y * width + x] {
+                depth.data[y * width + x] = weight;
+                owner[y * width + x] = Some(i);
+            }
+        }
+    }
+
+    let mut sizes = vec![(width, height)];
+    for _ in 0..levels {
+        let (w, h) = sizes[sizes.len() - 1];
+        sizes.push((w.div_ceil(2), h.div_ceil(2)));
+    }
+    let new_pyramid = || {
+        sizes
+            .iter()
+            .map(|(w, h)| Plane::new(*w, *h))
+            .collect::<Vec<Plane>>()
+    };
+    let mut sums = (0..num_bands).map(|_| new_pyramid()).collect::<Vec<_>>();
+    let mut weights = new_pyramid();
+
+    // Each tile is worked over its rectangle padded far enough for its coarsest mask and
+    // aligned to the coarsest level's grid so its pyramid lines up with the canvas's
+    let unit = 1 << levels;
+    let margin = 2 * unit;
+    for (i, (tile, rect)) in tiles.iter().enumerate() {
+        if rect.width == 0 || rect.height == 0 {
+            continue;
+        }
+        let (x0, y0) = (
+            rect.x.saturating_sub(margin) / unit * unit,
+            rect.y.saturating_sub(margin) / unit * unit,
+        );
+        let (x1, y1) = (
+            (rect.right() + margin).min(width),
+            (rect.bottom() + margin).min(height),
+        );
+        if x1 <= x0 || y1 <= y0 {
+            continue;
+        }
+        let (region_width, region_height) = (x1 - x0, y1 - y0);
+
+        let mut mask = Plane::new(region_width, region_height);
+        for y in 0..region_height {
+            for x in 0..region_width {
+                if owner[(y0 + y) * width + x0 + x] == Some(i) {
+                    mask.data[y * region_width + x] = 1.0;
+                }
+            }
+        }
+        let masks = mask.gaussian_pyramid(levels);
+
+        let accumulate = |sum: &mut Vec<Plane>, planes: &[Plane], weighted: bool| {
+            for k in 0..=levels {
+                let (ox, oy) = (x0 >> k, y0 >> k);
+                let level = &mut sum[k];
+                for y in 0..planes[k].height.min(level.height - oy) {
+                    for x in 0..planes[k].width.min(level.width - ox) {
+                        let value = match weighted {
+                            true => planes[k].get(x, y) * masks[k].get(x, y),
+                            false => planes[k].get(x, y),
+                        };
+                        level.data[(oy + y) * level.width + ox + x] += value;
+                    }
+                }
+            }
+        };
+        accumulate(&mut weights, &masks, false);
+
+        for (b, sum) in sums.iter_mut().enumerate() {
+            let band = tile.get_band(b.min(tile.num_bands() - 1));
+            let mut values = Plane::new(region_width, region_height);
+            for y in 0..region_height {
+                let ty = (y0 + y).clamp(rect.y, rect.bottom() - 1) - rect.y;
+                for x in 0..region_width {
+                    let tx = (x0 + x).clamp(rect.x, rect.right() - 1) - rect.x;
+                    values.data[y * region_width + x] = band.get(tx, ty);
+                }
+            }
+            accumulate(sum, &values.laplacian_pyramid(levels), true);
+        }
+    }
+
+    for (b, sum) in sums.into_iter().enumerate() {
+        let mut collapsed: Option<Plane> = None;
+        for (k, mut level) in sum.into_iter().enumerate().rev() {
+            level
+                .data
+                .iter_mut()
+                .zip(weights[k].data.iter())
+                .for_each(|(v, w)| *v = if *w > 1e-6 { *v / w } else { 0.0 });
+            if let Some(coarse) = collapsed {
+                let coarse = coarse.expand(level.width, level.height);
+                level
+                    .data
+                    .iter_mut()
+                    .zip(coarse.data.iter())
+                    .for_each(|(v, c)| *v += c);
+            }
+            collapsed = Some(level);
+        }
+
+        if let Some(blended) = collapsed {
+            for y in 0..height {
+                for x in 0..width {
+                    if owner[y * width + x].is_some() {
+                        image.put(x, y, blended.get(x, y).max(0.0), b);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{
+    calibfile::{self, BiasLevel, InstrumentProperties},
+    enums::Instrument,
+    marsimage::{self, MarsImage},
+    memcache::load_image,
+    metadata::Metadata,
+};
+
+use anyhow::{anyhow, Result};
+use sciimg::image::Image;
+
+/// Bias levels used before they could be configured in caldata.toml. Kept so that
+/// calibration data without a `bias` entry continues to give the same results.
+fn legacy_bias_level(instrument: Instrument) -> Option<f32> {
+    match instrument {
+        Instrument::M20NavcamLeft => Some(133.0),
+        Instrument::M20NavcamRight => Some(132.0),
+        Instrument::M20FrontHazLeft => Some(151.0),
+        Instrument::M20FrontHazRight => Some(148.0),
+        Instrument::M20RearHazLeft => Some(118.0),
+        Instrument::M20RearHazRight => Some(132.0),
+        _ => None,
+    }
+}
+
+/// The bias configured for the instrument in caldata.toml, falling back to the fixed level
+/// previously built in for the instrument, if any
+pub fn bias_for_instrument(instrument: Instrument) -> Option<BiasLevel> {
+    calibfile::get_instrument_properties(instrument)
+        .ok()
+        .and_then(|properties| properties.bias)
+        .or_else(|| legacy_bias_level(instrument).map(BiasLevel::Constant))
+}
+
+/// True if a dark frame is configured for the instrument
+pub fn has_dark_frame(instrument: Instrument) -> bool {
+    match calibfile::get_instrument_properties(instrument) {
+        Ok(properties) => !properties.dark.is_empty(),
+        Err(_) => false,
+    }
+}
+
+/// The factor the dark frame is multiplied by before subtraction. Dark current is assumed to
+/// scale linearly with exposure and to double with every `dark_doubling_temperature` degrees.
+/// Either correction is skipped when the dark frame's or the image's value is unknown.
+pub fn dark_scale_factor(properties: &InstrumentProperties, metadata: &Metadata) -> f32 {
+    let exposure_scale = match (properties.dark_exposure, metadata.exposure_duration) {
+        (Some(dark_exposure), Some(exposure)) if dark_exposure > 0.0 => exposure / dark_exposure,
+        _ => 1.0,
+    };
+
+    let temperature_scale = match (properties.dark_temperature, metadata.detector_temperature) {
+        (Some(dark_temperature), Some(temperature))
+            if properties.dark_doubling_temperature > 0.0 =>
+        {
+            2.0_f64.powf((temperature - dark_temperature) / properties.dark_doubling_temperature)
+        }
+        _ => 1.0,
+    };
+
+    (exposure_scale * temperature_scale) as f32
+}
+
+/// The dark frame scale factor for an image from the instrument, or 1.0 if the instrument
+/// has no calibration data.
+pub fn dark_scale_factor_for_instrument(instrument: Instrument, metadata: &Metadata) -> f32 {
+    match calibfile::get_instrument_properties(instrument) {
+        Ok(properties) => dark_scale_factor(&properties, metadata),
+        Err(_) => 1.0,
+    }
+}
+
+/// Loads a bias or dark frame, cropping full sensor frames to the image's subframe
+pub fn load_frame(file_path: &str, raw: &MarsImage) -> Result<Image> {
+    let mut frame = load_image(file_path)?;
+
+    if frame.width != raw.image.width || frame.height != raw.image.height {
+        if let Some(rect) = &raw.metadata.subframe_rect {
+            let [x, y, width, height] =
+                marsimage::subframe_region(raw.instrument, rect, 1, frame.width, frame.height)?;
+            info!(
+                "Cropping frame with x/y/width/height: {},{} {}x{}",
+                x, y, width, height
+            );
+            frame.crop(x, y, width, height);
+        }
+    }
+
+    if frame.width != raw.image.width || frame.height != raw.image.height {
+        Err(anyhow!(
+            "Frame {} is {}x{} but the image is {}x{}",
+            file_path,
+            frame.width,
+            frame.height,
+            raw.image.width,
+            raw.image.height
+        ))
+    } else {
+        Ok(frame)
+    }
+}
+
+/// Subtracts `frame`, multiplied by `scale`, from every band of the image, clamping at zero.
+/// A single band frame is subtracted from each band of a color image.
+pub fn subtract_frame(image: &mut Image, frame: &Image, scale: f32) -> Result<()> {
+    if frame.width != image.width || frame.height != image.height {
+        return Err(anyhow!(
+            "Frame is {}x{} but the image is {}x{}",
+            frame.width,
+            frame.height,
+            image.width,
+            image.height
+        ));
+    }
+
+    for band in 0..image.num_bands() {
+        let frame_band = frame.get_band(band.min(frame.num_bands() - 1));
+        let mut buffer = image.get_band(band).clone();
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let value = buffer.get(x, y) - frame_band.get(x, y) * scale;
+                buffer.put(x, y, value.max(0.0));
+            }
+        }
+        image.set_band(&buffer, band);
+    }
+    Ok(())
+}
@@ -1,9 +1,11 @@
 use crate::enums::CalFileType;
+use crate::error::CalError;
 use crate::{constants, enums};
 use anyhow::anyhow;
 use anyhow::Result;
 use dirs;
 use sciimg::path;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
@@ -15,15 +17,46 @@ fn default_blank() -> String {
     "".to_string()
 }
 
+fn default_dark_doubling_temperature() -> f64 {
+    6.0
+}
+
 fn default_instrument_properties() -> InstrumentProperties {
     InstrumentProperties {
         flat: default_blank(),
+        filter_flats: BTreeMap::new(),
         inpaint_mask: default_blank(),
         mask: default_blank(),
         lut: default_blank(),
+        bias: None,
+        dark: default_blank(),
+        dark_exposure: None,
+        dark_temperature: None,
+        dark_doubling_temperature: default_dark_doubling_temperature(),
+        responsivity: BTreeMap::new(),
+        solar_irradiance: BTreeMap::new(),
+        color_matrix: None,
+        color_offset: None,
     }
 }
 
+/// Sensor bias for an instrument, either a constant level in DN or the file name of a
+/// per-pixel bias frame:
+///
+/// ```toml
+/// [m20.nav_left]
+/// bias = 133.0
+///
+/// [m20.mastcamz_left]
+/// bias = "M20_MCZ_LEFT_BIAS_V1.png"
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum BiasLevel {
+    Constant(f32),
+    Frame(String),
+}
+
 #[derive(Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct Config {
@@ -39,6 +72,16 @@ pub struct InstrumentProperties {
     #[serde(default = "default_blank")]
     pub flat: String,
 
+    /// Flat fields for individual filters, by filter name, used in place of `flat` for
+    /// images taken through that filter:
+    ///
+    /// ```toml
+    /// [m20.mastcamz_left.filter_flats]
+    /// L1 = "M20_MCZ_LEFT_L1_FLATFIELD_-motorcount-_V1.png"
+    /// ```
+    #[serde(default)]
+    pub filter_flats: BTreeMap<String, String>,
+
     #[serde(default = "default_blank")]
     pub inpaint_mask: String,
 
@@ -47,6 +90,61 @@ pub struct InstrumentProperties {
 
     #[serde(default = "default_blank")]
     pub lut: String,
+
+    #[serde(default)]
+    pub bias: Option<BiasLevel>,
+
+    /// Per-pixel dark current frame
+    #[serde(default = "default_blank")]
+    pub dark: String,
+
+    /// Exposure duration, in milliseconds, the dark frame represents. When both this and the
+    /// image's exposure are known, the dark frame is scaled linearly to the image's exposure.
+    pub dark_exposure: Option<f64>,
+
+    /// Detector temperature, in degrees Celsius, the dark frame represents. When both this
+    /// and the image's detector temperature are known, the dark frame is scaled by the
+    /// change in temperature.
+    pub dark_temperature: Option<f64>,
+
+    /// Temperature change, in degrees Celsius, over which dark current doubles
+    #[serde(default = "default_dark_doubling_temperature")]
+    pub dark_doubling_temperature: f64,
+
+    /// Radiometric responsivity per filter, in DN/s per W/m^2/sr/um. Filters imaged through
+    /// the Bayer pattern list one coefficient per red, green, and blue channel.
+    #[serde(default)]
+    pub responsivity: BTreeMap<String, Vec<f64>>,
+
+    /// Band-averaged solar irradiance at 1 AU per filter, in W/m^2/um, used for I/F
+    #[serde(default)]
+    pub solar_irradiance: BTreeMap<String, Vec<f64>>,
+
+    /// 3x3 color correction matrix mapping camera RGB to linear sRGB primaries, row major.
+    /// The only source of an instrument's matrix, none are built in.
+    pub color_matrix: Option<[[f32; 3]; 3]>,
+
+    /// Offset added to each channel after the color matrix, as a fraction of full scale
+    pub color_offset: Option<[f32; 3]>,
+}
+
+impl InstrumentProperties {
+    /// The per-pixel bias frame file name, or blank when the bias is a constant or not set
+    pub fn bias_frame(&self) -> String {
+        match &self.bias {
+            Some(BiasLevel::Frame(file_name)) => file_name.clone(),
+            _ => default_blank(),
+        }
+    }
+
+    /// The flat field file name configured for a filter, ignoring case
+    pub fn filter_flat(&self, filter: &str) -> Option<String> {
+        self.filter_flats
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(filter))
+            .map(|(_, file_name)| file_name.clone())
+            .filter(|file_name| !file_name.is_empty())
+    }
 }
 
 #[derive(Clone)]
@@ -57,10 +155,18 @@ pub struct CalFilePathAndType {
 
 impl IntoIterator for InstrumentProperties {
     type Item = CalFilePathAndType;
-    type IntoIter = std::array::IntoIter<CalFilePathAndType, 4>;
+    type IntoIter = std::array::IntoIter<CalFilePathAndType, 6>;
 
     fn into_iter(self) -> Self::IntoIter {
         [
+            CalFilePathAndType {
+                file: self.bias_frame(),
+                file_type: enums::CalFileType::Bias,
+            },
+            CalFilePathAndType {
+                file: self.dark,
+                file_type: enums::CalFileType::Dark,
+            },
             CalFilePathAndType {
                 file: self.flat,
                 file_type: enums::CalFileType::FlatField,
@@ -198,14 +304,11 @@ pub fn load_caldata_mapping_file() -> Result<Config> {
     if let Ok(caldata_toml) = locate_calibration_file(&String::from("caldata.toml")) {
         info!("Loading calibration spec from {}", caldata_toml);
 
-        let mut file = match File::open(&caldata_toml) {
-            Err(why) => panic!("couldn't open {}", why),
-            Ok(file) => file,
-        };
+        let mut file = File::open(&caldata_toml)?;
 
         let mut buf: Vec<u8> = Vec::default();
-        file.read_to_end(&mut buf).unwrap();
-        let toml = String::from_utf8(buf).unwrap();
+        file.read_to_end(&mut buf)?;
+        let toml = String::from_utf8(buf)?;
 
         parse_caldata_from_string(&toml)
     } else {
@@ -228,12 +331,8 @@ pub fn locate_calibration_file_no_extention(
     }
 }
 
-pub fn locate_calibration_file(file_path: &str) -> Result<String> {
-    // If the file exists as-is, return it
-    if path::file_exists(file_path) {
-        return Ok(file_path.into());
-    }
-
+/// The directories searched for calibration data, in order of precedence
+pub fn calibration_search_paths() -> Vec<String> {
     // Some default locations
     let mut locations = vec![
         String::from("mars-raw-utils-data/caldata"), // Running within the repo directory (dev: cargo run --bin ...)
@@ -279,6 +378,16 @@ pub fn locate_calibration_file(file_path: &str) -> Result<String> {
         locations.insert(0, dir);
     }
 
+    locations
+}
+
+pub fn locate_calibration_file(file_path: &str) -> Result<String> {
+    // If the file exists as-is, return it
+    if path::file_exists(file_path) {
+        return Ok(file_path.into());
+    }
+
+    let locations = calibration_search_paths();
     debug!("Calibration file search path: {:?}", locations);
 
     // First match wins
@@ -290,7 +399,7 @@ pub fn locate_calibration_file(file_path: &str) -> Result<String> {
     }
 
     // Oh nos!
-    Err(anyhow!("Calibration file not found: {}", file_path))
+    Err(CalError::MissingFile(file_path.to_owned()).into())
 }
 
 pub fn get_calibration_file_for_type(
@@ -302,147 +411,71 @@ pub fn get_calibration_file_for_type(
         enums::CalFileType::InpaintMask => inst_props.inpaint_mask.clone(),
         enums::CalFileType::Mask => inst_props.mask.clone(),
         enums::CalFileType::Lut => inst_props.lut.clone(),
+        enums::CalFileType::Bias => inst_props.bias_frame(),
+        enums::CalFileType::Dark => inst_props.dark.clone(),
     }
 }
 
-pub fn get_calibration_base_file_for_instrument(
-    instrument: enums::Instrument,
-    cal_file_type: enums::CalFileType,
-) -> Result<String> {
+/// The calibration data configured for an instrument in the calibration manifest
+pub fn get_instrument_properties(instrument: enums::Instrument) -> Result<InstrumentProperties> {
     let config = load_caldata_mapping_file()?;
 
     match instrument {
-        enums::Instrument::MslMAHLI => Ok(get_calibration_file_for_type(
-            &config.msl.mahli,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMastcamLeft => Ok(get_calibration_file_for_type(
-            &config.msl.mastcam_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMastcamRight => Ok(get_calibration_file_for_type(
-            &config.msl.mastcam_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslNavCamRight => Ok(get_calibration_file_for_type(
-            &config.msl.nav_right,
-            cal_file_type,
-        )), // Limiting to RCE-B camera for ECAM. For now.
-        enums::Instrument::MslNavCamLeft => Ok(get_calibration_file_for_type(
-            &config.msl.nav_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslFrontHazLeft => Ok(get_calibration_file_for_type(
-            &config.msl.fhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslFrontHazRight => Ok(get_calibration_file_for_type(
-            &config.msl.fhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslRearHazLeft => Ok(get_calibration_file_for_type(
-            &config.msl.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslRearHazRight => Ok(get_calibration_file_for_type(
-            &config.msl.rhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMARDI => Ok(get_calibration_file_for_type(
-            &config.msl.mardi,
-            cal_file_type,
-        )),
-        enums::Instrument::MslChemCam => Ok(get_calibration_file_for_type(
-            &config.msl.chemcam,
-            cal_file_type,
-        )),
-        enums::Instrument::M20MastcamZLeft => Ok(get_calibration_file_for_type(
-            &config.m20.mastcamz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20MastcamZRight => Ok(get_calibration_file_for_type(
-            &config.m20.mastcamz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20NavcamLeft => Ok(get_calibration_file_for_type(
-            &config.m20.nav_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20NavcamRight => Ok(get_calibration_file_for_type(
-            &config.m20.nav_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20FrontHazLeft => Ok(get_calibration_file_for_type(
-            &config.m20.fhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20FrontHazRight => Ok(get_calibration_file_for_type(
-            &config.m20.fhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20RearHazLeft => Ok(get_calibration_file_for_type(
-            &config.m20.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20RearHazRight => Ok(get_calibration_file_for_type(
-            &config.m20.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20Watson => Ok(get_calibration_file_for_type(
-            &config.m20.watson,
-            cal_file_type,
-        )),
-        enums::Instrument::M20SuperCam => Ok(get_calibration_file_for_type(
-            &config.m20.supercam_rmi,
-            cal_file_type,
-        )),
-        enums::Instrument::M20HeliNav => Ok(get_calibration_file_for_type(
-            &config.m20.heli_nav,
-            cal_file_type,
-        )),
-        enums::Instrument::M20HeliRte => Ok(get_calibration_file_for_type(
-            &config.m20.heli_rte,
-            cal_file_type,
-        )),
-        enums::Instrument::M20Pixl => Ok(get_calibration_file_for_type(
-            &config.m20.pixl_mcc,
-            cal_file_type,
-        )),
-        enums::Instrument::M20SkyCam => Ok(get_calibration_file_for_type(
-            &config.m20.skycam,
-            cal_file_type,
-        )),
-        enums::Instrument::M20SherlocAci => Ok(get_calibration_file_for_type(
-            &config.m20.sherloc_aci,
-            cal_file_type,
-        )),
-        enums::Instrument::M20CacheCam => Ok(get_calibration_file_for_type(
-            &config.m20.cachecam,
-            cal_file_type,
-        )),
-        enums::Instrument::M20EdlRdcam => Ok(get_calibration_file_for_type(
-            &config.m20.edl_rdcam,
-            cal_file_type,
-        )),
-        enums::Instrument::NsytICC => Ok(get_calibration_file_for_type(
-            &config.nsyt.icc,
-            cal_file_type,
-        )),
-        enums::Instrument::NsytIDC => Ok(get_calibration_file_for_type(
-            &config.nsyt.idc,
-            cal_file_type,
-        )),
+        enums::Instrument::MslMAHLI => Ok(config.msl.mahli.clone()),
+        enums::Instrument::MslMastcamLeft => Ok(config.msl.mastcam_left.clone()),
+        enums::Instrument::MslMastcamRight => Ok(config.msl.mastcam_right.clone()),
+        enums::Instrument::MslNavCamRight => Ok(config.msl.nav_right.clone()), // Limiting to RCE-B camera for ECAM. For now.
+        enums::Instrument::MslNavCamLeft => Ok(config.msl.nav_left.clone()),
+        enums::Instrument::MslFrontHazLeft => Ok(config.msl.fhaz_left.clone()),
+        enums::Instrument::MslFrontHazRight => Ok(config.msl.fhaz_right.clone()),
+        enums::Instrument::MslRearHazLeft => Ok(config.msl.rhaz_left.clone()),
+        enums::Instrument::MslRearHazRight => Ok(config.msl.rhaz_right.clone()),
+        enums::Instrument::MslMARDI => Ok(config.msl.mardi.clone()),
+        enums::Instrument::MslChemCam => Ok(config.msl.chemcam.clone()),
+        enums::Instrument::M20MastcamZLeft => Ok(config.m20.mastcamz_left.clone()),
+        enums::Instrument::M20MastcamZRight => Ok(config.m20.mastcamz_right.clone()),
+        enums::Instrument::M20NavcamLeft => Ok(config.m20.nav_left.clone()),
+        enums::Instrument::M20NavcamRight => Ok(config.m20.nav_right.clone()),
+        enums::Instrument::M20FrontHazLeft => Ok(config.m20.fhaz_left.clone()),
+        enums::Instrument::M20FrontHazRight => Ok(config.m20.fhaz_right.clone()),
+        enums::Instrument::M20RearHazLeft => Ok(config.m20.rhaz_left.clone()),
+        enums::Instrument::M20RearHazRight => Ok(config.m20.rhaz_right.clone()),
+        enums::Instrument::M20Watson => Ok(config.m20.watson.clone()),
+        enums::Instrument::M20SuperCam => Ok(config.m20.supercam_rmi.clone()),
+        enums::Instrument::M20HeliNav => Ok(config.m20.heli_nav.clone()),
+        enums::Instrument::M20HeliRte => Ok(config.m20.heli_rte.clone()),
+        enums::Instrument::M20Pixl => Ok(config.m20.pixl_mcc.clone()),
+        enums::Instrument::M20SkyCam => Ok(config.m20.skycam.clone()),
+        enums::Instrument::M20SherlocAci => Ok(config.m20.sherloc_aci.clone()),
+        enums::Instrument::M20CacheCam => Ok(config.m20.cachecam.clone()),
+        enums::Instrument::M20EdlRdcam => Ok(config.m20.edl_rdcam.clone()),
+        enums::Instrument::NsytICC => Ok(config.nsyt.icc.clone()),
+        enums::Instrument::NsytIDC => Ok(config.nsyt.idc.clone()),
         enums::Instrument::None => Err(anyhow!(constants::status::UNSUPPORTED_INSTRUMENT)),
     }
 }
 
+pub fn get_calibration_base_file_for_instrument(
+    instrument: enums::Instrument,
+    cal_file_type: enums::CalFileType,
+) -> Result<String> {
+    Ok(get_calibration_file_for_type(
+        &get_instrument_properties(instrument)?,
+        cal_file_type,
+    ))
+}
+
 pub fn get_calibration_file_for_instrument(
     instrument: enums::Instrument,
     cal_file_type: enums::CalFileType,
 ) -> Result<String> {
     match get_calibration_base_file_for_instrument(instrument, cal_file_type) {
         Ok(file_name) => match file_name.len() {
-            0 => Err(anyhow!(constants::status::UNSUPPORTED_INSTRUMENT)),
+            0 => Err(CalError::MissingCalData {
+                instrument,
+                file_type: cal_file_type,
+            }
+            .into()),
             _ => locate_calibration_file(&file_name),
         },
         Err(e) => Err(e),
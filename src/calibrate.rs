@@ -1,6 +1,13 @@
 // use rayon::prelude::*;
 
-use crate::{calprofile::*, enums::Instrument};
+use crate::{
+    calpipeline::{self, CalStage, PipelineContext},
+    calplan::{self, CalPlan},
+    calprofile::*,
+    calreport::CalReport,
+    enums::Instrument,
+    marsimage::MarsImage,
+};
 
 use anyhow::Result;
 // use sciimg::path;
@@ -10,6 +17,7 @@ pub struct CompleteContext {
     pub status: CompleteStatus,
     pub cal_context: CalProfile,
     pub source_filename: String,
+    pub report: CalReport,
 }
 
 impl CompleteContext {
@@ -18,8 +26,14 @@ impl CompleteContext {
             status,
             cal_context: cal_context.clone(),
             source_filename: source_filename.to_owned(),
+            report: CalReport::new(source_filename, cal_context),
         }
     }
+
+    pub fn with_report(mut self, report: CalReport) -> Self {
+        self.report = report;
+        self
+    }
 }
 
 pub fn cal_warn(cal_context: &CalProfile, source_filename: &str) -> Result<CompleteContext> {
@@ -49,21 +63,64 @@ pub fn cal_fail(cal_context: &CalProfile, source_filename: &str) -> Result<Compl
 pub trait Calibration: Sync {
     fn accepts_instrument(&self, instrument: Instrument) -> bool;
 
+    /// Determines the specific instrument that captured the input file from its name, for
+    /// callers that haven't identified it from the file's metadata or label.
+    fn instrument_for_file(&self, input_file: &str) -> Result<Instrument>;
+
+    /// Loads the raw image taken by the instrument.
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage>;
+
+    /// The ordered calibration stages used when the profile does not declare its own pipeline.
+    fn default_pipeline(&self, input_file: &str, profile: &CalProfile) -> Vec<CalStage>;
+
+    /// Applies a single calibration stage. Calibrators override this for stages that need
+    /// instrument-specific handling and defer to `calpipeline::apply_stage` otherwise.
+    fn apply_stage(
+        &self,
+        stage: &CalStage,
+        raw: &mut MarsImage,
+        context: &mut PipelineContext,
+    ) -> Result<()> {
+        calpipeline::apply_stage(stage, raw, context)
+    }
+
     fn process_with_profile(
         &self,
         input_file: &str,
+        instrument: Instrument,
         only_new: bool,
         profile: &CalProfile,
     ) -> Result<CompleteContext> {
-        self.process_file(input_file, profile, only_new)
+        self.process_file(input_file, instrument, profile, only_new)
     }
 
     fn process_file(
         &self,
         input_file: &str,
+        instrument: Instrument,
         cal_context: &CalProfile,
         only_new: bool,
-    ) -> Result<CompleteContext>;
+    ) -> Result<CompleteContext> {
+        calpipeline::process_file(self, input_file, instrument, cal_context, only_new)
+    }
+
+    /// Records what a single stage would do without touching pixel data. Calibrators that
+    /// override `apply_stage` override this for the same stages and defer to
+    /// `calplan::plan_stage` otherwise.
+    fn plan_stage(&self, stage: &CalStage, plan: &mut CalPlan) -> Result<()> {
+        calplan::plan_stage(stage, plan)
+    }
+
+    /// Resolves the instrument, stages, and calibration files that `process_file` would use,
+    /// without loading the image.
+    fn plan_file(
+        &self,
+        input_file: &str,
+        instrument: Instrument,
+        cal_context: &CalProfile,
+    ) -> Result<CalPlan> {
+        calplan::plan_file(self, input_file, instrument, cal_context)
+    }
 }
 
 pub struct CalContainer {
@@ -73,6 +130,7 @@ pub struct CalContainer {
 pub fn process_with_profiles<F: Fn(Result<CompleteContext>)>(
     calibrator: &CalContainer,
     input_file: &str,
+    instrument: Instrument,
     only_new: bool,
     profile_names: &[CalProfile],
     on_cal_complete: F,
@@ -81,7 +139,7 @@ pub fn process_with_profiles<F: Fn(Result<CompleteContext>)>(
         on_cal_complete(
             calibrator
                 .calibrator
-                .process_with_profile(input_file, only_new, profile),
+                .process_with_profile(input_file, instrument, only_new, profile),
         );
     }
 }
@@ -0,0 +1,839 @@
+use crate::{
+    biasdark,
+    calibfile::{self, BiasLevel},
+    calibrate::*,
+    calprofile::CalProfile,
+    calreport::CalReport,
+    colormatrix, decompanding,
+    enums::{CalFileType, Instrument},
+    error::CalError,
+    identify, inpaintmask,
+    marsimage::MarsImage,
+    memcache,
+    radiometry::{self, RadiometricUnits},
+    util,
+};
+
+use anyhow::{anyhow, Result};
+use sciimg::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::time::Instant;
+
+/// A single step in a calibration pipeline. Stages are declared in a profile as an
+/// array of tables, each identified by its `stage` name and carrying optional parameters
+/// that override the equivalent profile-wide values:
+///
+/// ```toml
+/// [[pipeline]]
+/// stage = "decompand"
+///
+/// [[pipeline]]
+/// stage = "hot_pixel_correction"
+/// threshold = 2.5
+///
+/// [[pipeline]]
+/// stage = "flat"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum CalStage {
+    /// Apply the instrument's inverse lookup table
+    Decompand,
+
+    /// Debayer the image if it still appears to be a grayscale bayer frame
+    Debayer { method: Option<DebayerMethod> },
+
+    /// Remove histogram gaps introduced by onboard stretching
+    Destretch,
+
+    /// Apply the instrument's alpha mask
+    Mask,
+
+    /// Subtract the sensor bias. Without a level, the instrument's bias level or bias frame
+    /// from the calibration data is used.
+    BiasSubtraction { bias: Option<f32> },
+
+    /// Subtract the instrument's dark frame. Without a scale, the frame is scaled by the
+    /// image's exposure and detector temperature when they are known.
+    DarkSubtraction { scale: Option<f32> },
+
+    /// Flat field the image
+    Flat,
+
+    /// Inpaint known bad pixels
+    Inpaint,
+
+    /// Replace hot pixels based on their local variance
+    HotPixelCorrection {
+        window_size: Option<i32>,
+        threshold: Option<f32>,
+    },
+
+    /// Reduce chroma noise
+    ColorNoiseReduction { amount: Option<i32> },
+
+    /// Apply red, green, and blue channel weights
+    Weights {
+        red: Option<f32>,
+        green: Option<f32>,
+        blue: Option<f32>,
+    },
+
+    /// Crop the image. Without a rectangle the instrument's automatic subframing is used.
+    Crop { rect: Option<Vec<usize>> },
+
+    /// Apply a 3x3 color correction matrix and offset. Without a matrix, the profile's
+    /// `color_matrix` or the instrument's matrix is used.
+    ColorMatrix {
+        matrix: Option<[[f32; 3]; 3]>,
+        offset: Option<[f32; 3]>,
+    },
+
+    /// Convert from linear RGB to sRGB
+    SrgbConversion,
+
+    /// Normalize to the 16 bit output range
+    Normalize { decorrelate: Option<bool> },
+
+    /// Convert to radiance or I/F using the instrument's per-filter coefficients. Without
+    /// units, the profile's `radiometric_units` are used. The output is written as floating
+    /// point TIFF.
+    Radiometric { units: Option<RadiometricUnits> },
+}
+
+impl CalStage {
+    pub fn debayer() -> CalStage {
+        CalStage::Debayer { method: None }
+    }
+
+    pub fn bias_subtraction(bias: f32) -> CalStage {
+        CalStage::BiasSubtraction { bias: Some(bias) }
+    }
+
+    /// Bias subtraction using the instrument's calibration data
+    pub fn calibrated_bias_subtraction() -> CalStage {
+        CalStage::BiasSubtraction { bias: None }
+    }
+
+    pub fn dark_subtraction() -> CalStage {
+        CalStage::DarkSubtraction { scale: None }
+    }
+
+    pub fn hot_pixel_correction() -> CalStage {
+        CalStage::HotPixelCorrection {
+            window_size: None,
+            threshold: None,
+        }
+    }
+
+    pub fn color_noise_reduction() -> CalStage {
+        CalStage::ColorNoiseReduction { amount: None }
+    }
+
+    pub fn weights() -> CalStage {
+        CalStage::Weights {
+            red: None,
+            green: None,
+            blue: None,
+        }
+    }
+
+    pub fn color_matrix() -> CalStage {
+        CalStage::ColorMatrix {
+            matrix: None,
+            offset: None,
+        }
+    }
+
+    pub fn auto_crop() -> CalStage {
+        CalStage::Crop { rect: None }
+    }
+
+    pub fn crop(x: usize, y: usize, width: usize, height: usize) -> CalStage {
+        CalStage::Crop {
+            rect: Some(vec![x, y, width, height]),
+        }
+    }
+
+    pub fn normalize() -> CalStage {
+        CalStage::Normalize { decorrelate: None }
+    }
+
+    pub fn radiometric() -> CalStage {
+        CalStage::Radiometric { units: None }
+    }
+
+    /// Normalization that ignores the profile's request for decorrelated color
+    pub fn normalize_correlated() -> CalStage {
+        CalStage::Normalize {
+            decorrelate: Some(false),
+        }
+    }
+
+    /// The optional parameters a stage accepts in a profile, or `None` for an unknown stage
+    pub fn parameter_names(stage_name: &str) -> Option<&'static [&'static str]> {
+        match stage_name {
+            "decompand" | "destretch" | "mask" | "flat" | "inpaint" | "srgb_conversion" => {
+                Some(&[])
+            }
+            "debayer" => Some(&["method"]),
+            "bias_subtraction" => Some(&["bias"]),
+            "dark_subtraction" => Some(&["scale"]),
+            "hot_pixel_correction" => Some(&["window_size", "threshold"]),
+            "color_noise_reduction" => Some(&["amount"]),
+            "weights" => Some(&["red", "green", "blue"]),
+            "crop" => Some(&["rect"]),
+            "color_matrix" => Some(&["matrix", "offset"]),
+            "normalize" => Some(&["decorrelate"]),
+            "radiometric" => Some(&["units"]),
+            _ => None,
+        }
+    }
+
+    /// The stage name as it is written in a profile
+    pub fn name(&self) -> &'static str {
+        match self {
+            CalStage::Decompand => "decompand",
+            CalStage::Debayer { .. } => "debayer",
+            CalStage::Destretch => "destretch",
+            CalStage::Mask => "mask",
+            CalStage::BiasSubtraction { .. } => "bias_subtraction",
+            CalStage::DarkSubtraction { .. } => "dark_subtraction",
+            CalStage::Flat => "flat",
+            CalStage::Inpaint => "inpaint",
+            CalStage::HotPixelCorrection { .. } => "hot_pixel_correction",
+            CalStage::ColorNoiseReduction { .. } => "color_noise_reduction",
+            CalStage::Weights { .. } => "weights",
+            CalStage::Crop { .. } => "crop",
+            CalStage::ColorMatrix { .. } => "color_matrix",
+            CalStage::SrgbConversion => "srgb_conversion",
+            CalStage::Normalize { .. } => "normalize",
+            CalStage::Radiometric { .. } => "radiometric",
+        }
+    }
+}
+
+/// Mutable state carried from stage to stage while a single file is calibrated.
+pub struct PipelineContext<'a> {
+    pub input_file: String,
+    pub profile: &'a CalProfile,
+
+    /// Maximum possible data value, used by normalization. Updated by decompanding.
+    pub data_max: f32,
+
+    /// Set when the output should be written with an alpha channel
+    pub using_alpha: bool,
+
+    /// Calibration files, crops, warnings, and timings collected as the stages run
+    pub report: CalReport,
+
+    /// Set once the image has been normalized to the 16 bit output range
+    pub normalized: bool,
+
+    /// Set once the image has been converted to physical units and must be written as
+    /// floating point
+    pub float_output: bool,
+}
+
+impl<'a> PipelineContext<'a> {
+    pub fn new(input_file: &str, profile: &'a CalProfile) -> Self {
+        PipelineContext {
+            input_file: input_file.to_owned(),
+            profile,
+            data_max: 255.0,
+            using_alpha: false,
+            report: CalReport::new(input_file, profile),
+            normalized: false,
+            float_output: false,
+        }
+    }
+
+    /// Logs a non-fatal problem. The output will be flagged with a warning status.
+    pub fn add_warning(&mut self, message: &str) {
+        warn!("{}", message);
+        self.report.warnings.push(message.to_owned());
+    }
+
+    pub fn has_warnings(&self) -> bool {
+        !self.report.warnings.is_empty()
+    }
+
+    pub fn record_calibration_file(&mut self, file_type: CalFileType, path: &str) {
+        self.report.add_calibration_file(file_type, path);
+    }
+
+    pub fn record_weighted_calibration_file(
+        &mut self,
+        file_type: CalFileType,
+        path: &str,
+        weight: f32,
+    ) {
+        self.report
+            .add_weighted_calibration_file(file_type, path, weight);
+    }
+
+    /// Records the resolved path of the instrument's calibration file of the given type, if
+    /// one is configured.
+    pub fn record_calibration_file_for(&mut self, instrument: Instrument, file_type: CalFileType) {
+        if let Ok(path) = calibfile::get_calibration_file_for_instrument(instrument, file_type) {
+            self.record_calibration_file(file_type, &path);
+        }
+    }
+
+    pub fn record_crop(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.report.add_crop(x, y, width, height);
+    }
+}
+
+/// Returns the stages the profile will run, falling back to the calibrator's defaults when
+/// the profile does not specify its own pipeline. Default pipelines gain bias and dark
+/// subtraction for instruments that have them in their calibration data.
+pub fn stages_for_profile<C: Calibration + ?Sized>(
+    calibrator: &C,
+    input_file: &str,
+    instrument: Instrument,
+    profile: &CalProfile,
+) -> Vec<CalStage> {
+    match &profile.pipeline {
+        Some(pipeline) => pipeline.clone(),
+        None => {
+            let mut stages = calibrator.default_pipeline(input_file, profile);
+            insert_sensor_offset_stages(&mut stages, instrument);
+            stages
+        }
+    }
+}
+
+/// Inserts bias and dark subtraction, where configured for the instrument, directly after
+/// decompanding so that per-pixel frames are subtracted before debayering. Pipelines that
+/// already subtract a bias or dark frame are left alone.
+pub fn insert_sensor_offset_stages(stages: &mut Vec<CalStage>, instrument: Instrument) {
+    if stages.iter().any(|s| {
+        matches!(
+            s,
+            CalStage::BiasSubtraction { .. } | CalStage::DarkSubtraction { .. }
+        )
+    }) {
+        return;
+    }
+
+    let mut index = match stages.iter().position(|s| matches!(s, CalStage::Decompand)) {
+        Some(decompand) => decompand + 1,
+        None => 0,
+    };
+
+    if biasdark::bias_for_instrument(instrument).is_some() {
+        stages.insert(index, CalStage::calibrated_bias_subtraction());
+        index += 1;
+    }
+    if biasdark::has_dark_frame(instrument) {
+        stages.insert(index, CalStage::dark_subtraction());
+    }
+}
+
+/// Parameters of a single stage, written as a sidecar next to its intermediate image
+#[derive(Serialize)]
+struct StageDump<'a> {
+    input_file: &'a str,
+    profile: Option<&'a str>,
+    filename_suffix: &'a str,
+    stage_number: usize,
+    stage: &'a CalStage,
+    width: usize,
+    height: usize,
+    bands: usize,
+
+    /// Value the intermediate image was scaled from to fill the 16 bit range. Absent once
+    /// the pipeline has normalized the image itself.
+    scaled_from_max: Option<f32>,
+}
+
+/// Path of the intermediate image written after the given stage
+pub fn stage_dump_file_name(
+    input_file: &str,
+    profile: &CalProfile,
+    stage_number: usize,
+    stage: &CalStage,
+) -> String {
+    util::append_file_name(
+        input_file,
+        &format!(
+            "{}-stage{:02}-{}",
+            profile.filename_suffix,
+            stage_number,
+            stage.name()
+        ),
+    )
+}
+
+/// Writes the image as it stands after a stage, along with a sidecar describing the stage.
+/// Images that have not yet been normalized are scaled from the current data maximum to the
+/// 16 bit range so they can be viewed. Radiometrically calibrated images are written unscaled
+/// as floating point TIFF.
+fn dump_stage(
+    stage_number: usize,
+    stage: &CalStage,
+    raw: &MarsImage,
+    context: &PipelineContext,
+) -> Result<()> {
+    let mut dump_file =
+        stage_dump_file_name(&context.input_file, context.profile, stage_number, stage);
+    if context.float_output {
+        dump_file = util::replace_image_extension(&dump_file, ".tif");
+    }
+    vprintln!("Writing {} stage output to {}", stage.name(), dump_file);
+
+    let mut dump = raw.clone();
+    dump.update_history();
+    if context.float_output {
+        dump.save_float(&dump_file)?;
+    } else {
+        if !context.normalized {
+            dump.image.normalize_to_16bit_with_max(context.data_max);
+        }
+        if context.using_alpha {
+            dump.image.set_using_alpha(true);
+        }
+        dump.save(&dump_file)?;
+    }
+
+    let sidecar = StageDump {
+        input_file: &context.input_file,
+        profile: context.profile.description.as_deref(),
+        filename_suffix: &context.profile.filename_suffix,
+        stage_number,
+        stage,
+        width: raw.image.width,
+        height: raw.image.height,
+        bands: raw.image.num_bands(),
+        scaled_from_max: match context.normalized || context.float_output {
+            true => None,
+            false => Some(context.data_max),
+        },
+    };
+    let mut file = File::create(util::replace_image_extension(&dump_file, "-stage.json"))?;
+    file.write_all(serde_json::to_string_pretty(&sidecar)?.as_bytes())?;
+    Ok(())
+}
+
+/// Runs each stage, in order, against the image. When the profile enables `dump_stages`,
+/// the image is written to disk after every stage.
+pub fn run_pipeline<C: Calibration + ?Sized>(
+    calibrator: &C,
+    stages: &[CalStage],
+    raw: &mut MarsImage,
+    context: &mut PipelineContext,
+) -> Result<()> {
+    for (index, stage) in stages.iter().enumerate() {
+        debug!("Running calibration stage: {:?}", stage);
+        let start = Instant::now();
+        calibrator.apply_stage(stage, raw, context)?;
+        context
+            .report
+            .add_stage_timing(stage.name(), start.elapsed().as_secs_f64() * 1000.0);
+
+        if matches!(stage, CalStage::Normalize { .. }) && !context.float_output {
+            context.normalized = true;
+        }
+
+        if context.profile.dump_stages {
+            if let Err(why) = dump_stage(index + 1, stage, raw, context) {
+                context.add_warning(&format!(
+                    "Unable to write {} stage output: {}",
+                    stage.name(),
+                    why
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// True if the stages convert to physical units, which are written as floating point TIFF
+pub fn has_float_output(stages: &[CalStage]) -> bool {
+    stages
+        .iter()
+        .any(|s| matches!(s, CalStage::Radiometric { .. }))
+}
+
+/// Path of the calibrated image written for the input file
+pub fn output_file_name(input_file: &str, profile: &CalProfile, stages: &[CalStage]) -> String {
+    match has_float_output(stages) {
+        true => {
+            util::replace_image_extension(input_file, &format!("-{}.tif", profile.filename_suffix))
+        }
+        false => util::append_file_name(input_file, profile.filename_suffix.as_str()),
+    }
+}
+
+/// Converts the image to the given units, or the profile's, using the coefficients for
+/// `filter`. Calibrators that determine the filter other than from the image metadata call
+/// this from their own `apply_stage`.
+pub fn apply_radiometric(
+    raw: &mut MarsImage,
+    context: &mut PipelineContext,
+    units: Option<RadiometricUnits>,
+    filter: &str,
+) -> Result<()> {
+    let units = units
+        .or(context.profile.radiometric_units)
+        .unwrap_or(RadiometricUnits::Radiance);
+    vprintln!("Converting to {} for filter {}...", units.label(), filter);
+    identify::apply_label_metadata(&context.input_file, &mut raw.metadata);
+    if !raw.metadata.decompand {
+        context.add_warning(&format!(
+            "Converting to {} without decompanding, the result will be nonlinear",
+            units.label()
+        ));
+    }
+    radiometry::calibrate(raw, units, filter)?;
+    context.float_output = true;
+    Ok(())
+}
+
+/// Calibrates a single file taken by the instrument using the stages declared in the profile
+/// or the calibrator's defaults, then writes the result alongside the input.
+pub fn process_file<C: Calibration + ?Sized>(
+    calibrator: &C,
+    input_file: &str,
+    instrument: Instrument,
+    cal_context: &CalProfile,
+    only_new: bool,
+) -> Result<CompleteContext> {
+    let stages = stages_for_profile(calibrator, input_file, instrument, cal_context);
+    let out_file = output_file_name(input_file, cal_context, &stages);
+    let mut context = PipelineContext::new(input_file, cal_context);
+    context.report.output_file = Some(out_file.clone());
+
+    if path::file_exists(&out_file) && only_new {
+        vprintln!("Output file exists, skipping. ({})", out_file);
+        context.report.set_status("skipped");
+        return cal_warn(cal_context, &out_file).map(|c| c.with_report(context.report));
+    }
+
+    let mut raw = calibrator.open_raw(input_file, instrument)?;
+    context.report.instrument = Some(format!("{:?}", raw.instrument));
+
+    run_pipeline(calibrator, &stages, &mut raw, &mut context)?;
+
+    vprintln!("Writing to disk...");
+    raw.update_history();
+    if context.using_alpha {
+        raw.image.set_using_alpha(true);
+    }
+    let saved = match context.float_output {
+        true => raw.save_float(&out_file),
+        false => raw.save(&out_file),
+    };
+    let result = match saved {
+        Ok(_) => match context.has_warnings() {
+            true => {
+                context.report.set_status("warn");
+                cal_warn(cal_context, &out_file)
+            }
+            false => {
+                context.report.set_status("ok");
+                cal_ok(cal_context, &out_file)
+            }
+        },
+        Err(why) => {
+            veprintln!("Error saving file: {}", why);
+            context.report.set_error(&why.to_string());
+            cal_fail(cal_context, &out_file)
+        }
+    };
+    result.map(|c| c.with_report(context.report))
+}
+
+/// The instrument-agnostic implementation of each stage. Calibrators override individual
+/// stages via `Calibration::apply_stage` and defer to this for the rest.
+pub fn apply_stage(
+    stage: &CalStage,
+    raw: &mut MarsImage,
+    context: &mut PipelineContext,
+) -> Result<()> {
+    match stage {
+        CalStage::Decompand => {
+            vprintln!("Decompanding...");
+            context.record_calibration_file_for(raw.instrument, CalFileType::Lut);
+            let lut = decompanding::get_ilt_for_instrument(raw.instrument)?;
+            raw.decompand(&lut);
+            context.data_max = lut.max() as f32;
+        }
+        CalStage::Debayer { method } => {
+            if raw.image.is_grayscale() {
+                vprintln!("Image appears to be grayscale, applying debayering...");
+                raw.debayer_with_method(method.unwrap_or(context.profile.debayer_method));
+            }
+        }
+        CalStage::Destretch => {
+            vprintln!("Destretching...");
+            raw.destretch_image();
+        }
+        CalStage::Mask => {
+            vprintln!("Loading image mask");
+            let mask_file_path =
+                calibfile::get_calibration_file_for_instrument(raw.instrument, CalFileType::Mask)?;
+            context.record_calibration_file(CalFileType::Mask, &mask_file_path);
+            let mask = memcache::load_imagebuffer(&mask_file_path)?;
+            raw.apply_alpha(&mask);
+            context.using_alpha = true;
+        }
+        CalStage::BiasSubtraction { bias } => match bias
+            .map(BiasLevel::Constant)
+            .or_else(|| biasdark::bias_for_instrument(raw.instrument))
+        {
+            Some(BiasLevel::Constant(bias)) => {
+                vprintln!("Applying Bias Subtraction of {}...", bias);
+                raw.image.apply_bias_subtraction(bias);
+            }
+            Some(BiasLevel::Frame(file_name)) => {
+                let bias_file_path = calibfile::locate_calibration_file(&file_name)?;
+                vprintln!("Subtracting bias frame {}...", bias_file_path);
+                context.record_calibration_file(CalFileType::Bias, &bias_file_path);
+                let frame = biasdark::load_frame(&bias_file_path, raw)?;
+                biasdark::subtract_frame(&mut raw.image, &frame, 1.0)?;
+            }
+            None => {
+                context.add_warning(&format!(
+                    "No bias level known for instrument {:?}",
+                    raw.instrument
+                ));
+            }
+        },
+        CalStage::DarkSubtraction { scale } => {
+            match calibfile::get_calibration_file_for_instrument(raw.instrument, CalFileType::Dark)
+            {
+                Ok(dark_file_path) => {
+                    let scale = match scale {
+                        Some(scale) => *scale,
+                        None => {
+                            identify::apply_label_metadata(&context.input_file, &mut raw.metadata);
+                            biasdark::dark_scale_factor_for_instrument(
+                                raw.instrument,
+                                &raw.metadata,
+                            )
+                        }
+                    };
+                    vprintln!(
+                        "Subtracting dark frame {} scaled by {}...",
+                        dark_file_path,
+                        scale
+                    );
+                    context.record_calibration_file(CalFileType::Dark, &dark_file_path);
+                    let frame = biasdark::load_frame(&dark_file_path, raw)?;
+                    biasdark::subtract_frame(&mut raw.image, &frame, scale)?;
+                }
+                Err(why) => {
+                    context.add_warning(&format!(
+                        "No dark frame available for instrument {:?}: {}",
+                        raw.instrument, why
+                    ));
+                }
+            }
+        }
+        CalStage::Flat => {
+            vprintln!("Flatfielding...");
+            context.record_calibration_file_for(raw.instrument, CalFileType::FlatField);
+            raw.flatfield()?;
+        }
+        CalStage::Inpaint => {
+            if inpaintmask::inpaint_supported_for_instrument(raw.instrument) {
+                vprintln!("Inpainting...");
+                context.record_calibration_file_for(raw.instrument, CalFileType::InpaintMask);
+                let mask = inpaintmask::load_mask(raw.instrument)?;
+                raw.apply_inpaint_fix_with_mask(&mask)?;
+            } else {
+                context.add_warning(&format!(
+                    "Inpainting not supported for instrument {:?}",
+                    raw.instrument
+                ));
+            }
+        }
+        CalStage::HotPixelCorrection {
+            window_size,
+            threshold,
+        } => {
+            let threshold = threshold.unwrap_or(context.profile.hot_pixel_detection_threshold);
+            let window_size = window_size.unwrap_or(context.profile.hot_pixel_window_size);
+            if threshold > 0.0 {
+                vprintln!(
+                    "Hot pixel correction with variance threshold {}...",
+                    threshold
+                );
+                raw.hot_pixel_correction(window_size, threshold);
+            }
+        }
+        CalStage::ColorNoiseReduction { amount } => {
+            let amount = amount.unwrap_or(context.profile.color_noise_reduction_amount);
+            if amount > 0 && raw.image.num_bands() < 3 {
+                context.add_warning(&format!(
+                    "Color noise reduction not supported for single channel images from {:?}",
+                    raw.instrument
+                ));
+            } else if amount > 0 {
+                vprintln!("Color noise reduction...");
+                raw.image.reduce_color_noise(amount);
+            }
+        }
+        CalStage::Weights { red, green, blue } => {
+            vprintln!("Applying color weights...");
+            raw.apply_weight(
+                red.unwrap_or(context.profile.red_scalar),
+                green.unwrap_or(context.profile.green_scalar),
+                blue.unwrap_or(context.profile.blue_scalar),
+            );
+        }
+        CalStage::Crop { rect } => match rect {
+            Some(rect) if rect.len() == 4 => {
+                vprintln!("Cropping...");
+                crop_image(raw, context, [rect[0], rect[1], rect[2], rect[3]])?;
+            }
+            Some(_) => {
+                return Err(anyhow!(
+                    "Crop rectangle must be specified as [x, y, width, height]"
+                ))
+            }
+            None => {
+                context.add_warning(&format!(
+                    "No automatic subframing defined for instrument {:?}",
+                    raw.instrument
+                ));
+            }
+        },
+        CalStage::ColorMatrix { matrix, offset } => {
+            match colormatrix::resolve_color_matrix(matrix, offset, context.profile, raw.instrument)
+            {
+                _ if raw.image.num_bands() < 3 => {
+                    context.add_warning(&format!(
+                        "Color matrix not supported for single channel images from {:?}",
+                        raw.instrument
+                    ));
+                }
+                Some((color_matrix, source)) => {
+                    vprintln!("Applying {} color matrix...", source);
+                    colormatrix::apply_color_matrix(
+                        &mut raw.image,
+                        &color_matrix,
+                        context.data_max,
+                    )?;
+                }
+                None => return Err(CalError::MissingColorMatrix(raw.instrument).into()),
+            }
+        }
+        CalStage::SrgbConversion => {
+            vprintln!("Applying sRGB color conversion");
+            raw.image
+                .convert_colorspace(color::ColorSpaceType::RGB, color::ColorSpaceType::sRGB)?;
+        }
+        CalStage::Normalize { .. } if context.float_output => {
+            context.add_warning("Skipping normalization of radiometrically calibrated data");
+        }
+        CalStage::Normalize { decorrelate } => {
+            if decorrelate.unwrap_or(context.profile.decorrelate_color) {
+                vprintln!("Normalizing with decorrelated colors...");
+                raw.image.normalize_to_16bit_decorrelated();
+            } else {
+                vprintln!("Normalizing with correlated colors...");
+                raw.image.normalize_to_16bit_with_max(context.data_max);
+            }
+        }
+        CalStage::Radiometric { units } => match raw.metadata.filter_name.clone() {
+            Some(filter) => apply_radiometric(raw, context, *units, &filter)?,
+            None => {
+                return Err(anyhow!(
+                    "Image metadata does not include a filter name for radiometric calibration"
+                ))
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Crops the image to `[x, y, width, height]` and records the crop, failing if the rectangle
+/// doesn't fit within the image
+pub fn crop_image(
+    raw: &mut MarsImage,
+    context: &mut PipelineContext,
+    rect: [usize; 4],
+) -> Result<()> {
+    let [x, y, width, height] = rect;
+    let fits = |offset: usize, length: usize, limit: usize| {
+        length > 0 && offset.checked_add(length).is_some_and(|end| end <= limit)
+    };
+    if !fits(x, width, raw.image.width) || !fits(y, height, raw.image.height) {
+        return Err(CalError::InvalidCrop {
+            instrument: raw.instrument,
+            rect,
+            width: raw.image.width,
+            height: raw.image.height,
+        }
+        .into());
+    }
+    raw.crop(x, y, width, height);
+    context.record_crop(x, y, width, height);
+    Ok(())
+}
+
+/// Trims a border of the given widths from each side of the image and records the crop.
+/// Images too small to trim, such as thumbnails, are left whole with a warning. Returns
+/// whether the image was cropped.
+pub fn crop_border(
+    raw: &mut MarsImage,
+    context: &mut PipelineContext,
+    left: usize,
+    top: usize,
+    right: usize,
+    bottom: usize,
+) -> bool {
+    match (
+        raw.image.width.checked_sub(left + right),
+        raw.image.height.checked_sub(top + bottom),
+    ) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => {
+            raw.crop(left, top, width, height);
+            context.record_crop(left, top, width, height);
+            true
+        }
+        _ => {
+            context.add_warning(&format!(
+                "Image of {}x{} pixels is too small for a border crop of {},{},{},{} pixels, skipping crop",
+                raw.image.width, raw.image.height, left, top, right, bottom
+            ));
+            false
+        }
+    }
+}
+
+/// Appends the decompanding stage if requested by the profile or needed for its physical units
+pub fn push_decompand(stages: &mut Vec<CalStage>, profile: &CalProfile) {
+    // Physical units are only meaningful for linear DN, whatever the profile says
+    if profile.apply_ilt || profile.radiometric_units.is_some() {
+        stages.push(CalStage::Decompand);
+    }
+}
+
+/// Appends the color conversion and normalization stages common to most color instruments
+pub fn push_color_output(stages: &mut Vec<CalStage>, profile: &CalProfile) {
+    if profile.apply_color_matrix || profile.color_matrix.is_some() {
+        stages.push(CalStage::color_matrix());
+    }
+    if profile.srgb_color_correction {
+        stages.push(CalStage::SrgbConversion);
+    }
+    stages.push(CalStage::normalize());
+}
+
+/// Appends the hot pixel correction and color noise reduction stages requested by the
+/// profile. Calibrators call this once the sensor level corrections (bias, flat field,
+/// inpainting) are complete and before channel weights are applied.
+pub fn push_noise_reduction(stages: &mut Vec<CalStage>, profile: &CalProfile) {
+    if profile.hot_pixel_detection_threshold > 0.0 {
+        stages.push(CalStage::hot_pixel_correction());
+    }
+    if profile.color_noise_reduction && profile.color_noise_reduction_amount > 0 {
+        stages.push(CalStage::color_noise_reduction());
+    }
+}
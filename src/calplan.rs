@@ -0,0 +1,329 @@
+use crate::{
+    biasdark,
+    calibfile::{self, BiasLevel},
+    calibrate::Calibration,
+    calpipeline::{self, CalStage},
+    calprofile::CalProfile,
+    colormatrix, decompanding,
+    enums::{CalFileType, Instrument},
+    error::CalError,
+    identify, inpaintmask,
+    marsimage::MarsImage,
+    metadata::Metadata,
+    radiometry::{self, RadiometricUnits},
+    util,
+};
+
+use anyhow::{anyhow, Result};
+use sciimg::path;
+use serde::Serialize;
+
+/// What a calibration would do to one input file with one profile, resolved without loading
+/// or modifying any pixel data.
+#[derive(Serialize, Clone)]
+pub struct CalPlan {
+    pub input_file: String,
+    pub output_file: String,
+    pub instrument: String,
+
+    /// How the instrument was identified: "metadata", "forced", "label" or "product id"
+    pub instrument_source: Option<String>,
+
+    /// The profile after command line overrides have been applied
+    pub profile: CalProfile,
+
+    /// The automatically selected profile and why it was chosen
+    pub profile_selection: Option<String>,
+    pub stages: Vec<String>,
+
+    /// Inverse lookup table used for decompanding, or "built-in" for the default table
+    pub lut: Option<String>,
+    pub flat: Option<String>,
+    pub inpaint_mask: Option<String>,
+    pub mask: Option<String>,
+
+    /// Bias frame, or the constant bias level in DN
+    pub bias: Option<String>,
+    pub dark: Option<String>,
+
+    /// Sensor subframe rectangle from the image metadata, as [x, y, width, height]
+    pub subframe_rect: Option<Vec<f64>>,
+    pub scale_factor: u32,
+
+    /// Image dimensions as read from the file header
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+
+    /// Crops that would be applied to the output image, as [x, y, width, height]
+    pub crops: Vec<[usize; 4]>,
+
+    /// Calibration data the pipeline needs but which cannot be found
+    pub missing: Vec<String>,
+    pub notes: Vec<String>,
+
+    #[serde(skip)]
+    pub instrument_id: Instrument,
+
+    #[serde(skip)]
+    pub metadata: Metadata,
+}
+
+impl CalPlan {
+    pub fn new(
+        input_file: &str,
+        profile: &CalProfile,
+        instrument: Instrument,
+        metadata: Metadata,
+    ) -> Self {
+        let (width, height) = match image::image_dimensions(input_file) {
+            Ok((w, h)) => (Some(w as usize), Some(h as usize)),
+            Err(_) => (None, None),
+        };
+
+        CalPlan {
+            input_file: input_file.to_owned(),
+            output_file: util::append_file_name(input_file, profile.filename_suffix.as_str()),
+            instrument: format!("{:?}", instrument),
+            instrument_source: None,
+            profile: profile.clone(),
+            profile_selection: None,
+            stages: vec![],
+            lut: None,
+            flat: None,
+            inpaint_mask: None,
+            mask: None,
+            bias: None,
+            dark: None,
+            subframe_rect: metadata.subframe_rect.clone(),
+            scale_factor: metadata.scale_factor,
+            width,
+            height,
+            crops: vec![],
+            missing: vec![],
+            notes: vec![],
+            instrument_id: instrument,
+            metadata,
+        }
+    }
+
+    /// True if every calibration file the pipeline needs was found
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    pub fn add_missing(&mut self, why: &str) {
+        self.missing.push(why.to_owned());
+    }
+
+    pub fn add_note(&mut self, note: &str) {
+        self.notes.push(note.to_owned());
+    }
+
+    pub fn set_calibration_file(&mut self, file_type: CalFileType, path: &str) {
+        let path = Some(path.to_owned());
+        match file_type {
+            CalFileType::FlatField => self.flat = path,
+            CalFileType::InpaintMask => self.inpaint_mask = path,
+            CalFileType::Mask => self.mask = path,
+            CalFileType::Lut => self.lut = path,
+            CalFileType::Bias => self.bias = path,
+            CalFileType::Dark => self.dark = path,
+        }
+    }
+
+    /// Records a crop and tracks the resulting image size
+    pub fn add_crop(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.crops.push([x, y, width, height]);
+        self.width = Some(width);
+        self.height = Some(height);
+    }
+
+    /// Records the removal of a border of the given widths from each edge of the image
+    pub fn add_border_crop(&mut self, left: usize, top: usize, right: usize, bottom: usize) {
+        match (self.width, self.height) {
+            (Some(width), Some(height)) if width > left + right && height > top + bottom => {
+                self.add_crop(left, top, width - left - right, height - top - bottom);
+            }
+            (Some(width), Some(height)) => self.add_note(&format!(
+                "Image of {}x{} pixels is too small for a border crop of {},{},{},{} pixels, crop would be skipped",
+                width, height, left, top, right, bottom
+            )),
+            _ => self.add_note(&format!(
+                "Border crop of {},{},{},{} pixels depends on the image size, which is unknown",
+                left, top, right, bottom
+            )),
+        }
+    }
+}
+
+/// Resolves the path of a calibration file for the planned instrument, recording it in the
+/// plan or noting it as missing.
+pub fn plan_calibration_file(plan: &mut CalPlan, file_type: CalFileType) -> Option<String> {
+    match calibfile::get_calibration_file_for_instrument(plan.instrument_id, file_type) {
+        Ok(file_path) => {
+            plan.set_calibration_file(file_type, &file_path);
+            Some(file_path)
+        }
+        Err(why) => {
+            plan.add_missing(&why.to_string());
+            None
+        }
+    }
+}
+
+/// Records an already resolved calibration file path, noting it as missing if it does not
+/// exist on disk.
+pub fn plan_resolved_file(plan: &mut CalPlan, file_type: CalFileType, file_path: &str) {
+    if path::file_exists(file_path) {
+        plan.set_calibration_file(file_type, file_path);
+    } else {
+        plan.add_missing(&CalError::MissingFile(file_path.to_owned()).to_string());
+    }
+}
+
+/// Records the conversion to physical units for an image taken through `filter`, noting any
+/// coefficients or metadata the conversion needs but cannot find.
+pub fn plan_radiometric(plan: &mut CalPlan, units: Option<RadiometricUnits>, filter: &str) {
+    let units = units
+        .or(plan.profile.radiometric_units)
+        .unwrap_or(RadiometricUnits::Radiance);
+    identify::apply_label_metadata(&plan.input_file, &mut plan.metadata);
+    match radiometry::compute_calibration_for_instrument(
+        plan.instrument_id,
+        units,
+        filter,
+        &plan.metadata,
+    ) {
+        Ok(calibration) => plan.add_note(&format!(
+            "Converted to {} for filter {} with scale {:?}",
+            units.label(),
+            filter,
+            calibration.scale
+        )),
+        Err(why) => plan.add_missing(&format!(
+            "Unable to convert to {} for filter {}: {}",
+            units.label(),
+            filter,
+            why
+        )),
+    }
+}
+
+/// Builds the calibration plan for a file by walking the same stages `process_file` would run.
+pub fn plan_file<C: Calibration + ?Sized>(
+    calibrator: &C,
+    input_file: &str,
+    instrument: Instrument,
+    profile: &CalProfile,
+) -> Result<CalPlan> {
+    let metadata = MarsImage::load_image_metadata(input_file)?;
+    let mut plan = CalPlan::new(input_file, profile, instrument, metadata);
+
+    if !path::file_exists(input_file) {
+        plan.add_missing(&CalError::MissingFile(input_file.to_owned()).to_string());
+    }
+
+    let stages = calpipeline::stages_for_profile(calibrator, input_file, instrument, profile);
+    plan.output_file = calpipeline::output_file_name(input_file, profile, &stages);
+
+    for stage in stages.iter() {
+        plan.stages.push(stage.name().to_owned());
+        calibrator.plan_stage(stage, &mut plan)?;
+    }
+    Ok(plan)
+}
+
+/// The instrument-agnostic planning of each stage, mirroring `calpipeline::apply_stage`.
+pub fn plan_stage(stage: &CalStage, plan: &mut CalPlan) -> Result<()> {
+    match stage {
+        CalStage::Decompand => {
+            plan.lut = Some(
+                decompanding::get_ilt_path_for_instrument(plan.instrument_id)
+                    .unwrap_or_else(|| "built-in".to_owned()),
+            );
+        }
+        CalStage::Mask => {
+            plan_calibration_file(plan, CalFileType::Mask);
+        }
+        CalStage::BiasSubtraction { bias } => match bias
+            .map(BiasLevel::Constant)
+            .or_else(|| biasdark::bias_for_instrument(plan.instrument_id))
+        {
+            Some(BiasLevel::Constant(bias)) => plan.bias = Some(bias.to_string()),
+            Some(BiasLevel::Frame(file_name)) => {
+                match calibfile::locate_calibration_file(&file_name) {
+                    Ok(file_path) => plan.set_calibration_file(CalFileType::Bias, &file_path),
+                    Err(why) => plan.add_missing(&why.to_string()),
+                }
+            }
+            None => plan.add_note(&format!(
+                "No bias level known for instrument {:?}",
+                plan.instrument_id
+            )),
+        },
+        CalStage::DarkSubtraction { scale } => {
+            if plan_calibration_file(plan, CalFileType::Dark).is_some() {
+                let scale = match scale {
+                    Some(scale) => *scale,
+                    None => {
+                        identify::apply_label_metadata(&plan.input_file, &mut plan.metadata);
+                        biasdark::dark_scale_factor_for_instrument(
+                            plan.instrument_id,
+                            &plan.metadata,
+                        )
+                    }
+                };
+                plan.add_note(&format!("Dark frame scaled by {}", scale));
+            }
+        }
+        CalStage::Flat => {
+            plan_calibration_file(plan, CalFileType::FlatField);
+        }
+        CalStage::Inpaint => {
+            if inpaintmask::inpaint_supported_for_instrument(plan.instrument_id) {
+                plan_calibration_file(plan, CalFileType::InpaintMask);
+            } else {
+                plan.add_note(&format!(
+                    "Inpainting not supported for instrument {:?}",
+                    plan.instrument_id
+                ));
+            }
+        }
+        CalStage::Crop { rect } => match rect {
+            Some(rect) if rect.len() == 4 => plan.add_crop(rect[0], rect[1], rect[2], rect[3]),
+            Some(_) => {
+                return Err(anyhow!(
+                    "Crop rectangle must be specified as [x, y, width, height]"
+                ))
+            }
+            None => plan.add_note(&format!(
+                "No automatic subframing defined for instrument {:?}",
+                plan.instrument_id
+            )),
+        },
+        CalStage::ColorMatrix { matrix, offset } => {
+            match colormatrix::resolve_color_matrix(
+                matrix,
+                offset,
+                &plan.profile,
+                plan.instrument_id,
+            ) {
+                Some((color_matrix, source)) => plan.add_note(&format!(
+                    "Color matrix from {}: {:?}, offset {:?}",
+                    source, color_matrix.matrix, color_matrix.offset
+                )),
+                None => {
+                    plan.add_missing(&CalError::MissingColorMatrix(plan.instrument_id).to_string())
+                }
+            }
+        }
+        CalStage::Radiometric { units } => match plan.metadata.filter_name.clone() {
+            Some(filter) => plan_radiometric(plan, *units, &filter),
+            None => plan.add_missing(
+                "Image metadata does not include a filter name for radiometric calibration",
+            ),
+        },
+        _ => {}
+    }
+    Ok(())
+}
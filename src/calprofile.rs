@@ -1,14 +1,70 @@
-use crate::{calibfile, constants};
+use crate::{
+    calibfile,
+    calpipeline::CalStage,
+    colormatrix::ColorMatrix,
+    constants,
+    enums::{Instrument, Mission},
+    m20::fetch::M20Fetch,
+    msl::fetch::MslFetch,
+    nsyt::fetch::NsytFetch,
+    radiometry::RadiometricUnits,
+    remotequery::Fetch,
+    util::InstrumentMap,
+};
 use anyhow::anyhow;
 use anyhow::Result;
 use regex::Regex;
 use sciimg::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
+use std::str::FromStr;
+use toml::{Table, Value};
 
 lazy_static! {
     static ref CAL_TYPE_REGEX: Regex = Regex::new(r#"calfiletype\s+=\s+"profile""#).unwrap();
+
+    /// Every field a profile file may set: those of `CalProfile` along with `extends`
+    pub static ref PROFILE_FIELDS: Vec<&'static str> = {
+        let mut fields = vec!["extends"];
+        fields.extend(struct_fields::<CalProfile>());
+        fields
+    };
+}
+
+/// Captures the field names a derived `Deserialize` implementation asks for, then bails out
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> de::Deserializer<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("field names captured"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+/// The names of a struct's fields as serde deserializes them
+fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -59,6 +115,40 @@ pub struct CalProfile {
 
     #[serde(default = "default_true")]
     pub auto_subframing: bool,
+
+    /// An explicit, ordered list of calibration stages. When not set, the instrument
+    /// calibrator's default pipeline is used.
+    #[serde(default)]
+    pub pipeline: Option<Vec<CalStage>>,
+
+    /// Write the image after every calibration stage for debugging
+    #[serde(default = "default_false")]
+    pub dump_stages: bool,
+
+    /// Restricts automatic profile selection to images taken through this filter
+    pub filter_name: Option<String>,
+
+    /// Breaks ties between profiles that match an image equally well during automatic
+    /// profile selection. Higher wins.
+    #[serde(default)]
+    pub auto_priority: i32,
+
+    /// Convert to physical units, `radiance` or `iof`, and write floating point output
+    /// instead of a normalized 16 bit image. Only supported by instruments with radiometric
+    /// coefficients in their calibration data.
+    pub radiometric_units: Option<RadiometricUnits>,
+
+    /// Apply the instrument's color correction matrix after the color weights and before
+    /// any sRGB conversion
+    #[serde(default = "default_false")]
+    pub apply_color_matrix: bool,
+
+    /// A 3x3 color correction matrix, row major, replacing the instrument's. Setting one
+    /// implies `apply_color_matrix`.
+    pub color_matrix: Option<[[f32; 3]; 3]>,
+
+    /// Offset added to each channel after the color matrix, as a fraction of full scale
+    pub color_offset: Option<[f32; 3]>,
 }
 
 impl Default for CalProfile {
@@ -81,10 +171,89 @@ impl Default for CalProfile {
             debayer_method: default_debayer_method(),
             srgb_color_correction: default_false(),
             auto_subframing: default_true(),
+            pipeline: None,
+            dump_stages: default_false(),
+            filter_name: None,
+            auto_priority: 0,
+            radiometric_units: None,
+            apply_color_matrix: default_false(),
+            color_matrix: None,
+            color_offset: None,
         }
     }
 }
 
+impl CalProfile {
+    /// The instruments the profile declares itself for, resolved from its `mission` and
+    /// `instrument` fields. Empty when the profile does not name an instrument.
+    pub fn target_instruments(&self) -> Result<Vec<Instrument>> {
+        let mission = match &self.mission {
+            Some(mission) => Some(Mission::from_str(mission)?),
+            None => None,
+        };
+        match &self.instrument {
+            Some(instrument) => instruments_for_name(instrument, mission),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+fn instrument_map_for_mission(mission: Mission) -> Option<InstrumentMap> {
+    match mission {
+        Mission::MSL => Some(MslFetch::new().make_instrument_map()),
+        Mission::Mars2020 => Some(M20Fetch::new().make_instrument_map()),
+        Mission::InSight => Some(NsytFetch::new().make_instrument_map()),
+        Mission::MerA | Mission::MerB => None,
+    }
+}
+
+/// Resolves an instrument name as used in profiles to the instruments it covers. The name
+/// may be a metadata instrument id (`MCZ_LEFT`) or one of the mission's camera groups
+/// (`MASTCAM`, `NAVCAM`), as accepted by the fetch commands.
+pub fn instruments_for_name(name: &str, mission: Option<Mission>) -> Result<Vec<Instrument>> {
+    let name = name.to_uppercase();
+    let mut instruments: Vec<Instrument> = vec![];
+
+    match Instrument::from_str(&name) {
+        Ok(Instrument::None) | Err(_) => {
+            let missions = match mission {
+                Some(mission) => vec![mission],
+                None => vec![Mission::MSL, Mission::Mars2020, Mission::InSight],
+            };
+            for instrument_map in missions.into_iter().filter_map(instrument_map_for_mission) {
+                if let Ok(remote_names) = instrument_map.find_remote_instrument_names(&name) {
+                    remote_names.iter().for_each(|remote_name| {
+                        if let Ok(instrument) = Instrument::from_str(remote_name) {
+                            if instrument != Instrument::None && !instruments.contains(&instrument)
+                            {
+                                instruments.push(instrument);
+                            }
+                        }
+                    });
+                }
+            }
+        }
+        Ok(instrument) => instruments.push(instrument),
+    }
+
+    if let Some(mission) = mission {
+        instruments.retain(|i| i.mission() == Some(mission));
+    }
+
+    if instruments.is_empty() {
+        Err(anyhow!(
+            "Unknown instrument '{}'{}",
+            name,
+            match mission {
+                Some(mission) => format!(" for mission {:?}", mission),
+                None => String::new(),
+            }
+        ))
+    } else {
+        Ok(instruments)
+    }
+}
+
 fn default_debayer_method() -> DebayerMethod {
     DebayerMethod::Malvar
 }
@@ -121,35 +290,547 @@ fn default_hpc_threshold() -> f32 {
     0.0
 }
 
-pub fn load_calibration_profile(file_path: &String) -> Result<CalProfile> {
-    match calibfile::locate_calibration_file_no_extention(file_path, &".toml".to_string()) {
-        Ok(located_file) => {
-            let mut file = match File::open(&located_file) {
-                Err(why) => panic!("couldn't open {}", why),
-                Ok(file) => file,
+pub fn parse_calibration_profile(text: &str) -> Result<CalProfile> {
+    if !CAL_TYPE_REGEX.is_match(text) {
+        return Err(anyhow!("Invalid calibration profile file"));
+    }
+
+    match toml::from_str(text) {
+        Ok(calprof) => Ok(calprof),
+        Err(why) => {
+            error!("Reason: {:?}", why);
+            Err(anyhow!("Error parsing calibration profile file"))
+        }
+    }
+}
+
+/// Source label for values set on the command line
+pub const COMMAND_LINE_SOURCE: &str = "command line";
+
+/// Source label for values no layer has set
+pub const DEFAULT_SOURCE: &str = "default";
+
+/// A calibration profile assembled from one or more layers, along with the layer that set
+/// each value.
+#[derive(Debug, Clone)]
+pub struct ResolvedProfile {
+    pub profile: CalProfile,
+
+    /// Dotted field path -> the profile (or `COMMAND_LINE_SOURCE`) that set it
+    pub sources: BTreeMap<String, String>,
+}
+
+impl ResolvedProfile {
+    /// Where a top level field's value came from
+    pub fn source_of(&self, field: &str) -> &str {
+        match self.sources.get(field) {
+            Some(source) => source.as_str(),
+            None => {
+                let prefix = format!("{}.", field);
+                match self.sources.iter().find(|(k, _)| k.starts_with(&prefix)) {
+                    Some((_, source)) => source.as_str(),
+                    None => DEFAULT_SOURCE,
+                }
+            }
+        }
+    }
+}
+
+/// Calibration profile fragments merged in the order they are added. Later layers override
+/// earlier ones; tables are merged key by key while all other values, including arrays such as
+/// `pipeline`, are replaced whole. A profile may name a parent with `extends`, which is merged
+/// in before the profile itself.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileLayers {
+    table: Table,
+    sources: BTreeMap<String, String>,
+
+    /// Each layer as it was added, for validation
+    layers: Vec<(String, Table)>,
+}
+
+impl ProfileLayers {
+    pub fn new() -> Self {
+        ProfileLayers::default()
+    }
+
+    /// Layers for a profile specification. Several profiles may be stacked by joining their
+    /// names with `+`, e.g. `m20_zcam_rad+my_tweaks`, with later profiles taking precedence.
+    pub fn from_spec(spec: &str) -> Result<Self> {
+        let mut layers = ProfileLayers::new();
+        for name in spec.split('+').filter(|n| !n.is_empty()) {
+            layers.add_profile(name)?;
+        }
+        Ok(layers)
+    }
+
+    /// Layers starting from an existing profile, attributing all of its values to `source`
+    pub fn from_profile(profile: &CalProfile, source: &str) -> Result<Self> {
+        let mut layers = ProfileLayers::new();
+        layers.add_table(&Table::try_from(profile)?, source);
+        Ok(layers)
+    }
+
+    /// Adds a profile file, by name or path, along with its `extends` ancestors
+    pub fn add_profile(&mut self, name: &str) -> Result<()> {
+        let mut chain: Vec<String> = vec![];
+        self.add_profile_file(name, None, &mut chain)
+    }
+
+    fn add_profile_file(
+        &mut self,
+        name: &str,
+        relative_to: Option<&str>,
+        chain: &mut Vec<String>,
+    ) -> Result<()> {
+        let located_file = locate_profile(name, relative_to)?;
+        if chain.contains(&located_file) {
+            return Err(anyhow!(
+                "Calibration profile inheritance cycle: {} -> {}",
+                chain.join(" -> "),
+                located_file
+            ));
+        }
+        chain.push(located_file.clone());
+
+        let mut table = read_profile_table(&located_file)?;
+        let parents = match table.remove("extends") {
+            None => vec![],
+            Some(Value::String(parent)) => vec![parent],
+            Some(Value::Array(parents)) => parents
+                .into_iter()
+                .map(|p| match p {
+                    Value::String(parent) => Ok(parent),
+                    _ => Err(anyhow!("Invalid extends value in {}", located_file)),
+                })
+                .collect::<Result<Vec<String>>>()?,
+            Some(_) => return Err(anyhow!("Invalid extends value in {}", located_file)),
+        };
+
+        for parent in parents.iter() {
+            info!("Profile {} extends {}", name, parent);
+            self.add_profile_file(parent, Some(&located_file), chain)?;
+        }
+        chain.pop();
+
+        self.add_table(&table, name);
+        Ok(())
+    }
+
+    /// Merges a table of profile values, attributing each to `source`
+    pub fn add_table(&mut self, table: &Table, source: &str) {
+        merge_table(&mut self.table, table, source, "", &mut self.sources);
+        self.layers.push((source.to_owned(), table.clone()));
+    }
+
+    /// Overrides a single value from the command line
+    pub fn set_override<T: Serialize>(&mut self, field: &str, value: T) -> Result<()> {
+        let mut table = Table::new();
+        table.insert(field.to_owned(), Value::try_from(value)?);
+        self.add_table(&table, COMMAND_LINE_SOURCE);
+        Ok(())
+    }
+
+    /// Overrides a value from a `field=value` assignment. The value is read as TOML, falling
+    /// back to a plain string, so `red_scalar=1.2` and `debayer_method=amaze` both work.
+    pub fn set_override_from_str(&mut self, assignment: &str) -> Result<()> {
+        let table: Table = match toml::from_str(assignment) {
+            Ok(table) => table,
+            Err(_) => match assignment.split_once('=') {
+                Some((field, value)) => {
+                    let mut table = Table::new();
+                    table.insert(
+                        field.trim().to_owned(),
+                        Value::String(value.trim().to_owned()),
+                    );
+                    table
+                }
+                None => {
+                    return Err(anyhow!(
+                        "Invalid profile override '{}', expected field=value",
+                        assignment
+                    ))
+                }
+            },
+        };
+        self.add_table(&table, COMMAND_LINE_SOURCE);
+        Ok(())
+    }
+
+    /// Checks every layer for unknown fields and stage parameters, and the merged profile for
+    /// out of range values and unknown missions or instruments. Returns the problems found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut issues = vec![];
+        self.layers
+            .iter()
+            .for_each(|(source, table)| lint_profile_table(table, source, &mut issues));
+
+        match self.resolve() {
+            Ok(resolved) => issues.extend(validate_profile(&resolved.profile)),
+            Err(why) => issues.push(why.to_string()),
+        }
+        issues
+    }
+
+    /// Resolves the profile, failing if validation finds any problems
+    pub fn resolve_strict(&self) -> Result<ResolvedProfile> {
+        let issues = self.validate();
+        if issues.is_empty() {
+            self.resolve()
+        } else {
+            Err(anyhow!(
+                "Invalid calibration profile:\n    {}",
+                issues.join("\n    ")
+            ))
+        }
+    }
+
+    pub fn resolve(&self) -> Result<ResolvedProfile> {
+        let mut table = self.table.clone();
+        if !table.contains_key("calfiletype") {
+            table.insert("calfiletype".into(), Value::String("profile".into()));
+        }
+
+        let profile: CalProfile = match table.try_into() {
+            Ok(profile) => profile,
+            Err(why) => {
+                error!("Reason: {:?}", why);
+                return Err(anyhow!("Error parsing calibration profile: {}", why));
+            }
+        };
+
+        Ok(ResolvedProfile {
+            profile,
+            sources: self.sources.clone(),
+        })
+    }
+}
+
+/// The most similar known name, for suggesting corrections to misspelled keys
+fn closest_name<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn unknown_key_issue(source: &str, context: &str, key: &str, known: &[&str]) -> String {
+    match closest_name(key, known) {
+        Some(suggestion) => format!(
+            "{}: unknown {} '{}', did you mean '{}'?",
+            source, context, key, suggestion
+        ),
+        None => format!("{}: unknown {} '{}'", source, context, key),
+    }
+}
+
+fn is_valid_debayer_method(value: &Value) -> bool {
+    match value {
+        Value::String(method) => {
+            DebayerMethod::from_str(method).is_ok()
+                || value.clone().try_into::<DebayerMethod>().is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Checks a single profile layer for keys and stage parameters that would otherwise be ignored
+fn lint_profile_table(table: &Table, source: &str, issues: &mut Vec<String>) {
+    table
+        .keys()
+        .filter(|key| !PROFILE_FIELDS.contains(&key.as_str()))
+        .for_each(|key| issues.push(unknown_key_issue(source, "field", key, &PROFILE_FIELDS)));
+
+    if let Some(method) = table.get("debayer_method") {
+        if !is_valid_debayer_method(method) {
+            issues.push(format!("{}: invalid debayer method {}", source, method));
+        }
+    }
+
+    if let Some(pipeline) = table.get("pipeline") {
+        let stages = match pipeline {
+            Value::Array(stages) => stages,
+            _ => {
+                issues.push(format!("{}: pipeline must be an array of stages", source));
+                return;
+            }
+        };
+
+        for (index, stage) in stages.iter().enumerate() {
+            let stage_source = format!("{} pipeline stage {}", source, index + 1);
+            let stage_table = match stage {
+                Value::Table(stage_table) => stage_table,
+                _ => {
+                    issues.push(format!("{}: stage must be a table", stage_source));
+                    continue;
+                }
+            };
+            let stage_name = match stage_table.get("stage") {
+                Some(Value::String(stage_name)) => stage_name,
+                _ => {
+                    issues.push(format!("{}: missing stage name", stage_source));
+                    continue;
+                }
             };
+            let parameters = match CalStage::parameter_names(stage_name) {
+                Some(parameters) => parameters,
+                None => {
+                    issues.push(format!("{}: unknown stage '{}'", stage_source, stage_name));
+                    continue;
+                }
+            };
+            stage_table
+                .keys()
+                .filter(|key| key.as_str() != "stage" && !parameters.contains(&key.as_str()))
+                .for_each(|key| {
+                    issues.push(unknown_key_issue(
+                        &stage_source,
+                        &format!("{} parameter", stage_name),
+                        key,
+                        parameters,
+                    ))
+                });
+            if let Some(method) = stage_table.get("method") {
+                if !is_valid_debayer_method(method) {
+                    issues.push(format!(
+                        "{}: invalid debayer method {}",
+                        stage_source, method
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Checks the values of a resolved profile, returning the problems found
+pub fn validate_profile(profile: &CalProfile) -> Vec<String> {
+    let mut issues = vec![];
+
+    if profile.calfiletype != "profile" {
+        issues.push(format!(
+            "calfiletype must be \"profile\", found \"{}\"",
+            profile.calfiletype
+        ));
+    }
 
-            let mut buf: Vec<u8> = Vec::default();
-            file.read_to_end(&mut buf).unwrap();
-            let text = String::from_utf8(buf).unwrap();
+    [
+        ("red_scalar", profile.red_scalar),
+        ("green_scalar", profile.green_scalar),
+        ("blue_scalar", profile.blue_scalar),
+    ]
+    .iter()
+    .filter(|(_, scalar)| *scalar <= 0.0)
+    .for_each(|(field, scalar)| {
+        issues.push(format!(
+            "{} must be greater than zero, found {}",
+            field, scalar
+        ))
+    });
+
+    if profile.hot_pixel_window_size < 1 {
+        issues.push(format!(
+            "hot_pixel_window_size must be at least 1, found {}",
+            profile.hot_pixel_window_size
+        ));
+    }
+
+    if profile.hot_pixel_detection_threshold < 0.0 {
+        issues.push(format!(
+            "hot_pixel_detection_threshold cannot be negative, found {}",
+            profile.hot_pixel_detection_threshold
+        ));
+    }
 
-            if !CAL_TYPE_REGEX.is_match(&text) {
-                return Err(anyhow!("Invalid calibration profile file"));
+    if profile.color_noise_reduction_amount < 0 {
+        issues.push(format!(
+            "color_noise_reduction_amount cannot be negative, found {}",
+            profile.color_noise_reduction_amount
+        ));
+    }
+
+    if profile.filename_suffix.is_empty() {
+        issues.push("filename_suffix cannot be empty".to_string());
+    }
+
+    // An unknown mission makes the instrument unresolvable, so only report it once
+    match profile
+        .mission
+        .as_ref()
+        .map(|mission| Mission::from_str(mission))
+    {
+        Some(Err(why)) => issues.push(why.to_string()),
+        _ => {
+            if let Err(why) = profile.target_instruments() {
+                issues.push(why.to_string());
             }
+        }
+    }
 
-            match toml::from_str(&text) {
-                Ok(calprof) => {
-                    info!("Loaded calibration profile from {}", located_file);
-                    info!("Profile: {:?}", calprof);
-                    Ok(calprof)
+    if let Some(matrix) = profile.color_matrix {
+        if ColorMatrix::new(matrix, [0.0; 3]).is_singular() {
+            issues.push("color_matrix is singular".to_string());
+        }
+    }
+
+    if let Some(pipeline) = &profile.pipeline {
+        pipeline
+            .iter()
+            .enumerate()
+            .for_each(|(index, stage)| match stage {
+                CalStage::Weights { red, green, blue } => {
+                    if [red, green, blue].iter().any(|w| w.unwrap_or(1.0) <= 0.0) {
+                        issues.push(format!(
+                            "pipeline stage {}: weights must be greater than zero",
+                            index + 1
+                        ));
+                    }
+                }
+                CalStage::HotPixelCorrection {
+                    window_size,
+                    threshold,
+                } => {
+                    if window_size.unwrap_or(1) < 1 || threshold.unwrap_or(0.0) < 0.0 {
+                        issues.push(format!(
+                            "pipeline stage {}: hot pixel window size must be at least 1 and the threshold cannot be negative",
+                            index + 1
+                        ));
+                    }
+                }
+                CalStage::DarkSubtraction { scale: Some(scale) } if *scale < 0.0 => {
+                    issues.push(format!(
+                        "pipeline stage {}: dark frame scale cannot be negative",
+                        index + 1
+                    ));
+                }
+                CalStage::ColorMatrix {
+                    matrix: Some(matrix),
+                    ..
+                } if ColorMatrix::new(*matrix, [0.0; 3]).is_singular() => {
+                    issues.push(format!(
+                        "pipeline stage {}: color matrix is singular",
+                        index + 1
+                    ));
+                }
+                CalStage::ColorNoiseReduction { amount: Some(amount) } if *amount < 0 => {
+                    issues.push(format!(
+                        "pipeline stage {}: color noise reduction amount cannot be negative",
+                        index + 1
+                    ));
                 }
-                Err(why) => {
-                    error!("Error parsing calibration profile file: {}", located_file);
-                    error!("Reason: {:?}", why);
-                    Err(anyhow!("Error parsing calibration profile file"))
+                CalStage::Crop { rect: Some(rect) }
+                    if rect.len() != 4 || rect[2] == 0 || rect[3] == 0 =>
+                {
+                    issues.push(format!(
+                        "pipeline stage {}: crop rectangle must be [x, y, width, height] with a non-zero size",
+                        index + 1
+                    ));
                 }
+                _ => {}
+            });
+    }
+
+    issues
+}
+
+/// True if the file declares itself a calibration profile
+pub fn is_calibration_profile_file(file_path: &str) -> bool {
+    match std::fs::read_to_string(file_path) {
+        Ok(text) => CAL_TYPE_REGEX.is_match(&text),
+        Err(_) => false,
+    }
+}
+
+fn merge_table(
+    base: &mut Table,
+    layer: &Table,
+    source: &str,
+    prefix: &str,
+    sources: &mut BTreeMap<String, String>,
+) {
+    for (key, value) in layer.iter() {
+        let field_path = format!("{}{}", prefix, key);
+        match (base.get_mut(key), value) {
+            (Some(Value::Table(base_table)), Value::Table(layer_table)) => {
+                merge_table(
+                    base_table,
+                    layer_table,
+                    source,
+                    &format!("{}.", field_path),
+                    sources,
+                );
+            }
+            _ => {
+                // Values nested below a replaced value no longer come from the earlier layers
+                let nested_prefix = format!("{}.", field_path);
+                sources.retain(|k, _| !k.starts_with(&nested_prefix));
+                base.insert(key.clone(), value.clone());
+                sources.insert(field_path, source.to_owned());
             }
         }
-        Err(why) => Err(anyhow!(why)),
     }
 }
+
+/// Finds a profile by path or name, checking next to the profile that referenced it before
+/// the standard calibration data locations.
+fn locate_profile(name: &str, relative_to: Option<&str>) -> Result<String> {
+    if let Some(referencing_file) = relative_to {
+        let sibling = format!("{}/{}", path::get_parent(referencing_file), name);
+        for candidate in [sibling.clone(), format!("{}.toml", sibling)] {
+            if path::file_exists(&candidate) {
+                return Ok(candidate);
+            }
+        }
+    }
+    calibfile::locate_calibration_file_no_extention(&name.to_string(), &".toml".to_string())
+}
+
+fn read_profile_table(located_file: &str) -> Result<Table> {
+    let mut file = File::open(located_file)?;
+    let mut buf: Vec<u8> = Vec::default();
+    file.read_to_end(&mut buf)?;
+    let text = String::from_utf8(buf)?;
+
+    if !CAL_TYPE_REGEX.is_match(&text) {
+        error!("Error parsing calibration profile file: {}", located_file);
+        return Err(anyhow!("Invalid calibration profile file"));
+    }
+
+    match toml::from_str(&text) {
+        Ok(table) => {
+            info!("Loaded calibration profile from {}", located_file);
+            Ok(table)
+        }
+        Err(why) => {
+            error!("Error parsing calibration profile file: {}", located_file);
+            error!("Reason: {:?}", why);
+            Err(anyhow!("Error parsing calibration profile file"))
+        }
+    }
+}
+
+/// Resolves a profile specification, see `ProfileLayers::from_spec`
+pub fn resolve_calibration_profile(spec: &str) -> Result<ResolvedProfile> {
+    ProfileLayers::from_spec(spec)?.resolve()
+}
+
+pub fn load_calibration_profile(file_path: &String) -> Result<CalProfile> {
+    let resolved = resolve_calibration_profile(file_path)?;
+    info!("Profile: {:?}", resolved.profile);
+    Ok(resolved.profile)
+}
@@ -0,0 +1,136 @@
+use crate::{calprofile::CalProfile, enums::CalFileType};
+
+use anyhow::Result;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+
+/// A calibration file read while processing an image
+#[derive(Serialize, Debug, Clone)]
+pub struct CalFileUse {
+    pub file_type: String,
+    pub path: String,
+
+    /// The file's share of a blend, such as flats interpolated between zoom stops
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f32>,
+}
+
+/// Time spent in a single calibration stage
+#[derive(Serialize, Debug, Clone)]
+pub struct StageTiming {
+    pub stage: String,
+    pub elapsed_ms: f64,
+}
+
+/// Machine-readable record of the calibration of one input file with one profile
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CalReport {
+    pub input_file: String,
+    pub output_file: Option<String>,
+    pub profile: Option<String>,
+
+    /// The automatically selected profile and why it was chosen
+    pub profile_selection: Option<String>,
+    pub filename_suffix: String,
+    pub instrument: Option<String>,
+
+    /// How the instrument was identified: "metadata", "forced", "label" or "product id"
+    pub instrument_source: Option<String>,
+    pub calibration_files: Vec<CalFileUse>,
+
+    /// Crops applied to the output image, as [x, y, width, height]
+    pub crops: Vec<[usize; 4]>,
+    pub warnings: Vec<String>,
+    pub stages: Vec<StageTiming>,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+fn cal_file_type_name(file_type: CalFileType) -> &'static str {
+    match file_type {
+        CalFileType::FlatField => "flat",
+        CalFileType::InpaintMask => "inpaint_mask",
+        CalFileType::Mask => "mask",
+        CalFileType::Lut => "lut",
+        CalFileType::Bias => "bias",
+        CalFileType::Dark => "dark",
+    }
+}
+
+impl CalReport {
+    pub fn new(input_file: &str, profile: &CalProfile) -> Self {
+        CalReport {
+            input_file: input_file.to_owned(),
+            profile: profile.description.clone(),
+            filename_suffix: profile.filename_suffix.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Records a calibration file, ignoring repeated uses of the same file
+    pub fn add_calibration_file(&mut self, file_type: CalFileType, path: &str) {
+        self.push_calibration_file(file_type, path, None);
+    }
+
+    /// Records a calibration file that contributes `weight` of a blend
+    pub fn add_weighted_calibration_file(
+        &mut self,
+        file_type: CalFileType,
+        path: &str,
+        weight: f32,
+    ) {
+        self.push_calibration_file(file_type, path, Some(weight));
+    }
+
+    fn push_calibration_file(&mut self, file_type: CalFileType, path: &str, weight: Option<f32>) {
+        let file_type = cal_file_type_name(file_type);
+        if !self
+            .calibration_files
+            .iter()
+            .any(|f| f.file_type == file_type && f.path == path)
+        {
+            self.calibration_files.push(CalFileUse {
+                file_type: file_type.to_owned(),
+                path: path.to_owned(),
+                weight,
+            });
+        }
+    }
+
+    pub fn add_crop(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.crops.push([x, y, width, height]);
+    }
+
+    pub fn add_stage_timing(&mut self, stage: &str, elapsed_ms: f64) {
+        self.stages.push(StageTiming {
+            stage: stage.to_owned(),
+            elapsed_ms,
+        });
+    }
+
+    /// Sets the final status: "ok", "warn", "skipped" or "fail"
+    pub fn set_status(&mut self, status: &str) {
+        self.status = status.to_owned();
+    }
+
+    /// Marks the report as failed with the reason the calibration could not complete
+    pub fn set_error(&mut self, why: &str) {
+        self.status = "fail".to_owned();
+        self.error = Some(why.to_owned());
+    }
+}
+
+/// Writes the reports (or calibration plans) to disk. Files with a `.jsonl` extension receive
+/// one JSON object per line, anything else is written as a single JSON array.
+pub fn save_reports<T: Serialize>(output_file: &str, reports: &[T]) -> Result<()> {
+    let mut file = File::create(output_file)?;
+    if output_file.to_lowercase().ends_with(".jsonl") {
+        for report in reports.iter() {
+            writeln!(file, "{}", serde_json::to_string(report)?)?;
+        }
+    } else {
+        file.write_all(serde_json::to_string_pretty(reports)?.as_bytes())?;
+    }
+    Ok(())
+}
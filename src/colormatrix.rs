@@ -0,0 +1,113 @@
+use crate::{calibfile, calprofile::CalProfile, enums::Instrument};
+
+use anyhow::{anyhow, Result};
+use sciimg::image::Image;
+use serde::Serialize;
+
+/// A 3x3 color correction matrix and per-channel offset, applied to linear camera RGB
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+pub struct ColorMatrix {
+    /// Row major. Output channel `c` is `sum(matrix[c][i] * input[i])` plus its offset.
+    pub matrix: [[f32; 3]; 3],
+
+    /// Added to each output channel, as a fraction of the full data range
+    pub offset: [f32; 3],
+}
+
+impl ColorMatrix {
+    pub fn new(matrix: [[f32; 3]; 3], offset: [f32; 3]) -> Self {
+        ColorMatrix { matrix, offset }
+    }
+
+    pub fn determinant(&self) -> f32 {
+        let m = &self.matrix;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// True if the matrix cannot be inverted, which would collapse colors together
+    pub fn is_singular(&self) -> bool {
+        self.determinant().abs() < 1.0e-6
+    }
+
+    /// Applies the matrix to a single RGB value
+    pub fn transform(&self, rgb: [f32; 3], data_max: f32) -> [f32; 3] {
+        let mut out = [0.0; 3];
+        for (c, row) in self.matrix.iter().enumerate() {
+            out[c] =
+                row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2] + self.offset[c] * data_max;
+        }
+        out
+    }
+}
+
+/// The instrument's color matrix from the calibration data, along with where it came from.
+/// No matrices are built in, so instruments without a `color_matrix` in caldata.toml have
+/// none.
+pub fn color_matrix_for_instrument(instrument: Instrument) -> Option<(ColorMatrix, &'static str)> {
+    let properties = calibfile::get_instrument_properties(instrument).ok()?;
+    properties.color_matrix.map(|matrix| {
+        (
+            ColorMatrix::new(matrix, properties.color_offset.unwrap_or([0.0; 3])),
+            "caldata",
+        )
+    })
+}
+
+/// Resolves the matrix a `color_matrix` stage applies: the stage's own matrix, then the
+/// profile's, then the instrument's. An offset given by the stage or profile replaces the
+/// instrument's offset.
+pub fn resolve_color_matrix(
+    matrix: &Option<[[f32; 3]; 3]>,
+    offset: &Option<[f32; 3]>,
+    profile: &CalProfile,
+    instrument: Instrument,
+) -> Option<(ColorMatrix, &'static str)> {
+    let offset = offset.or(profile.color_offset);
+    let (color_matrix, source) = match (matrix, profile.color_matrix) {
+        (Some(matrix), _) => (ColorMatrix::new(*matrix, [0.0; 3]), "stage"),
+        (None, Some(matrix)) => (ColorMatrix::new(matrix, [0.0; 3]), "profile"),
+        (None, None) => color_matrix_for_instrument(instrument)?,
+    };
+
+    Some((
+        ColorMatrix::new(color_matrix.matrix, offset.unwrap_or(color_matrix.offset)),
+        source,
+    ))
+}
+
+/// Applies the color matrix to every pixel of a three band image, clamping at zero.
+/// `data_max` is the full data range the offset is scaled by.
+pub fn apply_color_matrix(
+    image: &mut Image,
+    color_matrix: &ColorMatrix,
+    data_max: f32,
+) -> Result<()> {
+    if image.num_bands() < 3 {
+        return Err(anyhow!(
+            "A color matrix requires a three band image, found {} bands",
+            image.num_bands()
+        ));
+    }
+
+    let mut bands = [
+        image.get_band(0).clone(),
+        image.get_band(1).clone(),
+        image.get_band(2).clone(),
+    ];
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let rgb = [bands[0].get(x, y), bands[1].get(x, y), bands[2].get(x, y)];
+            let out = color_matrix.transform(rgb, data_max);
+            for (band, value) in bands.iter_mut().zip(out.iter()) {
+                band.put(x, y, value.max(0.0));
+            }
+        }
+    }
+
+    for (b, band) in bands.iter().enumerate() {
+        image.set_band(band, b);
+    }
+    Ok(())
+}
@@ -1,6 +1,9 @@
 use crate::prelude::*;
-use anyhow::Result;
-use sciimg::{drawable::*, max, min, prelude::*, quaternion::Quaternion, vector::Vector};
+use crate::productid::ProductId;
+use crate::serializers::as_cahvore;
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use sciimg::{max, min, prelude::*, vector::Vector};
 use std::str::FromStr;
 
 pub fn get_cahvor(img: &MarsImage) -> Option<CameraModel> {
@@ -11,194 +14,861 @@ pub fn get_cahvor(img: &MarsImage) -> Option<CameraModel> {
     }
 }
 
+/// Radius of the sphere, centered on the mosaic origin, that the images are projected onto
+static SPHERE_RADIUS: f64 = 100.0;
+
+/// Number of points sampled along each image edge when finding its extent on the map
+const EDGE_SAMPLES: usize = 16;
+
+/// How source pixels are sampled onto the mosaic
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    Nearest,
+
+    #[default]
+    Bilinear,
+
+    /// Catmull-Rom
+    Bicubic,
+}
+
+impl FromStr for Interpolation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Ok(Interpolation::Nearest),
+            "bilinear" => Ok(Interpolation::Bilinear),
+            "bicubic" => Ok(Interpolation::Bicubic),
+            _ => Err(anyhow!(
+                "Invalid interpolation '{}', expected 'nearest', 'bilinear' or 'bicubic'",
+                s
+            )),
+        }
+    }
+}
+
+/// How source images are rendered onto the mosaic
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CompositeOptions {
+    pub interpolation: Interpolation,
+
+    /// Width in source pixels over which overlapping images are blended into each other.
+    /// With 0, each mosaic pixel is taken from the image it falls deepest within.
+    pub feather: usize,
+
+    /// Renders left eye images to the red channel and right eye images to green and blue
+    pub anaglyph: bool,
+}
+
+impl Default for CompositeOptions {
+    fn default() -> Self {
+        CompositeOptions {
+            interpolation: Interpolation::Bilinear,
+            feather: 32,
+            anaglyph: false,
+        }
+    }
+}
+
+/// Elevations beyond which the cylindrical and mercator projections are cut off, as both
+/// stretch toward infinity at the zenith and nadir
+const MAX_CYLINDRICAL_ELEVATION: f64 = 85.0;
+
+/// Widest field of view of the perspective projection, in degrees
+const MAX_PERSPECTIVE_FOV: f64 = 170.0;
+
+/// Largest width or height of a map, in pixels
+const MAX_MAP_SIZE: usize = 100_000;
+
+/// How directions around the origin are laid out on the map. Azimuth increases to the
+/// right and elevation upward on all but the polar projection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Projection {
+    /// Azimuth and elevation both linear with pixels
+    #[default]
+    Equirectangular,
+
+    /// Azimuth linear with pixels and the tangent of elevation vertically, keeping straight
+    /// vertical lines straight
+    Cylindrical,
+
+    /// Azimuth linear with pixels and elevation on the mercator scale, preserving local
+    /// shapes
+    Mercator,
+
+    /// A virtual pinhole camera looking toward the center of the bounds
+    Perspective,
+
+    /// Azimuthal equidistant about the nadir, with north up. The "little planet" view.
+    Polar,
+}
+
+impl FromStr for Projection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "equirectangular" => Ok(Projection::Equirectangular),
+            "cylindrical" => Ok(Projection::Cylindrical),
+            "mercator" => Ok(Projection::Mercator),
+            "perspective" => Ok(Projection::Perspective),
+            "polar" => Ok(Projection::Polar),
+            _ => Err(anyhow!(
+                "Invalid projection '{}', expected 'equirectangular', 'cylindrical', 'mercator', 'perspective' or 'polar'",
+                s
+            )),
+        }
+    }
+}
+
+/// Settings for the output map. Anything left as None is fit to the input images.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct MapOptions {
+    pub projection: Projection,
+
+    /// Rotation of the map about the vertical axis, in degrees
+    pub azimuth_rotation: f64,
+
+    /// Azimuths covered by the map, in degrees clockwise from north. The second may exceed
+    /// 180 to cross south.
+    pub azimuth_bounds: Option<[f64; 2]>,
+
+    /// Elevations covered by the map, in degrees above the horizon
+    pub elevation_bounds: Option<[f64; 2]>,
+
+    /// Map scale at the center of the projection
+    pub degrees_per_pixel: Option<f64>,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct MapContext {
-    pub top_lat: f64,
-    pub bottom_lat: f64,
-    pub left_lon: f64,
-    pub right_lon: f64,
+    pub projection: Projection,
+
+    /// Azimuth bounds in degrees. The maximum may exceed 180 for maps that cross south.
+    pub min_azimuth: f64,
+    pub max_azimuth: f64,
+
+    /// Elevation bounds in degrees
+    pub min_elevation: f64,
+    pub max_elevation: f64,
+
     pub width: usize,
     pub height: usize,
     pub degrees_per_pixel: f64,
+
+    /// Center of the projection sphere, the camera center of the first image
+    pub origin: [f64; 3],
+
+    /// Rotation of the map about the vertical axis, in degrees
+    pub azimuth_rotation: f64,
 }
 
-pub struct LatLon {
-    lat: f64,
-    lon: f64,
+/// Wraps an angle in degrees into [-180, 180)
+fn wrap_degrees(degrees: f64) -> f64 {
+    (degrees + 180.0).rem_euclid(360.0) - 180.0
 }
 
-fn vector_to_cylindrical(v: &Vector) -> LatLon {
-    LatLon {
-        lat: v.z.atan2((v.x * v.x + v.y * v.y).sqrt()).to_degrees(),
-        lon: v.y.atan2(v.x).to_degrees() + 180.0,
-    }
+/// Azimuth and elevation in degrees of a direction in a frame with x north, y east and z
+/// down
+fn direction_to_az_el(v: &Vector) -> (f64, f64) {
+    (
+        v.y.atan2(v.x).to_degrees(),
+        -v.z.atan2((v.x * v.x + v.y * v.y).sqrt()).to_degrees(),
+    )
 }
 
-fn lookvector_to_cylindrical(
-    lv: &LookVector,
-    quat_o: Option<&Quaternion>,
-    origin_diff: &Vector,
-) -> LatLon {
-    let ray = lv.intersect_to_sphere(SPHERE_RADIUS);
-    let ray_moved = ray.subtract(origin_diff);
-    let rotated = if let Some(quat) = quat_o {
-        quat.rotate_vector(&ray_moved)
-    } else {
-        ray
-    };
-    vector_to_cylindrical(&rotated)
+fn az_el_to_direction(azimuth: f64, elevation: f64) -> Vector {
+    let (az, el) = (azimuth.to_radians(), elevation.to_radians());
+    Vector::new(el.cos() * az.cos(), el.cos() * az.sin(), -el.sin())
 }
 
-static SPHERE_RADIUS: f64 = 100.0;
+fn rotate_about_z(v: &Vector, degrees: f64) -> Vector {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    Vector::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos, v.z)
+}
+
+fn dot(a: &Vector, b: &Vector) -> f64 {
+    a.x * b.x + a.y * b.y + a.z * b.z
+}
+
+fn normalized(v: &Vector) -> Vector {
+    let len = dot(v, v).sqrt();
+    Vector::new(v.x / len, v.y / len, v.z / len)
+}
+
+/// Points along the edges of an image, including its corners
+fn edge_points(width: usize, height: usize) -> Vec<(f64, f64)> {
+    let (w, h) = (width as f64, height as f64);
+    (0..=EDGE_SAMPLES)
+        .flat_map(|i| {
+            let t = i as f64 / EDGE_SAMPLES as f64;
+            [(t * w, 0.0), (t * w, h), (0.0, t * h), (w, t * h)]
+        })
+        .collect()
+}
 
-fn get_lat_lon(c: &CameraModel, x: usize, y: usize, _quat: &Quaternion) -> Result<LatLon> {
-    match c.ls_to_look_vector(&ImageCoordinate {
-        line: y as f64,
-        sample: x as f64,
-    }) {
-        Ok(lv) => Ok(lookvector_to_cylindrical(&lv, None, &Vector::default())),
-        Err(e) => Err(e),
+/// The vertical coordinate of an elevation in degrees on the cylindrical family of
+/// projections, in radians at the horizon
+fn vertical_scale(projection: Projection, elevation: f64) -> f64 {
+    let el = elevation.to_radians();
+    match projection {
+        Projection::Cylindrical => el.tan(),
+        Projection::Mercator => (std::f64::consts::FRAC_PI_4 + el / 2.0).tan().ln(),
+        _ => el,
     }
 }
 
-pub fn determine_map_context(input_files: &[String], quat: &Quaternion) -> MapContext {
-    let mut context = MapContext {
-        top_lat: -90.0,
-        bottom_lat: 90.0,
-        left_lon: 360.0,
-        right_lon: -360.0,
-        width: 0,
-        height: 0,
-        degrees_per_pixel: 0.0,
-    };
+fn inverse_vertical_scale(projection: Projection, v: f64) -> f64 {
+    match projection {
+        Projection::Cylindrical => v.atan(),
+        Projection::Mercator => 2.0 * v.exp().atan() - std::f64::consts::FRAC_PI_2,
+        _ => v,
+    }
+    .to_degrees()
+}
 
-    input_files.iter().for_each(|input_file| {
-        let img = MarsImage::open(input_file, Instrument::M20MastcamZLeft);
-        if let Some(c) = get_cahvor(&img) {
-            if let Ok(ll) = get_lat_lon(&c, 0, 0, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
-            }
+impl MapContext {
+    /// An empty context around `origin`, to be grown with `include` and then sized with
+    /// `finalize`
+    pub fn new(origin: &Vector, options: &MapOptions) -> Self {
+        MapContext {
+            projection: options.projection,
+            min_azimuth: f64::MAX,
+            max_azimuth: f64::MIN,
+            min_elevation: 90.0,
+            max_elevation: -90.0,
+            width: 0,
+            height: 0,
+            degrees_per_pixel: 0.0,
+            origin: [origin.x, origin.y, origin.z],
+            azimuth_rotation: options.azimuth_rotation,
+        }
+    }
 
-            if let Ok(ll) = get_lat_lon(&c, img.image.width, 0, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
-            }
+    fn origin_vector(&self) -> Vector {
+        Vector::new(self.origin[0], self.origin[1], self.origin[2])
+    }
+
+    fn center_azimuth(&self) -> f64 {
+        (self.min_azimuth + self.max_azimuth) / 2.0
+    }
+
+    fn center_elevation(&self) -> f64 {
+        (self.min_elevation + self.max_elevation) / 2.0
+    }
 
-            if let Ok(ll) = get_lat_lon(&c, 0, img.image.height, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
+    /// An azimuth expressed within 180 degrees of the center of the bounds
+    fn unwrap_azimuth(&self, azimuth: f64) -> f64 {
+        let center = self.center_azimuth();
+        center + wrap_degrees(azimuth - center)
+    }
+
+    fn spans_azimuth(&self, azimuth: f64) -> bool {
+        self.max_azimuth - self.min_azimuth >= 360.0
+            || (self.min_azimuth..=self.max_azimuth).contains(&self.unwrap_azimuth(azimuth))
+    }
+
+    /// Forward, right and down axes of the perspective projection's virtual camera, in the
+    /// map's frame
+    fn perspective_axes(&self) -> [Vector; 3] {
+        let (az, el) = (
+            self.center_azimuth().to_radians(),
+            self.center_elevation().to_radians(),
+        );
+        [
+            az_el_to_direction(self.center_azimuth(), self.center_elevation()),
+            Vector::new(-az.sin(), az.cos(), 0.0),
+            Vector::new(el.sin() * az.cos(), el.sin() * az.sin(), el.cos()),
+        ]
+    }
+
+    /// Focal length of the perspective projection in pixels
+    fn focal_length(&self) -> f64 {
+        1.0 / self.degrees_per_pixel.to_radians()
+    }
+
+    /// Where an image pixel lands on the projection sphere, as a direction from the origin.
+    /// The pixel's look ray is followed from the camera center to the sphere, so cameras
+    /// away from the origin are placed with their parallax.
+    fn ls_to_direction(&self, model: &CameraModel, sample: f64, line: f64) -> Option<Vector> {
+        let lv = model
+            .ls_to_look_vector(&ImageCoordinate { line, sample })
+            .ok()?;
+        let d = normalized(&lv.look_direction);
+        let oc = model.c().subtract(&self.origin_vector());
+
+        // |oc + t * d| = R, for the intersection in front of the camera
+        let b = dot(&oc, &d);
+        let c = dot(&oc, &oc) - SPHERE_RADIUS * SPHERE_RADIUS;
+        let discriminant = b * b - c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let t = -b + discriminant.sqrt();
+        if t <= 0.0 {
+            return None;
+        }
+        Some(Vector::new(oc.x + t * d.x, oc.y + t * d.y, oc.z + t * d.z))
+    }
+
+    /// The map position of a direction from the origin, as fractional pixel coordinates.
+    /// Positions may fall outside of the map. None for directions the projection can't
+    /// show at all.
+    pub fn direction_to_map(&self, direction: &Vector) -> Option<(f64, f64)> {
+        let d = rotate_about_z(direction, self.azimuth_rotation);
+        let (azimuth, elevation) = direction_to_az_el(&d);
+        let scale = self.degrees_per_pixel.to_radians();
+        let (w, h) = (self.width as f64, self.height as f64);
+
+        match self.projection {
+            Projection::Equirectangular | Projection::Cylindrical | Projection::Mercator => {
+                if matches!(
+                    self.projection,
+                    Projection::Cylindrical | Projection::Mercator
+                ) && elevation.abs() > MAX_CYLINDRICAL_ELEVATION
+                {
+                    return None;
+                }
+                let top = vertical_scale(self.projection, self.max_elevation);
+                Some((
+                    (self.unwrap_azimuth(azimuth) - self.min_azimuth).to_radians() / scale,
+                    (top - vertical_scale(self.projection, elevation)) / scale,
+                ))
+            }
+            Projection::Polar => {
+                let (radius, az) = (
+                    (elevation + 90.0).to_radians() / scale,
+                    azimuth.to_radians(),
+                );
+                Some((w / 2.0 + radius * az.sin(), h / 2.0 - radius * az.cos()))
             }
+            Projection::Perspective => {
+                let [forward, right, down] = self.perspective_axes();
+                let depth = dot(&d, &forward);
+                if depth <= 0.0 {
+                    return None;
+                }
+                let f = self.focal_length();
+                Some((
+                    w / 2.0 + f * dot(&d, &right) / depth,
+                    h / 2.0 + f * dot(&d, &down) / depth,
+                ))
+            }
+        }
+    }
 
-            if let Ok(ll) = get_lat_lon(&c, img.image.width, img.image.height, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
+    /// The unit direction from the origin seen at the center of a map pixel. None where
+    /// the pixel falls outside of the bounds.
+    pub fn map_to_direction(&self, x: usize, y: usize) -> Option<Vector> {
+        let (x, y) = (x as f64 + 0.5, y as f64 + 0.5);
+        let scale = self.degrees_per_pixel.to_radians();
+        let (w, h) = (self.width as f64, self.height as f64);
+
+        let d = match self.projection {
+            Projection::Equirectangular | Projection::Cylindrical | Projection::Mercator => {
+                let top = vertical_scale(self.projection, self.max_elevation);
+                az_el_to_direction(
+                    self.min_azimuth + (x * scale).to_degrees(),
+                    inverse_vertical_scale(self.projection, top - y * scale),
+                )
+            }
+            Projection::Polar => {
+                let (u, v) = ((x - w / 2.0) * scale, (h / 2.0 - y) * scale);
+                let azimuth = u.atan2(v).to_degrees();
+                let elevation = (u * u + v * v).sqrt().to_degrees() - 90.0;
+                if !(self.min_elevation..=self.max_elevation).contains(&elevation)
+                    || !self.spans_azimuth(azimuth)
+                {
+                    return None;
+                }
+                az_el_to_direction(azimuth, elevation)
+            }
+            Projection::Perspective => {
+                let [forward, right, down] = self.perspective_axes();
+                let f = self.focal_length();
+                let (u, v) = ((x - w / 2.0) / f, (y - h / 2.0) / f);
+                normalized(&Vector::new(
+                    forward.x + u * right.x + v * down.x,
+                    forward.y + u * right.y + v * down.y,
+                    forward.z + u * right.z + v * down.z,
+                ))
             }
+        };
+        Some(rotate_about_z(&d, -self.azimuth_rotation))
+    }
 
-            if let Ok(ll) = get_lat_lon(&c, img.image.width / 2, 0, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
+    /// Grows the map bounds to cover an image of the given size seen through `model`
+    pub fn include(&mut self, model: &CameraModel, width: usize, height: usize) {
+        let directions = edge_points(width, height)
+            .iter()
+            .filter_map(|(sample, line)| self.ls_to_direction(model, *sample, *line))
+            .collect::<Vec<Vector>>();
+        directions.iter().for_each(|direction| {
+            let (azimuth, elevation) =
+                direction_to_az_el(&rotate_about_z(direction, self.azimuth_rotation));
+            if self.min_azimuth > self.max_azimuth {
+                self.min_azimuth = azimuth;
+                self.max_azimuth = azimuth;
+            } else if self.max_azimuth - self.min_azimuth < 360.0 {
+                let azimuth = self.unwrap_azimuth(azimuth);
+                self.min_azimuth = min!(self.min_azimuth, azimuth);
+                self.max_azimuth = max!(self.max_azimuth, azimuth);
             }
+            self.min_elevation = min!(self.min_elevation, elevation);
+            self.max_elevation = max!(self.max_elevation, elevation);
+        });
+
+        let ang_horiz = model.pixel_angle_horiz().to_degrees();
+        self.degrees_per_pixel = max!(self.degrees_per_pixel, ang_horiz);
+    }
+
+    /// Applies any bounds and scale given in `options`, clamps the bounds to what the
+    /// projection can show, and sizes the map to them
+    pub fn finalize(&mut self, options: &MapOptions) {
+        if let Some([min_azimuth, max_azimuth]) = options.azimuth_bounds {
+            self.min_azimuth = min_azimuth;
+            self.max_azimuth = max_azimuth;
+        }
+        if let Some([min_elevation, max_elevation]) = options.elevation_bounds {
+            self.min_elevation = min_elevation;
+            self.max_elevation = max_elevation;
+        }
+        if let Some(degrees_per_pixel) = options.degrees_per_pixel {
+            self.degrees_per_pixel = degrees_per_pixel;
+        }
+
+        if self.max_azimuth - self.min_azimuth > 360.0 {
+            let center = self.center_azimuth();
+            self.min_azimuth = center - 180.0;
+            self.max_azimuth = center + 180.0;
+        }
+        let max_elevation = match self.projection {
+            Projection::Cylindrical | Projection::Mercator => MAX_CYLINDRICAL_ELEVATION,
+            _ => 90.0,
+        };
+        self.min_elevation = max!(self.min_elevation, -max_elevation);
+        self.max_elevation = min!(self.max_elevation, max_elevation);
+
+        self.width = 0;
+        self.height = 0;
+        if self.degrees_per_pixel <= 0.0
+            || self.max_elevation <= self.min_elevation
+            || self.max_azimuth <= self.min_azimuth
+        {
+            return;
+        }
 
-            if let Ok(ll) = get_lat_lon(&c, img.image.width / 2, img.image.height, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
+        let scale = self.degrees_per_pixel.to_radians();
+        let (width, height) = match self.projection {
+            Projection::Equirectangular | Projection::Cylindrical | Projection::Mercator => (
+                (self.max_azimuth - self.min_azimuth).to_radians() / scale,
+                (vertical_scale(self.projection, self.max_elevation)
+                    - vertical_scale(self.projection, self.min_elevation))
+                    / scale,
+            ),
+            Projection::Polar => {
+                let diameter = 2.0 * (self.max_elevation + 90.0).to_radians() / scale;
+                (diameter, diameter)
+            }
+            Projection::Perspective => {
+                let f = self.focal_length();
+                let fov = |span: f64| min!(span, MAX_PERSPECTIVE_FOV).to_radians() / 2.0;
+                (
+                    2.0 * f * fov(self.max_azimuth - self.min_azimuth).tan(),
+                    2.0 * f * fov(self.max_elevation - self.min_elevation).tan(),
+                )
             }
+        };
+        if width >= MAX_MAP_SIZE as f64 || height >= MAX_MAP_SIZE as f64 {
+            warn!(
+                "Map of {:.0}x{:.0} pixels is too large, reduce the bounds or scale",
+                width, height
+            );
+            return;
+        }
+        self.width = width.floor() as usize;
+        self.height = height.floor() as usize;
+    }
 
-            let ang_horiz = c.pixel_angle_horiz().to_degrees();
-            context.degrees_per_pixel = max!(context.degrees_per_pixel, ang_horiz);
+    /// The camera model of the perspective projection's virtual camera, None for the other
+    /// projections. Pixel coordinates follow the map's.
+    pub fn output_model(&self) -> Option<CameraModel> {
+        if self.projection != Projection::Perspective || self.width == 0 {
+            return None;
+        }
+        let [forward, right, down] = self
+            .perspective_axes()
+            .map(|v| rotate_about_z(&v, -self.azimuth_rotation));
+        let f = self.focal_length();
+        let (hc, vc) = (
+            self.width as f64 / 2.0 - 0.5,
+            self.height as f64 / 2.0 - 0.5,
+        );
+        let combine = |center: f64, axis: &Vector| {
+            vec![
+                center * forward.x + f * axis.x,
+                center * forward.y + f * axis.y,
+                center * forward.z + f * axis.z,
+            ]
         };
-    });
+        Some(as_cahvore::from_components(&[
+            self.origin.to_vec(),
+            vec![forward.x, forward.y, forward.z],
+            combine(hc, &right),
+            combine(vc, &down),
+        ]))
+    }
+
+    /// The map pixels an image of the given size seen through `model` may cover, as
+    /// `[left, top, right, bottom]` with the right and bottom exclusive. None if none of it
+    /// lands on the map.
+    fn footprint(&self, model: &CameraModel, width: usize, height: usize) -> Option<[usize; 4]> {
+        let points = edge_points(width, height)
+            .iter()
+            .filter_map(|(sample, line)| self.ls_to_direction(model, *sample, *line))
+            .filter_map(|direction| self.direction_to_map(&direction))
+            .collect::<Vec<(f64, f64)>>();
+        if points.is_empty() {
+            return None;
+        }
+
+        // Images around the nadir or zenith on the polar projection cover the middle of
+        // the map, which their edges alone don't reach
+        let (mut x0, mut y0, mut x1, mut y1) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+        if self.projection == Projection::Polar {
+            let (cx, cy) = (self.width as f64 / 2.0, self.height as f64 / 2.0);
+            x0 = cx;
+            y0 = cy;
+            x1 = cx;
+            y1 = cy;
+        }
+        points.iter().for_each(|(x, y)| {
+            x0 = min!(x0, *x);
+            y0 = min!(y0, *y);
+            x1 = max!(x1, *x);
+            y1 = max!(y1, *y);
+        });
+
+        let clamp = |v: f64, size: usize| v.clamp(0.0, size as f64) as usize;
+        let footprint = [
+            clamp(x0.floor() - 1.0, self.width),
+            clamp(y0.floor() - 1.0, self.height),
+            clamp(x1.ceil() + 1.0, self.width),
+            clamp(y1.ceil() + 1.0, self.height),
+        ];
+        match footprint[2] > footprint[0] && footprint[3] > footprint[1] {
+            true => Some(footprint),
+            false => None,
+        }
+    }
+}
 
-    context.top_lat = min!(context.top_lat, 90.0);
-    context.bottom_lat = max!(context.bottom_lat, -90.0);
-    context.left_lon = max!(context.left_lon, 0.0);
-    context.right_lon = min!(context.right_lon, 360.0);
-    context.height =
-        ((context.top_lat - context.bottom_lat) / context.degrees_per_pixel).floor() as usize;
-    context.width =
-        ((context.right_lon - context.left_lon) / context.degrees_per_pixel).floor() as usize;
+/// Determines the bounds and size of a map covering all of the input images, within any
+/// bounds given in `options`. The first image with a camera model sets the origin.
+pub fn determine_map_context(input_files: &[String], options: &MapOptions) -> MapContext {
+    let mut context: Option<MapContext> = None;
+
+    input_files.iter().for_each(|input_file| {
+        let img = match MarsImage::open(input_file, Instrument::M20MastcamZLeft) {
+            Ok(img) => img,
+            Err(why) => {
+                warn!("Skipping {} in map context: {}", input_file, why);
+                return;
+            }
+        };
+        if let Some(c) = get_cahvor(&img) {
+            context
+                .get_or_insert_with(|| MapContext::new(&c.c(), options))
+                .include(&c, img.image.width, img.image.height);
+        };
+    });
 
+    let mut context = context.unwrap_or_else(|| MapContext::new(&Vector::default(), options));
+    context.finalize(options);
     context
 }
 
-fn get_ls_from_map_xy(
-    model: &CameraModel,
-    map_context: &MapContext,
-    x: usize,
-    y: usize,
-    quat: &Quaternion,
-    origin_diff: &Vector,
-) -> (f64, f64) {
-    let img_x = x as f64;
-    let img_y = y as f64;
-
-    let lv = match model.ls_to_look_vector(&ImageCoordinate {
-        line: img_y,
-        sample: img_x,
-    }) {
-        Ok(lv) => lv,
-        Err(_) => panic!("Unable to convert ls to look vector"),
-    };
+/// Distance from each pixel to the nearest invalid pixel or the image edge, with pixels on
+/// the edge at 1 and invalid pixels at 0
+pub fn edge_distance(valid: &[bool], width: usize, height: usize) -> Vec<f32> {
+    let mut distance = valid
+        .iter()
+        .map(|v| if *v { f32::MAX } else { 0.0 })
+        .collect::<Vec<f32>>();
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let up = if y > 0 { distance[i - width] } else { 0.0 };
+            let left = if x > 0 { distance[i - 1] } else { 0.0 };
+            distance[i] = distance[i].min(up + 1.0).min(left + 1.0);
+        }
+    }
+    for y in (0..height).rev() {
+        for x in (0..width).rev() {
+            let i = y * width + x;
+            let down = if y + 1 < height {
+                distance[i + width]
+            } else {
+                0.0
+            };
+            let right = if x + 1 < width { distance[i + 1] } else { 0.0 };
+            distance[i] = distance[i].min(down + 1.0).min(right + 1.0);
+        }
+    }
+    distance
+}
+
+fn catmull_rom(t: f64) -> [f64; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        (-t3 + 2.0 * t2 - t) / 2.0,
+        (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+        (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+        (t3 - t2) / 2.0,
+    ]
+}
+
+/// Samples a band at a fractional pixel position, with pixel centers at whole numbers.
+/// Only valid pixels are used: where the neighborhood an interpolation needs isn't all
+/// valid, it falls back to the next simpler one. None outside of the image or on an
+/// invalid pixel.
+pub fn interpolate(
+    buffer: &ImageBuffer,
+    valid: &[bool],
+    x: f64,
+    y: f64,
+    interpolation: Interpolation,
+) -> Option<f32> {
+    let (width, height) = (buffer.width, buffer.height);
+    if x < -0.5 || y < -0.5 || x >= width as f64 - 0.5 || y >= height as f64 - 0.5 {
+        return None;
+    }
+    let clamp = |v: isize, size: usize| v.clamp(0, size as isize - 1) as usize;
+    let all_valid =
+        |xs: &[usize], ys: &[usize]| ys.iter().all(|y| xs.iter().all(|x| valid[y * width + x]));
+
+    let (fx, fy) = (x.floor(), y.floor());
+    let (tx, ty) = (x - fx, y - fy);
+    let (ix, iy) = (fx as isize, fy as isize);
+
+    if interpolation == Interpolation::Bicubic {
+        let xs = (-1..=2)
+            .map(|d| clamp(ix + d, width))
+            .collect::<Vec<usize>>();
+        let ys = (-1..=2)
+            .map(|d| clamp(iy + d, height))
+            .collect::<Vec<usize>>();
+        if all_valid(&xs, &ys) {
+            let (wx, wy) = (catmull_rom(tx), catmull_rom(ty));
+            let value = ys
+                .iter()
+                .zip(wy.iter())
+                .map(|(y, wy)| {
+                    wy * xs
+                        .iter()
+                        .zip(wx.iter())
+                        .map(|(x, wx)| wx * buffer.get(*x, *y) as f64)
+                        .sum::<f64>()
+                })
+                .sum::<f64>();
+            return Some(max!(value, 0.0) as f32);
+        }
+    }
 
-    let ll = lookvector_to_cylindrical(&lv, Some(quat), origin_diff);
-    let lat = ll.lat;
-    let lon = ll.lon;
+    if interpolation != Interpolation::Nearest {
+        let xs = [clamp(ix, width), clamp(ix + 1, width)];
+        let ys = [clamp(iy, height), clamp(iy + 1, height)];
+        if all_valid(&xs, &ys) {
+            let top =
+                buffer.get(xs[0], ys[0]) as f64 * (1.0 - tx) + buffer.get(xs[1], ys[0]) as f64 * tx;
+            let bottom =
+                buffer.get(xs[0], ys[1]) as f64 * (1.0 - tx) + buffer.get(xs[1], ys[1]) as f64 * tx;
+            return Some((top * (1.0 - ty) + bottom * ty) as f32);
+        }
+    }
 
-    let out_y_f = (lat - map_context.bottom_lat) / (map_context.top_lat - map_context.bottom_lat)
-        * map_context.height as f64;
-    let out_x_f = (lon - map_context.left_lon) / (map_context.right_lon - map_context.left_lon)
-        * map_context.width as f64;
+    let (nx, ny) = (
+        clamp(x.round() as isize, width),
+        clamp(y.round() as isize, height),
+    );
+    match valid[ny * width + nx] {
+        true => Some(buffer.get(nx, ny)),
+        false => None,
+    }
+}
 
-    (out_x_f, out_y_f)
+/// Whether an image of the given eye is drawn to a channel of the mosaic
+fn eye_draws_channel(eye: Eye, channel: usize) -> bool {
+    (channel == 0 && matches!(eye, Eye::Left | Eye::DontCare))
+        || ((channel == 1 || channel == 2) && matches!(eye, Eye::Right | Eye::DontCare))
 }
 
-pub fn process_file<D: Drawable>(
+/// Running totals of the mosaic as images are rendered onto it. Each pixel and band holds
+/// a weighted sum and its total weight when feathering, or the value and weight of the
+/// image the pixel falls deepest within when not.
+pub struct MosaicAccumulator {
+    pub width: usize,
+    pub height: usize,
+    pub num_bands: usize,
+    feather: bool,
+    data: Vec<f32>,
+}
+
+impl MosaicAccumulator {
+    pub fn new(map_context: &MapContext, num_bands: usize, options: &CompositeOptions) -> Self {
+        MosaicAccumulator {
+            width: map_context.width,
+            height: map_context.height,
+            num_bands,
+            feather: options.feather > 0,
+            data: vec![0.0; map_context.width * map_context.height * num_bands * 2],
+        }
+    }
+
+    /// Renders an image onto the mosaic. Every map pixel within the image's footprint is
+    /// projected into the image through its camera model and sampled there, in parallel
+    /// across map rows.
+    pub fn add_image(
+        &mut self,
+        img: &MarsImage,
+        model: &CameraModel,
+        map_context: &MapContext,
+        eye: Eye,
+        options: &CompositeOptions,
+    ) -> Result<()> {
+        let (width, height) = (img.image.width, img.image.height);
+        let [x0, y0, x1, y1] = match map_context.footprint(model, width, height) {
+            Some(footprint) => footprint,
+            None => {
+                return Err(anyhow!("Image doesn't fall on the map"));
+            }
+        };
+
+        let mask_band = img.image.get_band(0);
+        let valid = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| mask_band.get_mask_at_point(x, y))
+            .collect::<Vec<bool>>();
+        let distance = edge_distance(&valid, width, height);
+
+        let bands = (0..self.num_bands)
+            .map(|b| img.image.get_band(b.min(img.image.num_bands() - 1)))
+            .collect::<Vec<&ImageBuffer>>();
+        let origin = map_context.origin_vector();
+        let (camera, axis) = (model.c(), model.a());
+        let (num_bands, feather, feather_width) =
+            (self.num_bands, self.feather, options.feather as f32);
+        let stride = self.width * num_bands * 2;
+
+        self.data
+            .par_chunks_mut(stride)
+            .enumerate()
+            .skip(y0)
+            .take(y1 - y0)
+            .for_each(|(y, row)| {
+                for x in x0..x1 {
+                    let direction = match map_context.map_to_direction(x, y) {
+                        Some(direction) => direction,
+                        None => continue,
+                    };
+                    let point = Vector::new(
+                        origin.x + direction.x * SPHERE_RADIUS,
+                        origin.y + direction.y * SPHERE_RADIUS,
+                        origin.z + direction.z * SPHERE_RADIUS,
+                    );
+
+                    // Points behind the camera would otherwise project into the image too
+                    if dot(&point.subtract(&camera), &axis) <= 0.0 {
+                        continue;
+                    }
+                    let ls = model.xyz_to_ls(&point, false);
+                    let (nx, ny) = (ls.sample.round(), ls.line.round());
+                    if nx < 0.0 || ny < 0.0 || nx >= width as f64 || ny >= height as f64 {
+                        continue;
+                    }
+                    let depth = distance[ny as usize * width + nx as usize];
+                    if depth <= 0.0 {
+                        continue;
+                    }
+                    let weight = match feather {
+                        true => depth.min(feather_width),
+                        false => depth,
+                    };
+
+                    for (b, band) in bands.iter().enumerate() {
+                        if options.anaglyph && !eye_draws_channel(eye, b) {
+                            continue;
+                        }
+                        let value = match interpolate(
+                            band,
+                            &valid,
+                            ls.sample,
+                            ls.line,
+                            options.interpolation,
+                        ) {
+                            Some(value) => value,
+                            None => continue,
+                        };
+                        let i = (x * num_bands + b) * 2;
+                        if feather {
+                            row[i] += value * weight;
+                            row[i + 1] += weight;
+                        } else if weight > row[i + 1] {
+                            row[i] = value;
+                            row[i + 1] = weight;
+                        }
+                    }
+                }
+            });
+        Ok(())
+    }
+
+    /// The value of a band at a map pixel, None where no image was rendered
+    pub fn get(&self, x: usize, y: usize, band: usize) -> Option<f32> {
+        let i = ((y * self.width + x) * self.num_bands + band) * 2;
+        match self.data[i + 1] > 0.0 {
+            true if self.feather => Some(self.data[i] / self.data[i + 1]),
+            true => Some(self.data[i]),
+            false => None,
+        }
+    }
+
+    /// Writes the mosaic onto an image of the map's size. Pixels no image was rendered to
+    /// are left as they are.
+    pub fn paint(&self, map: &mut Image) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                for b in 0..self.num_bands.min(map.num_bands()) {
+                    if let Some(value) = self.get(x, y, b) {
+                        map.put(x, y, value, b);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn process_file(
     input_file: &str,
     map_context: &MapContext,
-    map: &mut D,
-    anaglyph: bool,
-    quat: &Quaternion,
-    initial_origin: &Vector,
-) {
-    let mut img = MarsImage::open(input_file, Instrument::M20MastcamZLeft);
+    accumulator: &mut MosaicAccumulator,
+    options: &CompositeOptions,
+) -> Result<()> {
+    let mut img = MarsImage::open(input_file, Instrument::M20MastcamZLeft)?;
     img.instrument = Instrument::from_str(img.metadata.instrument.as_str()).unwrap();
 
-    let eye = if anaglyph {
-        match util::filename_char_at_pos(input_file, 1) {
-            'R' => Eye::Right,
-            'L' => Eye::Left,
-            _ => Eye::DontCare,
-        }
+    let eye = if options.anaglyph {
+        ProductId::from_file_name(input_file)
+            .map(|product_id| product_id.eye())
+            .unwrap_or(Eye::DontCare)
     } else {
         Eye::DontCare
     };
 
-    //let pitch = (map_context.top_lat + map_context.bottom_lat) / 2.0;
-    //vprintln!("Rotating pitch to {}", pitch);
-    //let quat = Quaternion::from_pitch_roll_yaw(0.0, -20.0f64.to_radians(), azimuth_rotation.to_radians());
-
     match get_cahvor(&img) {
         Some(input_model) => {
-            // let input_model = match input_model_nonlinear.model_type() {
-            //     ModelType::CAHVORE => {
-            //         input_model_nonlinear.convert_to_type(ModelType::CAHVOR).expect("Failed to linearize camera model (forced CAHVOR)")
-            //     },
-            //     _ => input_model_nonlinear
-            // };
-
             debug!("");
             debug!("Input Model C: {:?}", input_model.c());
             debug!("Input Model A: {:?}", input_model.a());
@@ -209,75 +879,11 @@ pub fn process_file<D: Drawable>(
             debug!("Input Model E: {:?}", input_model.e());
             debug!("");
 
-            let band_0 = img.image.get_band(0);
-            let band_1 = img.image.get_band(1);
-            let band_2 = img.image.get_band(2);
-
-            for x in 0..(img.image.width - 1) {
-                for y in 0..(img.image.height - 1) {
-                    let origin_diff = input_model.c().subtract(initial_origin);
-
-                    let (tl_x, tl_y) =
-                        get_ls_from_map_xy(&input_model, map_context, x, y, quat, &origin_diff);
-                    let (tr_x, tr_y) =
-                        get_ls_from_map_xy(&input_model, map_context, x + 1, y, quat, &origin_diff);
-                    let (bl_x, bl_y) =
-                        get_ls_from_map_xy(&input_model, map_context, x, y + 1, quat, &origin_diff);
-                    let (br_x, br_y) = get_ls_from_map_xy(
-                        &input_model,
-                        map_context,
-                        x + 1,
-                        y + 1,
-                        quat,
-                        &origin_diff,
-                    );
-
-                    if !band_0.get_mask_at_point(x, y) {
-                        continue;
-                    }
-
-                    let tl = Point::create_rgb(
-                        tl_x,
-                        tl_y,
-                        band_0.get(x, y) as f64,
-                        band_1.get(x, y) as f64,
-                        band_2.get(x, y) as f64,
-                    );
-
-                    let tr = Point::create_rgb(
-                        tr_x,
-                        tr_y,
-                        band_0.get(x + 1, y) as f64,
-                        band_1.get(x + 1, y) as f64,
-                        band_2.get(x + 1, y) as f64,
-                    );
-
-                    let bl = Point::create_rgb(
-                        bl_x,
-                        bl_y,
-                        band_0.get(x, y + 1) as f64,
-                        band_1.get(x, y + 1) as f64,
-                        band_2.get(x, y + 1) as f64,
-                    );
-
-                    let br = Point::create_rgb(
-                        br_x,
-                        br_y,
-                        band_0.get(x + 1, y + 1) as f64,
-                        band_1.get(x + 1, y + 1) as f64,
-                        band_2.get(x + 1, y + 1) as f64,
-                    );
-
-                    map.paint_square_with_channel_rule(&tl, &bl, &br, &tr, false, |c| {
-                        (c == 0 && matches!(eye, Eye::Left | Eye::DontCare))
-                            || ((c == 1 || c == 2) && matches!(eye, Eye::Right | Eye::DontCare))
-                    });
-                }
-            }
+            accumulator.add_image(&img, &input_model, map_context, eye, options)
         }
         None => {
             error!("CAHVOR not found for image, cannot continue");
-            panic!("CAHVOR not found for image, cannot continue");
+            Err(CalError::bad_metadata(input_file, "CAHVOR not found for image").into())
         }
     }
 }
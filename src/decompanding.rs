@@ -1,5 +1,6 @@
 use crate::calibfile;
 use crate::enums;
+use crate::error::CalError;
 use crate::memcache;
 use regex::Regex;
 use sciimg::path;
@@ -97,15 +98,15 @@ impl LookUpTable {
     }
 }
 
-pub fn get_ilt_for_instrument(instrument: enums::Instrument) -> Result<LookUpTable> {
-    let lut_file_path =
-        calibfile::get_calibration_file_for_instrument(instrument, enums::CalFileType::Lut)
-            .unwrap_or("".to_string());
+/// Path of the lookup table file used for the instrument, or `None` if the built-in table applies
+pub fn get_ilt_path_for_instrument(instrument: enums::Instrument) -> Option<String> {
+    calibfile::get_calibration_file_for_instrument(instrument, enums::CalFileType::Lut).ok()
+}
 
-    if lut_file_path.is_empty() {
-        Ok(LookUpTable::new(&ILT))
-    } else {
-        load_ilut_spec_file(&lut_file_path)
+pub fn get_ilt_for_instrument(instrument: enums::Instrument) -> Result<LookUpTable> {
+    match get_ilt_path_for_instrument(instrument) {
+        Some(lut_file_path) if !lut_file_path.is_empty() => load_ilut_spec_file(&lut_file_path),
+        _ => Ok(LookUpTable::new(&ILT)),
     }
 }
 
@@ -114,7 +115,7 @@ pub fn load_ilut_spec_file(file_path: &String) -> Result<LookUpTable> {
 
     if !path::file_exists(file_path) {
         error!("ERROR: LUT file not found: {}", file_path);
-        return Err(anyhow!("Lookup table file not found"));
+        return Err(CalError::MissingFile(file_path.to_owned()).into());
     }
 
     let mut lut_vec: Vec<u32> = vec![];
@@ -130,4 +131,5 @@ pub fn load_ilut_spec_file(file_path: &String) -> Result<LookUpTable> {
         });
     info!("LUT file parse successfully with {} entries", lut_vec.len());
     LookUpTable::new_from_vec(&lut_vec)
+        .map_err(|why| CalError::decode_failure(file_path, why).into())
 }
@@ -11,6 +11,21 @@ pub enum Mission {
     MerB,     // Mars Exploration Rovers - Opportunity Rover
 }
 
+impl FromStr for Mission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Mission> {
+        match s.to_uppercase().as_str() {
+            "MSL" | "CURIOSITY" => Ok(Mission::MSL),
+            "MARS2020" | "M20" | "PERSEVERANCE" => Ok(Mission::Mars2020),
+            "INSIGHT" | "NSYT" => Ok(Mission::InSight),
+            "MERA" | "SPIRIT" => Ok(Mission::MerA),
+            "MERB" | "OPPORTUNITY" => Ok(Mission::MerB),
+            _ => Err(anyhow::anyhow!("Unknown mission: {}", s)),
+        }
+    }
+}
+
 /// Representation of left/right side of a stereo image with an option to simply not care (or unknown).
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Eye {
@@ -56,6 +71,78 @@ pub enum Instrument {
     None,
 }
 
+impl Instrument {
+    /// The mission the instrument flies on
+    pub fn mission(&self) -> Option<Mission> {
+        match self {
+            Instrument::MslMAHLI
+            | Instrument::MslMastcamLeft
+            | Instrument::MslMastcamRight
+            | Instrument::MslNavCamRight
+            | Instrument::MslNavCamLeft
+            | Instrument::MslFrontHazLeft
+            | Instrument::MslFrontHazRight
+            | Instrument::MslRearHazLeft
+            | Instrument::MslRearHazRight
+            | Instrument::MslMARDI
+            | Instrument::MslChemCam => Some(Mission::MSL),
+            Instrument::M20MastcamZLeft
+            | Instrument::M20MastcamZRight
+            | Instrument::M20NavcamLeft
+            | Instrument::M20NavcamRight
+            | Instrument::M20FrontHazLeft
+            | Instrument::M20FrontHazRight
+            | Instrument::M20RearHazLeft
+            | Instrument::M20RearHazRight
+            | Instrument::M20Watson
+            | Instrument::M20SuperCam
+            | Instrument::M20Pixl
+            | Instrument::M20SkyCam
+            | Instrument::M20HeliNav
+            | Instrument::M20HeliRte
+            | Instrument::M20SherlocAci
+            | Instrument::M20CacheCam
+            | Instrument::M20EdlRdcam => Some(Mission::Mars2020),
+            Instrument::NsytICC | Instrument::NsytIDC => Some(Mission::InSight),
+            Instrument::None => None,
+        }
+    }
+
+    /// Width and height of the full sensor frame in full resolution pixels, the space in
+    /// which image subframes are given. None where the sensor size isn't known.
+    pub fn sensor_size(&self) -> Option<(usize, usize)> {
+        match self {
+            Instrument::MslMAHLI
+            | Instrument::MslMastcamLeft
+            | Instrument::MslMastcamRight
+            | Instrument::MslMARDI
+            | Instrument::M20MastcamZLeft
+            | Instrument::M20MastcamZRight
+            | Instrument::M20Watson
+            | Instrument::M20SherlocAci => Some((1648, 1200)),
+            Instrument::MslNavCamRight
+            | Instrument::MslNavCamLeft
+            | Instrument::MslFrontHazLeft
+            | Instrument::MslFrontHazRight
+            | Instrument::MslRearHazLeft
+            | Instrument::MslRearHazRight
+            | Instrument::MslChemCam
+            | Instrument::NsytICC
+            | Instrument::NsytIDC => Some((1024, 1024)),
+            Instrument::M20NavcamLeft
+            | Instrument::M20NavcamRight
+            | Instrument::M20FrontHazLeft
+            | Instrument::M20FrontHazRight
+            | Instrument::M20RearHazLeft
+            | Instrument::M20RearHazRight => Some((5120, 3840)),
+            Instrument::M20SuperCam => Some((2048, 2048)),
+            Instrument::M20HeliNav => Some((640, 480)),
+            Instrument::M20HeliRte => Some((4208, 3120)),
+            _ => None,
+        }
+    }
+}
+
 impl FromStr for Instrument {
     type Err = ParseIntError;
 
@@ -103,4 +190,6 @@ pub enum CalFileType {
     InpaintMask,
     Mask,
     Lut,
+    Bias,
+    Dark,
 }
@@ -0,0 +1,62 @@
+use crate::enums::{CalFileType, Instrument};
+use thiserror::Error;
+
+/// Reasons a raw image cannot be loaded or calibrated. Library functions that return
+/// `anyhow::Result` carry one of these as the root cause, retrievable with
+/// `error.downcast_ref::<CalError>()`.
+#[derive(Error, Debug)]
+pub enum CalError {
+    #[error("File not found: {0}")]
+    MissingFile(String),
+
+    #[error("No {file_type:?} calibration data available for {instrument:?}")]
+    MissingCalData {
+        instrument: Instrument,
+        file_type: CalFileType,
+    },
+
+    #[error("Invalid metadata in {path}: {reason}")]
+    BadMetadata { path: String, reason: String },
+
+    #[error(
+        "Unsupported subframe {rect:?} for {instrument:?} with a {cal_width}x{cal_height} calibration image"
+    )]
+    UnsupportedSubframe {
+        instrument: Instrument,
+        rect: Vec<f64>,
+        cal_width: usize,
+        cal_height: usize,
+    },
+
+    #[error("Failed to decode {path}: {reason}")]
+    DecodeFailure { path: String, reason: String },
+
+    #[error(
+        "No color matrix configured for {0:?}, set color_matrix in caldata.toml or the profile"
+    )]
+    MissingColorMatrix(Instrument),
+
+    #[error("Crop {rect:?} does not fit within the {width}x{height} image from {instrument:?}")]
+    InvalidCrop {
+        instrument: Instrument,
+        rect: [usize; 4],
+        width: usize,
+        height: usize,
+    },
+}
+
+impl CalError {
+    pub fn bad_metadata<T: ToString>(path: &str, reason: T) -> Self {
+        CalError::BadMetadata {
+            path: path.to_owned(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn decode_failure<T: ToString>(path: &str, reason: T) -> Self {
+        CalError::DecodeFailure {
+            path: path.to_owned(),
+            reason: reason.to_string(),
+        }
+    }
+}
@@ -7,10 +7,7 @@ pub fn load_flat(instrument: enums::Instrument) -> Result<MarsImage> {
     {
         Ok(cal_file) => {
             info!("Loading calibration file from {}", cal_file);
-            Ok(MarsImage::from_image(
-                &load_image(&cal_file).unwrap(),
-                instrument,
-            ))
+            Ok(MarsImage::from_image(&load_image(&cal_file)?, instrument))
         }
         Err(e) => Err(e),
     }
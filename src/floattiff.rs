@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Result};
+use sciimg::{enums::ImageMode, image::Image};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::ColorType;
+
+/// Pixel values of one band, row major
+fn band_values(image: &Image, band: usize) -> Vec<f32> {
+    let buffer = image.get_band(band);
+    let mut values = Vec::with_capacity(image.width * image.height);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            values.push(buffer.get(x, y));
+        }
+    }
+    values
+}
+
+/// Pixel values of the first three bands, interleaved
+fn rgb_values(image: &Image) -> Vec<f32> {
+    let bands: Vec<Vec<f32>> = (0..3).map(|b| band_values(image, b)).collect();
+    let mut values = Vec::with_capacity(image.width * image.height * 3);
+    for i in 0..image.width * image.height {
+        bands.iter().for_each(|band| values.push(band[i]));
+    }
+    values
+}
+
+/// Writes the image as 32 bit floating point samples without any scaling. Single band
+/// images are written as grayscale and three band images as RGB. Any other band count is
+/// written as one grayscale page per band.
+pub fn save_image(image: &Image, to_file: &str) -> Result<()> {
+    info!("Writing floating point image to file at {}", to_file);
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(to_file)?))?;
+    let (width, height) = (image.width as u32, image.height as u32);
+
+    match image.num_bands() {
+        3 => encoder.write_image::<colortype::RGB32Float>(width, height, &rgb_values(image))?,
+        bands => {
+            for band in 0..bands {
+                encoder.write_image::<colortype::Gray32Float>(
+                    width,
+                    height,
+                    &band_values(image, band),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes each band of the image as its own 32 bit floating point grayscale page, regardless
+/// of the band count. Used for multispectral cubes, whose bands are not colors.
+pub fn save_bands(image: &Image, to_file: &str) -> Result<()> {
+    info!("Writing floating point image to file at {}", to_file);
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(to_file)?))?;
+    for band in 0..image.num_bands() {
+        encoder.write_image::<colortype::Gray32Float>(
+            image.width as u32,
+            image.height as u32,
+            &band_values(image, band),
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads a 32 bit floating point TIFF as written by `save_image`. Each page of a multi-page
+/// grayscale file becomes a band.
+pub fn open_image(file_path: &str) -> Result<Image> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(file_path)?))?;
+    let (width, height) = decoder.dimensions()?;
+    let (width, height) = (width as usize, height as usize);
+
+    let mut pages = vec![];
+    loop {
+        let samples_per_pixel = match decoder.colortype()? {
+            ColorType::Gray(32) => 1,
+            ColorType::RGB(32) => 3,
+            color_type => {
+                return Err(anyhow!(
+                    "Unsupported color type {:?} in {}",
+                    color_type,
+                    file_path
+                ))
+            }
+        };
+        match decoder.read_image()? {
+            DecodingResult::F32(values) => pages.push((samples_per_pixel, values)),
+            _ => {
+                return Err(anyhow!(
+                    "{} does not contain floating point data",
+                    file_path
+                ))
+            }
+        }
+
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    let num_bands = pages.iter().map(|(samples, _)| samples).sum();
+    let mut image = Image::new_with_bands(width, height, num_bands, ImageMode::U16BIT)?;
+
+    let mut band = 0;
+    for (samples_per_pixel, values) in pages.iter() {
+        for y in 0..height {
+            for x in 0..width {
+                for s in 0..*samples_per_pixel {
+                    image.put(
+                        x,
+                        y,
+                        values[(y * width + x) * samples_per_pixel + s],
+                        band + s,
+                    );
+                }
+            }
+        }
+        band += samples_per_pixel;
+    }
+    Ok(image)
+}
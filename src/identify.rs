@@ -0,0 +1,271 @@
+use crate::{
+    enums::Instrument,
+    metadata::{self, Metadata},
+    productid::ProductId,
+    util,
+};
+
+use sciimg::path;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Labels longer than this are not searched for the instrument
+const MAX_LABEL_BYTES: u64 = 65536;
+
+/// Extensions of the detached and attached VICAR or PDS labels checked alongside an image
+const LABEL_EXTENSIONS: [&str; 6] = ["LBL", "lbl", "IMG", "img", "VIC", "vic"];
+
+/// How an input file's instrument was determined
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InstrumentSource {
+    /// The `-metadata.json` sidecar
+    Metadata,
+
+    /// Given on the command line
+    Forced,
+
+    /// The `INSTRUMENT_ID` of a VICAR or PDS label
+    Label,
+
+    /// The file's product id naming convention
+    ProductId,
+}
+
+impl InstrumentSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InstrumentSource::Metadata => "metadata",
+            InstrumentSource::Forced => "forced",
+            InstrumentSource::Label => "label",
+            InstrumentSource::ProductId => "product id",
+        }
+    }
+}
+
+impl fmt::Display for InstrumentSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+fn known_instrument(name: &str) -> Option<Instrument> {
+    match Instrument::from_str(name) {
+        Ok(Instrument::None) | Err(_) => None,
+        Ok(instrument) => Some(instrument),
+    }
+}
+
+/// The elements of the first value assigned to `key` in VICAR or PDS label text, with any
+/// quotes removed. A list such as `(12.5 <degC>, 'FPA')` gives one element per entry, and a
+/// scalar gives one element. Units are kept with their value, e.g. `12.5 <ms>`. Handles both
+/// the VICAR `KEY='VALUE'` and PDS `KEY = "VALUE"` forms.
+pub fn label_values(label: &str, key: &str) -> Option<Vec<String>> {
+    label.match_indices(key).find_map(|(start, _)| {
+        let preceded_by_name = matches!(
+            label[..start].chars().next_back(),
+            Some(c) if c.is_ascii_alphanumeric() || c == '_'
+        );
+        if preceded_by_name {
+            return None;
+        }
+
+        let value = label[start + key.len()..]
+            .trim_start()
+            .strip_prefix('=')?
+            .trim_start();
+
+        let elements: Vec<&str> = if let Some(list) = value.strip_prefix('(') {
+            list[..list.find(')')?].split(',').collect()
+        } else if let Some(quoted) = value.strip_prefix(['\'', '"']) {
+            vec![&quoted[..quoted.find(['\'', '"'])?]]
+        } else {
+            // A bare value, keeping a unit such as the `<ms>` of `12.5 <ms>`
+            let end = value
+                .find(|c: char| c.is_whitespace() || c == ',' || c == ')')
+                .unwrap_or(value.len());
+            let rest = value[end..].trim_start_matches([' ', '\t']);
+            let end = match rest.strip_prefix('<').and_then(|unit| unit.find('>')) {
+                Some(close) => value.len() - rest.len() + close + 2,
+                None => end,
+            };
+            vec![&value[..end]]
+        };
+
+        Some(
+            elements
+                .iter()
+                .map(|e| e.trim().trim_matches(['\'', '"']).to_owned())
+                .collect(),
+        )
+    })
+}
+
+/// A number from a label element such as `12.5` or `12.5 <ms>`, with its unit if given
+pub fn label_number(element: &str) -> Option<(f64, Option<String>)> {
+    let (number, unit) = match element.split_once('<') {
+        Some((number, unit)) => (number, Some(unit.trim_end_matches('>').trim().to_owned())),
+        None => (element, None),
+    };
+    number.trim().parse::<f64>().ok().map(|n| (n, unit))
+}
+
+/// The exposure duration in milliseconds from VICAR or PDS label text
+pub fn exposure_duration_from_label_text(label: &str) -> Option<f64> {
+    let (exposure, unit) = label_number(label_values(label, "EXPOSURE_DURATION")?.first()?)?;
+    match unit.as_deref().map(|u| u.to_lowercase()).as_deref() {
+        None | Some("ms") | Some("msec") => Some(exposure),
+        Some("s") | Some("sec") => Some(exposure * 1000.0),
+        Some(_) => None,
+    }
+}
+
+/// The detector temperature in degrees Celsius from VICAR or PDS label text. Uses the
+/// `DETECTOR_TEMPERATURE` where given, otherwise the `INSTRUMENT_TEMPERATURE` whose
+/// `INSTRUMENT_TEMPERATURE_NAME` is the focal plane array or CCD.
+pub fn detector_temperature_from_label_text(label: &str) -> Option<f64> {
+    let celsius = |element: &str| match label_number(element)? {
+        (kelvin, Some(unit)) if unit.eq_ignore_ascii_case("k") => Some(kelvin - 273.15),
+        (celsius, _) => Some(celsius),
+    };
+
+    if let Some(values) = label_values(label, "DETECTOR_TEMPERATURE") {
+        return celsius(values.first()?);
+    }
+
+    let names = label_values(label, "INSTRUMENT_TEMPERATURE_NAME")?;
+    let temperatures = label_values(label, "INSTRUMENT_TEMPERATURE")?;
+    names
+        .iter()
+        .zip(temperatures.iter())
+        .find(|(name, _)| {
+            let name = name.to_uppercase();
+            ["FPA", "CCD", "DETECTOR"]
+                .iter()
+                .any(|part| name.contains(part))
+        })
+        .and_then(|(_, temperature)| celsius(temperature))
+}
+
+/// The instrument named by the first recognized `INSTRUMENT_ID` in VICAR or PDS label text
+pub fn instrument_from_label_text(label: &str) -> Option<Instrument> {
+    known_instrument(label_values(label, "INSTRUMENT_ID")?.first()?)
+}
+
+/// Reads the label at the start of a VICAR or PDS file, None for other files
+fn read_label(file_path: &str) -> Option<String> {
+    let mut buf = Vec::new();
+    File::open(file_path)
+        .ok()?
+        .take(MAX_LABEL_BYTES)
+        .read_to_end(&mut buf)
+        .ok()?;
+
+    let text = String::from_utf8_lossy(&buf);
+    match text.starts_with("LBLSIZE")
+        || text.starts_with("PDS_VERSION_ID")
+        || text.starts_with("ODL_VERSION_ID")
+    {
+        true => Some(text.into_owned()),
+        false => None,
+    }
+}
+
+/// The file's own VICAR or PDS label, or failing that a label file of the same name next to
+/// it, such as the `.IMG` a PNG was converted from, along with the file it was read from
+fn find_label(input_file: &str) -> Option<(String, String)> {
+    let stem = Path::new(input_file).with_extension("");
+    std::iter::once(input_file.to_owned())
+        .chain(LABEL_EXTENSIONS.iter().filter_map(|ext| {
+            stem.with_extension(ext)
+                .to_str()
+                .filter(|candidate| *candidate != input_file && path::file_exists(candidate))
+                .map(|candidate| candidate.to_owned())
+        }))
+        .find_map(|label_file| read_label(&label_file).map(|label| (label_file, label)))
+}
+
+/// The instrument from the file's own VICAR or PDS label, or a label file next to it
+pub fn instrument_from_label(input_file: &str) -> Option<Instrument> {
+    let (label_file, label) = find_label(input_file)?;
+    let instrument = instrument_from_label_text(&label)?;
+    vprintln!("Found instrument {:?} in label {}", instrument, label_file);
+    Some(instrument)
+}
+
+/// Fills in the exposure duration and detector temperature missing from an image's metadata
+/// from its VICAR or PDS label, when it has one. The raw image APIs don't provide either.
+/// Reading the label means probing for sibling files, so this is left to the dark
+/// subtraction and radiometric stages that use the values rather than done on every open.
+pub fn apply_label_metadata(input_file: &str, metadata: &mut Metadata) {
+    if metadata.exposure_duration.is_some() && metadata.detector_temperature.is_some() {
+        return;
+    }
+    let (label_file, label) = match find_label(input_file) {
+        Some(found) => found,
+        None => return,
+    };
+
+    if metadata.exposure_duration.is_none() {
+        metadata.exposure_duration = exposure_duration_from_label_text(&label);
+    }
+    if metadata.detector_temperature.is_none() {
+        metadata.detector_temperature = detector_temperature_from_label_text(&label);
+    }
+    info!(
+        "Exposure {:?} ms and detector temperature {:?} C from label {}",
+        metadata.exposure_duration, metadata.detector_temperature, label_file
+    );
+}
+
+/// The instrument from the file's product id naming convention
+pub fn instrument_from_product_id(input_file: &str) -> Option<Instrument> {
+    ProductId::from_file_name(input_file)
+        .ok()
+        .map(|product_id| product_id.instrument())
+        .filter(|instrument| *instrument != Instrument::None)
+}
+
+fn instrument_from_metadata(input_file: &str) -> Option<Instrument> {
+    let metadata_file = util::replace_image_extension(input_file, "-metadata.json");
+    info!("Checking for metadata file at {}", metadata_file);
+    if !path::file_exists(&metadata_file) {
+        return None;
+    }
+
+    vprintln!("Metadata file exists for loaded image: {}", metadata_file);
+    match metadata::load_image_metadata(&metadata_file) {
+        Ok(md) => known_instrument(&md.instrument),
+        Err(why) => {
+            warn!("Could not load metadata file: {}", why);
+            None
+        }
+    }
+}
+
+/// Determines the instrument that took an image, in order from its metadata sidecar, the
+/// instrument forced on the command line, its VICAR or PDS label, and its product id.
+pub fn identify_instrument(
+    input_file: &str,
+    forced: &Option<String>,
+) -> Option<(Instrument, InstrumentSource)> {
+    if let Some(instrument) = instrument_from_metadata(input_file) {
+        return Some((instrument, InstrumentSource::Metadata));
+    }
+
+    if let Some(name) = forced {
+        match known_instrument(name) {
+            Some(instrument) => return Some((instrument, InstrumentSource::Forced)),
+            None => warn!("Unknown instrument: {}", name),
+        }
+    }
+
+    instrument_from_label(input_file)
+        .map(|instrument| (instrument, InstrumentSource::Label))
+        .or_else(|| {
+            instrument_from_product_id(input_file)
+                .map(|instrument| (instrument, InstrumentSource::ProductId))
+        })
+}
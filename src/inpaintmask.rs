@@ -1,10 +1,9 @@
 // https://www.researchgate.net/publication/238183352_An_Image_Inpainting_Technique_Based_on_the_Fast_Marching_Method
 
-use crate::{calibfile, constants, enums, memcache};
+use crate::{calibfile, enums, error::CalError, memcache};
 
 use sciimg::{imagebuffer::ImageBuffer, path};
 
-use anyhow::anyhow;
 use anyhow::Result;
 
 fn determine_mask_file(instrument: enums::Instrument) -> Result<String> {
@@ -20,7 +19,7 @@ fn load_mask_file(filename: &str, instrument: enums::Instrument) -> Result<Image
     vprintln!("Loading inpaint mask file {}", filename);
 
     if !path::file_exists(filename) {
-        return Err(anyhow!(constants::status::FILE_NOT_FOUND));
+        return Err(CalError::MissingFile(filename.to_owned()).into());
     }
 
     let mask = memcache::load_imagebuffer(filename)?;
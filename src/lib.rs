@@ -11,15 +11,33 @@ extern crate stump;
 /// Routines for creating stereo anaglyph images
 pub mod anaglyph;
 
+/// Camera-agnostic assembly of subframed image tiles
+pub mod assemble;
+
+/// Bias and dark frame subtraction
+pub mod biasdark;
+
 /// Support for calibration file loading
 pub mod calibfile;
 
 /// Calibration entrypoint
 pub mod calibrate;
 
+/// Shared, ordered calibration stage pipeline
+pub mod calpipeline;
+
+/// Calibration dry-run planning
+pub mod calplan;
+
 /// Support for calibration specification profiles
 pub mod calprofile;
 
+/// Machine-readable calibration run reports
+pub mod calreport;
+
+/// Per-instrument color correction matrices
+pub mod colormatrix;
+
 /// Image linearization and mosaic compositing
 pub mod composite;
 
@@ -41,15 +59,24 @@ pub mod diffgif;
 /// Basic enumerations
 pub mod enums;
 
+/// Typed calibration and image loading errors
+pub mod error;
+
 /// Image flat field processing
 pub mod flatfield;
 
+/// Floating point TIFF image input and output
+pub mod floattiff;
+
 /// Focus stack processing
 pub mod focusmerge;
 
 /// Remote data retrieval via HTTP
 pub mod httpfetch;
 
+/// Identification of an image's instrument from its metadata, label or product id
+pub mod identify;
+
 /// Extensions to `RgbImage` to support Mars mission image data
 pub mod marsimage;
 
@@ -74,12 +101,27 @@ pub mod msl;
 /// Routines for InSight image processing
 pub mod nsyt;
 
+/// Automatic calibration profile selection by instrument metadata
+pub mod profileselect;
+
 /// Single-point import for most utilized MRU API
 pub mod prelude;
 
+/// Parsing of mission image product ids
+pub mod productid;
+
+/// Conversion of calibrated images to radiance and I/F
+pub mod radiometry;
+
+/// Multispectral cube assembly and spectrum extraction
+pub mod spectral;
+
 /// Time and date support
 pub mod time;
 
+/// White balance and color matrix derivation from calibration target images
+pub mod whitebalance;
+
 /// General utilities
 #[macro_use]
 pub mod util;
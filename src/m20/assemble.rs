@@ -1,4 +1,6 @@
+use crate::assemble::{self, BlendMode, TileRect};
 use crate::prelude::*;
+use anyhow::Result;
 use sciimg::{enums::ImageMode, image};
 
 lazy_static! {
@@ -72,12 +74,12 @@ impl NavcamTile {
     /// use mars_raw_utils::enums::Instrument;
     /// use mars_raw_utils::m20::assemble::NavcamTile;
     ///
-    /// NavcamTile::new_from_file(&String::from("tests/testdata/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png"), Instrument::M20NavcamRight);
+    /// NavcamTile::new_from_file(&String::from("tests/testdata/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png"), Instrument::M20NavcamRight).unwrap();
     /// ```
-    pub fn new_from_file(file_path: &str, instrument: Instrument) -> Self {
-        NavcamTile {
-            image: MarsImage::open(file_path, instrument),
-        }
+    pub fn new_from_file(file_path: &str, instrument: Instrument) -> Result<Self, CalError> {
+        Ok(NavcamTile {
+            image: MarsImage::open(file_path, instrument)?,
+        })
     }
 
     /// Constructs a new `NavcamTile` with an existing instance of `MarsImage`.
@@ -231,28 +233,64 @@ impl Composite {
         });
     }
 
+    /// Places a set of `NavcamTile`s onto the canvas, combining them where they overlap
+    /// according to `blend`
+    pub fn blend_tiles(&mut self, tiles: &[NavcamTile], blend: BlendMode) -> Result<()> {
+        if blend == BlendMode::Hard {
+            self.paste_tiles(tiles);
+            return Ok(());
+        }
+
+        let placements = tiles
+            .iter()
+            .filter_map(|t| self.get_tile_placement(t))
+            .collect::<Vec<(image::Image, TileRect)>>();
+        let placements = placements
+            .iter()
+            .map(|(image, rect)| (image, *rect))
+            .collect::<Vec<(&image::Image, TileRect)>>();
+        self.composite_image = assemble::blend_tiles(&placements, self.width, self.height, blend)?;
+        Ok(())
+    }
+
+    /// Returns the pixels of a `NavcamTile` that go onto the canvas along with where they
+    /// go. At scale factor 1 the tile edges are cropped to avoid compression artifacts and
+    /// telemetry pixels. None if the tile has no subframe or falls outside of the canvas.
+    pub fn get_tile_placement(&self, tile: &NavcamTile) -> Option<(image::Image, TileRect)> {
+        let tilecoord = tile.get_tile_coordinates()?;
+        let mut image = tile.image.image.clone();
+
+        let (x, y) = if self.scale == 1 {
+            image.crop(2, 2, image.width - 4, image.height - 4);
+            (tilecoord.top_left_x - 1, tilecoord.top_left_y - 1)
+        } else {
+            (tilecoord.top_left_x - 2, tilecoord.top_left_y - 2)
+        };
+
+        let rect = TileRect {
+            x,
+            y,
+            width: image.width,
+            height: image.height,
+        };
+        let canvas = TileRect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        let visible = rect.intersection(&canvas)?;
+        if visible != rect {
+            image.crop(0, 0, visible.width, visible.height);
+        }
+        Some((image, visible))
+    }
+
     /// Pastes a `NavcamTile` onto the canvas. Will also attempt to crop tile edges
     /// to avoid compression artifacts and telemetry pixels
     pub fn paste_tile(&mut self, tile: &NavcamTile) {
-        if let Some(tilecoord) = tile.get_tile_coordinates() {
-            let mut paste_image = tile.image.image.clone();
-
-            if self.scale == 1 {
-                paste_image.crop(2, 2, paste_image.width - 4, paste_image.height - 4);
-
-                self.composite_image.paste(
-                    &paste_image,
-                    tilecoord.top_left_x - 1,
-                    tilecoord.top_left_y - 1,
-                );
-            } else {
-                paste_image.crop(0, 0, paste_image.width, paste_image.height);
-                self.composite_image.paste(
-                    &paste_image,
-                    tilecoord.top_left_x - 2,
-                    tilecoord.top_left_y - 2,
-                );
-            }
+        if let Some((paste_image, rect)) = self.get_tile_placement(tile) {
+            self.composite_image.paste(&paste_image, rect.x, rect.y);
         }
     }
 
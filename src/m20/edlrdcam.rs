@@ -1,65 +1,36 @@
-use crate::{
-    calibrate::*, calprofile::CalProfile, decompanding, enums, enums::Instrument,
-    marsimage::MarsImage, util,
-};
-
-use anyhow::Result;
-use sciimg::path;
-
-#[derive(Copy, Clone)]
-pub struct M20EdlRdcam {}
-
-impl Calibration for M20EdlRdcam {
-    fn accepts_instrument(&self, instrument: Instrument) -> bool {
-        matches!(instrument, Instrument::M20EdlRdcam)
-    }
-
-    fn process_file(
-        &self,
-        input_file: &str,
-        cal_context: &CalProfile,
-        only_new: bool,
-    ) -> Result<CompleteContext> {
-        let out_file = util::append_file_name(input_file, cal_context.filename_suffix.as_str());
-        if path::file_exists(&out_file) && only_new {
-            vprintln!("Output file exists, skipping. ({})", out_file);
-            return cal_warn(cal_context, &out_file);
-        }
-
-        let mut raw = MarsImage::open(input_file, enums::Instrument::M20EdlRdcam);
-
-        let data_max = if cal_context.apply_ilt {
-            vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(enums::Instrument::M20EdlRdcam).unwrap();
-            raw.decompand(&lut);
-            lut.max() as f32
-        } else {
-            255.0
-        };
-
-        if raw.image.is_grayscale() {
-            vprintln!("Debayering...");
-            raw.debayer_with_method(cal_context.debayer_method);
-        }
-
-        vprintln!("Applying color weights...");
-        raw.apply_weight(
-            cal_context.red_scalar,
-            cal_context.green_scalar,
-            cal_context.blue_scalar,
-        );
-
-        vprintln!("Normalizing...");
-        raw.image.normalize_to_16bit_with_max(data_max);
-
-        vprintln!("Writing to disk...");
-        raw.update_history();
-        match raw.save(&out_file) {
-            Ok(_) => cal_ok(cal_context, &out_file),
-            Err(why) => {
-                veprintln!("Error saving file: {}", why);
-                cal_fail(cal_context, &out_file)
-            }
-        }
-    }
-}
+use crate::{
+    calibrate::*,
+    calpipeline::{self, CalStage},
+    calprofile::CalProfile,
+    enums::Instrument,
+    marsimage::MarsImage,
+};
+
+use anyhow::Result;
+
+#[derive(Copy, Clone)]
+pub struct M20EdlRdcam {}
+
+impl Calibration for M20EdlRdcam {
+    fn accepts_instrument(&self, instrument: Instrument) -> bool {
+        matches!(instrument, Instrument::M20EdlRdcam)
+    }
+
+    fn instrument_for_file(&self, _input_file: &str) -> Result<Instrument> {
+        Ok(Instrument::M20EdlRdcam)
+    }
+
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage> {
+        Ok(MarsImage::open(input_file, instrument)?)
+    }
+
+    fn default_pipeline(&self, _input_file: &str, profile: &CalProfile) -> Vec<CalStage> {
+        let mut stages = vec![];
+        calpipeline::push_decompand(&mut stages, profile);
+        stages.push(CalStage::debayer());
+        calpipeline::push_noise_reduction(&mut stages, profile);
+        stages.push(CalStage::weights());
+        stages.push(CalStage::normalize_correlated());
+        stages
+    }
+}
@@ -1,4 +1,5 @@
-use crate::m20::assemble::NavcamTile;
+use crate::assemble::{self, TileRect};
+use crate::m20::assemble::{Composite, NavcamTile};
 
 use anyhow::Result;
 use sciimg::{blur, image::Image, prelude::ImageBuffer};
@@ -475,3 +476,32 @@ pub fn match_levels(images: &mut [NavcamTile]) {
     }
     */
 }
+
+/// Matches levels with a gain and offset on each band of every tile, solved together over
+/// all of the overlaps between the tiles as they are placed on the composite. Unlike
+/// `match_levels`, no tile is held fixed and adjustments aren't chained from one pair of
+/// tiles to the next.
+pub fn match_levels_global(images: &mut [NavcamTile], composite: &Composite) {
+    let placements = images
+        .iter()
+        .enumerate()
+        .filter_map(|(i, t)| composite.get_tile_placement(t).map(|p| (i, p)))
+        .collect::<Vec<(usize, (Image, TileRect))>>();
+    let tiles = placements
+        .iter()
+        .map(|(_, (image, rect))| (image, *rect))
+        .collect::<Vec<(&Image, TileRect)>>();
+
+    let adjustments = assemble::solve_gain_offset(&tiles);
+    placements
+        .iter()
+        .zip(adjustments.iter())
+        .for_each(|((i, _), adjustment)| {
+            vprintln!(
+                "Adjusting tile {:?} with gains and offsets {:?}",
+                images[*i].image.file_path,
+                adjustment
+            );
+            assemble::apply_adjustments(&mut images[*i].image.image, adjustment);
+        });
+}
@@ -1,9 +1,11 @@
 use crate::{
-    calibrate::*, calprofile::CalProfile, enums, enums::Instrument, marsimage::MarsImage, util,
+    calibrate::*,
+    calpipeline::{self, CalStage},
+    calprofile::CalProfile,
+    enums::Instrument,
+    marsimage::MarsImage,
 };
 
-use sciimg::path;
-
 use anyhow::Result;
 
 #[derive(Copy, Clone)]
@@ -14,34 +16,18 @@ impl Calibration for M20Pixl {
         matches!(instrument, Instrument::M20Pixl)
     }
 
-    fn process_file(
-        &self,
-        input_file: &str,
-        cal_context: &CalProfile,
-        only_new: bool,
-    ) -> Result<CompleteContext> {
-        let out_file = util::append_file_name(input_file, cal_context.filename_suffix.as_str());
-        if path::file_exists(&out_file) && only_new {
-            vprintln!("Output file exists, skipping. ({})", out_file);
-            return cal_warn(cal_context, &out_file);
-        }
-
-        let mut raw = MarsImage::open(input_file, enums::Instrument::M20Pixl);
-
-        vprintln!("Flatfielding...");
-        raw.flatfield();
+    fn instrument_for_file(&self, _input_file: &str) -> Result<Instrument> {
+        Ok(Instrument::M20Pixl)
+    }
 
-        vprintln!("Normalizing...");
-        raw.image.normalize_to_16bit_with_max(255.0);
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage> {
+        Ok(MarsImage::open(input_file, instrument)?)
+    }
 
-        vprintln!("Writing to disk...");
-        raw.update_history();
-        match raw.save(&out_file) {
-            Ok(_) => cal_ok(cal_context, &out_file),
-            Err(why) => {
-                veprintln!("Error saving file: {}", why);
-                cal_fail(cal_context, &out_file)
-            }
-        }
+    fn default_pipeline(&self, _input_file: &str, profile: &CalProfile) -> Vec<CalStage> {
+        let mut stages = vec![CalStage::Flat];
+        calpipeline::push_noise_reduction(&mut stages, profile);
+        stages.push(CalStage::normalize_correlated());
+        stages
     }
 }
@@ -1,10 +1,17 @@
 use crate::{
-    calibfile, calibrate::*, calprofile::CalProfile, decompanding, enums, enums::Instrument,
-    flatfield, marsimage::MarsImage, util,
+    calibfile,
+    calibrate::*,
+    calpipeline::{self, CalStage, PipelineContext},
+    calplan::{self, CalPlan},
+    calprofile::CalProfile,
+    enums,
+    enums::Instrument,
+    flatfield,
+    marsimage::MarsImage,
 };
 
 use anyhow::Result;
-use sciimg::{imagebuffer, path};
+use sciimg::imagebuffer;
 
 #[derive(Copy, Clone)]
 pub struct M20SuperCam {}
@@ -14,86 +21,74 @@ impl Calibration for M20SuperCam {
         matches!(instrument, Instrument::M20SuperCam)
     }
 
-    fn process_file(
-        &self,
-        input_file: &str,
-        cal_context: &CalProfile,
-        only_new: bool,
-    ) -> Result<CompleteContext> {
-        let out_file = util::append_file_name(input_file, cal_context.filename_suffix.as_str());
-        if path::file_exists(&out_file) && only_new {
-            vprintln!("Output file exists, skipping. ({})", out_file);
-            return cal_warn(cal_context, &out_file);
-        }
-
-        let mut raw = MarsImage::open(input_file, enums::Instrument::M20SuperCam);
-        vprintln!("Destretching...");
-        raw.destretch_image();
-
-        vprintln!("Loading image mask");
-        let mask_file_path = calibfile::get_calibration_file_for_instrument(
-            enums::Instrument::M20SuperCam,
-            enums::CalFileType::Mask,
-        )
-        .unwrap();
-        vprintln!("Loading supercam mask from {}", mask_file_path);
-        let mut mask = imagebuffer::ImageBuffer::from_file(mask_file_path.as_str()).unwrap();
-        mask = mask
-            .get_subframe(1, 1, mask.width - 2, mask.height - 2)
-            .expect("Failed to extract subframe of M20 SuperCam mask image");
-        raw.apply_alpha(&mask);
+    fn instrument_for_file(&self, _input_file: &str) -> Result<Instrument> {
+        Ok(Instrument::M20SuperCam)
+    }
 
-        let data_max = if cal_context.apply_ilt {
-            vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(enums::Instrument::M20SuperCam)
-                .expect("Failed to determine ILT for M20 SuperCam");
-            raw.decompand(&lut);
-            lut.max() as f32
-        } else {
-            255.0
-        };
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage> {
+        Ok(MarsImage::open(input_file, instrument)?)
+    }
 
-        if input_file.contains("ECM") && raw.image.is_grayscale() {
-            vprintln!("Image appears to be grayscale, applying debayering...");
-            raw.debayer_with_method(cal_context.debayer_method);
+    fn default_pipeline(&self, input_file: &str, profile: &CalProfile) -> Vec<CalStage> {
+        let mut stages = vec![CalStage::Destretch, CalStage::Mask];
+        calpipeline::push_decompand(&mut stages, profile);
+        if input_file.contains("ECM") {
+            stages.push(CalStage::debayer());
         }
-
-        // Gonna start with standard rectangular flat field, but should really
-        // mask it to just the round light-collecting area of the image.
-        vprintln!("Flatfielding...");
-        let flat = flatfield::load_flat(enums::Instrument::M20SuperCam)
-            .expect("Failed to load flatfield image for M20 SuperCam");
-        raw.flatfield_with_flat(&flat);
-
-        vprintln!("Applying color weights...");
-        raw.apply_weight(
-            cal_context.red_scalar,
-            cal_context.green_scalar,
-            cal_context.blue_scalar,
-        );
-
-        if cal_context.auto_subframing {
-            raw.image
-                .crop(1, 1, raw.image.width - 2, raw.image.height - 2);
+        stages.push(CalStage::Flat);
+        calpipeline::push_noise_reduction(&mut stages, profile);
+        stages.push(CalStage::weights());
+        if profile.auto_subframing {
+            stages.push(CalStage::auto_crop());
         }
+        stages.push(CalStage::normalize());
+        stages
+    }
 
-        if cal_context.decorrelate_color {
-            vprintln!("Normalizing with decorrelated colors...");
-            raw.image.normalize_to_16bit_decorrelated();
-        } else {
-            vprintln!("Normalizing with correlated colors...");
-            raw.image.normalize_to_16bit_with_max(data_max);
+    fn apply_stage(
+        &self,
+        stage: &CalStage,
+        raw: &mut MarsImage,
+        context: &mut PipelineContext,
+    ) -> Result<()> {
+        match stage {
+            CalStage::Mask => {
+                vprintln!("Loading image mask");
+                let mask_file_path = calibfile::get_calibration_file_for_instrument(
+                    enums::Instrument::M20SuperCam,
+                    enums::CalFileType::Mask,
+                )?;
+                vprintln!("Loading supercam mask from {}", mask_file_path);
+                context.record_calibration_file(enums::CalFileType::Mask, &mask_file_path);
+                let mut mask = imagebuffer::ImageBuffer::from_file(mask_file_path.as_str())?;
+                mask = mask.get_subframe(1, 1, mask.width - 2, mask.height - 2)?;
+                raw.apply_alpha(&mask);
+                context.using_alpha = true;
+            }
+            CalStage::Flat => {
+                // Gonna start with standard rectangular flat field, but should really
+                // mask it to just the round light-collecting area of the image.
+                vprintln!("Flatfielding...");
+                let flat = flatfield::load_flat(enums::Instrument::M20SuperCam)?;
+                context.record_calibration_file_for(
+                    enums::Instrument::M20SuperCam,
+                    enums::CalFileType::FlatField,
+                );
+                raw.flatfield_with_flat(&flat);
+            }
+            CalStage::Crop { rect: None } => {
+                calpipeline::crop_border(raw, context, 1, 1, 1, 1);
+            }
+            _ => calpipeline::apply_stage(stage, raw, context)?,
         }
+        Ok(())
+    }
 
-        vprintln!("Writing to disk...");
-        raw.update_history();
-        raw.image.set_using_alpha(true);
-        match raw.save(&out_file) {
-            Ok(_) => cal_ok(cal_context, &out_file),
-            Err(why) => {
-                veprintln!("Error saving file: {}", why);
-                cal_fail(cal_context, &out_file)
-            }
+    fn plan_stage(&self, stage: &CalStage, plan: &mut CalPlan) -> Result<()> {
+        match stage {
+            CalStage::Crop { rect: None } => plan.add_border_crop(1, 1, 1, 1),
+            _ => calplan::plan_stage(stage, plan)?,
         }
+        Ok(())
     }
 }
@@ -1,9 +1,12 @@
 use crate::{
-    calibrate::*, calprofile::CalProfile, enums, enums::Instrument, marsimage::MarsImage, util,
+    calibrate::*,
+    calpipeline::{self, CalStage, PipelineContext},
+    calplan::{self, CalPlan},
+    calprofile::CalProfile,
+    enums::Instrument,
+    marsimage::MarsImage,
 };
 
-use sciimg::path;
-
 use anyhow::Result;
 
 #[derive(Copy, Clone)]
@@ -14,44 +17,56 @@ impl Calibration for M20SherlocAci {
         matches!(instrument, Instrument::M20SherlocAci)
     }
 
-    fn process_file(
-        &self,
-        input_file: &str,
-        cal_context: &CalProfile,
-        only_new: bool,
-    ) -> Result<CompleteContext> {
-        let out_file = util::append_file_name(input_file, cal_context.filename_suffix.as_str());
-        if path::file_exists(&out_file) && only_new {
-            vprintln!("Output file exists, skipping. ({})", out_file);
-            return cal_warn(cal_context, &out_file);
-        }
-
-        let mut raw = MarsImage::open(input_file, enums::Instrument::M20SherlocAci);
+    fn instrument_for_file(&self, _input_file: &str) -> Result<Instrument> {
+        Ok(Instrument::M20SherlocAci)
+    }
 
-        vprintln!("Flatfielding...");
-        raw.flatfield();
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage> {
+        Ok(MarsImage::open(input_file, instrument)?)
+    }
 
-        vprintln!("Normalizing...");
-        raw.image.normalize_to_16bit_with_max(255.0);
+    fn default_pipeline(&self, _input_file: &str, profile: &CalProfile) -> Vec<CalStage> {
+        let mut stages = vec![CalStage::Flat];
+        calpipeline::push_noise_reduction(&mut stages, profile);
+        stages.push(CalStage::normalize_correlated());
+        if profile.auto_subframing {
+            stages.push(CalStage::auto_crop());
+        }
+        stages
+    }
 
-        if cal_context.auto_subframing {
-            if raw.image.width == 1648 && raw.image.height == 1200 {
-                vprintln!("Cropping...");
-                raw.image.crop(23, 2, 1607, 1198);
-            } else if raw.image.width == 1600 && raw.image.height == 1200 {
-                vprintln!("Cropping...");
-                raw.image.crop(23, 2, 1577, 1198);
+    fn apply_stage(
+        &self,
+        stage: &CalStage,
+        raw: &mut MarsImage,
+        context: &mut PipelineContext,
+    ) -> Result<()> {
+        match stage {
+            CalStage::Crop { rect: None } => {
+                if raw.image.width == 1648 && raw.image.height == 1200 {
+                    vprintln!("Cropping...");
+                    raw.image.crop(23, 2, 1607, 1198);
+                    context.record_crop(23, 2, 1607, 1198);
+                } else if raw.image.width == 1600 && raw.image.height == 1200 {
+                    vprintln!("Cropping...");
+                    raw.image.crop(23, 2, 1577, 1198);
+                    context.record_crop(23, 2, 1577, 1198);
+                }
             }
+            _ => calpipeline::apply_stage(stage, raw, context)?,
         }
+        Ok(())
+    }
 
-        vprintln!("Writing to disk...");
-        raw.update_history();
-        match raw.save(&out_file) {
-            Ok(_) => cal_ok(cal_context, &out_file),
-            Err(why) => {
-                veprintln!("Error saving file: {}", why);
-                cal_fail(cal_context, &out_file)
-            }
+    fn plan_stage(&self, stage: &CalStage, plan: &mut CalPlan) -> Result<()> {
+        match stage {
+            CalStage::Crop { rect: None } => match (plan.width, plan.height) {
+                (Some(1648), Some(1200)) => plan.add_crop(23, 2, 1607, 1198),
+                (Some(1600), Some(1200)) => plan.add_crop(23, 2, 1577, 1198),
+                _ => {}
+            },
+            _ => calplan::plan_stage(stage, plan)?,
         }
+        Ok(())
     }
 }
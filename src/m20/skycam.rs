@@ -1,10 +1,14 @@
 use crate::{
-    calibrate::*, calprofile::CalProfile, enums, enums::Instrument, flatfield,
-    marsimage::MarsImage, util,
+    calibrate::*,
+    calpipeline::{self, CalStage, PipelineContext},
+    calplan::{self, CalPlan},
+    calprofile::CalProfile,
+    enums,
+    enums::Instrument,
+    flatfield,
+    marsimage::MarsImage,
 };
 
-use sciimg::path;
-
 use anyhow::Result;
 
 #[derive(Copy, Clone)]
@@ -15,52 +19,54 @@ impl Calibration for M20SkyCam {
         matches!(instrument, Instrument::M20SkyCam)
     }
 
-    fn process_file(
-        &self,
-        input_file: &str,
-        cal_context: &CalProfile,
-        only_new: bool,
-    ) -> Result<CompleteContext> {
-        let out_file = util::append_file_name(input_file, cal_context.filename_suffix.as_str());
-        if path::file_exists(&out_file) && only_new {
-            vprintln!("Output file exists, skipping. ({})", out_file);
-            return cal_warn(cal_context, &out_file);
-        }
-
-        let mut raw = MarsImage::open(input_file, enums::Instrument::M20SkyCam);
-
-        vprintln!("Flatfielding...");
-        let flat = flatfield::load_flat(enums::Instrument::M20SkyCam).unwrap();
-        raw.flatfield_with_flat(&flat);
+    fn instrument_for_file(&self, _input_file: &str) -> Result<Instrument> {
+        Ok(Instrument::M20SkyCam)
+    }
 
-        if cal_context.hot_pixel_detection_threshold > 0.0 {
-            vprintln!(
-                "Hot pixel correction with variance threshold {}...",
-                cal_context.hot_pixel_detection_threshold
-            );
-            raw.hot_pixel_correction(
-                cal_context.hot_pixel_window_size,
-                cal_context.hot_pixel_detection_threshold,
-            );
-        }
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage> {
+        Ok(MarsImage::open(input_file, instrument)?)
+    }
 
-        vprintln!("Normalizing...");
-        raw.image.normalize_to_16bit_with_max(255.0);
+    fn default_pipeline(&self, _input_file: &str, profile: &CalProfile) -> Vec<CalStage> {
+        let mut stages = vec![CalStage::Flat];
+        calpipeline::push_noise_reduction(&mut stages, profile);
+        stages.push(CalStage::normalize_correlated());
 
         // Trim off border pixels
-        vprintln!("Cropping border pixels...");
-        let crop_to_width = raw.image.width - 34;
-        let crop_to_height = raw.image.height - 2;
-        raw.image.crop(18, 1, crop_to_width, crop_to_height);
+        stages.push(CalStage::auto_crop());
+        stages
+    }
 
-        vprintln!("Writing to disk...");
-        raw.update_history();
-        match raw.save(&out_file) {
-            Ok(_) => cal_ok(cal_context, &out_file),
-            Err(why) => {
-                veprintln!("Error saving file: {}", why);
-                cal_fail(cal_context, &out_file)
+    fn apply_stage(
+        &self,
+        stage: &CalStage,
+        raw: &mut MarsImage,
+        context: &mut PipelineContext,
+    ) -> Result<()> {
+        match stage {
+            CalStage::Flat => {
+                vprintln!("Flatfielding...");
+                let flat = flatfield::load_flat(enums::Instrument::M20SkyCam)?;
+                context.record_calibration_file_for(
+                    enums::Instrument::M20SkyCam,
+                    enums::CalFileType::FlatField,
+                );
+                raw.flatfield_with_flat(&flat);
+            }
+            CalStage::Crop { rect: None } => {
+                vprintln!("Cropping border pixels...");
+                calpipeline::crop_border(raw, context, 18, 1, 16, 1);
             }
+            _ => calpipeline::apply_stage(stage, raw, context)?,
+        }
+        Ok(())
+    }
+
+    fn plan_stage(&self, stage: &CalStage, plan: &mut CalPlan) -> Result<()> {
+        match stage {
+            CalStage::Crop { rect: None } => plan.add_border_crop(18, 1, 16, 1),
+            _ => calplan::plan_stage(stage, plan)?,
         }
+        Ok(())
     }
 }
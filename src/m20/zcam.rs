@@ -1,6 +1,16 @@
 use crate::{
-    calibfile, calibrate::*, calprofile::CalProfile, decompanding, enums, enums::Instrument,
-    inpaintmask, marsimage::MarsImage, util,
+    calibfile,
+    calibrate::*,
+    calpipeline::{self, CalStage, PipelineContext},
+    calplan::{self, CalPlan},
+    calprofile::CalProfile,
+    enums,
+    enums::{Eye, Instrument},
+    error::CalError,
+    inpaintmask,
+    marsimage::{self, MarsImage},
+    metadata::Metadata,
+    productid::ProductId,
 };
 
 use sciimg::prelude::*;
@@ -13,21 +23,79 @@ pub const FOCAL_STOPS: [f32; 7] = [26.0, 34.0, 48.0, 63.0, 79.0, 100.0, 110.0];
 pub const MOTOR_COUNT_STOPS: [u16; 7] = [0, 2448, 3834, 5196, 6720, 8652, 9600];
 
 pub fn focal_length_from_file_name(filename: &str) -> Result<f32> {
-    let bn = path::basename(filename);
+    let product_id = match ProductId::from_file_name(filename) {
+        Ok(ProductId::M20(product_id)) => product_id,
+        _ => return Err(anyhow!("Filename is invalid M20/MCZ format")),
+    };
+
+    match product_id.focal_length() {
+        Some(focal_length) => Ok(focal_length),
+        None => {
+            eprintln!(
+                "Found invalid focal length value: {}",
+                product_id.camera_specific
+            );
+            Err(anyhow!("Invalid value"))
+        }
+    }
+}
 
-    if bn.len() < 48 {
-        return Err(anyhow!("Filename is invalid M20/MCZ format"));
+/// The camera eye and filter position encoded in a Mastcam-Z file name, such as `L0` for
+/// `ZL0_...` or `R6` for `ZR6_...`
+pub fn filter_from_file_name(filename: &str) -> Option<String> {
+    match ProductId::from_file_name(filename) {
+        Ok(product_id @ ProductId::M20(_)) => product_id.filter(),
+        _ => None,
     }
+}
 
-    let subs = &bn[45..48];
-    if util::string_is_valid_f32(subs) {
-        Ok(subs.parse::<f32>().unwrap())
-    } else {
-        eprintln!("Found invalid focal length value: {}", subs);
-        Err(anyhow!("Invalid value"))
+/// The camera eye and filter position within a metadata filter name, which may include a
+/// description of the filter along with its position, such as `L1` for `ZCAM_L1_800`
+pub fn filter_from_metadata_name(filter_name: &str) -> Option<String> {
+    filter_name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .find_map(|token| {
+            let chars: Vec<char> = token.to_uppercase().chars().collect();
+            match chars[..] {
+                [eye @ ('L' | 'R'), filter] | ['Z', eye @ ('L' | 'R'), filter]
+                    if filter.is_ascii_digit() =>
+                {
+                    Some(format!("{}{}", eye, filter))
+                }
+                _ => None,
+            }
+        })
+}
+
+/// True for the narrowband and solar filters, positions 1 through 7 on either eye. Images
+/// taken through them are single band and are not debayered.
+pub fn is_narrowband_filter(filter: &str) -> bool {
+    matches!(filter.chars().nth(1), Some('1'..='7'))
+}
+
+/// The filter an image was taken through, from its file name or failing that its metadata
+fn filter_for_image(input_file: &str, metadata: &Metadata) -> Result<String> {
+    let from_metadata = || {
+        metadata.filter_name.as_ref().map(|filter_name| {
+            filter_from_metadata_name(filter_name).unwrap_or_else(|| filter_name.clone())
+        })
+    };
+    match filter_from_file_name(input_file).or_else(from_metadata) {
+        Some(filter) => Ok(filter),
+        None => Err(CalError::bad_metadata(input_file, "Unable to determine zcam filter").into()),
     }
 }
 
+/// The filter an input file was taken through, read before the image itself is opened so
+/// that it can choose the processing path
+fn filter_for_file(input_file: &str) -> Option<String> {
+    filter_from_file_name(input_file).or_else(|| {
+        MarsImage::load_image_metadata(input_file)
+            .ok()
+            .and_then(|metadata| filter_for_image(input_file, &metadata).ok())
+    })
+}
+
 fn focal_length_from_cahvor(cahvor: &CameraModel) -> Result<f32> {
     if cahvor.is_valid() {
         Ok(cahvor.f() as f32) // Reconcile the type difference.
@@ -36,22 +104,270 @@ fn focal_length_from_cahvor(cahvor: &CameraModel) -> Result<f32> {
     }
 }
 
-fn close_enough(a: f32, b: f32) -> bool {
-    (a - b).abs() < 5.0
+/// Focal lengths within this distance of a zoom stop use that stop's flat alone
+const FOCAL_STOP_TOLERANCE_MM: f32 = 0.5;
+
+/// The zoom stop flats bracketing a focal length and the weight each contributes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ZoomFlatWeights {
+    /// Motor count of the zoom stop at or below the focal length
+    pub lower_stop: u16,
+
+    /// Motor count of the zoom stop at or above the focal length. The same as `lower_stop`
+    /// when the focal length is at a zoom stop.
+    pub upper_stop: u16,
+
+    /// Weight of the upper stop's flat. The lower stop's flat has one minus this weight.
+    pub upper_weight: f32,
+
+    /// False when the focal length is outside the calibrated zoom range and the nearest
+    /// stop's flat is used as-is
+    pub in_range: bool,
+}
+
+impl ZoomFlatWeights {
+    fn at_stop(index: usize, in_range: bool) -> Self {
+        ZoomFlatWeights {
+            lower_stop: MOTOR_COUNT_STOPS[index],
+            upper_stop: MOTOR_COUNT_STOPS[index],
+            upper_weight: 0.0,
+            in_range,
+        }
+    }
+
+    /// The motor count and weight of each flat that contributes
+    pub fn stops(&self) -> Vec<(u16, f32)> {
+        if self.lower_stop == self.upper_stop {
+            vec![(self.lower_stop, 1.0)]
+        } else {
+            vec![
+                (self.lower_stop, 1.0 - self.upper_weight),
+                (self.upper_stop, self.upper_weight),
+            ]
+        }
+    }
+}
+
+/// Brackets the focal length between the two nearest zoom stops, weighting each stop's flat
+/// linearly by how close the focal length is to it. Focal lengths outside the calibrated
+/// range use the nearest stop.
+pub fn flat_weights_for_focal_length(focal_length: f32) -> ZoomFlatWeights {
+    let last = FOCAL_STOPS.len() - 1;
+    if !focal_length.is_finite() || focal_length < FOCAL_STOPS[0] - FOCAL_STOP_TOLERANCE_MM {
+        return ZoomFlatWeights::at_stop(0, false);
+    }
+    if focal_length > FOCAL_STOPS[last] + FOCAL_STOP_TOLERANCE_MM {
+        return ZoomFlatWeights::at_stop(last, false);
+    }
+
+    if let Some(index) = FOCAL_STOPS
+        .iter()
+        .position(|stop| (focal_length - stop).abs() <= FOCAL_STOP_TOLERANCE_MM)
+    {
+        return ZoomFlatWeights::at_stop(index, true);
+    }
+
+    // Within the range and not at a stop, so there is always a bracketing pair
+    let index = FOCAL_STOPS
+        .windows(2)
+        .position(|pair| focal_length > pair[0] && focal_length < pair[1])
+        .unwrap_or(0);
+    ZoomFlatWeights {
+        lower_stop: MOTOR_COUNT_STOPS[index],
+        upper_stop: MOTOR_COUNT_STOPS[index + 1],
+        upper_weight: (focal_length - FOCAL_STOPS[index])
+            / (FOCAL_STOPS[index + 1] - FOCAL_STOPS[index]),
+        in_range: true,
+    }
+}
+
+/// Blends two flats of the same size, giving `upper_weight` to the second
+pub fn blend_flats(lower: &Image, upper: &Image, upper_weight: f32) -> Result<Image> {
+    if lower.width != upper.width
+        || lower.height != upper.height
+        || lower.num_bands() != upper.num_bands()
+    {
+        return Err(anyhow!(
+            "Cannot blend flats of different sizes: {}x{}x{} and {}x{}x{}",
+            lower.width,
+            lower.height,
+            lower.num_bands(),
+            upper.width,
+            upper.height,
+            upper.num_bands()
+        ));
+    }
+
+    let mut blended = lower.clone();
+    for band in 0..lower.num_bands() {
+        let (lower_band, upper_band) = (lower.get_band(band), upper.get_band(band));
+        for y in 0..lower.height {
+            for x in 0..lower.width {
+                let value = lower_band.get(x, y) * (1.0 - upper_weight)
+                    + upper_band.get(x, y) * upper_weight;
+                blended.put(x, y, value, band);
+            }
+        }
+    }
+    Ok(blended)
 }
 
-fn motor_stop_from_focal_length(fl: f32) -> u16 {
-    for i in 1..FOCAL_STOPS.len() {
-        if close_enough(fl, FOCAL_STOPS[i]) {
-            return MOTOR_COUNT_STOPS[i];
+/// The flat fields resolved for an image
+struct ZoomFlats {
+    focal_length: f32,
+    weights: ZoomFlatWeights,
+
+    /// Path and weight of each contributing flat
+    files: Vec<(String, f32)>,
+}
+
+impl ZoomFlats {
+    fn out_of_range_warning(&self) -> Option<String> {
+        match self.weights.in_range {
+            true => None,
+            false => Some(format!(
+                "Focal length {}mm is outside the {}-{}mm zoom range, using the {} motor count flat",
+                self.focal_length,
+                FOCAL_STOPS[0],
+                FOCAL_STOPS[FOCAL_STOPS.len() - 1],
+                self.weights.lower_stop
+            )),
         }
     }
-    MOTOR_COUNT_STOPS[0]
+
+    fn description(&self) -> String {
+        self.files
+            .iter()
+            .map(|(file_path, weight)| format!("{} ({:.3})", file_path, weight))
+            .collect::<Vec<String>>()
+            .join(" and ")
+    }
+}
+
+/// Resolves the flat fields for the filter and zoom position the image was captured at.
+/// Narrowband filters only use flats configured for that filter, since the Bayer flat does
+/// not describe their response, and have none otherwise.
+fn resolve_flats(
+    input_file: &str,
+    metadata: &Metadata,
+    instrument: Instrument,
+) -> Result<Option<ZoomFlats>> {
+    // I'm not wild about this
+    let focal_length = match focal_length_from_file_name(input_file) {
+        Ok(fl) => fl,
+        Err(_) => {
+            focal_length_from_cahvor(&metadata.camera_model_component_list).map_err(|_| {
+                CalError::bad_metadata(input_file, "Unable to determine zcam focal length")
+            })?
+        }
+    };
+    info!("Determined camera focal length at {}mm", focal_length);
+
+    let template = match filter_for_image(input_file, metadata).ok() {
+        Some(filter) if is_narrowband_filter(&filter) => {
+            match calibfile::get_instrument_properties(instrument)?.filter_flat(&filter) {
+                Some(file_name) => {
+                    info!("Using flat field for filter {}", filter);
+                    file_name
+                }
+                None => return Ok(None),
+            }
+        }
+        _ => {
+            let file_name = calibfile::get_calibration_base_file_for_instrument(
+                instrument,
+                enums::CalFileType::FlatField,
+            )?;
+            if file_name.is_empty() {
+                return Err(CalError::MissingCalData {
+                    instrument,
+                    file_type: enums::CalFileType::FlatField,
+                }
+                .into());
+            }
+            file_name
+        }
+    };
+
+    let weights = flat_weights_for_focal_length(focal_length);
+    let files = weights
+        .stops()
+        .iter()
+        .map(|(motor_stop, weight)| {
+            let file_name = template.replace("-motorcount-", &format!("{:04}", motor_stop));
+            Ok((calibfile::locate_calibration_file(&file_name)?, *weight))
+        })
+        .collect::<Result<Vec<(String, f32)>>>()?;
+
+    Ok(Some(ZoomFlats {
+        focal_length,
+        weights,
+        files,
+    }))
 }
 
 #[derive(Copy, Clone)]
 pub struct M20MastcamZ {}
 
+impl M20MastcamZ {
+    fn open_flat(
+        &self,
+        file_path: &str,
+        weight: f32,
+        raw: &MarsImage,
+        context: &mut PipelineContext,
+    ) -> Result<MarsImage> {
+        info!("Using flat file: {} (weight {:.3})", file_path, weight);
+        let mut flat = MarsImage::open(file_path, raw.instrument)?;
+        context.record_weighted_calibration_file(enums::CalFileType::FlatField, file_path, weight);
+
+        if let Some(rect) = &raw.metadata.subframe_rect {
+            let [x, y, width, height] = marsimage::subframe_region(
+                raw.instrument,
+                rect,
+                1,
+                flat.image.width,
+                flat.image.height,
+            )?;
+            flat.crop(x, y, width, height);
+        }
+        Ok(flat)
+    }
+
+    fn flatfield(&self, raw: &mut MarsImage, context: &mut PipelineContext) -> Result<()> {
+        info!("Flatfielding...");
+        let flats = match resolve_flats(&context.input_file, &raw.metadata, raw.instrument)? {
+            Some(flats) => flats,
+            None => {
+                let filter = filter_for_image(&context.input_file, &raw.metadata)?;
+                context.add_warning(&format!(
+                    "No flat field for filter {}, skipping flat fielding",
+                    filter
+                ));
+                return Ok(());
+            }
+        };
+
+        if let Some(warning) = flats.out_of_range_warning() {
+            context.add_warning(&warning);
+        }
+
+        let (file_path, weight) = &flats.files[0];
+        let mut flat = self.open_flat(file_path, *weight, raw, context)?;
+        if let Some((file_path, weight)) = flats.files.get(1) {
+            info!(
+                "Interpolating flats for focal length {}mm",
+                flats.focal_length
+            );
+            let upper = self.open_flat(file_path, *weight, raw, context)?;
+            flat.image = blend_flats(&flat.image, &upper.image, *weight)?;
+        }
+
+        raw.flatfield_with_flat(&flat);
+        Ok(())
+    }
+}
+
 impl Calibration for M20MastcamZ {
     fn accepts_instrument(&self, instrument: Instrument) -> bool {
         matches!(
@@ -60,177 +376,181 @@ impl Calibration for M20MastcamZ {
         )
     }
 
-    fn process_file(
-        &self,
-        input_file: &str,
-        cal_context: &CalProfile,
-        only_new: bool,
-    ) -> Result<CompleteContext> {
-        let out_file = util::append_file_name(input_file, cal_context.filename_suffix.as_str());
-        if path::file_exists(&out_file) && only_new {
-            warn!("Output file exists, skipping. ({})", out_file);
-            return cal_warn(cal_context, &out_file);
-        }
-
-        let mut warn = false;
-        let instrument;
-
-        let bn = path::basename(input_file);
-        if bn.chars().nth(1).unwrap() == 'R' {
-            instrument = Instrument::M20MastcamZRight;
+    fn instrument_for_file(&self, input_file: &str) -> Result<Instrument> {
+        let eye = ProductId::from_file_name(input_file).map(|product_id| product_id.eye());
+        if matches!(eye, Ok(Eye::Right)) {
             info!("Processing for Mastcam-Z Right");
+            Ok(Instrument::M20MastcamZRight)
         } else {
-            instrument = Instrument::M20MastcamZLeft;
             info!("Processing for Mastcam-Z Left");
+            Ok(Instrument::M20MastcamZLeft)
         }
+    }
 
-        let mut raw = MarsImage::open(input_file, instrument);
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage> {
+        Ok(MarsImage::open(input_file, instrument)?)
+    }
 
-        let data_max = if cal_context.apply_ilt {
-            info!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(instrument).unwrap();
-            raw.decompand(&lut);
-            lut.max() as f32
-        } else {
-            255.0
-        };
+    fn default_pipeline(&self, input_file: &str, profile: &CalProfile) -> Vec<CalStage> {
+        let mut stages = vec![];
+        calpipeline::push_decompand(&mut stages, profile);
+
+        // Narrowband filters produce a single band image with no color to recover
+        let narrowband = filter_for_file(input_file)
+            .map(|filter| is_narrowband_filter(&filter))
+            .unwrap_or(false);
 
-        // Looks like 'ECM' in the name seems to indicate that it still have the bayer pattern
+        // ECM products seem to still have the bayer pattern.
         // Update: Not always. Added a check to determine whether or not is is grayscale.
-        // It's not perfect so please validate results. Gonna keep the 'ECM' check for now.
-        if input_file.contains("ECM") && raw.image.is_grayscale() {
-            info!("Image appears to be grayscale, applying debayering...");
-            raw.debayer_with_method(cal_context.debayer_method);
+        // It's not perfect so please validate results.
+        let bayer = matches!(
+            ProductId::from_file_name(input_file),
+            Ok(ProductId::M20(product_id)) if product_id.product_type == "ECM"
+        );
+        if bayer && !narrowband {
+            stages.push(CalStage::debayer());
         }
 
-        // I'm not wild about this
-        let focal_length: Result<f32> = match focal_length_from_file_name(input_file) {
-            Ok(fl) => Ok(fl),
-            Err(_) => {
-                if let Ok(fl) = focal_length_from_cahvor(&raw.metadata.camera_model_component_list)
-                {
-                    Ok(fl)
-                } else {
-                    //print_fail(&format!("{} ({})", path::basename(input_file), &cal_context.filename_suffix.to_str()));
-                    panic!("Unable to determine zcam focal length")
-                }
+        stages.push(CalStage::Flat);
+        stages.push(CalStage::Inpaint);
+        if narrowband {
+            if profile.hot_pixel_detection_threshold > 0.0 {
+                stages.push(CalStage::hot_pixel_correction());
             }
-        };
-
-        info!("Determining if we can flat field the image...");
-        if let Ok(fl) = focal_length {
-            // Do flat fielding
-            info!("Flatfielding...");
-            info!("Determined camera focal length at {}mm", fl);
-
-            let calfile = calibfile::get_calibration_file_for_instrument(
-                instrument,
-                enums::CalFileType::FlatField,
-            )?;
+        } else {
+            calpipeline::push_noise_reduction(&mut stages, profile);
+        }
 
-            let motor_stop = motor_stop_from_focal_length(fl);
-            let motor_stop_str = format!("{:04}", motor_stop);
-            let file_path = calfile.replace("-motorcount-", motor_stop_str.as_str());
+        // Physical units replace the color weights and normalization
+        if profile.radiometric_units.is_some() {
+            stages.push(CalStage::radiometric());
+            if profile.auto_subframing {
+                stages.push(CalStage::auto_crop());
+            }
+            return stages;
+        }
 
-            info!("Using flat file: {}", file_path);
+        if narrowband {
+            if profile.auto_subframing {
+                stages.push(CalStage::auto_crop());
+            }
+            stages.push(CalStage::normalize());
+            return stages;
+        }
 
-            if path::file_exists(&file_path) {
-                let mut flat = MarsImage::open(&file_path, instrument);
+        stages.push(CalStage::weights());
+        if profile.auto_subframing {
+            stages.push(CalStage::auto_crop());
+        }
+        calpipeline::push_color_output(&mut stages, profile);
+        stages
+    }
 
+    fn apply_stage(
+        &self,
+        stage: &CalStage,
+        raw: &mut MarsImage,
+        context: &mut PipelineContext,
+    ) -> Result<()> {
+        match stage {
+            CalStage::Flat => {
+                info!("Determining if we can flat field the image...");
+                self.flatfield(raw, context)?;
+            }
+            CalStage::Inpaint => {
+                info!("Inpainting...");
+                let mut inpaint_mask = inpaintmask::load_mask(raw.instrument)?;
+                context
+                    .record_calibration_file_for(raw.instrument, enums::CalFileType::InpaintMask);
                 if let Some(rect) = &raw.metadata.subframe_rect {
-                    flat.crop(
-                        rect[0] as usize - 1,
-                        rect[1] as usize - 1,
-                        rect[2] as usize,
-                        rect[3] as usize,
-                    );
+                    let [x, y, width, height] = marsimage::subframe_region(
+                        raw.instrument,
+                        rect,
+                        1,
+                        inpaint_mask.width,
+                        inpaint_mask.height,
+                    )?;
+                    inpaint_mask = inpaint_mask.get_subframe(x, y, width, height)?;
                 }
 
-                raw.flatfield_with_flat(&flat);
-            } else {
-                eprintln!("Flat file not found: {}", file_path);
-                // print_fail(&format!("{} ({})", path::basename(input_file), filename_suffix));
-                panic!("Flat file not found!");
+                raw.apply_inpaint_fix_with_mask(&inpaint_mask)?;
             }
-        } else if let Err(e) = focal_length {
-            warn = true;
-            warn!("Could not determine focal length: {}", e)
-        }
-
-        info!("Inpainting...");
-        let mut inpaint_mask = inpaintmask::load_mask(instrument).unwrap();
-        if let Some(rect) = &raw.metadata.subframe_rect {
-            inpaint_mask = inpaint_mask
-                .get_subframe(
-                    rect[0] as usize - 1,
-                    rect[1] as usize - 1,
-                    rect[2] as usize,
-                    rect[3] as usize,
-                )
-                .unwrap();
-        }
-
-        raw.apply_inpaint_fix_with_mask(&inpaint_mask);
-
-        info!("Applying color weights...");
-        raw.apply_weight(
-            cal_context.red_scalar,
-            cal_context.green_scalar,
-            cal_context.blue_scalar,
-        );
-
-        info!(
-            "Current image width: {}, height: {}",
-            raw.image.width, raw.image.height
-        );
-
-        info!("Cropping...");
-
-        if cal_context.auto_subframing {
-            if let Some(rect) = &raw.metadata.subframe_rect {
-                let new_rect = vec![
-                    rect[0] + 29.0,
-                    rect[1] + 9.0,
-                    rect[2] - 58.0,
-                    rect[3] - 18.0,
-                ];
-                raw.metadata.subframe_rect = Some(new_rect);
+            CalStage::Radiometric { units } => {
+                let filter = filter_for_image(&context.input_file, &raw.metadata)?;
+                calpipeline::apply_radiometric(raw, context, *units, &filter)?;
             }
+            CalStage::Crop { rect: None } => {
+                info!(
+                    "Current image width: {}, height: {}",
+                    raw.image.width, raw.image.height
+                );
+
+                info!("Cropping...");
+                if calpipeline::crop_border(raw, context, 29, 9, 29, 9) {
+                    if let Some(rect) = &raw.metadata.subframe_rect {
+                        let new_rect = vec![
+                            rect[0] + 29.0,
+                            rect[1] + 9.0,
+                            rect[2] - 58.0,
+                            rect[3] - 18.0,
+                        ];
+                        raw.metadata.subframe_rect = Some(new_rect);
+                    }
+                }
 
-            raw.image
-                .crop(29, 9, raw.image.width - 29 - 29, raw.image.height - 9 - 9);
-        }
-
-        info!(
-            "Current image width: {}, height: {}",
-            raw.image.width, raw.image.height
-        );
-
-        if cal_context.srgb_color_correction {
-            info!("Applying sRGB color conversion");
-            raw.image
-                .convert_colorspace(color::ColorSpaceType::RGB, color::ColorSpaceType::sRGB)?;
+                info!(
+                    "Current image width: {}, height: {}",
+                    raw.image.width, raw.image.height
+                );
+            }
+            _ => calpipeline::apply_stage(stage, raw, context)?,
         }
+        Ok(())
+    }
 
-        if cal_context.decorrelate_color {
-            info!("Normalizing with decorrelated colors...");
-            raw.image.normalize_to_16bit_decorrelated();
-        } else {
-            info!("Normalizing with correlated colors...");
-            raw.image.normalize_to_16bit_with_max(data_max);
-        }
-
-        info!("Writing to {}", out_file);
-        raw.update_history();
-        match raw.save(&out_file) {
-            Ok(_) => match warn {
-                true => cal_warn(cal_context, &out_file),
-                false => cal_ok(cal_context, &out_file),
-            },
-            Err(why) => {
-                error!("Error saving file: {}", why);
-                cal_fail(cal_context, &out_file)
+    fn plan_stage(&self, stage: &CalStage, plan: &mut CalPlan) -> Result<()> {
+        match stage {
+            CalStage::Flat => {
+                match resolve_flats(&plan.input_file, &plan.metadata, plan.instrument_id) {
+                    Ok(Some(flats)) => {
+                        calplan::plan_resolved_file(
+                            plan,
+                            enums::CalFileType::FlatField,
+                            &flats.files[0].0,
+                        );
+                        if flats.files.len() > 1 {
+                            plan.add_note(&format!(
+                                "Flat field interpolated for focal length {}mm from {}",
+                                flats.focal_length,
+                                flats.description()
+                            ));
+                        }
+                        if let Some(warning) = flats.out_of_range_warning() {
+                            plan.add_note(&warning);
+                        }
+                    }
+                    Ok(None) => plan.add_note(&format!(
+                        "No flat field for filter {}, flat fielding skipped",
+                        filter_for_image(&plan.input_file, &plan.metadata)?
+                    )),
+                    Err(why) => plan.add_missing(&why.to_string()),
+                }
+            }
+            CalStage::Inpaint => {
+                calplan::plan_calibration_file(plan, enums::CalFileType::InpaintMask);
+                if let Some(rect) = &plan.subframe_rect {
+                    plan.add_note(&format!("Inpaint mask subframed to {:?}", rect));
+                }
+            }
+            CalStage::Radiometric { units } => {
+                match filter_for_image(&plan.input_file, &plan.metadata) {
+                    Ok(filter) => calplan::plan_radiometric(plan, *units, &filter),
+                    Err(why) => plan.add_missing(&why.to_string()),
+                }
             }
+            CalStage::Crop { rect: None } => plan.add_border_crop(29, 9, 29, 9),
+            _ => calplan::plan_stage(stage, plan)?,
         }
+        Ok(())
     }
 }
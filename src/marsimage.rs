@@ -6,9 +6,52 @@ use sciimg::{
     imagebuffer::ImageBuffer, inpaint, path, DnVec, VecMath,
 };
 
-use crate::{decompanding::LookUpTable, enums, flatfield, inpaintmask, metadata::*, util};
+use crate::{
+    decompanding::LookUpTable, enums, error::CalError, flatfield, floattiff, inpaintmask,
+    metadata::*, util,
+};
 use image::ImageReader;
 
+/// Validates a one-based metadata subframe against a full sensor calibration image of
+/// `cal_width` by `cal_height` pixels at `scale_factor`, returning the zero-based x, y, width
+/// and height of the matching region of the calibration image.
+pub fn subframe_region(
+    instrument: enums::Instrument,
+    rect: &[f64],
+    scale_factor: u32,
+    cal_width: usize,
+    cal_height: usize,
+) -> Result<[usize; 4], CalError> {
+    let unsupported = || CalError::UnsupportedSubframe {
+        instrument,
+        rect: rect.to_vec(),
+        cal_width,
+        cal_height,
+    };
+
+    if rect.len() != 4 || rect.iter().any(|v| !v.is_finite() || *v < 1.0) {
+        return Err(unsupported());
+    }
+
+    let scale = scale_factor.max(1) as usize;
+    let region = [
+        (rect[0] as usize - 1) / scale,
+        (rect[1] as usize - 1) / scale,
+        rect[2] as usize / scale,
+        rect[3] as usize / scale,
+    ];
+
+    if region[2] == 0
+        || region[3] == 0
+        || region[0] + region[2] > cal_width
+        || region[1] + region[3] > cal_height
+    {
+        Err(unsupported())
+    } else {
+        Ok(region)
+    }
+}
+
 #[derive(Clone)]
 pub struct MarsImage {
     pub image: Image,
@@ -53,20 +96,25 @@ impl MarsImage {
         self.empty
     }
 
-    pub fn open(file_path: &str, instrument: enums::Instrument) -> Self {
+    pub fn open(file_path: &str, instrument: enums::Instrument) -> Result<Self, CalError> {
         if !path::file_exists(file_path) {
-            panic!("File not found: {}", file_path);
+            return Err(CalError::MissingFile(file_path.to_owned()));
         }
 
         vprintln!("Loading image from {}", file_path);
 
-        MarsImage {
-            image: Image::open(file_path).unwrap(),
+        let image = match Image::open(file_path) {
+            Ok(image) => image,
+            Err(why) => return Err(CalError::decode_failure(file_path, why)),
+        };
+
+        Ok(MarsImage {
+            image,
             instrument,
-            metadata: MarsImage::load_image_metadata(file_path),
+            metadata: MarsImage::load_image_metadata(file_path)?,
             empty: false,
             file_path: Some(file_path.to_owned()),
-        }
+        })
     }
 
     fn is_jpeg(file_path: &str) -> Result<bool, Box<dyn std::error::Error>> {
@@ -75,43 +123,58 @@ impl MarsImage {
         Ok(matches!(reader.format(), Some(image::ImageFormat::Jpeg)))
     }
 
-    pub fn open_dct_coefficient_fix(file_path: &str, instrument: enums::Instrument) -> Self {
+    pub fn open_dct_coefficient_fix(
+        file_path: &str,
+        instrument: enums::Instrument,
+    ) -> Result<Self, CalError> {
         if !path::file_exists(file_path) {
-            panic!("File not found: {}", file_path);
+            return Err(CalError::MissingFile(file_path.to_owned()));
         }
 
         // We can only use this method if the input file is a jpeg. We use the image create to determine
         // this. If it is a jpeg, great, otherwise fallback to the MarsImage::open function.
         if match Self::is_jpeg(file_path) {
-            Err(why) => panic!("Cannot determine image type: {:?}", why),
+            Err(why) => return Err(CalError::decode_failure(file_path, why)),
             Ok(d) => d,
         } {
             // Load the jpeg
-            MarsImage {
-                image: Image::open_bayer_jpeg(file_path, true).unwrap(),
+            let image = match Image::open_bayer_jpeg(file_path, true) {
+                Ok(image) => image,
+                Err(why) => return Err(CalError::decode_failure(file_path, why)),
+            };
+
+            Ok(MarsImage {
+                image,
                 instrument,
-                metadata: MarsImage::load_image_metadata(file_path),
+                metadata: MarsImage::load_image_metadata(file_path)?,
                 empty: false,
                 file_path: Some(file_path.to_owned()),
-            }
+            })
         } else {
             // Otherwise fall back on the standard loading
             MarsImage::open(file_path, instrument)
         }
     }
 
-    fn load_image_metadata(file_path: &str) -> Metadata {
+    /// Loads the metadata sidecar for an image without reading its pixels. Images without a
+    /// sidecar receive default metadata. See `identify::apply_label_metadata` for filling in
+    /// the exposure and detector temperature from the image's label.
+    pub fn load_image_metadata(file_path: &str) -> Result<Metadata, CalError> {
         let metadata_file = util::replace_image_extension(file_path, "-metadata.json");
         info!("Checking for metadata file at {}", metadata_file);
-        if path::file_exists(metadata_file.as_str()) {
+        let metadata = if path::file_exists(metadata_file.as_str()) {
             info!("Metadata file exists for loaded image: {}", metadata_file);
             match load_image_metadata(&metadata_file) {
-                Err(why) => panic!("couldn't open {}", why),
+                Err(why) => match why.downcast::<CalError>() {
+                    Ok(e) => return Err(e),
+                    Err(why) => return Err(CalError::bad_metadata(&metadata_file, why)),
+                },
                 Ok(md) => md,
             }
         } else {
             Metadata::default()
-        }
+        };
+        Ok(metadata)
     }
 
     pub fn update_history(&mut self) {
@@ -127,7 +190,7 @@ impl MarsImage {
             if self.metadata.history.is_empty() {
                 warn!("Saving MarsImage without history");
             }
-            util::save_image_json(to_file, &self.metadata, None).unwrap();
+            util::save_image_json(to_file, &self.metadata, None)?;
             info!("File saved.");
             Ok(())
         } else {
@@ -138,6 +201,21 @@ impl MarsImage {
         }
     }
 
+    /// Saves the image as floating point TIFF, preserving values outside of the 16 bit range,
+    /// along with its metadata sidecar.
+    pub fn save_float(&self, to_file: &str) -> Result<()> {
+        if !path::parent_exists_and_writable(to_file) {
+            return Err(Error::msg(format!(
+                "Parent does not exist or cannot be written: {}",
+                path::get_parent(to_file)
+            )));
+        }
+        floattiff::save_image(&self.image, to_file)?;
+        util::save_image_json(to_file, &self.metadata, None)?;
+        info!("File saved.");
+        Ok(())
+    }
+
     pub fn apply_weight(&mut self, r_scalar: f32, g_scalar: f32, b_scalar: f32) {
         self.image.apply_weight_on_band(r_scalar, 0);
         self.image.apply_weight_on_band(g_scalar, 1);
@@ -181,28 +259,22 @@ impl MarsImage {
         self.image.crop(x, y, width, height);
     }
 
-    pub fn flatfield(&mut self) {
+    pub fn flatfield(&mut self) -> Result<(), CalError> {
         let mut flat = if let Ok(flat) = flatfield::load_flat(self.instrument) {
             flat
         } else {
             warn!("No flat field found for instrument {:?}", self.instrument);
-            return;
+            return Ok(());
         };
 
-        let subframe_opt = self.metadata.subframe_rect.clone();
-
-        if let Some(sf) = subframe_opt {
+        if let Some(sf) = &self.metadata.subframe_rect {
+            let [x, y, width, height] =
+                subframe_region(self.instrument, sf, 1, flat.image.width, flat.image.height)?;
             info!(
                 "Cropping flat with x/y/width/height: {},{} {}x{}",
-                sf[0], sf[1], sf[2], sf[3]
-            );
-
-            flat.image.crop(
-                sf[0] as usize - 1,
-                sf[1] as usize - 1,
-                sf[2] as usize,
-                sf[3] as usize,
+                x, y, width, height
             );
+            flat.image.crop(x, y, width, height);
         }
 
         // If the flat is still too big we'll
@@ -224,6 +296,7 @@ impl MarsImage {
         //     vprintln!("No inpaint available for flatfield image on {:?}", self.instrument);
         // }
         self.apply_flat(&flat.image);
+        Ok(())
     }
 
     pub fn apply_alpha(&mut self, mask: &ImageBuffer) {
@@ -238,17 +311,18 @@ impl MarsImage {
         self.image.get_alpha_at(x, y)
     }
 
-    pub fn apply_inpaint_fix(&mut self) {
-        let mask = inpaintmask::load_mask(self.instrument).unwrap();
-        self.apply_inpaint_fix_with_mask(&mask);
+    pub fn apply_inpaint_fix(&mut self) -> Result<()> {
+        let mask = inpaintmask::load_mask(self.instrument)?;
+        self.apply_inpaint_fix_with_mask(&mask)
     }
 
-    pub fn apply_inpaint_fix_with_mask(&mut self, mask: &ImageBuffer) {
-        let mut fixed = inpaint::apply_inpaint_to_buffer(&self.image, mask).unwrap();
+    pub fn apply_inpaint_fix_with_mask(&mut self, mask: &ImageBuffer) -> Result<()> {
+        let mut fixed = inpaint::apply_inpaint_to_buffer(&self.image, mask)?;
         fixed.set_mode(self.image.get_mode());
         self.image = fixed;
 
         self.metadata.inpaint = true;
+        Ok(())
     }
 
     pub fn hot_pixel_correction(&mut self, window_size: i32, threshold: f32) {
@@ -1,3 +1,4 @@
+use crate::error::CalError;
 use sciimg::prelude::*;
 use std::collections::HashMap;
 use std::fs;
@@ -55,7 +56,7 @@ impl<T: Clone> ImageCache<T> {
                     debug!("File inserted into cache");
                     Ok(img)
                 }
-                Err(why) => panic!("Failed to load image from {}: {:?}", file_path, why),
+                Err(why) => Err(CalError::decode_failure(file_path, why).into()),
             }
         }
     }
@@ -1,4 +1,4 @@
-use crate::serializers;
+use crate::{error::CalError, radiometry::RadiometricCalibration, serializers};
 use serde::{Deserialize, Serialize};
 
 use sciimg::prelude::*;
@@ -55,6 +55,14 @@ pub struct Metadata {
     pub mast_el: Option<f64>,
     pub sclk: Option<f64>,
 
+    /// Exposure duration in milliseconds, when known
+    #[serde(default)]
+    pub exposure_duration: Option<f64>,
+
+    /// Detector temperature in degrees Celsius, when known
+    #[serde(default)]
+    pub detector_temperature: Option<f64>,
+
     #[serde(default = "serializers::default_false")]
     pub thumbnail: bool,
 
@@ -98,6 +106,10 @@ pub struct Metadata {
     #[serde(default = "serializers::default_false")]
     pub radiometric: bool,
 
+    /// Conversion to physical units, when the image has been radiometrically calibrated
+    #[serde(default)]
+    pub radiometric_calibration: Option<RadiometricCalibration>,
+
     #[serde(default = "serializers::default_false")]
     pub inpaint: bool,
 
@@ -134,6 +146,7 @@ pub fn convert_to_std_metadata<T: ImageMetadata>(im: &T) -> Metadata {
         debayer: serializers::default_false(),
         flatfield: serializers::default_false(),
         radiometric: serializers::default_false(),
+        radiometric_calibration: None,
         inpaint: serializers::default_false(),
         cropped: serializers::default_false(),
         camera_vector: im.get_camera_vector(),
@@ -145,6 +158,8 @@ pub fn convert_to_std_metadata<T: ImageMetadata>(im: &T) -> Metadata {
         mast_el: im.get_mast_el(),
         mast_az: im.get_mast_az(),
         sclk: im.get_sclk(),
+        exposure_duration: None,
+        detector_temperature: None,
         thumbnail: im.is_thumbnail(),
         dimension: im.get_dimension(),
         xyz: im.get_xyz(),
@@ -158,15 +173,19 @@ pub fn convert_to_std_metadata<T: ImageMetadata>(im: &T) -> Metadata {
 
 pub fn load_image_metadata(json_path: &String) -> Result<Metadata> {
     let mut file = match File::open(json_path) {
-        Err(why) => panic!("couldn't open {}", why),
+        Err(_) => return Err(CalError::MissingFile(json_path.to_owned()).into()),
         Ok(file) => file,
     };
 
     let mut buf: Vec<u8> = Vec::default();
-    file.read_to_end(&mut buf).unwrap();
-    let json = String::from_utf8(buf).unwrap();
-
-    let metadata = serde_json::from_str(&json).unwrap();
+    file.read_to_end(&mut buf)?;
+    let json = match String::from_utf8(buf) {
+        Ok(s) => s,
+        Err(why) => return Err(CalError::bad_metadata(json_path, why).into()),
+    };
 
-    Ok(metadata)
+    match serde_json::from_str(&json) {
+        Ok(metadata) => Ok(metadata),
+        Err(why) => Err(CalError::bad_metadata(json_path, why).into()),
+    }
 }
@@ -1,11 +1,20 @@
 use crate::{
-    calibfile, calibrate::*, calprofile::CalProfile, enums, enums::Instrument, inpaintmask,
-    marsimage::MarsImage, util,
+    calibfile,
+    calibrate::*,
+    calpipeline::{self, CalStage, PipelineContext},
+    calplan::{self, CalPlan},
+    calprofile::CalProfile,
+    enums,
+    enums::Instrument,
+    error::CalError,
+    inpaintmask,
+    marsimage::MarsImage,
+    productid::ProductId,
 };
 
 use sciimg::path;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 // Doesn't support subframed images yet since we won't know what part of the sensor was
 // used from the raws alone. If it's in the JSON response from the raw image site, then
@@ -19,6 +28,20 @@ use anyhow::Result;
 #[derive(Copy, Clone)]
 pub struct MslEcam {}
 
+fn instrument_from_file_name(input_file: &str) -> Result<Instrument> {
+    match ProductId::from_file_name(input_file).map(|product_id| product_id.instrument()) {
+        Ok(
+            instrument @ (Instrument::MslNavCamLeft
+            | Instrument::MslNavCamRight
+            | Instrument::MslFrontHazLeft
+            | Instrument::MslFrontHazRight
+            | Instrument::MslRearHazLeft
+            | Instrument::MslRearHazRight),
+        ) => Ok(instrument),
+        _ => Err(anyhow!("Unrecognized camera option")),
+    }
+}
+
 impl Calibration for MslEcam {
     fn accepts_instrument(&self, instrument: Instrument) -> bool {
         matches!(
@@ -32,153 +55,105 @@ impl Calibration for MslEcam {
         )
     }
 
-    fn process_file(
-        &self,
-        input_file: &str,
-        cal_context: &CalProfile,
-        only_new: bool,
-    ) -> Result<CompleteContext> {
-        let out_file = util::append_file_name(input_file, cal_context.filename_suffix.as_str());
-        if path::file_exists(&out_file) && only_new {
-            vprintln!("Output file exists, skipping. ({})", out_file);
-            return cal_warn(cal_context, &out_file);
-        }
-
-        let instrument;
-        match util::filename_char_at_pos(input_file, 0) {
-            'N' => {
-                // NAVCAMS
-                match util::filename_char_at_pos(input_file, 1) == 'L' {
-                    // Left
-                    true => instrument = enums::Instrument::MslNavCamLeft,
-                    // Assume Right
-                    false => instrument = enums::Instrument::MslNavCamRight,
-                }
-            }
-            'F' => {
-                // FHAZ
-                match util::filename_char_at_pos(input_file, 1) == 'L' {
-                    // Left
-                    true => instrument = enums::Instrument::MslFrontHazLeft,
-                    // Assume Right
-                    false => instrument = enums::Instrument::MslFrontHazRight,
-                }
-            }
-            'R' => {
-                // RHAZ
-                match util::filename_char_at_pos(input_file, 1) == 'L' {
-                    // Left
-                    true => instrument = enums::Instrument::MslRearHazLeft,
-                    // Assume Right
-                    false => instrument = enums::Instrument::MslRearHazRight,
-                }
-            }
-            // TODO should never panic?
-            _ => panic!("Unrecognized camera option"),
-        }
-
-        // // Attempt to figure out camera from file name
-        // if util::filename_char_at_pos(input_file, 0) == 'N' {
-        //     // NAVCAMS
-        //     if util::filename_char_at_pos(input_file, 1) == 'L' {
-        //         // Left
-        //         instrument = enums::Instrument::MslNavCamLeft;
-        //     } else {
-        //         // Assume Right
-        //         instrument = enums::Instrument::MslNavCamRight;
-        //     }
-        // } else if util::filename_char_at_pos(input_file, 0) == 'F' {
-        //     // FHAZ
-        //     if util::filename_char_at_pos(input_file, 1) == 'L' {
-        //         // Left
-        //         instrument = enums::Instrument::MslFrontHazLeft;
-        //     } else {
-        //         // Assume Right
-        //         instrument = enums::Instrument::MslFrontHazRight;
-        //     }
-        // } else if util::filename_char_at_pos(input_file, 0) == 'R' {
-        //     // RHAZ
-        //     if util::filename_char_at_pos(input_file, 1) == 'L' {
-        //         // Left
-        //         instrument = enums::Instrument::MslRearHazLeft;
-        //     } else {
-        //         // Assume Right
-        //         instrument = enums::Instrument::MslRearHazRight;
-        //     }
-        // }
+    fn instrument_for_file(&self, input_file: &str) -> Result<Instrument> {
+        instrument_from_file_name(input_file)
+    }
 
-        let mut raw = MarsImage::open(input_file, instrument);
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage> {
+        Ok(MarsImage::open(input_file, instrument)?)
+    }
 
-        // Exclude subframed images for now...
-        if inpaintmask::inpaint_supported_for_instrument(instrument) && raw.image.height >= 1022 {
-            vprintln!("Inpainting...");
-            raw.apply_inpaint_fix();
-        } else {
-            vprintln!("Inpainting not supported for instrument {:?}", instrument);
-        }
+    fn default_pipeline(&self, _input_file: &str, profile: &CalProfile) -> Vec<CalStage> {
+        let mut stages = vec![CalStage::Inpaint, CalStage::Flat];
+        calpipeline::push_noise_reduction(&mut stages, profile);
+        stages.push(CalStage::weights());
+        stages.push(CalStage::normalize_correlated());
 
-        if cal_context.hot_pixel_detection_threshold > 0.0 {
-            vprintln!(
-                "Hot pixel correction with variance threshold {}...",
-                cal_context.hot_pixel_detection_threshold
-            );
-            raw.hot_pixel_correction(
-                cal_context.hot_pixel_window_size,
-                cal_context.hot_pixel_detection_threshold,
-            );
+        // Trim off border pixels
+        if profile.auto_subframing {
+            stages.push(CalStage::auto_crop());
         }
+        stages
+    }
 
-        let data_max = 255.0;
-
-        let flat_file_path = calibfile::get_calibration_file_for_instrument(
-            instrument,
-            enums::CalFileType::FlatField,
-        )?;
-        vprintln!("Using flat file: {}", flat_file_path);
-
-        if path::file_exists(&flat_file_path) {
-            let mut flat = MarsImage::open(&flat_file_path, instrument);
-
-            if let Some(rect) = &raw.metadata.subframe_rect {
-                flat.crop(
-                    rect[0] as usize - 1,
-                    rect[1] as usize - 1,
-                    rect[2] as usize,
-                    rect[3] as usize,
-                );
+    fn apply_stage(
+        &self,
+        stage: &CalStage,
+        raw: &mut MarsImage,
+        context: &mut PipelineContext,
+    ) -> Result<()> {
+        match stage {
+            CalStage::Inpaint => {
+                // Exclude subframed images for now...
+                if inpaintmask::inpaint_supported_for_instrument(raw.instrument)
+                    && raw.image.height >= 1022
+                {
+                    vprintln!("Inpainting...");
+                    let mask = inpaintmask::load_mask(raw.instrument)?;
+                    context.record_calibration_file_for(
+                        raw.instrument,
+                        enums::CalFileType::InpaintMask,
+                    );
+                    raw.apply_inpaint_fix_with_mask(&mask)?;
+                } else {
+                    vprintln!(
+                        "Inpainting not supported for instrument {:?}",
+                        raw.instrument
+                    );
+                }
             }
+            CalStage::Flat => {
+                let flat_file_path = calibfile::get_calibration_file_for_instrument(
+                    raw.instrument,
+                    enums::CalFileType::FlatField,
+                )?;
+                vprintln!("Using flat file: {}", flat_file_path);
+
+                if !path::file_exists(&flat_file_path) {
+                    return Err(CalError::MissingFile(flat_file_path).into());
+                }
 
-            raw.flatfield_with_flat(&flat);
-        } else {
-            eprintln!("Flat file not found: {}", flat_file_path);
-            panic!("Flat file not found!");
-        }
-
-        vprintln!("Applying color weights...");
-        raw.apply_weight(
-            cal_context.red_scalar,
-            cal_context.green_scalar,
-            cal_context.blue_scalar,
-        );
+                let mut flat = MarsImage::open(&flat_file_path, raw.instrument)?;
+                context.record_calibration_file(enums::CalFileType::FlatField, &flat_file_path);
 
-        vprintln!("Normalizing...");
-        raw.image.normalize_to_16bit_with_max(data_max);
+                if let Some(rect) = &raw.metadata.subframe_rect {
+                    flat.crop(
+                        rect[0] as usize - 1,
+                        rect[1] as usize - 1,
+                        rect[2] as usize,
+                        rect[3] as usize,
+                    );
+                }
 
-        // Trim off border pixels
-        if cal_context.auto_subframing {
-            let crop_to_width = raw.image.width - 2;
-            let crop_to_height = raw.image.height - 2;
-            raw.image.crop(1, 1, crop_to_width, crop_to_height);
+                raw.flatfield_with_flat(&flat);
+            }
+            CalStage::Crop { rect: None } => {
+                calpipeline::crop_border(raw, context, 1, 1, 1, 1);
+            }
+            _ => calpipeline::apply_stage(stage, raw, context)?,
         }
+        Ok(())
+    }
 
-        vprintln!("Writing to disk...");
-        raw.update_history();
-        match raw.save(&out_file) {
-            Ok(_) => cal_ok(cal_context, &out_file),
-            Err(why) => {
-                veprintln!("Error saving file: {}", why);
-                cal_fail(cal_context, &out_file)
+    fn plan_stage(&self, stage: &CalStage, plan: &mut CalPlan) -> Result<()> {
+        match stage {
+            CalStage::Inpaint => {
+                if plan.height.unwrap_or(0) >= 1022 {
+                    calplan::plan_stage(stage, plan)?;
+                } else {
+                    plan.add_note("Inpainting skipped for subframed images");
+                }
+            }
+            CalStage::Flat => {
+                if let Some(file_path) =
+                    calplan::plan_calibration_file(plan, enums::CalFileType::FlatField)
+                {
+                    calplan::plan_resolved_file(plan, enums::CalFileType::FlatField, &file_path);
+                }
             }
+            CalStage::Crop { rect: None } => plan.add_border_crop(1, 1, 1, 1),
+            _ => calplan::plan_stage(stage, plan)?,
         }
+        Ok(())
     }
 }
@@ -1,6 +1,13 @@
 use crate::{
-    calibrate::*, calprofile::CalProfile, decompanding, enums, enums::Instrument, flatfield,
-    inpaintmask, marsimage::MarsImage, util,
+    calibrate::*,
+    calpipeline::{self, CalStage, PipelineContext},
+    calplan::{self, CalPlan},
+    calprofile::CalProfile,
+    decompanding, enums,
+    enums::{Eye, Instrument},
+    flatfield, inpaintmask,
+    marsimage::MarsImage,
+    productid::ProductId,
 };
 
 use sciimg::prelude::*;
@@ -10,196 +17,345 @@ use anyhow::Result;
 #[derive(Copy, Clone)]
 pub struct MslMastcam {}
 
-impl Calibration for MslMastcam {
-    fn accepts_instrument(&self, instrument: Instrument) -> bool {
-        matches!(
-            instrument,
-            Instrument::MslMastcamLeft | Instrument::MslMastcamRight
-        )
+/// Crops the image, keeping the metadata subframe in step so that it continues to give the
+/// image's position on the sensor
+fn crop_with_subframe(
+    raw: &mut MarsImage,
+    context: &mut PipelineContext,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+) {
+    raw.image.crop(x, y, width, height);
+    context.record_crop(x, y, width, height);
+    if let Some(rect) = &raw.metadata.subframe_rect {
+        raw.metadata.subframe_rect = Some(vec![
+            rect[0] + x as f64,
+            rect[1] + y as f64,
+            width as f64,
+            height as f64,
+        ]);
     }
+}
 
-    fn process_file(
-        &self,
-        input_file: &str,
-        cal_context: &CalProfile,
-        only_new: bool,
-    ) -> Result<CompleteContext> {
-        let out_file = util::append_file_name(input_file, cal_context.filename_suffix.as_str());
-        if path::file_exists(&out_file) && only_new {
-            vprintln!("Output file exists, skipping. ({})", out_file);
-            return cal_warn(cal_context, &out_file);
-        }
+/// Trims the unused sensor margins from full width frames. Must be done after debayering.
+/// 1600x1200 left eye frames are kept whole and matched to the flat at their known sensor
+/// position instead.
+fn trim_sensor_margins(raw: &mut MarsImage, context: &mut PipelineContext) {
+    if raw.image.width == 1536 {
+        let height = raw.image.height;
+        crop_with_subframe(raw, context, 161, 0, 1328, height);
+    }
+}
 
-        let mut instrument = enums::Instrument::MslMastcamLeft;
+/// Records the sensor margin trim `trim_sensor_margins` would apply
+fn plan_sensor_margins(plan: &mut CalPlan) {
+    let crop = match (plan.width, plan.height) {
+        (Some(1536), Some(height)) => [161, 0, 1328, height],
+        _ => return,
+    };
+    plan.add_crop(crop[0], crop[1], crop[2], crop[3]);
+    if let Some(rect) = &plan.subframe_rect {
+        plan.subframe_rect = Some(vec![
+            rect[0] + crop[0] as f64,
+            rect[1] + crop[1] as f64,
+            crop[2] as f64,
+            crop[3] as f64,
+        ]);
+    }
+}
 
-        if util::filename_char_at_pos(input_file, 5) == 'R' {
-            instrument = enums::Instrument::MslMastcamRight;
-            vprintln!("Processing for Mastcam Right");
-        } else {
-            vprintln!("Processing for Mastcam Left");
-        }
+/// Known sensor locations of common Mastcam subframes, as x, y, width, height
+fn known_subframe(instrument: Instrument, width: usize, height: usize) -> Option<[usize; 4]> {
+    match (instrument, width, height) {
+        (Instrument::MslMastcamRight, 1328, 1184) => Some([160, 16, 1328, 1184]),
+        (Instrument::MslMastcamRight, 848, 848) => Some([400, 192, 848, 848]),
+        (Instrument::MslMastcamRight, 1344, 1200) => Some([160, 0, 1344, 1200]),
+        (Instrument::MslMastcamLeft, 1328, 1184) => Some([160, 16, 1328, 1184]),
+        (Instrument::MslMastcamLeft, 1152, 432) => Some([305, 385, 1152, 432]),
+        (Instrument::MslMastcamLeft, 1600, 1200) => Some([33, 0, 1600, 1200]),
+        (Instrument::MslMastcamLeft, 1456, 640) => Some([96, 280, 1456, 640]),
+        _ => None,
+    }
+}
 
-        let mut raw = MarsImage::open_dct_coefficient_fix(input_file, instrument);
+/// How the region of a calibration image matching a raw image was determined
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SubframeSource {
+    /// The image's `subframe_rect` metadata
+    Metadata,
 
-        let lut = decompanding::get_ilt_for_instrument(instrument).unwrap();
-        let data_max = if cal_context.apply_ilt {
-            vprintln!("Decompanding...");
-            raw.decompand(&lut);
-            lut.max() as f32
-        } else {
-            255.0
-        };
-
-        if
-        /*util::filename_char_at_pos(&input_file, 22) == 'E' &&*/
-        raw.image.is_grayscale() {
-            vprintln!("Image appears to be grayscale, applying debayering...");
-            raw.debayer_with_method(cal_context.debayer_method);
-        }
+    /// The table of common Mastcam subframe sizes
+    KnownSize,
 
-        let mut inpaint_mask = inpaintmask::load_mask(instrument).unwrap();
-        let mut flat = flatfield::load_flat(instrument).unwrap();
+    /// Centered in the calibration image
+    Centered,
+}
 
-        if raw.image.width == 1536 {
-            raw.image.crop(161, 0, 1328, raw.image.height);
+impl SubframeSource {
+    fn describe(&self) -> &'static str {
+        match self {
+            SubframeSource::Metadata => "from metadata subframe",
+            SubframeSource::KnownSize => "from known subframe size",
+            SubframeSource::Centered => "centered",
         }
+    }
+}
 
-        if raw.image.height == 1600 && raw.image.height == 1200 {
-            raw.image.crop(125, 13, 1328, 1184);
-        }
+/// The image's position in a calibration image, from the metadata subframe, when it is the
+/// size of the image and lies within the calibration image. The subframe is one-based.
+fn metadata_subframe(
+    subframe_rect: Option<&Vec<f64>>,
+    cal_width: usize,
+    cal_height: usize,
+    width: usize,
+    height: usize,
+) -> Option<[usize; 4]> {
+    let rect = subframe_rect.filter(|r| r.len() == 4 && r[0] >= 1.0 && r[1] >= 1.0)?;
+    let sf = [
+        rect[0] as usize - 1,
+        rect[1] as usize - 1,
+        rect[2] as usize,
+        rect[3] as usize,
+    ];
 
-        vprintln!("Flatfielding...");
-
-        if instrument == enums::Instrument::MslMastcamRight {
-            if raw.image.width == 1328 && raw.image.height == 1184 {
-                //x160, y16
-                flat.image.crop(160, 16, 1328, 1184);
-                inpaint_mask = inpaint_mask.get_subframe(160, 16, 1328, 1184).unwrap();
-            } else if raw.image.width == 848 && raw.image.height == 848 {
-                //x400, y192
-                flat.image.crop(400, 192, 848, 848);
-                inpaint_mask = inpaint_mask.get_subframe(400, 192, 848, 848).unwrap();
-            } else if raw.image.width == 1344 && raw.image.height == 1200 {
-                //x400, y192
-                flat.image.crop(160, 0, 1344, 1200);
-                inpaint_mask = inpaint_mask.get_subframe(160, 0, 1344, 1200).unwrap();
-            }
+    if sf[2] != width || sf[3] != height {
+        vprintln!(
+            "Metadata subframe {}x{} does not match the {}x{} image",
+            sf[2],
+            sf[3],
+            width,
+            height
+        );
+        None
+    } else if sf[0] + sf[2] > cal_width || sf[1] + sf[3] > cal_height {
+        vprintln!(
+            "Metadata subframe {},{} {}x{} is outside of the {}x{} calibration image",
+            sf[0],
+            sf[1],
+            sf[2],
+            sf[3],
+            cal_width,
+            cal_height
+        );
+        None
+    } else {
+        Some(sf)
+    }
+}
 
-            if raw.image.get_mode() == ImageMode::U8BIT {
-                flat.image
-                    .normalize_to_12bit_with_max(lut.max() as f32, 255.0);
-                flat.compand(&lut);
-            }
-        }
+/// Determines the region of a full-sensor calibration image matching the raw image, as x,
+/// y, width, height. The metadata subframe is used when it agrees with the image, falling
+/// back to the known locations of common subframe sizes and then to a centered crop. None
+/// when the calibration image needs no crop.
+pub fn calibration_subframe(
+    instrument: Instrument,
+    subframe_rect: Option<&Vec<f64>>,
+    cal_width: usize,
+    cal_height: usize,
+    width: usize,
+    height: usize,
+) -> Option<([usize; 4], SubframeSource)> {
+    if let Some(sf) = metadata_subframe(subframe_rect, cal_width, cal_height, width, height) {
+        return Some((sf, SubframeSource::Metadata));
+    }
 
-        if instrument == enums::Instrument::MslMastcamLeft {
-            if raw.image.width == 1328 && raw.image.height == 1184 {
-                //9
-                flat.image.crop(160, 16, 1328, 1184);
-                inpaint_mask = inpaint_mask.get_subframe(160, 16, 1328, 1184).unwrap();
-            } else if raw.image.width == 1152 && raw.image.height == 432 {
-                flat.image.crop(305, 385, 1152, 432);
-                inpaint_mask = inpaint_mask.get_subframe(305, 385, 1152, 432).unwrap();
-            } else if raw.image.width == 1600 && raw.image.height == 1200 {
-                flat.image.crop(33, 0, 1600, 1200);
-                inpaint_mask = inpaint_mask.get_subframe(33, 0, 1600, 1200).unwrap();
-            } else if raw.image.width == 1456 && raw.image.height == 640 {
-                flat.image.crop(96, 280, 1456, 640);
-                inpaint_mask = inpaint_mask.get_subframe(96, 280, 1456, 640).unwrap();
-            }
+    if let Some(sf) = known_subframe(instrument, width, height) {
+        return Some((sf, SubframeSource::KnownSize));
+    }
 
-            if raw.image.get_mode() == ImageMode::U8BIT {
-                flat.image
-                    .normalize_to_12bit_with_max(lut.max() as f32, 255.0);
-                flat.compand(&lut);
-            }
+    // Catch some subframing edge cases
+    if cal_width > width {
+        let x = (cal_width - width) / 2;
+        let y = (cal_height - height) / 2;
+        Some(([x, y, width, height], SubframeSource::Centered))
+    } else {
+        None
+    }
+}
+
+/// The calibration image region for the raw image, logging where it came from
+fn raw_calibration_subframe(
+    raw: &MarsImage,
+    cal_width: usize,
+    cal_height: usize,
+) -> Option<[usize; 4]> {
+    let (sf, source) = calibration_subframe(
+        raw.instrument,
+        raw.metadata.subframe_rect.as_ref(),
+        cal_width,
+        cal_height,
+        raw.image.width,
+        raw.image.height,
+    )?;
+    vprintln!(
+        "Cropping flat/inpaint mask with x/y/width/height: {},{} {}x{} ({})",
+        sf[0],
+        sf[1],
+        sf[2],
+        sf[3],
+        source.describe()
+    );
+    Some(sf)
+}
+
+fn load_subframed_mask(raw: &MarsImage) -> Result<ImageBuffer> {
+    let inpaint_mask = inpaintmask::load_mask(raw.instrument)?;
+    match raw_calibration_subframe(raw, inpaint_mask.width, inpaint_mask.height) {
+        Some(sf) => inpaint_mask.get_subframe(sf[0], sf[1], sf[2], sf[3]),
+        None => Ok(inpaint_mask),
+    }
+}
+
+/// Notes where the calibration images would be cropped for the planned image
+fn plan_calibration_subframe(plan: &mut CalPlan, file_type: enums::CalFileType) {
+    let file_path = match calplan::plan_calibration_file(plan, file_type) {
+        Some(file_path) => file_path,
+        None => return,
+    };
+
+    let (width, height) = match (plan.width, plan.height) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return,
+    };
+
+    if let Ok((cal_width, cal_height)) = image::image_dimensions(&file_path) {
+        if let Some((sf, source)) = calibration_subframe(
+            plan.instrument_id,
+            plan.subframe_rect.as_ref(),
+            cal_width as usize,
+            cal_height as usize,
+            width,
+            height,
+        ) {
+            plan.add_note(&format!(
+                "{} cropped to {},{} {}x{} ({})",
+                match file_type {
+                    enums::CalFileType::FlatField => "Flat field",
+                    _ => "Inpaint mask",
+                },
+                sf[0],
+                sf[1],
+                sf[2],
+                sf[3],
+                source.describe()
+            ));
         }
+    }
+}
 
-        vprintln!(
-            "Raw: {}/{}, Flat: {}/{}",
-            raw.image.width,
-            raw.image.height,
-            flat.image.width,
-            flat.image.height
-        );
+impl Calibration for MslMastcam {
+    fn accepts_instrument(&self, instrument: Instrument) -> bool {
+        matches!(
+            instrument,
+            Instrument::MslMastcamLeft | Instrument::MslMastcamRight
+        )
+    }
 
-        // Catch some subframing edge cases
-        if flat.image.width > raw.image.width {
-            let x = (flat.image.width - raw.image.width) / 2;
-            let y = (flat.image.height - raw.image.height) / 2;
-            vprintln!(
-                "Cropping flat/inpaint mask with x/y/width/height: {},{} {}x{}",
-                x,
-                y,
-                raw.image.width,
-                raw.image.height
-            );
-            flat.image.crop(x, y, raw.image.width, raw.image.height);
-            inpaint_mask = inpaint_mask
-                .get_subframe(x, y, raw.image.width, raw.image.height)
-                .unwrap();
+    fn instrument_for_file(&self, input_file: &str) -> Result<Instrument> {
+        let eye = ProductId::from_file_name(input_file).map(|product_id| product_id.eye());
+        if matches!(eye, Ok(Eye::Right)) {
+            vprintln!("Processing for Mastcam Right");
+            Ok(enums::Instrument::MslMastcamRight)
+        } else {
+            vprintln!("Processing for Mastcam Left");
+            Ok(enums::Instrument::MslMastcamLeft)
         }
+    }
 
-        flat.apply_inpaint_fix_with_mask(&inpaint_mask);
+    fn open_raw(&self, input_file: &str, instrument: Instrument) -> Result<MarsImage> {
+        Ok(MarsImage::open_dct_coefficient_fix(input_file, instrument)?)
+    }
 
-        vprintln!(
-            "Raw: {}/{}, Flat: {}/{}",
-            raw.image.width,
-            raw.image.height,
-            flat.image.width,
-            flat.image.height
-        );
+    fn default_pipeline(&self, _input_file: &str, profile: &CalProfile) -> Vec<CalStage> {
+        let mut stages = vec![];
+        calpipeline::push_decompand(&mut stages, profile);
+        stages.push(CalStage::debayer());
+        stages.push(CalStage::Flat);
+        stages.push(CalStage::Inpaint);
+        calpipeline::push_noise_reduction(&mut stages, profile);
+        stages.push(CalStage::weights());
+        if profile.auto_subframing {
+            stages.push(CalStage::auto_crop());
+        }
+        calpipeline::push_color_output(&mut stages, profile);
+        stages
+    }
 
-        raw.flatfield_with_flat(&flat);
+    fn apply_stage(
+        &self,
+        stage: &CalStage,
+        raw: &mut MarsImage,
+        context: &mut PipelineContext,
+    ) -> Result<()> {
+        match stage {
+            CalStage::Flat => {
+                trim_sensor_margins(raw, context);
 
-        // Only inpaint with the same size as the mask until we can reliably determine
-        // subframing sensor location.
-        //if raw.image.width == 1328 && raw.image.height == 1184 {
-        vprintln!("Inpainting...");
-        raw.apply_inpaint_fix_with_mask(&inpaint_mask);
-        //}
+                vprintln!("Flatfielding...");
+                let inpaint_mask = load_subframed_mask(raw)?;
+                let mut flat = flatfield::load_flat(raw.instrument)?;
+                context.record_calibration_file_for(raw.instrument, enums::CalFileType::FlatField);
+                context
+                    .record_calibration_file_for(raw.instrument, enums::CalFileType::InpaintMask);
 
-        vprintln!("Applying color weights...");
-        raw.apply_weight(
-            cal_context.red_scalar,
-            cal_context.green_scalar,
-            cal_context.blue_scalar,
-        );
+                if let Some(sf) = raw_calibration_subframe(raw, flat.image.width, flat.image.height)
+                {
+                    flat.image.crop(sf[0], sf[1], sf[2], sf[3]);
+                }
 
-        if cal_context.color_noise_reduction && cal_context.color_noise_reduction_amount > 0 {
-            vprintln!("Color noise reduction...");
-            raw.image
-                .reduce_color_noise(cal_context.color_noise_reduction_amount);
-        }
+                if raw.image.get_mode() == ImageMode::U8BIT {
+                    let lut = decompanding::get_ilt_for_instrument(raw.instrument)?;
+                    context.record_calibration_file_for(raw.instrument, enums::CalFileType::Lut);
+                    flat.image
+                        .normalize_to_12bit_with_max(lut.max() as f32, 255.0);
+                    flat.compand(&lut);
+                }
 
-        vprintln!("Cropping...");
-        if cal_context.auto_subframing {
-            raw.image
-                .crop(3, 3, raw.image.width - 6, raw.image.height - 6);
-        }
+                vprintln!(
+                    "Raw: {}/{}, Flat: {}/{}",
+                    raw.image.width,
+                    raw.image.height,
+                    flat.image.width,
+                    flat.image.height
+                );
 
-        if cal_context.srgb_color_correction {
-            vprintln!("Applying sRGB color conversion");
-            raw.image
-                .convert_colorspace(color::ColorSpaceType::RGB, color::ColorSpaceType::sRGB)?;
-        }
+                flat.apply_inpaint_fix_with_mask(&inpaint_mask)?;
+                raw.flatfield_with_flat(&flat);
+            }
+            CalStage::Inpaint => {
+                trim_sensor_margins(raw, context);
 
-        if cal_context.decorrelate_color {
-            vprintln!("Normalizing with decorrelated colors...");
-            raw.image.normalize_to_16bit_decorrelated();
-        } else {
-            vprintln!("Normalizing with correlated colors...");
-            raw.image.normalize_to_16bit_with_max(data_max);
+                vprintln!("Inpainting...");
+                let inpaint_mask = load_subframed_mask(raw)?;
+                context
+                    .record_calibration_file_for(raw.instrument, enums::CalFileType::InpaintMask);
+                raw.apply_inpaint_fix_with_mask(&inpaint_mask)?;
+            }
+            CalStage::Crop { rect: None } => {
+                vprintln!("Cropping...");
+                calpipeline::crop_border(raw, context, 3, 3, 3, 3);
+            }
+            _ => calpipeline::apply_stage(stage, raw, context)?,
         }
+        Ok(())
+    }
 
-        vprintln!("Writing to disk...");
-        raw.update_history();
-        match raw.save(&out_file) {
-            Ok(_) => cal_ok(cal_context, &out_file),
-            Err(why) => {
-                veprintln!("Error saving file: {}", why);
-                cal_fail(cal_context, &out_file)
+    fn plan_stage(&self, stage: &CalStage, plan: &mut CalPlan) -> Result<()> {
+        match stage {
+            CalStage::Flat => {
+                plan_sensor_margins(plan);
+                plan_calibration_subframe(plan, enums::CalFileType::FlatField);
+                plan_calibration_subframe(plan, enums::CalFileType::InpaintMask);
+            }
+            CalStage::Inpaint => {
+                plan_sensor_margins(plan);
+                plan_calibration_subframe(plan, enums::CalFileType::InpaintMask);
             }
+            CalStage::Crop { rect: None } => plan.add_border_crop(3, 3, 3, 3),
+            _ => calplan::plan_stage(stage, plan)?,
         }
+        Ok(())
     }
 }
@@ -1,9 +1,13 @@
 pub use crate::anaglyph;
 pub use crate::calibrate::*;
+pub use crate::calpipeline::CalStage;
+pub use crate::calplan::CalPlan;
 pub use crate::calprofile::CalProfile;
 pub use crate::constants;
 pub use crate::decorr;
 pub use crate::enums::*;
+pub use crate::error::CalError;
+pub use crate::identify::{self, InstrumentSource};
 pub use crate::m20;
 pub use crate::m20::fetch::M20Fetch;
 pub use crate::marsimage::MarsImage;
@@ -95,6 +99,18 @@ pub fn calibrator_for_instrument_from_str(instrument: &str) -> Option<&'static C
     calibrator_for_instrument(Instrument::from_str(instrument).unwrap())
 }
 
+/// The calibrator for an input file's instrument, along with the instrument and how it was
+/// identified. `forced` is used when the file has no metadata sidecar. Pass the instrument
+/// on to the calibrator, which can't always tell it from the file name alone.
+pub fn calibrator_for_file(
+    input_file: &str,
+    forced: &Option<String>,
+) -> Option<(&'static CalContainer, Instrument, InstrumentSource)> {
+    let (instrument, source) = identify::identify_instrument(input_file, forced)?;
+    vprintln!("Identified instrument {:?} from {}", instrument, source);
+    calibrator_for_instrument(instrument).map(|calibrator| (calibrator, instrument, source))
+}
+
 use backtrace::Backtrace;
 use std::panic;
 
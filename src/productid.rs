@@ -0,0 +1,740 @@
+use crate::enums::{Eye, Instrument, Mission};
+
+use anyhow::{anyhow, Result};
+use sciimg::path;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+fn field(id: &str, start: usize, end: usize) -> Option<&str> {
+    id.get(start..end)
+}
+
+fn digits(id: &str, start: usize, end: usize) -> Option<&str> {
+    field(id, start, end).filter(|s| s.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn letters(id: &str, start: usize, end: usize) -> Option<&str> {
+    field(id, start, end).filter(|s| s.bytes().all(|b| b.is_ascii_uppercase()))
+}
+
+fn char_at(id: &str, index: usize) -> Option<char> {
+    id.as_bytes().get(index).map(|b| *b as char)
+}
+
+fn eye_from_char(c: char) -> Eye {
+    match c {
+        'L' => Eye::Left,
+        'R' => Eye::Right,
+        _ => Eye::DontCare,
+    }
+}
+
+/// Mars 2020 image product id, such as
+/// `ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct M20ProductId {
+    /// Camera and eye, such as `ZL` for the left Mastcam-Z or `NR` for the right Navcam
+    pub camera: String,
+
+    /// Filter position, or the camera configuration for cameras without a filter wheel
+    pub filter: char,
+
+    /// Special processing flag, `_` for none
+    pub special_flag: char,
+
+    pub sol: u32,
+
+    /// Venue, `_` for flight
+    pub venue: char,
+
+    /// Spacecraft clock seconds
+    pub sclk: u64,
+
+    /// Spacecraft clock milliseconds
+    pub sclk_millis: u32,
+
+    /// Product type, such as `ECM`, `EDR` or `EBY`
+    pub product_type: String,
+
+    /// Geometry, `_` for raw and `L` for linearized
+    pub geometry: char,
+
+    /// `T` for thumbnails, `N` otherwise
+    pub thumbnail: char,
+
+    pub site: String,
+    pub drive: String,
+    pub sequence_id: String,
+
+    /// Camera specific field. For Mastcam-Z, the focal length in millimeters.
+    pub camera_specific: String,
+
+    pub compression: String,
+
+    /// Producer, such as `J` for JPL
+    pub producer: char,
+
+    /// Product version. Not included in the image ids of the raw image metadata.
+    pub version: Option<String>,
+}
+
+impl M20ProductId {
+    fn parse(id: &str) -> Option<Self> {
+        if char_at(id, 19)? != '_' || char_at(id, 44)? != '_' {
+            return None;
+        }
+
+        Some(M20ProductId {
+            camera: field(id, 0, 2)?.to_owned(),
+            filter: char_at(id, 2)?,
+            special_flag: char_at(id, 3)?,
+            sol: digits(id, 4, 8)?.parse().ok()?,
+            venue: char_at(id, 8)?,
+            sclk: digits(id, 9, 19)?.parse().ok()?,
+            sclk_millis: digits(id, 20, 23)?.parse().ok()?,
+            product_type: letters(id, 23, 26)?.to_owned(),
+            geometry: char_at(id, 26)?,
+            thumbnail: char_at(id, 27)?,
+            site: field(id, 28, 31)?.to_owned(),
+            drive: field(id, 31, 35)?.to_owned(),
+            sequence_id: field(id, 35, 44)?.to_owned(),
+            camera_specific: field(id, 45, 48)?.to_owned(),
+            compression: field(id, 48, 51)?.to_owned(),
+            producer: char_at(id, 51)?,
+            version: digits(id, 52, 54).map(|v| v.to_owned()),
+        })
+    }
+
+    /// The Mastcam-Z focal length, in millimeters
+    pub fn focal_length(&self) -> Option<f32> {
+        match self.camera_specific.bytes().all(|b| b.is_ascii_digit()) {
+            true => self.camera_specific.parse().ok(),
+            false => None,
+        }
+    }
+
+    fn instrument(&self) -> Instrument {
+        match self.camera.as_str() {
+            "ZL" => Instrument::M20MastcamZLeft,
+            "ZR" => Instrument::M20MastcamZRight,
+            "NL" => Instrument::M20NavcamLeft,
+            "NR" => Instrument::M20NavcamRight,
+            "FL" => Instrument::M20FrontHazLeft,
+            "FR" => Instrument::M20FrontHazRight,
+            "RL" => Instrument::M20RearHazLeft,
+            "RR" => Instrument::M20RearHazRight,
+            "SI" => Instrument::M20Watson,
+            "SC" => Instrument::M20SherlocAci,
+            "LR" => Instrument::M20SuperCam,
+            "CC" => Instrument::M20CacheCam,
+            "HN" => Instrument::M20HeliNav,
+            "HS" => Instrument::M20HeliRte,
+            _ => Instrument::None,
+        }
+    }
+}
+
+impl fmt::Display for M20ProductId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}{:04}{}{:010}_{:03}{}{}{}{}{}{}_{}{}{}{}",
+            self.camera,
+            self.filter,
+            self.special_flag,
+            self.sol,
+            self.venue,
+            self.sclk,
+            self.sclk_millis,
+            self.product_type,
+            self.geometry,
+            self.thumbnail,
+            self.site,
+            self.drive,
+            self.sequence_id,
+            self.camera_specific,
+            self.compression,
+            self.producer,
+            self.version.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+/// MSL engineering camera and ChemCam RMI image product id, such as
+/// `NLB_747123456EDR_F0950000NCAM00554M_`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MslEcamProductId {
+    /// Camera and eye, such as `NL` for the left Navcam or `FR` for the right front Hazcam
+    pub camera: String,
+
+    /// Rover compute element the image was taken through, `A` or `B`
+    pub compute_element: char,
+
+    /// Special processing flag, `_` for none
+    pub special_flag: char,
+
+    /// Spacecraft clock seconds
+    pub sclk: u64,
+
+    /// Product type, such as `EDR` or `RAD`
+    pub product_type: String,
+
+    /// Geometry, `_` for raw and `L` for linearized
+    pub geometry: char,
+
+    /// `F` for full frames, `T` for thumbnails, `S` for subframes, `D` for downsampled
+    pub frame_type: char,
+
+    pub site: String,
+    pub drive: String,
+    pub sequence_id: String,
+
+    /// Producer, such as `M` for MIPL
+    pub producer: char,
+
+    /// Product version, `_` when unversioned
+    pub version: char,
+}
+
+impl MslEcamProductId {
+    fn parse(id: &str) -> Option<Self> {
+        Some(MslEcamProductId {
+            camera: letters(id, 0, 2)?.to_owned(),
+            compute_element: char_at(id, 2)?,
+            special_flag: char_at(id, 3)?,
+            sclk: digits(id, 4, 13)?.parse().ok()?,
+            product_type: letters(id, 13, 16)?.to_owned(),
+            geometry: char_at(id, 16)?,
+            frame_type: char_at(id, 17)?,
+            site: field(id, 18, 21)?.to_owned(),
+            drive: field(id, 21, 25)?.to_owned(),
+            sequence_id: field(id, 25, 34)?.to_owned(),
+            producer: char_at(id, 34)?,
+            version: char_at(id, 35)?,
+        })
+    }
+
+    fn instrument(&self) -> Instrument {
+        match self.camera.as_str() {
+            "NL" => Instrument::MslNavCamLeft,
+            "NR" => Instrument::MslNavCamRight,
+            "FL" => Instrument::MslFrontHazLeft,
+            "FR" => Instrument::MslFrontHazRight,
+            "RL" => Instrument::MslRearHazLeft,
+            "RR" => Instrument::MslRearHazRight,
+            "CR" => Instrument::MslChemCam,
+            _ => Instrument::None,
+        }
+    }
+}
+
+impl fmt::Display for MslEcamProductId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}{:09}{}{}{}{}{}{}{}{}",
+            self.camera,
+            self.compute_element,
+            self.special_flag,
+            self.sclk,
+            self.product_type,
+            self.geometry,
+            self.frame_type,
+            self.site,
+            self.drive,
+            self.sequence_id,
+            self.producer,
+            self.version
+        )
+    }
+}
+
+/// MSL Mastcam, MAHLI and MARDI image product id, such as `3372MR0176880011204530C00`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MslMmmProductId {
+    pub sol: u32,
+
+    /// `ML` and `MR` for the Mastcam eyes, `MH` for MAHLI, `MD` for MARDI
+    pub camera: String,
+
+    pub sequence_number: String,
+
+    /// The image's command within its sequence
+    pub command_number: String,
+
+    /// Product counter between the command number and the product type
+    pub product_code: String,
+
+    /// Product type, such as `E` for EDR or `C` for compressed products
+    pub product_type: char,
+
+    /// True for thumbnails, which have product type `I`
+    pub thumbnail: bool,
+
+    pub version: String,
+}
+
+impl MslMmmProductId {
+    fn parse(id: &str) -> Option<Self> {
+        let camera = field(id, 4, 6)?;
+        if !matches!(camera, "ML" | "MR" | "MH" | "MD") {
+            return None;
+        }
+
+        let rest = id.get(15..)?;
+        let code_len = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+        let product_type = char_at(rest, code_len).filter(|c| c.is_ascii_uppercase())?;
+        let version_len = rest[code_len + 1..]
+            .bytes()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+
+        Some(MslMmmProductId {
+            sol: digits(id, 0, 4)?.parse().ok()?,
+            camera: camera.to_owned(),
+            sequence_number: digits(id, 6, 12)?.to_owned(),
+            command_number: digits(id, 12, 15)?.to_owned(),
+            product_code: rest[..code_len].to_owned(),
+            product_type,
+            thumbnail: product_type == 'I',
+            version: rest[code_len + 1..code_len + 1 + version_len].to_owned(),
+        })
+    }
+
+    fn instrument(&self) -> Instrument {
+        match self.camera.as_str() {
+            "ML" => Instrument::MslMastcamLeft,
+            "MR" => Instrument::MslMastcamRight,
+            "MH" => Instrument::MslMAHLI,
+            "MD" => Instrument::MslMARDI,
+            _ => Instrument::None,
+        }
+    }
+
+    /// Both Mastcam eyes share the MCAM sequence. Sequences are numbered with five digits.
+    fn sequence_id(&self) -> String {
+        match self.camera.as_str() {
+            "ML" | "MR" => format!("MCAM{}", &self.sequence_number[1..]),
+            camera => format!("{}{}", camera, &self.sequence_number[1..]),
+        }
+    }
+}
+
+impl fmt::Display for MslMmmProductId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}{}{}{}{}{}{}",
+            self.sol,
+            self.camera,
+            self.sequence_number,
+            self.command_number,
+            self.product_code,
+            self.product_type,
+            self.version
+        )
+    }
+}
+
+/// InSight IDC and ICC image product id, such as `D001R0018_598491924EDR_F0000_0122M_`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NsytProductId {
+    /// `D` for the deployment camera (IDC), `C` for the context camera (ICC)
+    pub camera: char,
+
+    /// Camera configuration and venue flags
+    pub config: String,
+
+    pub sol: u32,
+
+    /// Spacecraft clock seconds
+    pub sclk: u64,
+
+    /// Product type, such as `EDR`
+    pub product_type: String,
+
+    /// Geometry, `_` for raw and `L` for linearized
+    pub geometry: char,
+
+    /// `F` for full frames, `T` for thumbnails
+    pub frame_type: char,
+
+    /// Instrument arm pointing counter
+    pub pointing: String,
+
+    pub sequence_id: String,
+
+    /// Producer, such as `M` for MIPL
+    pub producer: char,
+
+    /// Product version, `_` when unversioned
+    pub version: char,
+}
+
+impl NsytProductId {
+    fn parse(id: &str) -> Option<Self> {
+        let camera = char_at(id, 0).filter(|c| matches!(c, 'C' | 'D'))?;
+        if char_at(id, 9)? != '_' || char_at(id, 28)? != '_' {
+            return None;
+        }
+
+        Some(NsytProductId {
+            camera,
+            config: field(id, 1, 5)?.to_owned(),
+            sol: digits(id, 5, 9)?.parse().ok()?,
+            sclk: digits(id, 10, 19)?.parse().ok()?,
+            product_type: letters(id, 19, 22)?.to_owned(),
+            geometry: char_at(id, 22)?,
+            frame_type: char_at(id, 23)?,
+            pointing: field(id, 24, 28)?.to_owned(),
+            sequence_id: field(id, 29, 33)?.to_owned(),
+            producer: char_at(id, 33)?,
+            version: char_at(id, 34)?,
+        })
+    }
+}
+
+impl fmt::Display for NsytProductId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{:04}_{:09}{}{}{}{}_{}{}{}",
+            self.camera,
+            self.config,
+            self.sol,
+            self.sclk,
+            self.product_type,
+            self.geometry,
+            self.frame_type,
+            self.pointing,
+            self.sequence_id,
+            self.producer,
+            self.version
+        )
+    }
+}
+
+/// Mars Exploration Rover image product id, such as `1P128287205ESF0200P2222L2M1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerProductId {
+    /// `1` for Opportunity (MER-B), `2` for Spirit (MER-A)
+    pub spacecraft: char,
+
+    /// `P` Pancam, `N` Navcam, `F` front Hazcam, `R` rear Hazcam, `M` Microscopic Imager,
+    /// `E` descent camera
+    pub camera: char,
+
+    /// Spacecraft clock seconds
+    pub sclk: u64,
+
+    /// Product type, such as `EFF` or `ESF`
+    pub product_type: String,
+
+    pub site: String,
+    pub drive: String,
+    pub sequence_id: String,
+    pub eye: char,
+    pub filter: char,
+
+    /// Producer, such as `M` for MIPL
+    pub producer: char,
+
+    pub version: char,
+}
+
+impl MerProductId {
+    fn parse(id: &str) -> Option<Self> {
+        let spacecraft = char_at(id, 0).filter(|c| matches!(c, '1' | '2'))?;
+        let camera = char_at(id, 1).filter(|c| matches!(c, 'P' | 'N' | 'F' | 'R' | 'M' | 'E'))?;
+
+        Some(MerProductId {
+            spacecraft,
+            camera,
+            sclk: digits(id, 2, 11)?.parse().ok()?,
+            product_type: letters(id, 11, 14)?.to_owned(),
+            site: field(id, 14, 16)?.to_owned(),
+            drive: field(id, 16, 18)?.to_owned(),
+            sequence_id: field(id, 18, 23)?.to_owned(),
+            eye: char_at(id, 23)?,
+            filter: char_at(id, 24)?,
+            producer: char_at(id, 25)?,
+            version: char_at(id, 26)?,
+        })
+    }
+}
+
+impl fmt::Display for MerProductId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{:09}{}{}{}{}{}{}{}{}",
+            self.spacecraft,
+            self.camera,
+            self.sclk,
+            self.product_type,
+            self.site,
+            self.drive,
+            self.sequence_id,
+            self.eye,
+            self.filter,
+            self.producer,
+            self.version
+        )
+    }
+}
+
+/// How products of the same observation compare: full frames rank above thumbnails, then
+/// higher versions above lower
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProductRank {
+    pub full_frame: bool,
+    pub version: u32,
+}
+
+/// An image product id, parsed into its fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProductId {
+    M20(M20ProductId),
+    MslEcam(MslEcamProductId),
+    MslMmm(MslMmmProductId),
+    Nsyt(NsytProductId),
+    Mer(MerProductId),
+}
+
+impl ProductId {
+    /// Parses a product id. Anything following the id, such as a suffix added when the
+    /// image was processed, is ignored.
+    pub fn parse(id: &str) -> Result<Self> {
+        if !id.is_ascii() {
+            return Err(anyhow!("Unrecognized product id: {}", id));
+        }
+
+        M20ProductId::parse(id)
+            .map(ProductId::M20)
+            .or_else(|| MslEcamProductId::parse(id).map(ProductId::MslEcam))
+            .or_else(|| NsytProductId::parse(id).map(ProductId::Nsyt))
+            .or_else(|| MerProductId::parse(id).map(ProductId::Mer))
+            .or_else(|| MslMmmProductId::parse(id).map(ProductId::MslMmm))
+            .ok_or_else(|| anyhow!("Unrecognized product id: {}", id))
+    }
+
+    /// Parses the product id a file is named by
+    pub fn from_file_name(file_name: &str) -> Result<Self> {
+        ProductId::parse(&path::basename(file_name))
+    }
+
+    pub fn mission(&self) -> Mission {
+        match self {
+            ProductId::M20(_) => Mission::Mars2020,
+            ProductId::MslEcam(_) | ProductId::MslMmm(_) => Mission::MSL,
+            ProductId::Nsyt(_) => Mission::InSight,
+            ProductId::Mer(id) if id.spacecraft == '1' => Mission::MerB,
+            ProductId::Mer(_) => Mission::MerA,
+        }
+    }
+
+    /// The instrument that took the image, `Instrument::None` for cameras this crate doesn't
+    /// support
+    pub fn instrument(&self) -> Instrument {
+        match self {
+            ProductId::M20(id) => id.instrument(),
+            ProductId::MslEcam(id) => id.instrument(),
+            ProductId::MslMmm(id) => id.instrument(),
+            ProductId::Nsyt(id) if id.camera == 'D' => Instrument::NsytIDC,
+            ProductId::Nsyt(_) => Instrument::NsytICC,
+            ProductId::Mer(_) => Instrument::None,
+        }
+    }
+
+    /// The stereo eye, from the camera, `Eye::DontCare` for cameras that aren't part of a
+    /// stereo pair
+    pub fn eye(&self) -> Eye {
+        let eye_of = |camera: &str| eye_from_char(char_at(camera, 1).unwrap_or_default());
+        match self {
+            ProductId::M20(id) if matches!(char_at(&id.camera, 0), Some('Z' | 'N' | 'F' | 'R')) => {
+                eye_of(&id.camera)
+            }
+            ProductId::MslEcam(id) if matches!(char_at(&id.camera, 0), Some('N' | 'F' | 'R')) => {
+                eye_of(&id.camera)
+            }
+            ProductId::MslMmm(id) => eye_of(&id.camera),
+            ProductId::Mer(id) => eye_from_char(id.eye),
+            _ => Eye::DontCare,
+        }
+    }
+
+    pub fn sol(&self) -> Option<u32> {
+        match self {
+            ProductId::M20(id) => Some(id.sol),
+            ProductId::MslMmm(id) => Some(id.sol),
+            ProductId::Nsyt(id) => Some(id.sol),
+            ProductId::MslEcam(_) | ProductId::Mer(_) => None,
+        }
+    }
+
+    /// Spacecraft clock seconds
+    pub fn sclk(&self) -> Option<u64> {
+        match self {
+            ProductId::M20(id) => Some(id.sclk),
+            ProductId::MslEcam(id) => Some(id.sclk),
+            ProductId::Nsyt(id) => Some(id.sclk),
+            ProductId::Mer(id) => Some(id.sclk),
+            ProductId::MslMmm(_) => None,
+        }
+    }
+
+    pub fn product_type(&self) -> String {
+        match self {
+            ProductId::M20(id) => id.product_type.clone(),
+            ProductId::MslEcam(id) => id.product_type.clone(),
+            ProductId::MslMmm(id) => id.product_type.to_string(),
+            ProductId::Nsyt(id) => id.product_type.clone(),
+            ProductId::Mer(id) => id.product_type.clone(),
+        }
+    }
+
+    /// The observation sequence the image belongs to
+    pub fn sequence_id(&self) -> String {
+        match self {
+            ProductId::M20(id) => id.sequence_id.clone(),
+            ProductId::MslEcam(id) => id.sequence_id.clone(),
+            ProductId::MslMmm(id) => id.sequence_id(),
+            ProductId::Nsyt(id) => id.sequence_id.clone(),
+            ProductId::Mer(id) => id.sequence_id.clone(),
+        }
+    }
+
+    /// The eye and filter position of cameras with a filter wheel, such as `L0` for the
+    /// left Mastcam-Z's Bayer filter or `R6` for the right Pancam's filter 6
+    pub fn filter(&self) -> Option<String> {
+        match self {
+            ProductId::M20(id) if id.camera.starts_with('Z') && id.filter.is_ascii_digit() => {
+                Some(format!("{}{}", &id.camera[1..], id.filter))
+            }
+            ProductId::Mer(id) if id.camera == 'P' => Some(format!("{}{}", id.eye, id.filter)),
+            _ => None,
+        }
+    }
+
+    /// True for thumbnail products
+    pub fn is_thumbnail(&self) -> bool {
+        match self {
+            ProductId::M20(id) => id.thumbnail == 'T',
+            ProductId::MslEcam(id) => id.frame_type == 'T',
+            ProductId::Nsyt(id) => id.frame_type == 'T',
+            ProductId::Mer(id) => id.product_type == "ETH",
+            ProductId::MslMmm(id) => id.thumbnail,
+        }
+    }
+
+    /// Identifies the observation the product was made from. Thumbnails and every version
+    /// of a product share the key. MSL Mastcam, MAHLI, and MARDI product types are encodings
+    /// of the same image, thumbnails included, so their key leaves out the type.
+    pub fn observation_key(&self) -> String {
+        match self {
+            ProductId::M20(id) => format!(
+                "M20_{}{}_{:010}_{:03}{}",
+                id.camera, id.filter, id.sclk, id.sclk_millis, id.product_type
+            ),
+            ProductId::MslEcam(id) => format!(
+                "MSL_{}{}_{:09}{}",
+                id.camera, id.compute_element, id.sclk, id.product_type
+            ),
+            ProductId::MslMmm(id) => format!(
+                "MSL_{:04}{}{}{}{}",
+                id.sol, id.camera, id.sequence_number, id.command_number, id.product_code
+            ),
+            ProductId::Nsyt(id) => format!(
+                "NSYT_{}{}_{:09}{}",
+                id.camera, id.config, id.sclk, id.product_type
+            ),
+            ProductId::Mer(id) => format!(
+                "MER_{}{}{:09}{}",
+                id.spacecraft, id.camera, id.sclk, id.product_type
+            ),
+        }
+    }
+
+    pub fn rank(&self) -> ProductRank {
+        ProductRank {
+            full_frame: !self.is_thumbnail(),
+            version: self
+                .version()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn version(&self) -> Option<String> {
+        match self {
+            ProductId::M20(id) => id.version.clone(),
+            ProductId::MslEcam(id) if id.version != '_' => Some(id.version.to_string()),
+            ProductId::MslMmm(id) if !id.version.is_empty() => Some(id.version.clone()),
+            ProductId::Nsyt(id) if id.version != '_' => Some(id.version.to_string()),
+            ProductId::Mer(id) => Some(id.version.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ProductId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ProductId::parse(s)
+    }
+}
+
+impl fmt::Display for ProductId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProductId::M20(id) => fmt::Display::fmt(id, f),
+            ProductId::MslEcam(id) => fmt::Display::fmt(id, f),
+            ProductId::MslMmm(id) => fmt::Display::fmt(id, f),
+            ProductId::Nsyt(id) => fmt::Display::fmt(id, f),
+            ProductId::Mer(id) => fmt::Display::fmt(id, f),
+        }
+    }
+}
+
+/// Splits items into those to keep and those superseded by a full frame or a newer version of
+/// the same observation. `product_of` gives an item's product id along with a group it is
+/// compared within, such as its directory and file name suffix. Items without a product id
+/// are kept.
+pub fn partition_superseded<T, F>(items: Vec<T>, product_of: F) -> (Vec<T>, Vec<T>)
+where
+    F: Fn(&T) -> Option<(String, ProductId)>,
+{
+    let keys: Vec<Option<(String, ProductRank)>> = items
+        .iter()
+        .map(|item| {
+            product_of(item).map(|(group, product_id)| {
+                (
+                    format!("{}/{}", group, product_id.observation_key()),
+                    product_id.rank(),
+                )
+            })
+        })
+        .collect();
+
+    let mut best: HashMap<&str, ProductRank> = HashMap::new();
+    keys.iter().flatten().for_each(|(key, rank)| {
+        let entry = best.entry(key.as_str()).or_insert(*rank);
+        *entry = (*entry).max(*rank);
+    });
+
+    let (kept, superseded): (Vec<_>, Vec<_>) =
+        items
+            .into_iter()
+            .zip(keys.iter())
+            .partition(|(_, key)| match key {
+                Some((key, rank)) => best[key.as_str()] <= *rank,
+                None => true,
+            });
+
+    (
+        kept.into_iter().map(|(item, _)| item).collect(),
+        superseded.into_iter().map(|(item, _)| item).collect(),
+    )
+}
@@ -0,0 +1,248 @@
+use crate::{
+    calibfile,
+    calprofile::{self, CalProfile},
+    enums::{Instrument, Mission},
+};
+
+use anyhow::Result;
+use glob::glob;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How specifically a profile's declared target matches an image's instrument, from least to
+/// most specific.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InstrumentMatch {
+    /// The profile only declares a mission
+    Mission,
+
+    /// The profile declares a camera group, such as `MASTCAM` or `NAVCAM`
+    CameraGroup,
+
+    /// The profile declares the instrument itself, such as `MCZ_LEFT`
+    Instrument,
+}
+
+/// An installed or user supplied profile that may be selected automatically
+#[derive(Debug, Clone)]
+pub struct ProfileCandidate {
+    pub name: String,
+    pub path: String,
+    pub profile: CalProfile,
+    mission: Option<Mission>,
+    instruments: Vec<Instrument>,
+    exact_instrument: bool,
+}
+
+impl ProfileCandidate {
+    pub fn from_file(file_path: &str) -> Result<Self> {
+        let profile = calprofile::load_calibration_profile(&file_path.to_string())?;
+        let mission = match &profile.mission {
+            Some(mission) => Some(Mission::from_str(mission)?),
+            None => None,
+        };
+        let instruments = profile.target_instruments()?;
+        let exact_instrument = match &profile.instrument {
+            Some(instrument) => {
+                Instrument::from_str(instrument).unwrap_or_default() != Instrument::None
+            }
+            None => false,
+        };
+
+        Ok(ProfileCandidate {
+            name: Path::new(file_path)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or(file_path)
+                .to_owned(),
+            path: file_path.to_owned(),
+            profile,
+            mission,
+            instruments,
+            exact_instrument,
+        })
+    }
+
+    /// How the profile's declared mission and instrument match the given instrument, or
+    /// `None` if the profile does not apply to it. Profiles declaring neither never match.
+    pub fn instrument_match(&self, instrument: Instrument) -> Option<InstrumentMatch> {
+        if !self.instruments.is_empty() {
+            match (
+                self.instruments.contains(&instrument),
+                self.exact_instrument,
+            ) {
+                (true, true) => Some(InstrumentMatch::Instrument),
+                (true, false) => Some(InstrumentMatch::CameraGroup),
+                (false, _) => None,
+            }
+        } else {
+            match self.mission {
+                Some(mission) if instrument.mission() == Some(mission) => {
+                    Some(InstrumentMatch::Mission)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    /// `Some(true)` if the profile declares the image's filter, `Some(false)` if it does not
+    /// declare a filter, and `None` if it declares a different one.
+    pub fn filter_match(&self, filter_name: Option<&str>) -> Option<bool> {
+        match (&self.profile.filter_name, filter_name) {
+            (None, _) => Some(false),
+            (Some(profile_filter), Some(filter_name))
+                if profile_filter.eq_ignore_ascii_case(filter_name) =>
+            {
+                Some(true)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The profile chosen for an image and why
+#[derive(Debug, Clone)]
+pub struct ProfileSelection {
+    /// Profile specification to load, a path for matched profiles
+    pub spec: String,
+    pub name: String,
+    pub reason: String,
+
+    /// Other profiles that matched equally well and lost on name order
+    pub tied_with: Vec<String>,
+}
+
+/// Chooses a calibration profile for each image from the mission and instrument declared by
+/// the candidate profiles. Matching profiles are ranked by, in order:
+///  1. How specific the instrument match is: instrument id, then camera group, then mission
+///  2. Profiles declaring the image's filter over those declaring no filter
+///  3. The profiles' `auto_priority`, highest first
+///  4. Profile name, alphabetically
+///
+/// Images matching no profile get the fallback profile, if one was given.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileSelector {
+    candidates: Vec<ProfileCandidate>,
+    fallback: Option<String>,
+}
+
+impl ProfileSelector {
+    pub fn new() -> Self {
+        ProfileSelector::default()
+    }
+
+    /// A selector choosing among every profile in the calibration data directories. Where
+    /// several directories hold a profile of the same name, the one found first on the search
+    /// path is used, as it would be when loading the profile by name.
+    pub fn from_installed_profiles() -> Self {
+        let mut selector = ProfileSelector::new();
+        for directory in calibfile::calibration_search_paths().iter() {
+            for file_path in glob(&format!("{}/*.toml", directory))
+                .expect("Failed to read glob pattern")
+                .flatten()
+            {
+                let file_path = String::from(file_path.to_str().unwrap());
+                if !calprofile::is_calibration_profile_file(&file_path) {
+                    continue;
+                }
+                match ProfileCandidate::from_file(&file_path) {
+                    Ok(candidate) => {
+                        if !selector.candidates.iter().any(|c| c.name == candidate.name) {
+                            selector.add_candidate(candidate);
+                        }
+                    }
+                    Err(why) => warn!("Skipping profile {}: {}", file_path, why),
+                }
+            }
+        }
+        selector
+    }
+
+    /// A selector choosing among the named profiles
+    pub fn from_profiles(profile_names: &[String]) -> Result<Self> {
+        let mut selector = ProfileSelector::new();
+        for name in profile_names.iter() {
+            let file_path =
+                calibfile::locate_calibration_file_no_extention(name, &".toml".to_string())?;
+            selector.add_candidate(ProfileCandidate::from_file(&file_path)?);
+        }
+        Ok(selector)
+    }
+
+    /// Sets the profile specification used for images that match no candidate
+    pub fn with_fallback(mut self, fallback: Option<String>) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    pub fn add_candidate(&mut self, candidate: ProfileCandidate) {
+        self.candidates.push(candidate);
+    }
+
+    pub fn candidates(&self) -> &[ProfileCandidate] {
+        &self.candidates
+    }
+
+    /// Chooses the profile for an image taken by `instrument` through `filter_name`
+    pub fn select(
+        &self,
+        instrument: Instrument,
+        filter_name: Option<&str>,
+    ) -> Option<ProfileSelection> {
+        let mut ranked: Vec<((InstrumentMatch, bool, i32), &ProfileCandidate)> = self
+            .candidates
+            .iter()
+            .filter_map(|candidate| {
+                match (
+                    candidate.instrument_match(instrument),
+                    candidate.filter_match(filter_name),
+                ) {
+                    (Some(instrument_match), Some(filter_match)) => Some((
+                        (
+                            instrument_match,
+                            filter_match,
+                            candidate.profile.auto_priority,
+                        ),
+                        candidate,
+                    )),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        // Best rank first, then by name
+        ranked.sort_by(|(a_rank, a), (b_rank, b)| {
+            b_rank.cmp(a_rank).then_with(|| a.name.cmp(&b.name))
+        });
+
+        match ranked.first() {
+            Some(((instrument_match, filter_match, priority), best)) => {
+                let mut reason = format!("{:?} match", instrument_match);
+                if *filter_match {
+                    reason.push_str(", filter match");
+                }
+                if *priority != 0 {
+                    reason.push_str(&format!(", priority {}", priority));
+                }
+
+                Some(ProfileSelection {
+                    spec: best.path.clone(),
+                    name: best.name.clone(),
+                    reason,
+                    tied_with: ranked
+                        .iter()
+                        .skip(1)
+                        .filter(|(rank, _)| rank == &ranked[0].0)
+                        .map(|(_, c)| c.name.clone())
+                        .collect(),
+                })
+            }
+            None => self.fallback.as_ref().map(|fallback| ProfileSelection {
+                spec: fallback.clone(),
+                name: fallback.clone(),
+                reason: "fallback".to_string(),
+                tied_with: vec![],
+            }),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{
+    calibfile::{self, InstrumentProperties},
+    enums::Instrument,
+    marsimage::MarsImage,
+    metadata::Metadata,
+    time,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sciimg::image::Image;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+/// Physical units a calibrated image can be converted to
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RadiometricUnits {
+    /// Spectral radiance, W/m^2/sr/um
+    Radiance,
+
+    /// Radiance factor: radiance relative to that of a perfect Lambertian surface
+    /// illuminated by the sun at normal incidence
+    Iof,
+}
+
+impl RadiometricUnits {
+    /// The unit label recorded in the output metadata
+    pub fn label(&self) -> &'static str {
+        match self {
+            RadiometricUnits::Radiance => "W/m^2/sr/um",
+            RadiometricUnits::Iof => "I/F",
+        }
+    }
+}
+
+impl FromStr for RadiometricUnits {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "radiance" => Ok(RadiometricUnits::Radiance),
+            "iof" | "i/f" => Ok(RadiometricUnits::Iof),
+            _ => Err(anyhow!(
+                "Invalid radiometric units '{}', expected 'radiance' or 'iof'",
+                s
+            )),
+        }
+    }
+}
+
+/// The conversion applied to an image, recorded in its metadata sidecar. Calibrated values
+/// are the decompanded, flat fielded DN multiplied by `scale`, per band.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RadiometricCalibration {
+    pub units: RadiometricUnits,
+    pub filter: String,
+
+    /// Exposure duration in milliseconds
+    pub exposure_duration: f64,
+
+    /// DN/s per W/m^2/sr/um, per band
+    pub responsivity: Vec<f64>,
+
+    /// Solar irradiance at 1 AU in W/m^2/um, per band. Only used for I/F.
+    pub solar_irradiance: Option<Vec<f64>>,
+
+    /// Mars' distance from the sun when the image was taken. Only used for I/F.
+    pub sun_distance_au: Option<f64>,
+
+    /// Factor applied to each band
+    pub scale: Vec<f64>,
+}
+
+/// Looks up a filter's coefficients, ignoring case
+fn coefficients_for_filter<'a>(
+    coefficients: &'a BTreeMap<String, Vec<f64>>,
+    filter: &str,
+) -> Option<&'a Vec<f64>> {
+    coefficients
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(filter))
+        .map(|(_, values)| values)
+        .filter(|values| !values.is_empty())
+}
+
+/// Parses the image's capture time as it appears in the raw image metadata, which usually
+/// omits the time zone.
+pub fn parse_date_taken_utc(date_taken_utc: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date_taken_utc) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    match NaiveDateTime::parse_from_str(date_taken_utc, "%Y-%m-%dT%H:%M:%S%.f") {
+        Ok(date) => Ok(date.and_utc()),
+        Err(why) => Err(anyhow!(
+            "Invalid capture date '{}': {}",
+            date_taken_utc,
+            why
+        )),
+    }
+}
+
+/// Computes the conversion from DN to the requested units for an image taken through
+/// `filter`. Requires the image's exposure duration, and for I/F its capture time.
+pub fn compute_calibration(
+    properties: &InstrumentProperties,
+    units: RadiometricUnits,
+    filter: &str,
+    metadata: &Metadata,
+) -> Result<RadiometricCalibration> {
+    let responsivity = coefficients_for_filter(&properties.responsivity, filter)
+        .ok_or_else(|| anyhow!("No responsivity coefficients for filter {}", filter))?;
+
+    let exposure_duration = match metadata.exposure_duration {
+        Some(exposure) if exposure > 0.0 => exposure,
+        _ => {
+            return Err(anyhow!(
+                "Image metadata does not include an exposure duration"
+            ))
+        }
+    };
+
+    if responsivity.iter().any(|r| *r <= 0.0) {
+        return Err(anyhow!(
+            "Responsivity coefficients for filter {} must be greater than zero",
+            filter
+        ));
+    }
+
+    // Radiance = DN / (exposure seconds * responsivity)
+    let mut scale: Vec<f64> = responsivity
+        .iter()
+        .map(|r| 1.0 / (exposure_duration / 1000.0 * r))
+        .collect();
+
+    let (solar_irradiance, sun_distance_au) = match units {
+        RadiometricUnits::Radiance => (None, None),
+        RadiometricUnits::Iof => {
+            let irradiance = coefficients_for_filter(&properties.solar_irradiance, filter)
+                .ok_or_else(|| anyhow!("No solar irradiance for filter {}", filter))?;
+            if irradiance.len() != responsivity.len() {
+                return Err(anyhow!(
+                    "Filter {} has {} responsivity coefficients but {} solar irradiance values",
+                    filter,
+                    responsivity.len(),
+                    irradiance.len()
+                ));
+            }
+
+            // I/F = pi * radiance * r^2 / solar irradiance at 1 AU
+            let r = time::mars_sun_distance_au(&parse_date_taken_utc(&metadata.date_taken_utc)?);
+            scale
+                .iter_mut()
+                .zip(irradiance.iter())
+                .for_each(|(s, f)| *s *= PI * r * r / f);
+            (Some(irradiance.clone()), Some(r))
+        }
+    };
+
+    Ok(RadiometricCalibration {
+        units,
+        filter: filter.to_owned(),
+        exposure_duration,
+        responsivity: responsivity.clone(),
+        solar_irradiance,
+        sun_distance_au,
+        scale,
+    })
+}
+
+/// `compute_calibration` using the instrument's coefficients from the calibration data
+pub fn compute_calibration_for_instrument(
+    instrument: Instrument,
+    units: RadiometricUnits,
+    filter: &str,
+    metadata: &Metadata,
+) -> Result<RadiometricCalibration> {
+    let properties = calibfile::get_instrument_properties(instrument)?;
+    compute_calibration(&properties, units, filter, metadata)
+}
+
+/// Multiplies each band of the image by its scale factor. A single factor applies to every
+/// band.
+pub fn apply_calibration(image: &mut Image, calibration: &RadiometricCalibration) -> Result<()> {
+    let scale = &calibration.scale;
+    if scale.len() != 1 && scale.len() != image.num_bands() {
+        return Err(anyhow!(
+            "Filter {} has {} coefficients but the image has {} bands",
+            calibration.filter,
+            scale.len(),
+            image.num_bands()
+        ));
+    }
+
+    for band in 0..image.num_bands() {
+        let factor = scale[band.min(scale.len() - 1)] as f32;
+        let mut buffer = image.get_band(band).clone();
+        for y in 0..image.height {
+            for x in 0..image.width {
+                buffer.put(x, y, buffer.get(x, y) * factor);
+            }
+        }
+        image.set_band(&buffer, band);
+    }
+    Ok(())
+}
+
+/// Converts the image to the requested units and records the conversion in its metadata
+pub fn calibrate(raw: &mut MarsImage, units: RadiometricUnits, filter: &str) -> Result<()> {
+    let calibration =
+        compute_calibration_for_instrument(raw.instrument, units, filter, &raw.metadata)?;
+    apply_calibration(&mut raw.image, &calibration)?;
+    raw.metadata.radiometric = true;
+    raw.metadata.radiometric_calibration = Some(calibration);
+    Ok(())
+}
@@ -2,12 +2,14 @@ use crate::constants;
 use crate::enums::Mission;
 use crate::httpfetch;
 use crate::metadata::Metadata;
+use crate::productid::{self, ProductId, ProductRank};
 use crate::util::{save_image_json, InstrumentMap};
 use anyhow::Result;
 use cli_table::{Cell, Style, Table};
 use rayon::prelude::*;
 use sciimg::path;
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{error::Error, fmt};
@@ -27,6 +29,14 @@ pub struct RemoteQuery {
     pub only_new: bool,
     pub product_types: Vec<String>,
     pub output_path: String,
+
+    /// Keep every version and thumbnail in the results rather than only the newest full
+    /// frame of each observation
+    pub all_versions: bool,
+
+    /// Only download products that supersede a lower version or thumbnail already in the
+    /// output directory
+    pub updates_only: bool,
 }
 
 /// Generic all-mission api stats from query results
@@ -209,6 +219,66 @@ async fn download_remote_image(
     }
 }
 
+/// The product id of a remote image, from the image's file name, which unlike the image id
+/// includes the version
+fn remote_product_id(md: &Metadata) -> Option<ProductId> {
+    ProductId::from_file_name(&md.remote_image_url)
+        .or_else(|_| ProductId::parse(&md.imageid))
+        .ok()
+}
+
+/// The highest ranked product of each observation among the downloaded images in a
+/// directory. Processed files, whose names add a suffix such as `-rjcal` to the product id,
+/// are not counted.
+pub fn local_product_ranks(dir: &str) -> HashMap<String, ProductRank> {
+    let mut ranks: HashMap<String, ProductRank> = HashMap::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return ranks,
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .for_each(|file_name| {
+            if let Ok(product_id) = ProductId::parse(&file_name) {
+                let id_len = product_id.to_string().len();
+                if matches!(file_name.get(id_len..), Some(rest) if !rest.contains('-')) {
+                    let rank = product_id.rank();
+                    let entry = ranks.entry(product_id.observation_key()).or_insert(rank);
+                    *entry = (*entry).max(rank);
+                }
+            }
+        });
+    ranks
+}
+
+/// Whether a remote image should be downloaded given the products already in the output
+/// directory
+fn wanted_for_download(
+    md: &Metadata,
+    query: &RemoteQuery,
+    local: &HashMap<String, ProductRank>,
+) -> bool {
+    if query.only_new && image_exists_on_filesystem(&md.remote_image_url, Some(&query.output_path))
+    {
+        return false;
+    }
+
+    let product_id = match remote_product_id(md) {
+        Some(product_id) => product_id,
+        None => return !query.updates_only,
+    };
+    let local_rank = local.get(&product_id.observation_key());
+    if query.updates_only {
+        matches!(local_rank, Some(rank) if *rank < product_id.rank())
+    } else if query.only_new {
+        !matches!(local_rank, Some(rank) if *rank >= product_id.rank())
+    } else {
+        true
+    }
+}
+
 /// Callback to inform the caller as to the total number of images that will be downloaded
 type OnTotalKnown = fn(usize);
 
@@ -230,21 +300,37 @@ pub async fn perform_fetch<T: Fetch>(
 ) -> Result<(), FetchError> {
     match fetch_available(client, query).await {
         Ok(results) => {
+            // Drop older versions, and thumbnails of images that have come down in full
+            let results = match query.all_versions {
+                true => results,
+                false => {
+                    let (kept, superseded) = productid::partition_superseded(results, |md| {
+                        remote_product_id(md).map(|product_id| (String::new(), product_id))
+                    });
+                    if !superseded.is_empty() {
+                        info!(
+                            "Skipping {} images superseded by newer versions or full frames",
+                            superseded.len()
+                        );
+                    }
+                    kept
+                }
+            };
+
             // print a table of all the results.
             print_table(&results, query);
 
+            let local = match query.only_new || query.updates_only {
+                true => local_product_ranks(&query.output_path),
+                false => HashMap::new(),
+            };
+
             // Iterate over the results and remove existing images
             // if the user has selected to skip any images that already exist locally
             let to_download: Vec<Metadata> = results
                 .into_iter()
                 .filter(|_| !query.list_only)
-                .filter(|md| {
-                    !query.only_new
-                        || !image_exists_on_filesystem(
-                            &md.remote_image_url,
-                            Some(&query.output_path),
-                        )
-                })
+                .filter(|md| wanted_for_download(md, query, &local))
                 .collect();
 
             // Don't bother with the result if we have nothing to download
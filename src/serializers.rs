@@ -502,135 +502,141 @@ pub mod as_cahvore {
         let r: Result<&str, D::Error> = Deserialize::deserialize(deserializer);
         match r {
             Err(_) => Ok(CameraModel::default()),
-            Ok(s) => {
-                let s0 = String::from(s);
-
-                let split = s0.split(';');
-                let mut parts: Vec<Vec<f64>> = Vec::new();
-
-                for n in split {
-                    match n.find('(') {
-                        None => {
-                            if string_is_valid_f64(n) {
-                                parts.push(vec![n.parse::<f64>().unwrap()]);
-                            }
-                        }
-                        Some(_i) => {
-                            parts.push(str_to_vec(n).unwrap());
-                        }
-                    }
-                }
+            Ok(s) => Ok(from_components(&components(s))),
+        }
+    }
 
-                match parts.len() {
-                    4 => {
-                        // CAHV
-                        Ok(CameraModel::new(Box::new(Cahv {
-                            c: if !parts.is_empty() {
-                                Vector::from_vec(&parts[0]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            a: if parts.len() >= 2 {
-                                Vector::from_vec(&parts[1]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            h: if parts.len() >= 3 {
-                                Vector::from_vec(&parts[2]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            v: if parts.len() >= 4 {
-                                Vector::from_vec(&parts[3]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                        })))
-                    }
-                    6 => {
-                        // CAHVOR
-                        Ok(CameraModel::new(Box::new(Cahvor {
-                            c: if !parts.is_empty() {
-                                Vector::from_vec(&parts[0]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            a: if parts.len() >= 2 {
-                                Vector::from_vec(&parts[1]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            h: if parts.len() >= 3 {
-                                Vector::from_vec(&parts[2]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            v: if parts.len() >= 4 {
-                                Vector::from_vec(&parts[3]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            o: if parts.len() >= 5 {
-                                Vector::from_vec(&parts[4]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            r: if parts.len() >= 6 {
-                                Vector::from_vec(&parts[5]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                        })))
-                    }
-                    9 => {
-                        // CAHVORE
-                        Ok(CameraModel::new(Box::new(Cahvore {
-                            c: if !parts.is_empty() {
-                                Vector::from_vec(&parts[0]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            a: if parts.len() >= 2 {
-                                Vector::from_vec(&parts[1]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            h: if parts.len() >= 3 {
-                                Vector::from_vec(&parts[2]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            v: if parts.len() >= 4 {
-                                Vector::from_vec(&parts[3]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            o: if parts.len() >= 5 {
-                                Vector::from_vec(&parts[4]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            r: if parts.len() >= 6 {
-                                Vector::from_vec(&parts[5]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            e: if parts.len() >= 7 {
-                                Vector::from_vec(&parts[6]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            linearity: if parts.len() >= 8 {
-                                parts[7][0]
-                            } else {
-                                LINEARITY_PERSPECTIVE
-                            },
-                            pupil_type: PupilType::General,
-                        })))
+    /// Splits a serialized camera model into its vector and scalar components
+    pub fn components(s: &str) -> Vec<Vec<f64>> {
+        let split = s.split(';');
+        let mut parts: Vec<Vec<f64>> = Vec::new();
+
+        for n in split {
+            match n.find('(') {
+                None => {
+                    if string_is_valid_f64(n) {
+                        parts.push(vec![n.parse::<f64>().unwrap()]);
                     }
-                    _ => Ok(CameraModel::default()),
                 }
+                Some(_i) => {
+                    parts.push(str_to_vec(n).unwrap());
+                }
+            }
+        }
+        parts
+    }
+
+    /// Builds a CAHV, CAHVOR or CAHVORE model from its components, the default (invalid)
+    /// model for any other number of components
+    pub fn from_components(parts: &[Vec<f64>]) -> CameraModel {
+        match parts.len() {
+            4 => {
+                // CAHV
+                CameraModel::new(Box::new(Cahv {
+                    c: if !parts.is_empty() {
+                        Vector::from_vec(&parts[0]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    a: if parts.len() >= 2 {
+                        Vector::from_vec(&parts[1]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    h: if parts.len() >= 3 {
+                        Vector::from_vec(&parts[2]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    v: if parts.len() >= 4 {
+                        Vector::from_vec(&parts[3]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                }))
+            }
+            6 => {
+                // CAHVOR
+                CameraModel::new(Box::new(Cahvor {
+                    c: if !parts.is_empty() {
+                        Vector::from_vec(&parts[0]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    a: if parts.len() >= 2 {
+                        Vector::from_vec(&parts[1]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    h: if parts.len() >= 3 {
+                        Vector::from_vec(&parts[2]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    v: if parts.len() >= 4 {
+                        Vector::from_vec(&parts[3]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    o: if parts.len() >= 5 {
+                        Vector::from_vec(&parts[4]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    r: if parts.len() >= 6 {
+                        Vector::from_vec(&parts[5]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                }))
+            }
+            9 => {
+                // CAHVORE
+                CameraModel::new(Box::new(Cahvore {
+                    c: if !parts.is_empty() {
+                        Vector::from_vec(&parts[0]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    a: if parts.len() >= 2 {
+                        Vector::from_vec(&parts[1]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    h: if parts.len() >= 3 {
+                        Vector::from_vec(&parts[2]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    v: if parts.len() >= 4 {
+                        Vector::from_vec(&parts[3]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    o: if parts.len() >= 5 {
+                        Vector::from_vec(&parts[4]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    r: if parts.len() >= 6 {
+                        Vector::from_vec(&parts[5]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    e: if parts.len() >= 7 {
+                        Vector::from_vec(&parts[6]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    linearity: if parts.len() >= 8 {
+                        parts[7][0]
+                    } else {
+                        LINEARITY_PERSPECTIVE
+                    },
+                    pupil_type: PupilType::General,
+                }))
             }
+            _ => CameraModel::default(),
         }
     }
 }
@@ -0,0 +1,943 @@
+use crate::{
+    enums::Instrument, floattiff, m20::zcam, marsimage::MarsImage, metadata::Metadata,
+    productid::ProductId, util,
+};
+
+use anyhow::{anyhow, Result};
+use sciimg::{enums::ImageMode, image::Image, path};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+/// Nominal center wavelengths, in nanometers, of the Mastcam-Z left eye filters. Filter 0 is
+/// the Bayer broadband filter, listed as its red, green, and blue channels.
+const MASTCAMZ_LEFT_WAVELENGTHS: [(&str, &[f32]); 8] = [
+    ("L0", &[630.0, 544.0, 480.0]),
+    ("L1", &[800.0]),
+    ("L2", &[754.0]),
+    ("L3", &[677.0]),
+    ("L4", &[605.0]),
+    ("L5", &[528.0]),
+    ("L6", &[442.0]),
+    ("L7", &[590.0]),
+];
+
+const MASTCAMZ_RIGHT_WAVELENGTHS: [(&str, &[f32]); 8] = [
+    ("R0", &[631.0, 544.0, 480.0]),
+    ("R1", &[800.0]),
+    ("R2", &[866.0]),
+    ("R3", &[910.0]),
+    ("R4", &[939.0]),
+    ("R5", &[978.0]),
+    ("R6", &[1022.0]),
+    ("R7", &[880.0]),
+];
+
+/// Nominal center wavelengths of the MSL Mastcam M-34 (left) filters
+const MSL_MASTCAM_LEFT_WAVELENGTHS: [(&str, &[f32]); 8] = [
+    ("L0", &[640.0, 554.0, 495.0]),
+    ("L1", &[527.0]),
+    ("L2", &[445.0]),
+    ("L3", &[751.0]),
+    ("L4", &[676.0]),
+    ("L5", &[867.0]),
+    ("L6", &[1012.0]),
+    ("L7", &[440.0]),
+];
+
+/// Nominal center wavelengths of the MSL Mastcam M-100 (right) filters
+const MSL_MASTCAM_RIGHT_WAVELENGTHS: [(&str, &[f32]); 8] = [
+    ("R0", &[638.0, 551.0, 493.0]),
+    ("R1", &[527.0]),
+    ("R2", &[447.0]),
+    ("R3", &[805.0]),
+    ("R4", &[908.0]),
+    ("R5", &[937.0]),
+    ("R6", &[1013.0]),
+    ("R7", &[880.0]),
+];
+
+const BAYER_CHANNELS: [&str; 3] = ["R", "G", "B"];
+
+/// Frames with mast pointings within this many degrees are treated as the same pointing
+const POINTING_PRECISION_DEGREES: f64 = 0.1;
+
+/// The nominal center wavelength of each band of an image taken through the filter, or None
+/// for instruments and filters without a known passband
+pub fn filter_wavelengths(instrument: Instrument, filter: &str) -> Option<Vec<f32>> {
+    let table = match instrument {
+        Instrument::M20MastcamZLeft => &MASTCAMZ_LEFT_WAVELENGTHS,
+        Instrument::M20MastcamZRight => &MASTCAMZ_RIGHT_WAVELENGTHS,
+        Instrument::MslMastcamLeft => &MSL_MASTCAM_LEFT_WAVELENGTHS,
+        Instrument::MslMastcamRight => &MSL_MASTCAM_RIGHT_WAVELENGTHS,
+        _ => return None,
+    };
+    table
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(filter))
+        .map(|(_, wavelengths)| wavelengths.to_vec())
+}
+
+/// What is known about an input frame from its file name and metadata, before its pixels
+/// are loaded
+#[derive(Debug, Clone)]
+pub struct FrameInfo {
+    pub file_path: String,
+    pub instrument: Instrument,
+    pub filter: Option<String>,
+    pub sequence_id: Option<String>,
+    pub sol: u32,
+
+    /// Mast azimuth and elevation, in degrees
+    pub pointing: Option<[f64; 2]>,
+
+    /// Position of the frame on the sensor, as [x, y, width, height], in sensor pixels
+    pub subframe_rect: Option<Vec<f64>>,
+
+    /// Sensor pixels per image pixel
+    pub scale_factor: u32,
+}
+
+impl FrameInfo {
+    pub fn from_metadata(file_path: &str, metadata: &Metadata) -> Self {
+        let product_id = match metadata.imageid.is_empty() {
+            true => ProductId::from_file_name(file_path),
+            false => ProductId::parse(&metadata.imageid),
+        }
+        .ok();
+
+        let filter = product_id.as_ref().and_then(ProductId::filter).or_else(|| {
+            metadata
+                .filter_name
+                .as_deref()
+                .and_then(zcam::filter_from_metadata_name)
+        });
+
+        // Product ids identify the camera even without a metadata sidecar
+        let instrument = match Instrument::from_str(&metadata.instrument).unwrap_or_default() {
+            Instrument::None => product_id
+                .as_ref()
+                .map(ProductId::instrument)
+                .unwrap_or_default(),
+            instrument => instrument,
+        };
+
+        FrameInfo {
+            file_path: file_path.to_owned(),
+            instrument,
+            filter,
+            sequence_id: product_id.as_ref().map(ProductId::sequence_id),
+            sol: metadata.sol,
+            pointing: match (metadata.mast_az, metadata.mast_el) {
+                (Some(az), Some(el)) => Some([az, el]),
+                _ => None,
+            },
+            subframe_rect: metadata.subframe_rect.clone(),
+            scale_factor: metadata.scale_factor.max(1),
+        }
+    }
+
+    /// Reads the frame's metadata sidecar, if present
+    pub fn load(file_path: &str) -> Result<Self> {
+        let metadata = MarsImage::load_image_metadata(file_path)?;
+        Ok(FrameInfo::from_metadata(file_path, &metadata))
+    }
+
+    /// The nominal center wavelength of each band, when known
+    pub fn wavelengths(&self) -> Option<Vec<f32>> {
+        filter_wavelengths(self.instrument, self.filter.as_deref()?)
+    }
+
+    /// Opens the frame. Floating point TIFF products, such as radiometrically calibrated
+    /// images, are read without scaling.
+    pub fn open(&self) -> Result<MarsImage> {
+        if self.file_path.to_lowercase().ends_with(".tif") {
+            let image = floattiff::open_image(&self.file_path)?;
+            let mut raw = MarsImage::from_image(&image, self.instrument);
+            raw.metadata = MarsImage::load_image_metadata(&self.file_path)?;
+            Ok(raw)
+        } else {
+            Ok(MarsImage::open(&self.file_path, self.instrument)?)
+        }
+    }
+
+    /// Distinguishes the two eyes of a stereo camera, which see the scene through different
+    /// filters and from different positions
+    fn camera_key(&self) -> &'static str {
+        match self.instrument {
+            Instrument::M20MastcamZLeft | Instrument::MslMastcamLeft => "_L",
+            Instrument::M20MastcamZRight | Instrument::MslMastcamRight => "_R",
+            _ => "",
+        }
+    }
+
+    fn pointing_key(&self) -> String {
+        match self.pointing {
+            Some([az, el]) => format!(
+                "{:.0},{:.0}",
+                az / POINTING_PRECISION_DEGREES,
+                el / POINTING_PRECISION_DEGREES
+            ),
+            None => String::default(),
+        }
+    }
+}
+
+/// Frames taken by one camera at the same pointing within one sequence
+#[derive(Debug, Clone)]
+pub struct FrameGroup {
+    /// Sol, sequence, and eye, such as `0395_ZCAM08419_L`, with a pointing number when the
+    /// sequence covers more than one pointing
+    pub name: String,
+    pub frames: Vec<FrameInfo>,
+}
+
+/// Groups frames by sol, sequence id, camera, and mast pointing. The left and right eyes
+/// share sequence ids but differ in field of view and parallax, so each gets its own group.
+/// Frames without a sequence id are grouped together.
+pub fn group_frames(frames: Vec<FrameInfo>) -> Vec<FrameGroup> {
+    let mut sequences: BTreeMap<String, BTreeMap<String, Vec<FrameInfo>>> = BTreeMap::new();
+    for frame in frames.into_iter() {
+        let sequence = format!(
+            "{:04}_{}{}",
+            frame.sol,
+            frame.sequence_id.as_deref().unwrap_or("UNKNOWN"),
+            frame.camera_key()
+        );
+        sequences
+            .entry(sequence)
+            .or_default()
+            .entry(frame.pointing_key())
+            .or_default()
+            .push(frame);
+    }
+
+    let mut groups = vec![];
+    for (sequence, pointings) in sequences.into_iter() {
+        let count = pointings.len();
+        for (index, (_, frames)) in pointings.into_iter().enumerate() {
+            groups.push(FrameGroup {
+                name: match count {
+                    1 => sequence.clone(),
+                    _ => format!("{}_p{}", sequence, index + 1),
+                },
+                frames,
+            });
+        }
+    }
+    groups
+}
+
+/// A single band image, normalized to zero mean and unit variance for registration
+struct Plane {
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+}
+
+impl Plane {
+    fn from_image(image: &Image) -> Self {
+        let bands = image.num_bands();
+        let mut values = Vec::with_capacity(image.width * image.height);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let sum: f32 = (0..bands).map(|b| image.get_band(b).get(x, y)).sum();
+                values.push(sum / bands as f32);
+            }
+        }
+
+        let count = values.len().max(1) as f32;
+        let mean = values.iter().sum::<f32>() / count;
+        let std_dev = (values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / count).sqrt();
+        let std_dev = if std_dev > 0.0 { std_dev } else { 1.0 };
+        values.iter_mut().for_each(|v| *v = (*v - mean) / std_dev);
+
+        Plane {
+            width: image.width,
+            height: image.height,
+            values,
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> f32 {
+        self.values[y * self.width + x]
+    }
+
+    /// Box filtered reduction by a whole factor
+    fn downsample(&self, factor: usize) -> Plane {
+        let (width, height) = (self.width / factor, self.height / factor);
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0.0;
+                for dy in 0..factor {
+                    for dx in 0..factor {
+                        sum += self.get(x * factor + dx, y * factor + dy);
+                    }
+                }
+                values.push(sum / (factor * factor) as f32);
+            }
+        }
+        Plane {
+            width,
+            height,
+            values,
+        }
+    }
+
+    /// Mean squared difference between this plane and `target` shifted by the offset, over
+    /// their overlap. None when they overlap by less than a quarter of this plane.
+    fn difference(&self, target: &Plane, offset: [i32; 2]) -> Option<f64> {
+        let [dx, dy] = offset;
+        let x0 = 0.max(-dx);
+        let y0 = 0.max(-dy);
+        let x1 = (self.width as i32).min(target.width as i32 - dx);
+        let y1 = (self.height as i32).min(target.height as i32 - dy);
+        if x1 <= x0 || y1 <= y0 {
+            return None;
+        }
+
+        let overlap = ((x1 - x0) * (y1 - y0)) as usize;
+        if overlap * 4 < self.width * self.height {
+            return None;
+        }
+
+        let mut sum = 0.0_f64;
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let d = self.get(x as usize, y as usize)
+                    - target.get((x + dx) as usize, (y + dy) as usize);
+                sum += (d * d) as f64;
+            }
+        }
+        Some(sum / overlap as f64)
+    }
+
+    /// The offset within `radius` of `center` with the smallest difference
+    fn best_offset(&self, target: &Plane, center: [i32; 2], radius: i32) -> Option<[i32; 2]> {
+        let mut best: Option<([i32; 2], f64)> = None;
+        for dy in center[1] - radius..=center[1] + radius {
+            for dx in center[0] - radius..=center[0] + radius {
+                if let Some(difference) = self.difference(target, [dx, dy]) {
+                    if best.map(|(_, d)| difference < d).unwrap_or(true) {
+                        best = Some(([dx, dy], difference));
+                    }
+                }
+            }
+        }
+        best.map(|(offset, _)| offset)
+    }
+}
+
+/// Finds the whole pixel translation that best aligns `target` with `reference`, searching
+/// up to `max_shift` pixels either way from `initial`. The result `[dx, dy]` maps reference
+/// pixel (x, y) to target pixel (x + dx, y + dy). Larger searches are made on reduced images
+/// first and refined at full resolution.
+pub fn register_translation(
+    reference: &Image,
+    target: &Image,
+    initial: [i32; 2],
+    max_shift: usize,
+) -> [i32; 2] {
+    let reference = Plane::from_image(reference);
+    let target = Plane::from_image(target);
+
+    let factor = if max_shift >= 16 { 4 } else { 1 };
+    let (center, radius) = match factor {
+        1 => (initial, max_shift as i32),
+        _ => {
+            let f = factor as i32;
+            let coarse = reference.downsample(factor).best_offset(
+                &target.downsample(factor),
+                [initial[0] / f, initial[1] / f],
+                max_shift as i32 / f + 1,
+            );
+            match coarse {
+                Some([dx, dy]) => ([dx * f, dy * f], f),
+                None => (initial, max_shift as i32),
+            }
+        }
+    };
+
+    reference
+        .best_offset(&target, center, radius)
+        .unwrap_or(initial)
+}
+
+/// The offset of a frame relative to the reference frame implied by their positions on the
+/// sensor, in image pixels. Only frames from the same camera share a sensor.
+fn subframe_offset(reference: &FrameInfo, frame: &FrameInfo) -> [i32; 2] {
+    match (&reference.subframe_rect, &frame.subframe_rect) {
+        (Some(r), Some(f)) if reference.instrument == frame.instrument => {
+            let scale = reference.scale_factor as f64;
+            [
+                ((r[0] - f[0]) / scale).round() as i32,
+                ((r[1] - f[1]) / scale).round() as i32,
+            ]
+        }
+        _ => [0, 0],
+    }
+}
+
+/// A band of a multispectral cube
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CubeBand {
+    /// Filter name, with the channel for bands taken from a Bayer image, such as `L0R`
+    pub name: String,
+    pub filter: String,
+
+    /// Nominal center wavelength, in nanometers
+    pub wavelength: Option<f32>,
+    pub source_file: String,
+
+    /// Position of the cube's origin in the source frame, in pixels
+    pub offset: [i32; 2],
+}
+
+/// A stack of aligned bands of one scene, with their wavelengths
+pub struct Cube {
+    pub description: String,
+    pub image: Image,
+    pub bands: Vec<CubeBand>,
+}
+
+/// Band descriptions written alongside a TIFF cube
+#[derive(Serialize, Deserialize)]
+struct CubeLabel {
+    description: String,
+    bands: Vec<CubeBand>,
+}
+
+/// Where a cube band's values come from: a frame and one of its bands, or the mean of all
+/// of its bands
+struct BandSource {
+    frame: usize,
+    band: Option<usize>,
+    info: CubeBand,
+}
+
+/// Stacks the frames into a cube covering the area they all share. Frames are placed by
+/// their position on the sensor and, with `max_shift`, registered against the first frame.
+/// Bayer frames contribute a band per channel. Narrowband frames contribute a single band,
+/// averaging their channels if they have more than one. Bands are ordered by wavelength.
+pub fn assemble_cube(
+    description: &str,
+    frames: &[(FrameInfo, MarsImage)],
+    max_shift: Option<usize>,
+) -> Result<Cube> {
+    let (reference_info, reference) = match frames.first() {
+        Some(frame) => frame,
+        None => return Err(anyhow!("No frames to assemble")),
+    };
+    if let Some((info, _)) = frames
+        .iter()
+        .find(|(info, _)| info.scale_factor != reference_info.scale_factor)
+    {
+        return Err(anyhow!(
+            "{} is downsampled by {}, the reference frame by {}",
+            path::basename(&info.file_path),
+            info.scale_factor,
+            reference_info.scale_factor
+        ));
+    }
+
+    let offsets: Vec<[i32; 2]> = frames
+        .iter()
+        .enumerate()
+        .map(|(index, (info, raw))| {
+            let initial = subframe_offset(reference_info, info);
+            match max_shift {
+                Some(max_shift) if index > 0 => {
+                    let offset =
+                        register_translation(&reference.image, &raw.image, initial, max_shift);
+                    vprintln!(
+                        "Registered {} at offset {},{}",
+                        path::basename(&info.file_path),
+                        offset[0],
+                        offset[1]
+                    );
+                    offset
+                }
+                _ => initial,
+            }
+        })
+        .collect();
+
+    // The region every frame covers, in the reference frame's pixels
+    let x0 = offsets.iter().map(|o| -o[0]).max().unwrap_or(0);
+    let y0 = offsets.iter().map(|o| -o[1]).max().unwrap_or(0);
+    let x1 = frames
+        .iter()
+        .zip(offsets.iter())
+        .map(|((_, raw), o)| raw.image.width as i32 - o[0])
+        .min()
+        .unwrap_or(0);
+    let y1 = frames
+        .iter()
+        .zip(offsets.iter())
+        .map(|((_, raw), o)| raw.image.height as i32 - o[1])
+        .min()
+        .unwrap_or(0);
+    if x1 <= x0 || y1 <= y0 {
+        return Err(anyhow!("Frames do not share a common area"));
+    }
+    let (width, height) = ((x1 - x0) as usize, (y1 - y0) as usize);
+
+    let mut sources: Vec<BandSource> = vec![];
+    for (index, (info, raw)) in frames.iter().enumerate() {
+        let filter = info.filter.clone().unwrap_or_else(|| "unknown".to_owned());
+        let offset = [x0 + offsets[index][0], y0 + offsets[index][1]];
+        let band_info = |name: String, wavelength: Option<f32>| CubeBand {
+            name,
+            filter: filter.clone(),
+            wavelength,
+            source_file: info.file_path.clone(),
+            offset,
+        };
+
+        match info.wavelengths() {
+            Some(wavelengths) if wavelengths.len() == 3 && raw.image.num_bands() >= 3 => {
+                for (band, wavelength) in wavelengths.iter().enumerate() {
+                    sources.push(BandSource {
+                        frame: index,
+                        band: Some(band),
+                        info: band_info(
+                            format!("{}{}", filter, BAYER_CHANNELS[band]),
+                            Some(*wavelength),
+                        ),
+                    });
+                }
+            }
+            Some(wavelengths) if wavelengths.len() == 1 => sources.push(BandSource {
+                frame: index,
+                band: None,
+                info: band_info(filter.clone(), Some(wavelengths[0])),
+            }),
+            _ => {
+                warn!(
+                    "No wavelength known for {}, filter {}",
+                    path::basename(&info.file_path),
+                    filter
+                );
+                for band in 0..raw.image.num_bands() {
+                    let name = match raw.image.num_bands() {
+                        1 => filter.clone(),
+                        _ => format!("{}_{}", filter, band),
+                    };
+                    sources.push(BandSource {
+                        frame: index,
+                        band: Some(band),
+                        info: band_info(name, None),
+                    });
+                }
+            }
+        }
+    }
+
+    sources.sort_by(|a, b| match (a.info.wavelength, b.info.wavelength) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+
+    let mut image = Image::new_with_bands(width, height, sources.len(), ImageMode::U16BIT)?;
+    for (cube_band, source) in sources.iter().enumerate() {
+        let frame = &frames[source.frame].1.image;
+        let [ox, oy] = source.info.offset;
+        let source_bands: Vec<usize> = match source.band {
+            Some(band) => vec![band],
+            None => (0..frame.num_bands()).collect(),
+        };
+        for y in 0..height {
+            for x in 0..width {
+                let (fx, fy) = ((x as i32 + ox) as usize, (y as i32 + oy) as usize);
+                let sum: f32 = source_bands
+                    .iter()
+                    .map(|b| frame.get_band(*b).get(fx, fy))
+                    .sum();
+                image.put(x, y, sum / source_bands.len() as f32, cube_band);
+            }
+        }
+    }
+
+    Ok(Cube {
+        description: description.to_owned(),
+        image,
+        bands: sources.into_iter().map(|s| s.info).collect(),
+    })
+}
+
+/// Cube file formats
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CubeFormat {
+    /// ENVI raster: a text `.hdr` header and a band sequential 32 bit float `.img`
+    Envi,
+
+    /// Multi-page 32 bit float TIFF, one page per band, with a `-cube.json` band description
+    Tiff,
+}
+
+impl CubeFormat {
+    /// The extension of the file a user opens
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CubeFormat::Envi => "hdr",
+            CubeFormat::Tiff => "tif",
+        }
+    }
+}
+
+impl FromStr for CubeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "envi" => Ok(CubeFormat::Envi),
+            "tiff" | "tif" => Ok(CubeFormat::Tiff),
+            _ => Err(anyhow!(
+                "Invalid cube format '{}', expected 'envi' or 'tiff'",
+                s
+            )),
+        }
+    }
+}
+
+/// The ENVI header describing the cube's layout, band names, and wavelengths. Wavelengths
+/// are only listed when every band has one.
+pub fn envi_header(cube: &Cube) -> String {
+    let mut lines = vec![
+        "ENVI".to_owned(),
+        format!("description = {{{}}}", cube.description),
+        format!("samples = {}", cube.image.width),
+        format!("lines = {}", cube.image.height),
+        format!("bands = {}", cube.bands.len()),
+        "header offset = 0".to_owned(),
+        "file type = ENVI Standard".to_owned(),
+        "data type = 4".to_owned(),
+        "interleave = bsq".to_owned(),
+        "byte order = 0".to_owned(),
+        format!(
+            "band names = {{{}}}",
+            cube.bands
+                .iter()
+                .map(|b| b.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ),
+    ];
+
+    let wavelengths: Option<Vec<f32>> = cube.bands.iter().map(|b| b.wavelength).collect();
+    if let Some(wavelengths) = wavelengths {
+        lines.push("wavelength units = Nanometers".to_owned());
+        lines.push(format!(
+            "wavelength = {{{}}}",
+            wavelengths
+                .iter()
+                .map(|w| format!("{:.1}", w))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Parses the `key = value` fields of an ENVI header. Values in braces may span lines.
+pub fn parse_envi_header(text: &str) -> Result<BTreeMap<String, String>> {
+    let mut lines = text.lines();
+    if lines.next().map(|l| l.trim()) != Some("ENVI") {
+        return Err(anyhow!("Not an ENVI header"));
+    }
+
+    let mut fields = BTreeMap::new();
+    let mut pending: Option<(String, String)> = None;
+    for line in lines {
+        if let Some((key, mut value)) = pending.take() {
+            value.push(' ');
+            value.push_str(line.trim());
+            match value.contains('}') {
+                true => {
+                    fields.insert(key, value);
+                }
+                false => pending = Some((key, value)),
+            }
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once('=') {
+            let (key, value) = (key.trim().to_lowercase(), value.trim().to_owned());
+            if value.starts_with('{') && !value.contains('}') {
+                pending = Some((key, value));
+            } else {
+                fields.insert(key, value);
+            }
+        }
+    }
+    if let Some((key, _)) = pending {
+        return Err(anyhow!("Unterminated value for {} in ENVI header", key));
+    }
+    Ok(fields)
+}
+
+/// The items of a braced ENVI list value
+fn envi_list(value: &str) -> Vec<String> {
+    value
+        .trim()
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .split(',')
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn envi_paths(file_path: &str) -> Result<(String, String)> {
+    Ok((
+        util::replace_extension(file_path, "hdr")?,
+        util::replace_extension(file_path, "img")?,
+    ))
+}
+
+/// Writes the cube as an ENVI header and band sequential data file
+pub fn save_envi(cube: &Cube, to_file: &str) -> Result<()> {
+    let (header_path, data_path) = envi_paths(to_file)?;
+    info!("Writing ENVI cube to {}", header_path);
+    fs::write(&header_path, envi_header(cube))?;
+
+    let mut writer = BufWriter::new(File::create(&data_path)?);
+    for band in 0..cube.image.num_bands() {
+        let buffer = cube.image.get_band(band);
+        for y in 0..cube.image.height {
+            for x in 0..cube.image.width {
+                writer.write_all(&buffer.get(x, y).to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads an ENVI cube of 32 bit floats in band sequential order, as written by `save_envi`.
+/// Band descriptions other than names and wavelengths are not stored in ENVI headers.
+pub fn open_envi(file_path: &str) -> Result<Cube> {
+    let (header_path, data_path) = envi_paths(file_path)?;
+    let fields = parse_envi_header(&fs::read_to_string(&header_path)?)?;
+
+    let field = |key: &str| -> Result<usize> {
+        fields
+            .get(key)
+            .ok_or_else(|| anyhow!("ENVI header is missing {}", key))?
+            .parse::<usize>()
+            .map_err(|why| anyhow!("Invalid {} in ENVI header: {}", key, why))
+    };
+    let (width, height, num_bands) = (field("samples")?, field("lines")?, field("bands")?);
+
+    if field("data type")? != 4 {
+        return Err(anyhow!(
+            "Only 32 bit floating point ENVI cubes are supported"
+        ));
+    }
+    if let Some(interleave) = fields.get("interleave") {
+        if !interleave.eq_ignore_ascii_case("bsq") {
+            return Err(anyhow!("Only band sequential ENVI cubes are supported"));
+        }
+    }
+    let big_endian = fields.get("byte order").map(|v| v.as_str()) == Some("1");
+    let offset = match fields.get("header offset") {
+        Some(_) => field("header offset")?,
+        None => 0,
+    };
+
+    let data = fs::read(&data_path)?;
+    if data.len() < offset + width * height * num_bands * 4 {
+        return Err(anyhow!("ENVI data file {} is too short", data_path));
+    }
+
+    let mut image = Image::new_with_bands(width, height, num_bands, ImageMode::U16BIT)?;
+    for (i, chunk) in data[offset..]
+        .chunks_exact(4)
+        .take(width * height * num_bands)
+        .enumerate()
+    {
+        let bytes = [chunk[0], chunk[1], chunk[2], chunk[3]];
+        let value = match big_endian {
+            true => f32::from_be_bytes(bytes),
+            false => f32::from_le_bytes(bytes),
+        };
+        let (band, pixel) = (i / (width * height), i % (width * height));
+        image.put(pixel % width, pixel / width, value, band);
+    }
+
+    let names = fields.get("band names").map(String::as_str).map(envi_list);
+    let wavelengths = fields.get("wavelength").map(String::as_str).map(envi_list);
+    let bands = (0..num_bands)
+        .map(|b| {
+            let name = names
+                .as_ref()
+                .and_then(|n| n.get(b).cloned())
+                .unwrap_or_else(|| format!("band{}", b + 1));
+            CubeBand {
+                filter: name.clone(),
+                name,
+                wavelength: wavelengths
+                    .as_ref()
+                    .and_then(|w| w.get(b))
+                    .and_then(|w| w.parse::<f32>().ok()),
+                source_file: String::default(),
+                offset: [0, 0],
+            }
+        })
+        .collect();
+
+    Ok(Cube {
+        description: fields
+            .get("description")
+            .map(|d| d.trim_start_matches('{').trim_end_matches('}').to_owned())
+            .unwrap_or_default(),
+        image,
+        bands,
+    })
+}
+
+fn label_path(file_path: &str) -> String {
+    util::replace_image_extension(file_path, "-cube.json")
+}
+
+/// Writes the cube as a multi-page floating point TIFF with a JSON band description
+pub fn save_tiff(cube: &Cube, to_file: &str) -> Result<()> {
+    floattiff::save_bands(&cube.image, to_file)?;
+    let label = CubeLabel {
+        description: cube.description.clone(),
+        bands: cube.bands.clone(),
+    };
+    fs::write(label_path(to_file), serde_json::to_string_pretty(&label)?)?;
+    Ok(())
+}
+
+/// Reads a TIFF cube and its band description
+pub fn open_tiff(file_path: &str) -> Result<Cube> {
+    let image = floattiff::open_image(file_path)?;
+    let label: CubeLabel = match fs::read_to_string(label_path(file_path)) {
+        Ok(text) => serde_json::from_str(&text)?,
+        Err(_) => {
+            warn!("No band description found for {}", file_path);
+            CubeLabel {
+                description: String::default(),
+                bands: (0..image.num_bands())
+                    .map(|b| CubeBand {
+                        name: format!("band{}", b + 1),
+                        filter: format!("band{}", b + 1),
+                        wavelength: None,
+                        source_file: String::default(),
+                        offset: [0, 0],
+                    })
+                    .collect(),
+            }
+        }
+    };
+
+    if label.bands.len() != image.num_bands() {
+        return Err(anyhow!(
+            "{} has {} bands but its description lists {}",
+            file_path,
+            image.num_bands(),
+            label.bands.len()
+        ));
+    }
+
+    Ok(Cube {
+        description: label.description,
+        image,
+        bands: label.bands,
+    })
+}
+
+pub fn save_cube(cube: &Cube, to_file: &str, format: CubeFormat) -> Result<()> {
+    match format {
+        CubeFormat::Envi => save_envi(cube, to_file),
+        CubeFormat::Tiff => save_tiff(cube, to_file),
+    }
+}
+
+/// Opens a cube, choosing the format by extension
+pub fn open_cube(file_path: &str) -> Result<Cube> {
+    let lower = file_path.to_lowercase();
+    if lower.ends_with(".hdr") || lower.ends_with(".img") {
+        open_envi(file_path)
+    } else {
+        open_tiff(file_path)
+    }
+}
+
+/// The mean value of one cube band within a region
+#[derive(Serialize, Debug, Clone)]
+pub struct SpectrumSample {
+    pub band: String,
+    pub filter: String,
+    pub wavelength: Option<f32>,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub pixels: usize,
+}
+
+/// Averages each band of the cube over a region given as [x, y, width, height], ignoring
+/// values that are not finite
+pub fn extract_spectrum(cube: &Cube, rect: [usize; 4]) -> Result<Vec<SpectrumSample>> {
+    let [x, y, width, height] = rect;
+    if width == 0 || height == 0 || x + width > cube.image.width || y + height > cube.image.height {
+        return Err(anyhow!(
+            "Region {},{} {}x{} is outside of the {}x{} cube",
+            x,
+            y,
+            width,
+            height,
+            cube.image.width,
+            cube.image.height
+        ));
+    }
+
+    let mut samples = vec![];
+    for (b, band) in cube.bands.iter().enumerate() {
+        let buffer = cube.image.get_band(b);
+        let values: Vec<f64> = (y..y + height)
+            .flat_map(|py| (x..x + width).map(move |px| (px, py)))
+            .map(|(px, py)| buffer.get(px, py) as f64)
+            .filter(|v| v.is_finite())
+            .collect();
+
+        let pixels = values.len();
+        let mean = values.iter().sum::<f64>() / pixels.max(1) as f64;
+        let variance =
+            values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / pixels.max(1) as f64;
+        samples.push(SpectrumSample {
+            band: band.name.clone(),
+            filter: band.filter.clone(),
+            wavelength: band.wavelength,
+            mean,
+            std_dev: variance.sqrt(),
+            pixels,
+        });
+    }
+    Ok(samples)
+}
+
+/// Formats a spectrum as CSV with a header row
+pub fn spectrum_to_csv(samples: &[SpectrumSample]) -> String {
+    let mut csv = String::from("band,filter,wavelength_nm,mean,std_dev,pixels\n");
+    for s in samples.iter() {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            s.band,
+            s.filter,
+            s.wavelength
+                .map(|w| format!("{:.1}", w))
+                .unwrap_or_default(),
+            s.mean,
+            s.std_dev,
+            s.pixels
+        ));
+    }
+    csv
+}
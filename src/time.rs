@@ -188,3 +188,22 @@ pub fn get_time(sol_offset: f64, longitude: f64, time_system: TimeSystem) -> Res
         earth_time_utc: Utc.timestamp_opt(seconds_since_epoch as i64, 0).unwrap(),
     })
 }
+
+/// Mars' heliocentric distance, in astronomical units, at the given time. Follows the
+/// Mars24 algorithm (Allison & McEwen, 2000).
+pub fn mars_sun_distance_au(utc: &DateTime<Utc>) -> f64 {
+    let millis = utc.timestamp_millis() as f64;
+
+    let jd_ut = 2440587.5 + (millis / 8.64E7);
+    let jd_tt = jd_ut + (constants::time::TAI_OFFSET + 32.184) / 86400.0;
+    let j2000 = jd_tt - 2451545.0;
+
+    let m = (19.3870 + 0.52402075 * j2000) % 360.0;
+
+    1.52367934
+        * (1.00436
+            - 0.09309 * cos(m)
+            - 0.004336 * cos(2.0 * m)
+            - 0.00031 * cos(3.0 * m)
+            - 0.00003 * cos(4.0 * m))
+}
@@ -0,0 +1,387 @@
+use crate::{calibfile, calprofile::CalProfile, colormatrix::ColorMatrix};
+
+use anyhow::{anyhow, Result};
+use sciimg::image::Image;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use toml::Table;
+
+/// A region of a calibration target image and its known linear reflectance
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub name: String,
+
+    /// Region of the image as [x, y, width, height]
+    pub rect: [usize; 4],
+
+    /// Reflectance of a gray or white patch, the same in every channel
+    pub reflectance: Option<f32>,
+
+    /// Per-channel linear reflectance of a colored patch
+    pub reference: Option<[f32; 3]>,
+}
+
+impl Patch {
+    /// A gray patch covering the region
+    pub fn gray(name: &str, rect: [usize; 4], reflectance: f32) -> Self {
+        Patch {
+            name: name.to_owned(),
+            rect,
+            reflectance: Some(reflectance),
+            reference: None,
+        }
+    }
+
+    /// The patch's expected red, green, and blue reflectance
+    pub fn reference(&self) -> Option<[f32; 3]> {
+        self.reference
+            .or_else(|| self.reflectance.map(|r| [r, r, r]))
+    }
+
+    /// True if the patch reflects all three channels equally
+    pub fn is_gray(&self) -> bool {
+        match self.reference() {
+            Some([r, g, b]) => r == g && g == b,
+            None => false,
+        }
+    }
+}
+
+/// The patch layout of a calibration target, loaded from a TOML file:
+///
+/// ```toml
+/// calfiletype = "caltarget"
+/// description = "Mastcam-Z calibration target at its standard pointing"
+///
+/// [[patch]]
+/// name = "white"
+/// rect = [812, 540, 40, 40]
+/// reflectance = 0.9
+///
+/// [[patch]]
+/// name = "red"
+/// rect = [900, 540, 40, 40]
+/// reference = [0.45, 0.08, 0.05]
+/// ```
+///
+/// With `relative = true`, patch rects are given in thousandths of the target's bounding box
+/// rather than in image pixels, so one layout serves any pointing of the target.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CalTarget {
+    pub calfiletype: String,
+    pub description: Option<String>,
+
+    #[serde(default)]
+    pub relative: bool,
+
+    #[serde(rename = "patch", default)]
+    pub patches: Vec<Patch>,
+}
+
+impl CalTarget {
+    /// Loads a target from a file path or by name from the calibration data directories
+    pub fn load(spec: &str) -> Result<Self> {
+        let file_path = calibfile::locate_calibration_file_no_extention(
+            &spec.to_string(),
+            &".toml".to_string(),
+        )?;
+        CalTarget::parse(&fs::read_to_string(&file_path)?)
+            .map_err(|why| anyhow!("Invalid calibration target {}: {}", file_path, why))
+    }
+
+    /// The target's patches in image pixels. Relative layouts are scaled into the target's
+    /// bounding box, given as [x, y, width, height].
+    pub fn place(&self, target_rect: Option<[usize; 4]>) -> Result<Vec<Patch>> {
+        if !self.relative {
+            return Ok(self.patches.clone());
+        }
+        let [tx, ty, tw, th] = target_rect.ok_or_else(|| {
+            anyhow!("Target layout is relative to the target; its bounding box is required")
+        })?;
+        let scale = |v: usize, size: usize| (v * size + 500) / 1000;
+        Ok(self
+            .patches
+            .iter()
+            .map(|patch| {
+                let [x, y, width, height] = patch.rect;
+                Patch {
+                    rect: [
+                        tx + scale(x, tw),
+                        ty + scale(y, th),
+                        scale(width, tw).max(1),
+                        scale(height, th).max(1),
+                    ],
+                    ..patch.clone()
+                }
+            })
+            .collect())
+    }
+
+    /// Parses and checks a target definition
+    pub fn parse(text: &str) -> Result<Self> {
+        let target: CalTarget = toml::from_str(text)?;
+        if target.calfiletype != "caltarget" {
+            return Err(anyhow!(
+                "calfiletype must be \"caltarget\", found \"{}\"",
+                target.calfiletype
+            ));
+        }
+        if let Some(patch) = target.patches.iter().find(|p| p.reference().is_none()) {
+            return Err(anyhow!(
+                "Patch {} needs a reflectance or reference",
+                patch.name
+            ));
+        }
+        Ok(target)
+    }
+}
+
+/// The mean response of an image within a patch
+#[derive(Debug, Clone)]
+pub struct PatchMeasurement {
+    pub patch: Patch,
+    pub mean: [f32; 3],
+
+    /// Number of pixels averaged. Pixels that are zero in any channel, such as masked or
+    /// clipped pixels, are excluded.
+    pub pixels: usize,
+}
+
+/// Measures the mean red, green, and blue response within the patch
+pub fn measure_patch(image: &Image, patch: &Patch) -> Result<PatchMeasurement> {
+    let [x, y, width, height] = patch.rect;
+    if image.num_bands() < 3 {
+        return Err(anyhow!(
+            "White balance requires a three band image, found {} bands",
+            image.num_bands()
+        ));
+    }
+    if width == 0 || height == 0 || x + width > image.width || y + height > image.height {
+        return Err(anyhow!(
+            "Patch {} at {},{} {}x{} is outside of the {}x{} image",
+            patch.name,
+            x,
+            y,
+            width,
+            height,
+            image.width,
+            image.height
+        ));
+    }
+
+    let mut sums = [0.0_f64; 3];
+    let mut pixels = 0;
+    for py in y..y + height {
+        for px in x..x + width {
+            let rgb = [
+                image.get_band(0).get(px, py),
+                image.get_band(1).get(px, py),
+                image.get_band(2).get(px, py),
+            ];
+            if rgb.iter().all(|v| *v > 0.0) {
+                sums.iter_mut()
+                    .zip(rgb.iter())
+                    .for_each(|(s, v)| *s += *v as f64);
+                pixels += 1;
+            }
+        }
+    }
+
+    if pixels == 0 {
+        return Err(anyhow!("Patch {} contains no valid pixels", patch.name));
+    }
+
+    Ok(PatchMeasurement {
+        patch: patch.clone(),
+        mean: sums.map(|s| (s / pixels as f64) as f32),
+        pixels,
+    })
+}
+
+/// Channel weights that best map the gray patches' responses to their reflectances, relative
+/// to green. Each channel is a least squares fit through the origin across all gray patches.
+pub fn compute_weights(measurements: &[PatchMeasurement]) -> Result<[f32; 3]> {
+    let gray: Vec<&PatchMeasurement> = measurements.iter().filter(|m| m.patch.is_gray()).collect();
+    if gray.is_empty() {
+        return Err(anyhow!("White balance requires at least one gray patch"));
+    }
+
+    let mut weights = [0.0_f64; 3];
+    for (c, weight) in weights.iter_mut().enumerate() {
+        let (numerator, denominator) = gray.iter().fold((0.0, 0.0), |(n, d), m| {
+            let measured = m.mean[c] as f64;
+            let reference = m.patch.reference().unwrap()[c] as f64;
+            (n + measured * reference, d + measured * measured)
+        });
+        if denominator <= 0.0 {
+            return Err(anyhow!("Gray patches have no response in channel {}", c));
+        }
+        *weight = numerator / denominator;
+    }
+
+    Ok([
+        (weights[0] / weights[1]) as f32,
+        1.0,
+        (weights[2] / weights[1]) as f32,
+    ])
+}
+
+/// Composes weights measured from an image calibrated with `source` with the source
+/// profile's own red, green, and blue scalars, so the derived profile replaces them rather
+/// than applying white balance twice. Radiometric output doesn't apply the scalars, so
+/// weights measured from it are returned unchanged.
+pub fn compose_weights(weights: &[f32; 3], source: &CalProfile) -> [f32; 3] {
+    if source.radiometric_units.is_some() {
+        return *weights;
+    }
+    [
+        weights[0] * source.red_scalar,
+        weights[1] * source.green_scalar,
+        weights[2] * source.blue_scalar,
+    ]
+}
+
+fn invert(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1.0e-12 {
+        return None;
+    }
+
+    let mut inverse = [[0.0; 3]; 3];
+    for (r, row) in inverse.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            // Transposed cofactor
+            let (r1, r2) = ((c + 1) % 3, (c + 2) % 3);
+            let (c1, c2) = ((r + 1) % 3, (r + 2) % 3);
+            *value = (m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]) / det;
+        }
+    }
+    Some(inverse)
+}
+
+/// Fits a color correction matrix mapping the white balanced patch responses to their
+/// reference reflectances. The responses are first scaled so that the gray patches match
+/// their reflectance, which keeps the matrix independent of exposure. Requires at least
+/// three patches whose responses are not all the same color.
+pub fn compute_color_matrix(
+    measurements: &[PatchMeasurement],
+    weights: &[f32; 3],
+) -> Result<ColorMatrix> {
+    if measurements.len() < 3 {
+        return Err(anyhow!(
+            "Fitting a color matrix requires at least three patches, found {}",
+            measurements.len()
+        ));
+    }
+
+    let balanced: Vec<[f64; 3]> = measurements
+        .iter()
+        .map(|m| [0, 1, 2].map(|c| (m.mean[c] * weights[c]) as f64))
+        .collect();
+
+    // Exposure scale from the gray patches' green response
+    let (numerator, denominator) = measurements
+        .iter()
+        .zip(balanced.iter())
+        .filter(|(m, _)| m.patch.is_gray())
+        .fold((0.0, 0.0), |(n, d), (m, b)| {
+            (
+                n + b[1] * m.patch.reference().unwrap()[1] as f64,
+                d + b[1] * b[1],
+            )
+        });
+    if denominator <= 0.0 {
+        return Err(anyhow!("Fitting a color matrix requires a gray patch"));
+    }
+    let scale = numerator / denominator;
+
+    // Normal equations: M = (sum of Y X^T)(sum of X X^T)^-1
+    let mut xx = [[0.0; 3]; 3];
+    let mut yx = [[0.0; 3]; 3];
+    for (m, b) in measurements.iter().zip(balanced.iter()) {
+        let x = b.map(|v| v * scale);
+        let y = m.patch.reference().unwrap().map(|v| v as f64);
+        for (i, (xx_row, yx_row)) in xx.iter_mut().zip(yx.iter_mut()).enumerate() {
+            for (j, (xx_value, yx_value)) in xx_row.iter_mut().zip(yx_row.iter_mut()).enumerate() {
+                *xx_value += x[i] * x[j];
+                *yx_value += y[i] * x[j];
+            }
+        }
+    }
+
+    let xx_inverse = invert(&xx).ok_or_else(|| {
+        anyhow!("Patch colors are too similar to fit a color matrix; add more colored patches")
+    })?;
+
+    let mut matrix = [[0.0_f32; 3]; 3];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| yx[i][k] * xx_inverse[k][j]).sum::<f64>() as f32;
+        }
+    }
+    Ok(ColorMatrix::new(matrix, [0.0; 3]))
+}
+
+/// Settings for the profile written from a white balance measurement
+#[derive(Debug, Clone, Default)]
+pub struct DerivedProfile {
+    pub description: String,
+    pub extends: Option<String>,
+    pub mission: Option<String>,
+    pub instrument: Option<String>,
+    pub filter_name: Option<String>,
+    pub filename_suffix: Option<String>,
+    pub weights: [f32; 3],
+    pub color_matrix: Option<ColorMatrix>,
+}
+
+impl DerivedProfile {
+    /// The profile as a TOML table holding only the derived values, so that everything else
+    /// comes from the profile it extends
+    pub fn to_table(&self) -> Table {
+        let mut table = Table::new();
+        table.insert("calfiletype".into(), "profile".into());
+        if let Some(extends) = &self.extends {
+            table.insert("extends".into(), extends.as_str().into());
+        }
+        table.insert("description".into(), self.description.as_str().into());
+        if let Some(mission) = &self.mission {
+            table.insert("mission".into(), mission.as_str().into());
+        }
+        if let Some(instrument) = &self.instrument {
+            table.insert("instrument".into(), instrument.as_str().into());
+        }
+        if let Some(filter_name) = &self.filter_name {
+            table.insert("filter_name".into(), filter_name.as_str().into());
+        }
+        if let Some(suffix) = &self.filename_suffix {
+            table.insert("filename_suffix".into(), suffix.as_str().into());
+        }
+
+        // Round to keep the file readable; far below the precision of the measurement
+        let round = |v: f32| ((v as f64) * 1.0e4).round() / 1.0e4;
+        table.insert("red_scalar".into(), round(self.weights[0]).into());
+        table.insert("green_scalar".into(), round(self.weights[1]).into());
+        table.insert("blue_scalar".into(), round(self.weights[2]).into());
+
+        if let Some(color_matrix) = &self.color_matrix {
+            table.insert(
+                "color_matrix".into(),
+                toml::Value::Array(
+                    color_matrix
+                        .matrix
+                        .iter()
+                        .map(|row| row.iter().map(|v| round(*v)).collect::<Vec<f64>>().into())
+                        .collect(),
+                ),
+            );
+        }
+        table
+    }
+
+    pub fn to_toml_string(&self) -> Result<String> {
+        Ok(toml::to_string(&self.to_table())?)
+    }
+}
@@ -0,0 +1,419 @@
+use mars_raw_utils::assemble::{
+    self, Assembly, AssemblyOptions, BlendMode, LevelAdjustment, Seam, Tile, TileRect,
+};
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::marsimage::MarsImage;
+use mars_raw_utils::serializers::as_cahvore;
+use sciimg::enums::ImageMode;
+use sciimg::image::Image;
+use std::str::FromStr;
+
+fn rect(x: usize, y: usize, width: usize, height: usize) -> TileRect {
+    TileRect {
+        x,
+        y,
+        width,
+        height,
+    }
+}
+
+/// A tile filled by `value(x, y)` in canvas coordinates at the tile's scale factor,
+/// positioned by a 1-based full resolution subframe
+fn tile<F>(subframe: [f64; 2], width: usize, height: usize, scale: u32, value: F) -> Tile
+where
+    F: Fn(usize, usize) -> f32,
+{
+    let (x0, y0) = (
+        (subframe[0] as usize - 1) / scale as usize,
+        (subframe[1] as usize - 1) / scale as usize,
+    );
+    let mut image = Image::new_with_bands(width, height, 1, ImageMode::U16BIT).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            image.put(x, y, value(x0 + x, y0 + y), 0);
+        }
+    }
+    let mut raw = MarsImage::from_image(&image, Instrument::MslNavCamLeft);
+    raw.metadata.scale_factor = scale;
+    raw.metadata.subframe_rect = Some(vec![
+        subframe[0],
+        subframe[1],
+        (width * scale as usize) as f64,
+        (height * scale as usize) as f64,
+    ]);
+    Tile::new_with_image(&raw)
+}
+
+/// A single band image filled by `value(x, y)` in canvas coordinates, for the tile at `rect`
+fn placed<F>(rect: &TileRect, value: F) -> Image
+where
+    F: Fn(usize, usize) -> f32,
+{
+    let mut image = Image::new_with_bands(rect.width, rect.height, 1, ImageMode::U16BIT).unwrap();
+    for y in 0..rect.height {
+        for x in 0..rect.width {
+            image.put(x, y, value(rect.x + x, rect.y + y), 0);
+        }
+    }
+    image
+}
+
+fn gradient(gain: f32) -> impl Fn(usize, usize) -> f32 {
+    move |x, _| (100 + x) as f32 * gain
+}
+
+#[test]
+fn test_sensor_size() {
+    assert_eq!(Instrument::MslNavCamLeft.sensor_size(), Some((1024, 1024)));
+    assert_eq!(Instrument::M20NavcamRight.sensor_size(), Some((5120, 3840)));
+    assert_eq!(Instrument::MslMastcamLeft.sensor_size(), Some((1648, 1200)));
+    assert_eq!(Instrument::None.sensor_size(), None);
+}
+
+#[test]
+fn test_tile_rect() {
+    let t = tile([513.0, 257.0], 64, 32, 1, gradient(1.0));
+    assert_eq!(t.get_tile_rect(1), Some(rect(512, 256, 64, 32)));
+
+    // At a finer composite scale factor the tile is enlarged
+    let t = tile([513.0, 257.0], 64, 32, 2, gradient(1.0));
+    assert_eq!(t.get_tile_rect(2), Some(rect(256, 128, 64, 32)));
+    assert_eq!(t.get_tile_rect(1), Some(rect(512, 256, 128, 64)));
+
+    let mut t = tile([1.0, 1.0], 8, 8, 1, gradient(1.0));
+    t.image.metadata.subframe_rect = None;
+    assert_eq!(t.get_tile_rect(1), None);
+}
+
+#[test]
+fn test_rect_union_and_intersection() {
+    let a = rect(0, 0, 100, 50);
+    let b = rect(80, 10, 100, 50);
+    assert_eq!(a.union(&b), rect(0, 0, 180, 60));
+    assert_eq!(a.intersection(&b), Some(rect(80, 10, 20, 40)));
+    assert_eq!(a.intersection(&rect(100, 0, 10, 10)), None);
+}
+
+#[test]
+fn test_seam_between() {
+    // Overlapping
+    assert_eq!(
+        assemble::seam_between(&rect(0, 0, 100, 50), &rect(80, 10, 100, 50), 12),
+        Some(Seam {
+            a: [80, 10, 20, 40],
+            b: [0, 0, 20, 40],
+        })
+    );
+
+    // Side by side, in either order
+    let left = rect(0, 0, 100, 50);
+    let right = rect(100, 20, 100, 50);
+    assert_eq!(
+        assemble::seam_between(&left, &right, 12),
+        Some(Seam {
+            a: [88, 20, 12, 30],
+            b: [0, 0, 12, 30],
+        })
+    );
+    assert_eq!(
+        assemble::seam_between(&right, &left, 12),
+        Some(Seam {
+            a: [0, 0, 12, 30],
+            b: [88, 20, 12, 30],
+        })
+    );
+
+    // One above the other
+    assert_eq!(
+        assemble::seam_between(&rect(0, 0, 100, 50), &rect(10, 50, 100, 50), 12),
+        Some(Seam {
+            a: [10, 38, 90, 12],
+            b: [0, 0, 90, 12],
+        })
+    );
+
+    // Diagonal neighbors and distant tiles don't meet
+    assert_eq!(
+        assemble::seam_between(&rect(0, 0, 100, 50), &rect(100, 50, 100, 50), 12),
+        None
+    );
+    assert_eq!(
+        assemble::seam_between(&rect(0, 0, 100, 50), &rect(300, 0, 100, 50), 12),
+        None
+    );
+}
+
+#[test]
+fn test_match_levels_and_composite() {
+    let tiles = vec![
+        tile([1.0, 1.0], 64, 48, 4, gradient(1.0)),
+        tile([241.0, 1.0], 48, 48, 4, gradient(1.5)),
+    ];
+    let (mut assembly, discarded) = Assembly::new(
+        tiles,
+        Instrument::MslNavCamLeft,
+        &AssemblyOptions::default(),
+    )
+    .unwrap();
+    assert!(discarded.is_empty());
+    assert_eq!(assembly.scale, 4);
+    assert_eq!(assembly.canvas, rect(0, 0, 256, 256));
+    assert_eq!(
+        assembly.rects,
+        vec![rect(0, 0, 64, 48), rect(60, 0, 48, 48)]
+    );
+
+    assembly.match_levels();
+
+    // The tiles are brought into agreement across their overlap
+    let left = assembly.tiles[0].image.image.get_band(0).get(63, 10);
+    let right = assembly.tiles[1].image.image.get_band(0).get(3, 10);
+    assert!((right - left).abs() < 0.5, "{} {}", left, right);
+
+    // Pasted by default, later tiles covering earlier ones
+    let composite = assembly.composite().unwrap();
+    let band = composite.get_band(0);
+    assert_eq!((composite.width, composite.height), (256, 256));
+    assert_eq!(
+        band.get(10, 10),
+        assembly.tiles[0].image.image.get_band(0).get(10, 10)
+    );
+    assert_eq!(
+        band.get(62, 10),
+        assembly.tiles[1].image.image.get_band(0).get(2, 10)
+    );
+    assert_eq!(band.get(200, 200), 0.0);
+
+    // Feathered, the overlap mixes both tiles
+    assembly.blend = BlendMode::Feather;
+    let feathered = assembly.composite().unwrap();
+    let mixed = feathered.get_band(0).get(62, 10);
+    let (a, b) = (
+        assembly.tiles[0].image.image.get_band(0).get(62, 10),
+        assembly.tiles[1].image.image.get_band(0).get(2, 10),
+    );
+    assert!(
+        mixed >= a.min(b) && mixed <= a.max(b),
+        "{} {} {}",
+        mixed,
+        a,
+        b
+    );
+    assert_eq!(feathered.get_band(0).get(10, 10), band.get(10, 10));
+}
+
+#[test]
+fn test_mixed_scale_factors() {
+    let tiles = vec![
+        tile([513.0, 513.0], 32, 32, 4, |_, _| 50.0),
+        tile([1.0, 1.0], 128, 128, 8, |_, _| 100.0),
+    ];
+    let (mut assembly, _) = Assembly::new(
+        tiles,
+        Instrument::MslNavCamLeft,
+        &AssemblyOptions::default(),
+    )
+    .unwrap();
+
+    // The downsampled full frame is enlarged to the finest scale factor and pasted first
+    assert_eq!(assembly.scale, 4);
+    assert_eq!(
+        assembly.rects,
+        vec![rect(0, 0, 256, 256), rect(128, 128, 32, 32)]
+    );
+
+    // Levels meet between the two
+    assembly.match_levels();
+    let composite = assembly.composite().unwrap();
+    let band = composite.get_band(0);
+    let (inner, outer) = (band.get(140, 140), band.get(10, 10));
+    assert!((inner - outer).abs() < 0.5, "{} {}", inner, outer);
+    assert!(inner > 50.0 && inner < 100.0, "{}", inner);
+}
+
+#[test]
+fn test_partial_tiles_and_metadata() {
+    let mut first = tile([513.0, 257.0], 32, 16, 4, gradient(1.0));
+    first.image.metadata.camera_model_component_list = as_cahvore::from_components(&[
+        vec![0.0, 0.0, 0.0],
+        vec![0.0, 0.0, 1.0],
+        vec![1000.0, 0.0, 100.0],
+        vec![0.0, 1000.0, 50.0],
+    ]);
+    let mut unplaced = tile([1.0, 1.0], 8, 8, 4, gradient(1.0));
+    unplaced.image.metadata.subframe_rect = None;
+
+    let options = AssemblyOptions {
+        crop_to_tiles: true,
+        ..Default::default()
+    };
+    let (assembly, discarded) = Assembly::new(
+        vec![
+            first,
+            unplaced,
+            tile([641.0, 257.0], 32, 16, 4, gradient(1.0)),
+        ],
+        Instrument::MslNavCamLeft,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(discarded.len(), 1);
+    assert_eq!(assembly.canvas, rect(128, 64, 64, 16));
+    assert_eq!(
+        assembly.rects,
+        vec![rect(0, 0, 32, 16), rect(32, 0, 32, 16)]
+    );
+
+    assert_eq!(
+        assembly.metadata.subframe_rect,
+        Some(vec![513.0, 257.0, 256.0, 64.0])
+    );
+    assert_eq!(assembly.metadata.scale_factor, 4);
+
+    // The first tile sits at the canvas origin so its model carries over unchanged
+    let model = &assembly.metadata.camera_model_component_list;
+    let parts = as_cahvore::components(&model.serialize());
+    assert_eq!(parts[2], vec![1000.0, 0.0, 100.0]);
+
+    // Without a known sensor size the canvas covers just the tiles
+    let (assembly, _) = Assembly::new(
+        vec![tile([641.0, 257.0], 32, 16, 4, gradient(1.0))],
+        Instrument::None,
+        &AssemblyOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(assembly.canvas, rect(160, 64, 32, 16));
+
+    assert!(Assembly::new(vec![], Instrument::None, &AssemblyOptions::default()).is_err());
+}
+
+#[test]
+fn test_transform_camera_model() {
+    let model = as_cahvore::from_components(&[
+        vec![1.0, 2.0, 3.0],
+        vec![0.0, 0.0, 1.0],
+        vec![1000.0, 0.0, 100.0],
+        vec![0.0, 1000.0, 50.0],
+    ]);
+    let transformed = assemble::transform_camera_model(&model, 2.0, [10.0, 20.0]);
+    let parts = as_cahvore::components(&transformed.serialize());
+    assert_eq!(parts[0], vec![1.0, 2.0, 3.0]);
+    assert_eq!(parts[1], vec![0.0, 0.0, 1.0]);
+    assert_eq!(parts[2], vec![2000.0, 0.0, 210.0]);
+    assert_eq!(parts[3], vec![0.0, 2000.0, 120.0]);
+}
+
+#[test]
+fn test_solve_gain_offset() {
+    let rects = [
+        rect(0, 0, 64, 32),
+        rect(56, 0, 64, 32),
+        rect(0, 32, 120, 16),
+    ];
+    let mut images = vec![
+        placed(&rects[0], gradient(1.0)),
+        placed(&rects[1], |x, y| gradient(1.5)(x, y) + 20.0),
+        placed(&rects[2], |x, y| gradient(0.8)(x, y) - 10.0),
+    ];
+    let tiles = images
+        .iter()
+        .zip(rects.iter())
+        .map(|(image, rect)| (image, *rect))
+        .collect::<Vec<(&Image, TileRect)>>();
+
+    let adjustments = assemble::solve_gain_offset(&tiles);
+    assert_eq!(adjustments.len(), 3);
+    assert_eq!(adjustments[0].len(), 1);
+
+    images
+        .iter_mut()
+        .zip(adjustments.iter())
+        .for_each(|(image, adjustment)| assemble::apply_adjustments(image, adjustment));
+
+    // Every tile agrees with the others across the seams, gradient included
+    for x in [56, 60, 63] {
+        let a = images[0].get_band(0).get(x, 10);
+        let b = images[1].get_band(0).get(x - 56, 10);
+        assert!((a - b).abs() < 0.5, "{} {} {}", x, a, b);
+    }
+    for x in [0, 60, 110] {
+        let (tile, tx) = if x < 64 { (0, x) } else { (1, x - 56) };
+        let above = images[tile].get_band(0).get(tx, 31);
+        let below = images[2].get_band(0).get(x, 0);
+        assert!((above - below).abs() < 0.5, "{} {} {}", x, above, below);
+    }
+
+    // Tiles that touch no others are left alone
+    let lone = placed(&rect(0, 0, 8, 8), gradient(1.0));
+    let adjustments = assemble::solve_gain_offset(&[(&lone, rect(0, 0, 8, 8))]);
+    assert_eq!(adjustments, vec![vec![LevelAdjustment::default()]]);
+}
+
+#[test]
+fn test_blend_mode_from_str() {
+    assert_eq!(BlendMode::from_str("hard").unwrap(), BlendMode::Hard);
+    assert_eq!(BlendMode::from_str("Feather").unwrap(), BlendMode::Feather);
+    assert_eq!(
+        BlendMode::from_str("multiband").unwrap(),
+        BlendMode::MultiBand
+    );
+    assert!(BlendMode::from_str("smooth").is_err());
+}
+
+/// Two flat tiles of different levels overlapping by 20 pixels, leaving the bottom rows of
+/// the canvas uncovered
+fn flat_tiles() -> Vec<(Image, TileRect)> {
+    let rects = [rect(0, 0, 266, 64), rect(246, 0, 266, 64)];
+    vec![
+        (placed(&rects[0], |_, _| 100.0), rects[0]),
+        (placed(&rects[1], |_, _| 200.0), rects[1]),
+    ]
+}
+
+fn blend(mode: BlendMode) -> Image {
+    let tiles = flat_tiles();
+    let tiles = tiles
+        .iter()
+        .map(|(image, rect)| (image, *rect))
+        .collect::<Vec<(&Image, TileRect)>>();
+    assemble::blend_tiles(&tiles, 512, 80, mode).unwrap()
+}
+
+#[test]
+fn test_blend_hard() {
+    let image = blend(BlendMode::Hard);
+    let band = image.get_band(0);
+    assert_eq!(band.get(250, 10), 200.0);
+    assert_eq!(band.get(245, 10), 100.0);
+    assert_eq!(band.get(250, 70), 0.0);
+}
+
+#[test]
+fn test_blend_feather() {
+    let image = blend(BlendMode::Feather);
+    let band = image.get_band(0);
+    assert_eq!(band.get(10, 10), 100.0);
+    assert_eq!(band.get(500, 10), 200.0);
+    assert_eq!(band.get(250, 70), 0.0);
+
+    // The overlap ramps from one tile to the other
+    let ramp = (246..266).map(|x| band.get(x, 32)).collect::<Vec<f32>>();
+    assert!(ramp.windows(2).all(|w| w[1] >= w[0]), "{:?}", ramp);
+    assert!(ramp[0] < 110.0 && ramp[19] > 190.0, "{:?}", ramp);
+    assert!((band.get(255, 32) - 150.0).abs() < 10.0);
+}
+
+#[test]
+fn test_blend_multiband() {
+    let image = blend(BlendMode::MultiBand);
+    let band = image.get_band(0);
+    assert!((band.get(10, 10) - 100.0).abs() < 0.5);
+    assert!((band.get(500, 10) - 200.0).abs() < 0.5);
+    assert_eq!(band.get(250, 70), 0.0);
+
+    // The step between the tiles is spread out beyond the overlap
+    let ramp = (200..312).map(|x| band.get(x, 32)).collect::<Vec<f32>>();
+    assert!(ramp.windows(2).all(|w| w[1] >= w[0] - 0.01), "{:?}", ramp);
+    let max_step = ramp.windows(2).map(|w| w[1] - w[0]).fold(0.0, f32::max);
+    assert!(max_step < 50.0, "{}", max_step);
+}
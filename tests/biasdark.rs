@@ -0,0 +1,99 @@
+use mars_raw_utils::biasdark;
+use mars_raw_utils::calibfile::{self, BiasLevel};
+use mars_raw_utils::calpipeline::{self, CalStage};
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::metadata::Metadata;
+use sciimg::enums::ImageMode;
+use sciimg::image::Image;
+
+const CALDATA: &str = r#"
+[msl]
+
+[m20.nav_left]
+bias = 133.0
+
+[m20.mastcamz_left]
+bias = "M20_MCZ_LEFT_BIAS_V1.png"
+dark = "M20_MCZ_LEFT_DARK_V1.png"
+dark_exposure = 100.0
+dark_temperature = -20.0
+
+[nsyt]
+"#;
+
+#[test]
+fn test_parse_bias_and_dark() {
+    let config = calibfile::parse_caldata_from_string(CALDATA).unwrap();
+    assert_eq!(config.m20.nav_left.bias, Some(BiasLevel::Constant(133.0)));
+    assert_eq!(config.m20.nav_left.bias_frame(), "");
+    assert_eq!(
+        config.m20.mastcamz_left.bias_frame(),
+        "M20_MCZ_LEFT_BIAS_V1.png"
+    );
+    assert_eq!(config.m20.mastcamz_left.dark, "M20_MCZ_LEFT_DARK_V1.png");
+    assert_eq!(config.m20.mastcamz_left.dark_doubling_temperature, 6.0);
+    assert_eq!(config.msl.mahli.bias, None);
+}
+
+#[test]
+fn test_dark_scale_factor() {
+    let config = calibfile::parse_caldata_from_string(CALDATA).unwrap();
+    let properties = &config.m20.mastcamz_left;
+
+    let mut metadata = Metadata::default();
+    assert_eq!(biasdark::dark_scale_factor(properties, &metadata), 1.0);
+
+    // Twice the exposure, twelve degrees (two doublings) warmer
+    metadata.exposure_duration = Some(200.0);
+    metadata.detector_temperature = Some(-8.0);
+    assert_eq!(biasdark::dark_scale_factor(properties, &metadata), 8.0);
+}
+
+#[test]
+fn test_subtract_frame() {
+    let mut image = Image::new_with_bands(4, 4, 3, ImageMode::U16BIT).unwrap();
+    let mut frame = Image::new_with_bands(4, 4, 1, ImageMode::U16BIT).unwrap();
+    for y in 0..4 {
+        for x in 0..4 {
+            for b in 0..3 {
+                image.put(x, y, 100.0, b);
+            }
+            frame.put(x, y, (x * 20) as f32, 0);
+        }
+    }
+
+    biasdark::subtract_frame(&mut image, &frame, 2.0).unwrap();
+    assert_eq!(image.get_band(0).get(0, 0), 100.0);
+    assert_eq!(image.get_band(1).get(1, 0), 60.0);
+    assert_eq!(image.get_band(2).get(3, 3), 0.0);
+
+    let small = Image::new_with_bands(2, 2, 1, ImageMode::U16BIT).unwrap();
+    assert!(biasdark::subtract_frame(&mut image, &small, 1.0).is_err());
+}
+
+#[test]
+fn test_insert_sensor_offset_stages() {
+    let mut stages = vec![CalStage::Decompand, CalStage::debayer(), CalStage::Flat];
+    calpipeline::insert_sensor_offset_stages(&mut stages, Instrument::M20NavcamLeft);
+    assert_eq!(stages[0].name(), "decompand");
+    assert_eq!(stages[1].name(), "bias_subtraction");
+
+    let mut stages = vec![CalStage::bias_subtraction(10.0), CalStage::Flat];
+    calpipeline::insert_sensor_offset_stages(&mut stages, Instrument::M20NavcamLeft);
+    assert_eq!(stages.len(), 2);
+}
+
+#[test]
+fn test_engineering_camera_bias_fallback() {
+    // Set by caldata.toml when present, otherwise by the previously built in levels
+    [
+        Instrument::M20NavcamLeft,
+        Instrument::M20NavcamRight,
+        Instrument::M20FrontHazLeft,
+        Instrument::M20FrontHazRight,
+        Instrument::M20RearHazLeft,
+        Instrument::M20RearHazRight,
+    ]
+    .iter()
+    .for_each(|instrument| assert!(biasdark::bias_for_instrument(*instrument).is_some()));
+}
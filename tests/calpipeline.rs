@@ -0,0 +1,338 @@
+use mars_raw_utils::calibrate::Calibration;
+use mars_raw_utils::calpipeline::{self, stage_dump_file_name, CalStage, PipelineContext};
+use mars_raw_utils::calprofile::{parse_calibration_profile, CalProfile};
+use mars_raw_utils::error::CalError;
+use mars_raw_utils::m20::zcam::M20MastcamZ;
+use mars_raw_utils::marsimage::MarsImage;
+use mars_raw_utils::msl::mcam::MslMastcam;
+use mars_raw_utils::prelude::{
+    calibrator_for_file, calibrator_for_instrument, Instrument, InstrumentSource,
+};
+use mars_raw_utils::radiometry::RadiometricUnits;
+use sciimg::{enums::ImageMode, image::Image};
+
+const PROFILE_WITH_PIPELINE: &str = r#"
+calfiletype = "profile"
+apply_ilt = true
+
+[[pipeline]]
+stage = "decompand"
+
+[[pipeline]]
+stage = "debayer"
+
+[[pipeline]]
+stage = "hot_pixel_correction"
+threshold = 2.5
+window_size = 5
+
+[[pipeline]]
+stage = "flat"
+
+[[pipeline]]
+stage = "crop"
+rect = [10, 20, 300, 400]
+
+[[pipeline]]
+stage = "normalize"
+"#;
+
+fn stage_names(stages: &[CalStage]) -> Vec<&'static str> {
+    stages.iter().map(|s| s.name()).collect()
+}
+
+#[test]
+fn test_parse_profile_pipeline() {
+    let profile = parse_calibration_profile(PROFILE_WITH_PIPELINE).unwrap();
+    let pipeline = profile.pipeline.expect("Pipeline not parsed");
+
+    assert_eq!(
+        stage_names(&pipeline),
+        vec![
+            "decompand",
+            "debayer",
+            "hot_pixel_correction",
+            "flat",
+            "crop",
+            "normalize"
+        ]
+    );
+
+    match &pipeline[2] {
+        CalStage::HotPixelCorrection {
+            window_size,
+            threshold,
+        } => {
+            assert_eq!(*window_size, Some(5));
+            assert_eq!(*threshold, Some(2.5));
+        }
+        _ => panic!("Expected hot pixel correction stage"),
+    }
+
+    match &pipeline[4] {
+        CalStage::Crop { rect } => assert_eq!(rect, &Some(vec![10, 20, 300, 400])),
+        _ => panic!("Expected crop stage"),
+    }
+}
+
+#[test]
+fn test_parse_profile_without_pipeline() {
+    let profile = parse_calibration_profile("calfiletype = \"profile\"\n").unwrap();
+    assert!(profile.pipeline.is_none());
+}
+
+#[test]
+fn test_parse_profile_invalid_stage() {
+    assert!(parse_calibration_profile(
+        "calfiletype = \"profile\"\n[[pipeline]]\nstage = \"sharpen\"\n"
+    )
+    .is_err());
+}
+
+#[test]
+fn test_default_pipelines() {
+    let profile = CalProfile {
+        apply_ilt: true,
+        ..Default::default()
+    };
+
+    let zcam = M20MastcamZ {};
+    assert_eq!(
+        stage_names(&zcam.default_pipeline(
+            "ZR0_0395_0702017827_081ECM_N0171064ZCAM08419_1100LMJ01.png",
+            &profile
+        )),
+        vec![
+            "decompand",
+            "debayer",
+            "flat",
+            "inpaint",
+            "weights",
+            "crop",
+            "normalize"
+        ]
+    );
+
+    // Only the product type marks a Bayer image, not the rest of the path
+    assert!(!stage_names(&zcam.default_pipeline(
+        "/data/ECM/ZR0_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ01.png",
+        &profile
+    ))
+    .contains(&"debayer"));
+
+    let mcam = MslMastcam {};
+    let no_ilt = CalProfile {
+        apply_ilt: false,
+        auto_subframing: false,
+        ..Default::default()
+    };
+    assert_eq!(
+        stage_names(&mcam.default_pipeline("0001ML0000000000000000000E01_DXXX.jpg", &no_ilt)),
+        vec!["debayer", "flat", "inpaint", "weights", "normalize"]
+    );
+
+    // Radiometric output always starts from decompanded DN
+    let radiance = CalProfile {
+        apply_ilt: false,
+        auto_subframing: false,
+        radiometric_units: Some(RadiometricUnits::Radiance),
+        ..Default::default()
+    };
+    assert_eq!(
+        stage_names(&zcam.default_pipeline(
+            "ZR0_0395_0702017827_081ECM_N0171064ZCAM08419_1100LMJ01.png",
+            &radiance
+        )),
+        vec!["decompand", "debayer", "flat", "inpaint", "radiometric"]
+    );
+}
+
+#[test]
+fn test_default_pipelines_honor_noise_reduction() {
+    let profile = CalProfile {
+        hot_pixel_detection_threshold: 2.5,
+        color_noise_reduction: true,
+        color_noise_reduction_amount: 5,
+        ..Default::default()
+    };
+
+    vec![
+        Instrument::MslMAHLI,
+        Instrument::MslMastcamLeft,
+        Instrument::MslNavCamRight,
+        Instrument::MslMARDI,
+        Instrument::MslChemCam,
+        Instrument::M20MastcamZLeft,
+        Instrument::M20NavcamLeft,
+        Instrument::M20Watson,
+        Instrument::M20SuperCam,
+        Instrument::M20Pixl,
+        Instrument::M20SkyCam,
+        Instrument::M20HeliNav,
+        Instrument::M20HeliRte,
+        Instrument::M20SherlocAci,
+        Instrument::M20CacheCam,
+        Instrument::M20EdlRdcam,
+        Instrument::NsytICC,
+        Instrument::NsytIDC,
+    ]
+    .into_iter()
+    .for_each(|instrument| {
+        let cal = calibrator_for_instrument(instrument).unwrap();
+        let names = stage_names(&cal.calibrator.default_pipeline("test.png", &profile));
+        assert!(
+            names.contains(&"hot_pixel_correction"),
+            "{:?} missing hot pixel correction",
+            instrument
+        );
+        assert!(
+            names.contains(&"color_noise_reduction"),
+            "{:?} missing color noise reduction",
+            instrument
+        );
+    });
+
+    let cal = calibrator_for_instrument(Instrument::MslMastcamLeft).unwrap();
+    let names = stage_names(
+        &cal.calibrator
+            .default_pipeline("test.png", &CalProfile::default()),
+    );
+    assert!(!names.contains(&"hot_pixel_correction"));
+    assert!(!names.contains(&"color_noise_reduction"));
+}
+
+#[test]
+fn test_dump_stages() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_file = dir
+        .path()
+        .join("ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01.png");
+    std::fs::copy(
+        "tests/testdata/ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01.png",
+        &input_file,
+    )
+    .unwrap();
+    let input_file = input_file.to_str().unwrap();
+
+    let stages = vec![CalStage::crop(0, 0, 64, 64), CalStage::normalize()];
+    let profile = CalProfile {
+        filename_suffix: "dbg".to_string(),
+        pipeline: Some(stages.clone()),
+        dump_stages: true,
+        ..Default::default()
+    };
+
+    let zcam = M20MastcamZ {};
+    let result = zcam
+        .process_with_profile(input_file, Instrument::M20MastcamZLeft, false, &profile)
+        .unwrap();
+    assert!(result.report.warnings.is_empty());
+
+    stages.iter().enumerate().for_each(|(i, stage)| {
+        let dump_file = stage_dump_file_name(input_file, &profile, i + 1, stage);
+        assert!(dump_file.ends_with(&format!("-dbg-stage{:02}-{}.png", i + 1, stage.name())));
+        assert!(std::path::Path::new(&dump_file).exists());
+
+        let sidecar: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(dump_file.replace(".png", "-stage.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(sidecar["stage"]["stage"], stage.name());
+        assert_eq!(sidecar["stage_number"], i + 1);
+    });
+}
+
+#[test]
+fn test_calibrate_file_identified_by_label() {
+    // Not named for its product id, so only the label identifies the camera
+    let dir = tempfile::tempdir().unwrap();
+    let input_file = dir.path().join("navcam_frame.jpg");
+    std::fs::copy(
+        "tests/testdata/NRB_670586006EDR_S0871444NCAM00545M_.jpg",
+        &input_file,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("navcam_frame.LBL"),
+        "PDS_VERSION_ID = PDS3\nINSTRUMENT_HOST_ID = MSL\nINSTRUMENT_ID = NAV_RIGHT_B\n",
+    )
+    .unwrap();
+    let input_file = input_file.to_str().unwrap();
+
+    let (cal, instrument, source) = calibrator_for_file(input_file, &None).unwrap();
+    assert_eq!(instrument, Instrument::MslNavCamRight);
+    assert_eq!(source, InstrumentSource::Label);
+    assert!(cal.calibrator.instrument_for_file(input_file).is_err());
+
+    let profile = CalProfile {
+        filename_suffix: "lbl".to_string(),
+        pipeline: Some(vec![CalStage::crop(0, 0, 64, 64), CalStage::normalize()]),
+        ..Default::default()
+    };
+    let plan = cal
+        .calibrator
+        .plan_file(input_file, instrument, &profile)
+        .unwrap();
+    assert_eq!(plan.instrument, "MslNavCamRight");
+
+    let result = cal
+        .calibrator
+        .process_with_profile(input_file, instrument, false, &profile)
+        .unwrap();
+    assert_eq!(result.report.instrument, Some("MslNavCamRight".to_string()));
+    assert!(std::path::Path::new(&result.report.output_file.unwrap()).exists());
+}
+
+#[test]
+fn test_crop_outside_image() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_file = dir
+        .path()
+        .join("ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01.png");
+    std::fs::copy(
+        "tests/testdata/ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01.png",
+        &input_file,
+    )
+    .unwrap();
+    let input_file = input_file.to_str().unwrap();
+
+    let profile = CalProfile {
+        filename_suffix: "crop".to_string(),
+        pipeline: Some(vec![
+            CalStage::crop(16, 0, usize::MAX, 64),
+            CalStage::normalize(),
+        ]),
+        ..Default::default()
+    };
+
+    let err = M20MastcamZ {}
+        .process_with_profile(input_file, Instrument::M20MastcamZLeft, false, &profile)
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<CalError>(),
+        Some(CalError::InvalidCrop { .. })
+    ));
+}
+
+#[test]
+fn test_crop_border_too_small() {
+    let profile = CalProfile::default();
+    let mut context = PipelineContext::new("thumbnail.png", &profile);
+
+    let image = Image::new_with_bands(40, 16, 3, ImageMode::U16BIT).unwrap();
+    let mut raw = MarsImage::from_image(&image, Instrument::M20MastcamZLeft);
+    assert!(!calpipeline::crop_border(
+        &mut raw,
+        &mut context,
+        29,
+        9,
+        29,
+        9
+    ));
+    assert_eq!((raw.image.width, raw.image.height), (40, 16));
+    assert_eq!(context.report.warnings.len(), 1);
+
+    assert!(calpipeline::crop_border(&mut raw, &mut context, 2, 2, 2, 2));
+    assert_eq!((raw.image.width, raw.image.height), (36, 12));
+    assert_eq!(context.report.warnings.len(), 1);
+}
@@ -0,0 +1,73 @@
+use mars_raw_utils::metadata::Metadata;
+use mars_raw_utils::prelude::*;
+
+const ZCAM_FILE: &str = "tests/testdata/ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01.png";
+
+fn test_profile() -> CalProfile {
+    CalProfile {
+        filename_suffix: "-rjcal".to_string(),
+        apply_ilt: true,
+        auto_subframing: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_plan_border_crop() {
+    let mut plan = CalPlan::new(
+        "missing.png",
+        &test_profile(),
+        Instrument::M20NavcamLeft,
+        Metadata::default(),
+    );
+    assert_eq!(plan.width, None);
+    plan.add_border_crop(2, 2, 2, 2);
+    assert!(plan.crops.is_empty());
+    assert_eq!(plan.notes.len(), 1);
+
+    plan.width = Some(1288);
+    plan.height = Some(968);
+    plan.add_border_crop(2, 2, 2, 2);
+    assert_eq!(plan.crops, vec![[2, 2, 1284, 964]]);
+    assert_eq!(plan.width, Some(1284));
+
+    plan.set_calibration_file(CalFileType::FlatField, "/data/flat.png");
+    assert_eq!(plan.flat, Some("/data/flat.png".to_string()));
+    assert!(plan.is_complete());
+}
+
+#[test]
+fn test_plan_zcam_file() {
+    let calibrator = calibrator_for_instrument(Instrument::M20MastcamZLeft).unwrap();
+    let plan = calibrator
+        .calibrator
+        .plan_file(ZCAM_FILE, Instrument::M20MastcamZLeft, &test_profile())
+        .unwrap();
+
+    let (width, height) = image::image_dimensions(ZCAM_FILE).unwrap();
+
+    assert_eq!(plan.instrument, "M20MastcamZLeft");
+    assert!(plan.output_file.ends_with("-rjcal.png"));
+    assert_eq!(plan.stages[0], "decompand");
+    assert!(plan.stages.contains(&"flat".to_string()));
+    assert!(plan.lut.is_some());
+    assert_eq!(
+        plan.crops,
+        vec![[29, 9, width as usize - 58, height as usize - 18]]
+    );
+}
+
+#[test]
+fn test_plan_missing_input() {
+    let calibrator = calibrator_for_instrument(Instrument::M20MastcamZLeft).unwrap();
+    let plan = calibrator
+        .calibrator
+        .plan_file(
+            "ZL0_NOT_A_REAL_FILE.png",
+            Instrument::M20MastcamZLeft,
+            &test_profile(),
+        )
+        .unwrap();
+    assert!(!plan.is_complete());
+    assert!(plan.crops.is_empty());
+}
@@ -0,0 +1,178 @@
+use mars_raw_utils::calprofile::{
+    instruments_for_name, load_calibration_profile, resolve_calibration_profile, validate_profile,
+    CalProfile, ProfileLayers, COMMAND_LINE_SOURCE, DEFAULT_SOURCE, PROFILE_FIELDS,
+};
+use mars_raw_utils::enums::{Instrument, Mission};
+use std::fs;
+use std::path::Path;
+
+const BASE_PROFILE: &str = r#"
+calfiletype = "profile"
+apply_ilt = true
+red_scalar = 1.2
+green_scalar = 0.9
+description = "Base profile"
+"#;
+
+const CHILD_PROFILE: &str = r#"
+calfiletype = "profile"
+extends = "base"
+green_scalar = 1.1
+filename_suffix = "child"
+"#;
+
+const TWEAK_PROFILE: &str = r#"
+calfiletype = "profile"
+red_scalar = 1.5
+"#;
+
+fn write_profile(dir: &Path, name: &str, text: &str) -> String {
+    let file_path = dir.join(format!("{}.toml", name));
+    fs::write(&file_path, text).unwrap();
+    file_path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_profile_extends() {
+    let dir = tempfile::tempdir().unwrap();
+    write_profile(dir.path(), "base", BASE_PROFILE);
+    let child = write_profile(dir.path(), "child", CHILD_PROFILE);
+
+    let profile = load_calibration_profile(&child).unwrap();
+    assert!(profile.apply_ilt);
+    assert_eq!(profile.red_scalar, 1.2);
+    assert_eq!(profile.green_scalar, 1.1);
+    assert_eq!(profile.filename_suffix, "child");
+    assert_eq!(profile.description, Some("Base profile".to_string()));
+
+    let resolved = resolve_calibration_profile(&child).unwrap();
+    assert_eq!(resolved.source_of("red_scalar"), "base");
+    assert_eq!(resolved.source_of("green_scalar"), child);
+    assert_eq!(resolved.source_of("blue_scalar"), DEFAULT_SOURCE);
+}
+
+#[test]
+fn test_profile_stacking_and_overrides() {
+    let dir = tempfile::tempdir().unwrap();
+    write_profile(dir.path(), "base", BASE_PROFILE);
+    let child = write_profile(dir.path(), "child", CHILD_PROFILE);
+    let tweak = write_profile(dir.path(), "tweak", TWEAK_PROFILE);
+
+    let mut layers = ProfileLayers::from_spec(&format!("{}+{}", child, tweak)).unwrap();
+    let resolved = layers.resolve().unwrap();
+    assert_eq!(resolved.profile.red_scalar, 1.5);
+    assert_eq!(resolved.profile.green_scalar, 1.1);
+    assert_eq!(resolved.source_of("red_scalar"), tweak);
+
+    layers.set_override_from_str("blue_scalar=0.7").unwrap();
+    layers
+        .set_override_from_str("filename_suffix=tweaked")
+        .unwrap();
+    layers.set_override("red_scalar", 2.0).unwrap();
+    let resolved = layers.resolve().unwrap();
+    assert_eq!(resolved.profile.blue_scalar, 0.7);
+    assert_eq!(resolved.profile.red_scalar, 2.0);
+    assert_eq!(resolved.profile.filename_suffix, "tweaked");
+    assert_eq!(resolved.source_of("blue_scalar"), COMMAND_LINE_SOURCE);
+    assert_eq!(resolved.source_of("red_scalar"), COMMAND_LINE_SOURCE);
+
+    assert!(layers.set_override_from_str("blue_scalar").is_err());
+}
+
+#[test]
+fn test_profile_extends_cycle() {
+    let dir = tempfile::tempdir().unwrap();
+    write_profile(
+        dir.path(),
+        "a",
+        "calfiletype = \"profile\"\nextends = \"b\"\n",
+    );
+    let b = write_profile(
+        dir.path(),
+        "b",
+        "calfiletype = \"profile\"\nextends = \"a\"\n",
+    );
+    assert!(load_calibration_profile(&b).is_err());
+}
+
+#[test]
+fn test_profile_validation_unknown_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let base = write_profile(dir.path(), "base", BASE_PROFILE);
+    assert!(ProfileLayers::from_spec(&base)
+        .unwrap()
+        .validate()
+        .is_empty());
+
+    let typo = write_profile(
+        dir.path(),
+        "typo",
+        "calfiletype = \"profile\"\nred_scaler = 1.2\n\n[[pipeline]]\nstage = \"hot_pixel_correction\"\nwindow = 5\n",
+    );
+    let layers = ProfileLayers::from_spec(&typo).unwrap();
+
+    // Unknown keys are ignored by a normal load
+    assert!(layers.resolve().is_ok());
+
+    let issues = layers.validate();
+    assert_eq!(issues.len(), 2);
+    assert!(issues[0].contains("red_scaler"));
+    assert!(issues[0].contains("red_scalar"));
+    assert!(issues[1].contains("window"));
+    assert!(layers.resolve_strict().is_err());
+}
+
+#[test]
+fn test_profile_validation_values() {
+    let profile = CalProfile {
+        red_scalar: 0.0,
+        hot_pixel_window_size: 0,
+        mission: Some("Mars2020".to_string()),
+        instrument: Some("WATSON".to_string()),
+        ..Default::default()
+    };
+    let issues = validate_profile(&profile);
+    assert_eq!(issues.len(), 2);
+    assert!(issues[0].starts_with("red_scalar"));
+    assert!(issues[1].starts_with("hot_pixel_window_size"));
+
+    let profile = CalProfile {
+        mission: Some("Mars2020".to_string()),
+        instrument: Some("MAHLI".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(validate_profile(&profile).len(), 1);
+
+    let profile = CalProfile {
+        mission: Some("Phobos".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(validate_profile(&profile).len(), 1);
+}
+
+#[test]
+fn test_profile_instruments() {
+    assert_eq!(
+        instruments_for_name("MASTCAM", Some(Mission::Mars2020)).unwrap(),
+        vec![Instrument::M20MastcamZLeft, Instrument::M20MastcamZRight]
+    );
+    assert_eq!(
+        instruments_for_name("mahli", None).unwrap(),
+        vec![Instrument::MslMAHLI]
+    );
+    assert!(instruments_for_name("NOT_A_CAMERA", None).is_err());
+}
+
+#[test]
+fn test_profile_fields_complete() {
+    let table = toml::Table::try_from(&CalProfile::default()).unwrap();
+    for field in table.keys() {
+        assert!(PROFILE_FIELDS.contains(&field.as_str()), "{}", field);
+    }
+
+    // Optional fields are left out when serialized but are still known
+    for field in ["extends", "pipeline", "radiometric_units", "color_offset"] {
+        assert!(PROFILE_FIELDS.contains(&field), "{}", field);
+    }
+    assert!(!PROFILE_FIELDS.contains(&"red_scaler"));
+}
@@ -0,0 +1,86 @@
+use mars_raw_utils::calpipeline::PipelineContext;
+use mars_raw_utils::calprofile::CalProfile;
+use mars_raw_utils::calreport::{self, CalReport};
+use mars_raw_utils::enums::CalFileType;
+use std::fs;
+
+fn sample_report(input_file: &str) -> CalReport {
+    let profile = CalProfile {
+        filename_suffix: "-rjcal".to_string(),
+        ..Default::default()
+    };
+    let mut report = CalReport::new(input_file, &profile);
+    report.add_calibration_file(CalFileType::FlatField, "/data/flat.png");
+    report.add_calibration_file(CalFileType::FlatField, "/data/flat.png");
+    report.add_calibration_file(CalFileType::Lut, "/data/lut.txt");
+    report.add_crop(3, 3, 1018, 1018);
+    report.add_stage_timing("flat", 12.5);
+    report.set_status("ok");
+    report
+}
+
+#[test]
+fn test_report_contents() {
+    let report = sample_report("test.png");
+    assert_eq!(report.filename_suffix, "-rjcal");
+    assert_eq!(report.calibration_files.len(), 2);
+    assert_eq!(report.calibration_files[0].file_type, "flat");
+    assert_eq!(report.calibration_files[0].weight, None);
+    assert_eq!(report.crops, vec![[3, 3, 1018, 1018]]);
+    assert_eq!(report.status, "ok");
+}
+
+#[test]
+fn test_report_weighted_files() {
+    let mut report = sample_report("test.png");
+    report.add_weighted_calibration_file(CalFileType::FlatField, "/data/flat_2448.png", 0.75);
+    report.add_weighted_calibration_file(CalFileType::FlatField, "/data/flat_3834.png", 0.25);
+    assert_eq!(report.calibration_files.len(), 4);
+    assert_eq!(report.calibration_files[3].weight, Some(0.25));
+
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["calibration_files"][2]["weight"], 0.75);
+    assert!(json["calibration_files"][0].get("weight").is_none());
+}
+
+#[test]
+fn test_report_failure() {
+    let mut report = sample_report("test.png");
+    report.set_error("File not found: test.png");
+    assert_eq!(report.status, "fail");
+    assert_eq!(report.error, Some("File not found: test.png".to_string()));
+}
+
+#[test]
+fn test_pipeline_context_warnings() {
+    let profile = CalProfile::default();
+    let mut context = PipelineContext::new("test.png", &profile);
+    assert!(!context.has_warnings());
+    context.add_warning("Inpainting not supported");
+    assert!(context.has_warnings());
+    assert_eq!(context.report.warnings, vec!["Inpainting not supported"]);
+}
+
+#[test]
+fn test_save_reports() {
+    let dir = tempfile::tempdir().unwrap();
+    let reports = vec![sample_report("a.png"), sample_report("b.png")];
+
+    let jsonl_path = dir.path().join("report.jsonl");
+    calreport::save_reports(jsonl_path.to_str().unwrap(), &reports).unwrap();
+    let jsonl = fs::read_to_string(&jsonl_path).unwrap();
+    let lines: Vec<serde_json::Value> = jsonl
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1]["input_file"], "b.png");
+    assert_eq!(lines[0]["stages"][0]["stage"], "flat");
+
+    let json_path = dir.path().join("report.json");
+    calreport::save_reports(json_path.to_str().unwrap(), &reports).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 2);
+    assert_eq!(json[0]["calibration_files"][1]["file_type"], "lut");
+}
@@ -0,0 +1,145 @@
+use mars_raw_utils::calibfile;
+use mars_raw_utils::calpipeline::{self, CalStage, PipelineContext};
+use mars_raw_utils::calprofile::{validate_profile, CalProfile};
+use mars_raw_utils::colormatrix::{self, ColorMatrix};
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::error::CalError;
+use mars_raw_utils::marsimage::MarsImage;
+use sciimg::enums::ImageMode;
+use sciimg::image::Image;
+
+const CALDATA: &str = r#"
+[msl]
+
+[m20.watson]
+color_matrix = [[1.2, -0.1, -0.1], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+color_offset = [0.01, 0.0, 0.0]
+
+[nsyt]
+"#;
+
+#[test]
+fn test_no_builtin_color_matrices() {
+    // Nothing is built in, and the shipped calibration data sets no matrices
+    for instrument in [
+        Instrument::MslMastcamLeft,
+        Instrument::MslMAHLI,
+        Instrument::M20MastcamZLeft,
+        Instrument::M20Watson,
+    ] {
+        assert!(colormatrix::color_matrix_for_instrument(instrument).is_none());
+    }
+    assert!(!CalProfile::default().apply_color_matrix);
+}
+
+#[test]
+fn test_parse_color_matrix() {
+    let config = calibfile::parse_caldata_from_string(CALDATA).unwrap();
+    assert_eq!(
+        config.m20.watson.color_matrix,
+        Some([[1.2, -0.1, -0.1], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+    );
+    assert_eq!(config.m20.watson.color_offset, Some([0.01, 0.0, 0.0]));
+    assert_eq!(config.m20.mastcamz_left.color_matrix, None);
+}
+
+#[test]
+fn test_resolve_color_matrix() {
+    let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    let profile = CalProfile {
+        color_matrix: Some(identity),
+        color_offset: Some([0.0, 0.1, 0.0]),
+        ..Default::default()
+    };
+
+    let (color_matrix, source) =
+        colormatrix::resolve_color_matrix(&None, &None, &profile, Instrument::M20NavcamLeft)
+            .unwrap();
+    assert_eq!(source, "profile");
+    assert_eq!(color_matrix, ColorMatrix::new(identity, [0.0, 0.1, 0.0]));
+
+    let swap = [[0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+    let (color_matrix, source) =
+        colormatrix::resolve_color_matrix(&Some(swap), &None, &profile, Instrument::MslMAHLI)
+            .unwrap();
+    assert_eq!(source, "stage");
+    assert_eq!(color_matrix.matrix, swap);
+
+    assert!(colormatrix::resolve_color_matrix(
+        &None,
+        &None,
+        &CalProfile::default(),
+        Instrument::NsytIDC
+    )
+    .is_none());
+}
+
+#[test]
+fn test_apply_color_matrix() {
+    let color_matrix = ColorMatrix::new(
+        [[0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.5, 0.0, 0.5]],
+        [0.0, 0.0, -0.5],
+    );
+
+    let mut image = Image::new_with_bands(2, 2, 3, ImageMode::U16BIT).unwrap();
+    for y in 0..2 {
+        for x in 0..2 {
+            image.put(x, y, 10.0, 0);
+            image.put(x, y, 20.0, 1);
+            image.put(x, y, 30.0, 2);
+        }
+    }
+    colormatrix::apply_color_matrix(&mut image, &color_matrix, 100.0).unwrap();
+    assert_eq!(image.get_band(0).get(1, 1), 20.0);
+    assert_eq!(image.get_band(1).get(1, 1), 10.0);
+    assert_eq!(image.get_band(2).get(1, 1), 0.0);
+
+    let mut mono = Image::new_with_bands(2, 2, 1, ImageMode::U16BIT).unwrap();
+    assert!(colormatrix::apply_color_matrix(&mut mono, &color_matrix, 100.0).is_err());
+}
+
+#[test]
+fn test_color_matrix_stage_order() {
+    let profile = CalProfile {
+        apply_color_matrix: true,
+        srgb_color_correction: true,
+        ..Default::default()
+    };
+    let mut stages = vec![];
+    calpipeline::push_color_output(&mut stages, &profile);
+    let names: Vec<&str> = stages.iter().map(CalStage::name).collect();
+    assert_eq!(names, vec!["color_matrix", "srgb_conversion", "normalize"]);
+
+    let singular = CalProfile {
+        color_matrix: Some([[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 0.0, 1.0]]),
+        ..Default::default()
+    };
+    assert_eq!(
+        validate_profile(&singular),
+        vec!["color_matrix is singular"]
+    );
+}
+
+#[test]
+fn test_missing_color_matrix_fails() {
+    let profile = CalProfile {
+        apply_color_matrix: true,
+        ..Default::default()
+    };
+    let mut context = PipelineContext::new("mastcam.png", &profile);
+
+    let image = Image::new_with_bands(4, 4, 3, ImageMode::U16BIT).unwrap();
+    let mut raw = MarsImage::from_image(&image, Instrument::MslMastcamLeft);
+    let err =
+        calpipeline::apply_stage(&CalStage::color_matrix(), &mut raw, &mut context).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<CalError>(),
+        Some(CalError::MissingColorMatrix(Instrument::MslMastcamLeft))
+    ));
+
+    // Single channel images are skipped whether or not a matrix is known
+    let mono = Image::new_with_bands(4, 4, 1, ImageMode::U16BIT).unwrap();
+    let mut raw = MarsImage::from_image(&mono, Instrument::MslMastcamLeft);
+    calpipeline::apply_stage(&CalStage::color_matrix(), &mut raw, &mut context).unwrap();
+    assert_eq!(context.report.warnings.len(), 1);
+}
@@ -0,0 +1,235 @@
+use mars_raw_utils::composite::{
+    self, CompositeOptions, Interpolation, MapContext, MapOptions, MosaicAccumulator, Projection,
+};
+use mars_raw_utils::enums::{Eye, Instrument};
+use mars_raw_utils::marsimage::MarsImage;
+use mars_raw_utils::serializers::as_cahvore;
+use sciimg::enums::ImageMode;
+use sciimg::image::Image;
+use sciimg::vector::Vector;
+use std::str::FromStr;
+
+fn image_of<F>(width: usize, height: usize, value: F) -> Image
+where
+    F: Fn(usize, usize) -> f32,
+{
+    let mut image = Image::new_with_bands(width, height, 1, ImageMode::U16BIT).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            image.put(x, y, value(x, y), 0);
+        }
+    }
+    image
+}
+
+#[test]
+fn test_interpolation_from_str() {
+    assert_eq!(
+        Interpolation::from_str("Bilinear").unwrap(),
+        Interpolation::Bilinear
+    );
+    assert_eq!(
+        Interpolation::from_str("bicubic").unwrap(),
+        Interpolation::Bicubic
+    );
+    assert_eq!(
+        Interpolation::from_str("nearest").unwrap(),
+        Interpolation::Nearest
+    );
+    assert!(Interpolation::from_str("lanczos").is_err());
+}
+
+#[test]
+fn test_interpolate() {
+    let image = image_of(6, 6, |x, y| (x * 10 + y) as f32);
+    let band = image.get_band(0);
+    let mut valid = vec![true; 36];
+
+    let sample = |valid: &[bool], x: f64, y: f64, interpolation: Interpolation| {
+        composite::interpolate(band, valid, x, y, interpolation)
+    };
+
+    assert_eq!(sample(&valid, 1.4, 2.6, Interpolation::Nearest), Some(13.0));
+    assert_eq!(
+        sample(&valid, 1.5, 2.25, Interpolation::Bilinear),
+        Some(17.25)
+    );
+
+    // Catmull-Rom reproduces a linear ramp exactly
+    let value = sample(&valid, 2.5, 2.25, Interpolation::Bicubic).unwrap();
+    assert!((value - 27.25).abs() < 1e-4, "{}", value);
+
+    assert_eq!(sample(&valid, -1.0, 0.0, Interpolation::Bilinear), None);
+    assert_eq!(sample(&valid, 0.0, 5.6, Interpolation::Bilinear), None);
+
+    // Invalid pixels are never sampled, falling back to nearest where they would be
+    valid[2 * 6 + 2] = false;
+    assert_eq!(
+        sample(&valid, 1.4, 1.4, Interpolation::Bilinear),
+        Some(11.0)
+    );
+    assert_eq!(sample(&valid, 1.4, 1.4, Interpolation::Bicubic), Some(11.0));
+    assert_eq!(sample(&valid, 1.6, 1.6, Interpolation::Bilinear), None);
+}
+
+#[test]
+fn test_edge_distance() {
+    let distance = composite::edge_distance(&[true; 25], 5, 5);
+    assert_eq!(distance[0], 1.0);
+    assert_eq!(distance[5 + 1], 2.0);
+    assert_eq!(distance[2 * 5 + 2], 3.0);
+
+    let mut valid = [true; 25];
+    valid[2 * 5 + 2] = false;
+    let distance = composite::edge_distance(&valid, 5, 5);
+    assert_eq!(distance[2 * 5 + 2], 0.0);
+    assert_eq!(distance[2 * 5 + 1], 1.0);
+    assert_eq!(distance[5 + 1], 2.0);
+}
+
+#[test]
+fn test_projection_from_str() {
+    assert_eq!(
+        Projection::from_str("Cylindrical").unwrap(),
+        Projection::Cylindrical
+    );
+    assert_eq!(Projection::from_str("polar").unwrap(), Projection::Polar);
+    assert_eq!(
+        Projection::from_str("perspective").unwrap(),
+        Projection::Perspective
+    );
+    assert_eq!(Projection::default(), Projection::Equirectangular);
+    assert!(Projection::from_str("fisheye").is_err());
+}
+
+fn bounded_context(projection: Projection) -> MapContext {
+    let options = MapOptions {
+        projection,
+        azimuth_rotation: 30.0,
+        azimuth_bounds: Some([150.0, 230.0]),
+        elevation_bounds: Some([-40.0, 30.0]),
+        degrees_per_pixel: Some(0.5),
+    };
+    let mut context = MapContext::new(&Vector::new(1.0, 2.0, 0.5), &options);
+    context.finalize(&options);
+    context
+}
+
+#[test]
+fn test_map_round_trip() {
+    for projection in [
+        Projection::Equirectangular,
+        Projection::Cylindrical,
+        Projection::Mercator,
+        Projection::Polar,
+        Projection::Perspective,
+    ] {
+        let context = bounded_context(projection);
+        assert!(context.width > 100 && context.height > 100, "{:?}", context);
+
+        let (w, h) = (context.width, context.height);
+        for (x, y) in [(10, 20), (w / 2 + 30, h - 5), (w - 1, h - 1)] {
+            let direction = context.map_to_direction(x, y).unwrap();
+            let (mx, my) = context.direction_to_map(&direction).unwrap();
+            assert!(
+                (mx - (x as f64 + 0.5)).abs() < 1e-6,
+                "{:?} {} {}",
+                projection,
+                x,
+                mx
+            );
+            assert!(
+                (my - (y as f64 + 0.5)).abs() < 1e-6,
+                "{:?} {} {}",
+                projection,
+                y,
+                my
+            );
+        }
+    }
+
+    // Equirectangular maps are 0.5 degrees per pixel across the bounds
+    let context = bounded_context(Projection::Equirectangular);
+    assert_eq!((context.width, context.height), (160, 140));
+
+    // The polar projection leaves out elevations below the bounds around the nadir
+    let context = bounded_context(Projection::Polar);
+    assert!(context
+        .map_to_direction(context.width / 2, context.height / 2)
+        .is_none());
+}
+
+#[test]
+fn test_perspective_output_model() {
+    let context = bounded_context(Projection::Perspective);
+    let model = context.output_model().unwrap();
+    assert!(bounded_context(Projection::Polar).output_model().is_none());
+
+    // The model projects each map pixel's direction back onto that pixel
+    let origin = Vector::new(1.0, 2.0, 0.5);
+    for (x, y) in [(0, 0), (context.width / 2, context.height / 3)] {
+        let d = context.map_to_direction(x, y).unwrap();
+        let point = Vector::new(
+            origin.x + d.x * 10.0,
+            origin.y + d.y * 10.0,
+            origin.z + d.z * 10.0,
+        );
+        let ls = model.xyz_to_ls(&point, false);
+        assert!((ls.sample - x as f64).abs() < 1e-6, "{} {}", x, ls.sample);
+        assert!((ls.line - y as f64).abs() < 1e-6, "{} {}", y, ls.line);
+    }
+}
+
+#[test]
+fn test_render_image() {
+    // A 64x64 pixel camera looking down +X with a 50 pixel focal length
+    let model = as_cahvore::from_components(&[
+        vec![0.0, 0.0, 0.0],
+        vec![1.0, 0.0, 0.0],
+        vec![32.0, 50.0, 0.0],
+        vec![32.0, 0.0, 50.0],
+    ]);
+    let image = image_of(64, 64, |_, _| 100.0);
+    let mut raw = MarsImage::from_image(&image, Instrument::M20MastcamZLeft);
+    raw.metadata.camera_model_component_list = model.clone();
+
+    let options = MapOptions::default();
+    let mut context = MapContext::new(&model.c(), &options);
+    context.include(&model, 64, 64);
+    context.finalize(&options);
+    assert!(context.width > 32 && context.height > 32);
+
+    for interpolation in [
+        Interpolation::Nearest,
+        Interpolation::Bilinear,
+        Interpolation::Bicubic,
+    ] {
+        let options = CompositeOptions {
+            interpolation,
+            ..Default::default()
+        };
+        let mut accumulator = MosaicAccumulator::new(&context, 1, &options);
+        accumulator
+            .add_image(&raw, &model, &context, Eye::DontCare, &options)
+            .unwrap();
+
+        // Every rendered pixel comes from the flat image, and the middle of the map is
+        // covered without holes
+        let mut covered = 0;
+        for y in 0..context.height {
+            for x in 0..context.width {
+                if let Some(value) = accumulator.get(x, y, 0) {
+                    assert!((value - 100.0).abs() < 1e-3, "{} {} {}", x, y, value);
+                    covered += 1;
+                }
+            }
+        }
+        assert!(covered > context.width * context.height / 2);
+        let (cx, cy) = (context.width / 2, context.height / 2);
+        for y in (cy - 8)..(cy + 8) {
+            for x in (cx - 8)..(cx + 8) {
+                assert!(accumulator.get(x, y, 0).is_some(), "{} {}", x, y);
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use mars_raw_utils::calibfile;
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::error::CalError;
+use mars_raw_utils::marsimage::{self, MarsImage};
+use mars_raw_utils::metadata;
+use std::io::Write;
+
+#[test]
+fn test_open_missing_file() {
+    match MarsImage::open("tests/testdata/does_not_exist.png", Instrument::None) {
+        Err(CalError::MissingFile(f)) => assert_eq!(f, "tests/testdata/does_not_exist.png"),
+        _ => panic!("Expected a missing file error"),
+    }
+
+    assert!(matches!(
+        MarsImage::open_dct_coefficient_fix(
+            "tests/testdata/does_not_exist.jpg",
+            Instrument::MslMastcamLeft
+        ),
+        Err(CalError::MissingFile(_))
+    ));
+}
+
+#[test]
+fn test_open_undecodable_file() {
+    let mut file = tempfile::Builder::new().suffix(".png").tempfile().unwrap();
+    file.write_all(b"not an image").unwrap();
+
+    assert!(matches!(
+        MarsImage::open(file.path().to_str().unwrap(), Instrument::None),
+        Err(CalError::DecodeFailure { .. })
+    ));
+}
+
+#[test]
+fn test_bad_metadata() {
+    let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+    file.write_all(b"{ this is not json").unwrap();
+
+    match metadata::load_image_metadata(&file.path().to_str().unwrap().to_string()) {
+        Err(err) => assert!(matches!(
+            err.downcast_ref::<CalError>(),
+            Some(CalError::BadMetadata { .. })
+        )),
+        Ok(_) => panic!("Metadata should fail to parse"),
+    }
+}
+
+#[test]
+fn test_missing_calibration_file() {
+    let err = calibfile::locate_calibration_file("NOT_A_REAL_CALIBRATION_FILE.png")
+        .expect_err("Calibration file should not be found");
+    assert!(matches!(
+        err.downcast_ref::<CalError>(),
+        Some(CalError::MissingFile(_))
+    ));
+}
+
+#[test]
+fn test_unsupported_subframe() {
+    assert_eq!(
+        marsimage::subframe_region(
+            Instrument::M20NavcamLeft,
+            &[1281.0, 961.0, 2560.0, 1920.0],
+            2,
+            2560,
+            1920
+        )
+        .unwrap(),
+        [640, 480, 1280, 960]
+    );
+
+    for rect in [
+        vec![0.0, 1.0, 100.0, 100.0],
+        vec![1.0, 1.0, 0.0, 100.0],
+        vec![1.0, f64::NAN, 100.0, 100.0],
+        vec![1.0, 1.0, 100.0],
+        vec![1601.0, 1.0, 100.0, 100.0],
+    ] {
+        assert!(matches!(
+            marsimage::subframe_region(Instrument::M20MastcamZLeft, &rect, 1, 1648, 1200),
+            Err(CalError::UnsupportedSubframe { .. })
+        ));
+    }
+}
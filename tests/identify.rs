@@ -0,0 +1,107 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::identify::{self, InstrumentSource};
+use std::fs;
+
+#[test]
+fn test_instrument_from_label_text() {
+    assert_eq!(
+        identify::instrument_from_label_text(
+            "LBLSIZE=2048 FORMAT='HALF' INSTRUMENT_HOST_ID='MSL' INSTRUMENT_ID='MAST_LEFT'"
+        ),
+        Some(Instrument::MslMastcamLeft)
+    );
+    assert_eq!(
+        identify::instrument_from_label_text(
+            "PDS_VERSION_ID = PDS3\r\nINSTRUMENT_NAME = \"NAVIGATION CAMERA LEFT\"\r\nINSTRUMENT_ID = \"NAVCAM_LEFT\"\r\n"
+        ),
+        Some(Instrument::M20NavcamLeft)
+    );
+    assert_eq!(
+        identify::instrument_from_label_text("PDS_VERSION_ID = PDS3\nINSTRUMENT_ID = (IDC)\n"),
+        Some(Instrument::NsytIDC)
+    );
+    assert_eq!(
+        identify::instrument_from_label_text("MY_INSTRUMENT_ID = 'MAST_LEFT'"),
+        None
+    );
+    assert_eq!(
+        identify::instrument_from_label_text("INSTRUMENT_ID = 'SOMETHING_ELSE'"),
+        None
+    );
+}
+
+#[test]
+fn test_label_values() {
+    let vicar = "LBLSIZE=4096 PROPERTY='INSTRUMENT_STATE_PARMS' EXPOSURE_DURATION=12.5 \
+        INSTRUMENT_TEMPERATURE=(-21.4, -35.25) INSTRUMENT_TEMPERATURE_NAME=('ZCAM_OPTICS', 'ZCAM_FPA')";
+    assert_eq!(
+        identify::label_values(vicar, "INSTRUMENT_TEMPERATURE"),
+        Some(vec!["-21.4".to_string(), "-35.25".to_string()])
+    );
+    assert_eq!(
+        identify::exposure_duration_from_label_text(vicar),
+        Some(12.5)
+    );
+    assert_eq!(
+        identify::detector_temperature_from_label_text(vicar),
+        Some(-35.25)
+    );
+
+    let pds = "PDS_VERSION_ID = PDS3\r\nGROUP = INSTRUMENT_STATE_PARMS\r\n  \
+        EXPOSURE_DURATION = 0.25 <s>\r\n  DETECTOR_TEMPERATURE = 250.0 <K>\r\n";
+    assert_eq!(
+        identify::label_values(pds, "EXPOSURE_DURATION"),
+        Some(vec!["0.25 <s>".to_string()])
+    );
+    assert_eq!(
+        identify::exposure_duration_from_label_text(pds),
+        Some(250.0)
+    );
+    let temperature = identify::detector_temperature_from_label_text(pds).unwrap();
+    assert!((temperature + 23.15).abs() < 1.0e-9);
+
+    assert_eq!(
+        identify::exposure_duration_from_label_text("LBLSIZE=1024"),
+        None
+    );
+}
+
+#[test]
+fn test_identify_instrument() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = |name: &str, contents: &str| {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_owned()
+    };
+
+    // Product id naming
+    let ecam = file("NLB_747123456EDR_F0950000NCAM00554M_.png", "");
+    assert_eq!(
+        identify::identify_instrument(&ecam, &None),
+        Some((Instrument::MslNavCamLeft, InstrumentSource::ProductId))
+    );
+
+    // A detached label takes precedence over the file name
+    let labeled = file("3372ML0176880011204530C00_DXXX.png", "");
+    file(
+        "3372ML0176880011204530C00_DXXX.LBL",
+        "PDS_VERSION_ID = PDS3\nINSTRUMENT_ID = MAST_RIGHT\n",
+    );
+    assert_eq!(
+        identify::identify_instrument(&labeled, &None),
+        Some((Instrument::MslMastcamRight, InstrumentSource::Label))
+    );
+
+    // Forcing the instrument overrides inference, but not the metadata sidecar
+    let unknown = file("image.png", "");
+    assert_eq!(identify::identify_instrument(&unknown, &None), None);
+    assert_eq!(
+        identify::identify_instrument(&unknown, &Some("MAHLI".to_string())),
+        Some((Instrument::MslMAHLI, InstrumentSource::Forced))
+    );
+    assert_eq!(
+        identify::identify_instrument(&ecam, &Some("NOT_A_CAMERA".to_string())),
+        Some((Instrument::MslNavCamLeft, InstrumentSource::ProductId))
+    );
+}
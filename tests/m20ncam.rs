@@ -1,6 +1,7 @@
+use mars_raw_utils::assemble::TileRect;
 use mars_raw_utils::enums::Instrument;
 use mars_raw_utils::m20::assemble::{
-    NavcamTile, FRAME_MATCH_PAIRS_SCALEFACTOR_1, FRAME_MATCH_PAIRS_SCALEFACTOR_2,
+    Composite, NavcamTile, FRAME_MATCH_PAIRS_SCALEFACTOR_1, FRAME_MATCH_PAIRS_SCALEFACTOR_2,
 };
 use mars_raw_utils::m20::ncamlevels;
 use mars_raw_utils::m20::ncamlevels::BufferGetBorderOverLap;
@@ -10,7 +11,8 @@ fn load_test_image_navright_sf_2() -> MarsImage {
     let raw = MarsImage::open(
         "tests/testdata/NRF_0731_0731848568_991ECM_N0361610NCAM12731_04_195J01.png",
         Instrument::M20NavcamRight,
-    );
+    )
+    .unwrap();
     let expected_width = 1288;
     let expected_height = 968;
     assert_eq!(raw.image.height, expected_height);
@@ -22,7 +24,8 @@ fn load_test_image_navright_sf_1() -> MarsImage {
     let raw = MarsImage::open(
         "tests/testdata/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png",
         Instrument::M20NavcamLeft,
-    );
+    )
+    .unwrap();
     let expected_width = 1288;
     let expected_height = 968;
     assert_eq!(raw.image.height, expected_height);
@@ -185,3 +188,35 @@ fn test_get_subframes_for_tile_id_pairs_scale_factor_1() {
         );
     });
 }
+
+#[test]
+fn test_tile_placement() {
+    // At scale factor 1 the 2 pixel border is trimmed
+    let tiles = vec![NavcamTile::new_with_image(&load_test_image_navright_sf_1())];
+    let composite = Composite::new(&tiles);
+    let (image, rect) = composite.get_tile_placement(&tiles[0]).unwrap();
+    assert_eq!(
+        rect,
+        TileRect {
+            x: 2,
+            y: 2,
+            width: 1284,
+            height: 964
+        }
+    );
+    assert_eq!((image.width, image.height), (1284, 964));
+
+    let tiles = vec![NavcamTile::new_with_image(&load_test_image_navright_sf_2())];
+    let composite = Composite::new(&tiles);
+    let (image, rect) = composite.get_tile_placement(&tiles[0]).unwrap();
+    assert_eq!(
+        rect,
+        TileRect {
+            x: 1272,
+            y: 0,
+            width: 1288,
+            height: 968
+        }
+    );
+    assert_eq!((image.width, image.height), (1288, 968));
+}
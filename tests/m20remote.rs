@@ -50,6 +50,8 @@ async fn test_m20_instrument_fetches() {
                 only_new: false,
                 product_types: vec![],
                 output_path: String::from(""),
+                all_versions: false,
+                updates_only: false,
             },
             |_| {},
             |_| {},
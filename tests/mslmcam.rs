@@ -0,0 +1,112 @@
+use mars_raw_utils::calibrate::Calibration;
+use mars_raw_utils::calpipeline::CalStage;
+use mars_raw_utils::calplan::CalPlan;
+use mars_raw_utils::calprofile::CalProfile;
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::metadata::Metadata;
+use mars_raw_utils::msl::mcam::{self, MslMastcam, SubframeSource};
+
+#[test]
+fn test_calibration_subframe_from_metadata() {
+    // One-based metadata subframe, converted to a zero-based crop
+    let rect = vec![401.0, 193.0, 512.0, 512.0];
+    assert_eq!(
+        mcam::calibration_subframe(
+            Instrument::MslMastcamLeft,
+            Some(&rect),
+            1648,
+            1200,
+            512,
+            512
+        ),
+        Some(([400, 192, 512, 512], SubframeSource::Metadata))
+    );
+
+    // Metadata takes precedence over the table of known subframe sizes
+    let rect = vec![201.0, 101.0, 1328.0, 1184.0];
+    assert_eq!(
+        mcam::calibration_subframe(
+            Instrument::MslMastcamRight,
+            Some(&rect),
+            1648,
+            1300,
+            1328,
+            1184
+        ),
+        Some(([200, 100, 1328, 1184], SubframeSource::Metadata))
+    );
+}
+
+#[test]
+fn test_calibration_subframe_fallbacks() {
+    // No metadata, a known subframe size
+    assert_eq!(
+        mcam::calibration_subframe(Instrument::MslMastcamRight, None, 1648, 1200, 848, 848),
+        Some(([400, 192, 848, 848], SubframeSource::KnownSize))
+    );
+
+    // Metadata that doesn't match the image size, such as for a downsampled image
+    let rect = vec![1.0, 1.0, 1024.0, 1024.0];
+    assert_eq!(
+        mcam::calibration_subframe(
+            Instrument::MslMastcamLeft,
+            Some(&rect),
+            1648,
+            1200,
+            512,
+            512
+        ),
+        Some(([568, 344, 512, 512], SubframeSource::Centered))
+    );
+
+    // Metadata extending past the calibration image
+    let rect = vec![1500.0, 1.0, 512.0, 512.0];
+    assert_eq!(
+        mcam::calibration_subframe(
+            Instrument::MslMastcamLeft,
+            Some(&rect),
+            1648,
+            1200,
+            512,
+            512
+        ),
+        Some(([568, 344, 512, 512], SubframeSource::Centered))
+    );
+
+    // Full frame images need no crop
+    assert_eq!(
+        mcam::calibration_subframe(Instrument::MslMastcamLeft, None, 1648, 1200, 1648, 1200),
+        None
+    );
+}
+
+#[test]
+fn test_left_1600x1200_placement() {
+    // Kept whole by the margin trim and placed at its known sensor position
+    let mut plan = CalPlan::new(
+        "missing.png",
+        &CalProfile::default(),
+        Instrument::MslMastcamLeft,
+        Metadata::default(),
+    );
+    plan.width = Some(1600);
+    plan.height = Some(1200);
+    MslMastcam {}
+        .plan_stage(&CalStage::Flat, &mut plan)
+        .unwrap();
+    assert!(plan.crops.is_empty());
+    assert_eq!((plan.width, plan.height), (Some(1600), Some(1200)));
+
+    assert_eq!(
+        mcam::calibration_subframe(Instrument::MslMastcamLeft, None, 1648, 1200, 1600, 1200),
+        Some(([33, 0, 1600, 1200], SubframeSource::KnownSize))
+    );
+
+    // Full width frames lose their margins
+    plan.width = Some(1536);
+    plan.height = Some(1152);
+    MslMastcam {}
+        .plan_stage(&CalStage::Flat, &mut plan)
+        .unwrap();
+    assert_eq!(plan.crops, vec![[161, 0, 1328, 1152]]);
+}
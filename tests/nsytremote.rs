@@ -29,6 +29,8 @@ async fn test_nsyt_instrument_fetches() {
                 only_new: false,
                 product_types: vec![],
                 output_path: String::from(""),
+                all_versions: false,
+                updates_only: false,
             },
             |_| {},
             |_| {},
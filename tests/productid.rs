@@ -0,0 +1,240 @@
+use mars_raw_utils::enums::{Eye, Instrument, Mission};
+use mars_raw_utils::productid::{self, ProductId, ProductRank};
+
+const M20_ZCAM: &str = "ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01";
+const M20_NCAM: &str = "NLF_0670_0726421423_795ECM_N0320000NCAM00500_01_295J01";
+const MSL_ECAM: &str = "NLB_747123456EDR_F0950000NCAM00554M_";
+const MSL_MCAM: &str = "3372MR0176880011204530C00";
+const MSL_MCAM_OLD: &str = "0044ML0002170000E1";
+const NSYT_IDC: &str = "D001R0018_598491924EDR_F0000_0122M_";
+const MER_PANCAM: &str = "1P128287205ESF0200P2222L2M1";
+
+#[test]
+fn test_round_trip() {
+    for id in [
+        M20_ZCAM,
+        M20_NCAM,
+        MSL_ECAM,
+        MSL_MCAM,
+        MSL_MCAM_OLD,
+        NSYT_IDC,
+        MER_PANCAM,
+    ] {
+        assert_eq!(ProductId::parse(id).unwrap().to_string(), id);
+    }
+
+    // Raw image metadata omits the Mars 2020 version
+    let id = "ZL1_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ";
+    assert_eq!(ProductId::parse(id).unwrap().to_string(), id);
+}
+
+#[test]
+fn test_m20() {
+    let product_id = ProductId::parse(M20_ZCAM).unwrap();
+    assert_eq!(product_id.mission(), Mission::Mars2020);
+    assert_eq!(product_id.instrument(), Instrument::M20MastcamZLeft);
+    assert_eq!(product_id.eye(), Eye::Left);
+    assert_eq!(product_id.sol(), Some(53));
+    assert_eq!(product_id.sclk(), Some(671642352));
+    assert_eq!(product_id.product_type(), "ECM");
+    assert_eq!(product_id.sequence_id(), "ZCAM05025");
+    assert_eq!(product_id.filter(), Some("L0".to_string()));
+    assert_eq!(product_id.version(), Some("01".to_string()));
+
+    match product_id {
+        ProductId::M20(id) => {
+            assert_eq!(id.focal_length(), Some(110.0));
+            assert_eq!(id.compression, "085");
+            assert_eq!(id.thumbnail, 'N');
+        }
+        _ => panic!("Expected a Mars 2020 product id"),
+    }
+
+    let product_id = ProductId::parse(M20_NCAM).unwrap();
+    assert_eq!(product_id.instrument(), Instrument::M20NavcamLeft);
+    assert_eq!(product_id.filter(), None);
+}
+
+#[test]
+fn test_msl() {
+    let product_id = ProductId::parse(MSL_ECAM).unwrap();
+    assert_eq!(product_id.mission(), Mission::MSL);
+    assert_eq!(product_id.instrument(), Instrument::MslNavCamLeft);
+    assert_eq!(product_id.eye(), Eye::Left);
+    assert_eq!(product_id.sol(), None);
+    assert_eq!(product_id.sclk(), Some(747123456));
+    assert_eq!(product_id.product_type(), "EDR");
+    assert_eq!(product_id.version(), None);
+
+    let product_id = ProductId::parse(MSL_MCAM).unwrap();
+    assert_eq!(product_id.instrument(), Instrument::MslMastcamRight);
+    assert_eq!(product_id.eye(), Eye::Right);
+    assert_eq!(product_id.sol(), Some(3372));
+    assert_eq!(product_id.product_type(), "C");
+    assert_eq!(product_id.version(), Some("00".to_string()));
+
+    let product_id = ProductId::parse(MSL_MCAM_OLD).unwrap();
+    assert_eq!(product_id.instrument(), Instrument::MslMastcamLeft);
+    assert_eq!(product_id.product_type(), "E");
+    assert_eq!(product_id.version(), Some("1".to_string()));
+}
+
+#[test]
+fn test_nsyt_and_mer() {
+    let product_id = ProductId::parse(NSYT_IDC).unwrap();
+    assert_eq!(product_id.mission(), Mission::InSight);
+    assert_eq!(product_id.instrument(), Instrument::NsytIDC);
+    assert_eq!(product_id.eye(), Eye::DontCare);
+    assert_eq!(product_id.sol(), Some(18));
+    assert_eq!(product_id.sequence_id(), "0122");
+
+    let product_id = ProductId::parse(MER_PANCAM).unwrap();
+    assert_eq!(product_id.mission(), Mission::MerB);
+    assert_eq!(product_id.instrument(), Instrument::None);
+    assert_eq!(product_id.eye(), Eye::Left);
+    assert_eq!(product_id.sclk(), Some(128287205));
+    assert_eq!(product_id.sequence_id(), "P2222");
+    assert_eq!(product_id.filter(), Some("L2".to_string()));
+}
+
+#[test]
+fn test_sequence_id() {
+    let sequence_id = |id: &str| ProductId::parse(id).ok().map(|p| p.sequence_id());
+    assert_eq!(
+        sequence_id("ZL1_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ"),
+        Some("ZCAM08419".to_string())
+    );
+    assert_eq!(sequence_id(MSL_ECAM), Some("NCAM00554".to_string()));
+    assert_eq!(sequence_id(MSL_MCAM), Some("MCAM17688".to_string()));
+    assert_eq!(
+        sequence_id("3372ML0176880011204530C00"),
+        Some("MCAM17688".to_string())
+    );
+    assert_eq!(sequence_id("foo.png"), None);
+    assert_eq!(sequence_id("a_picture_of_mars.png"), None);
+}
+
+#[test]
+fn test_from_file_name() {
+    let product_id = ProductId::from_file_name(
+        "/data/M20/0395/ZCAM/ZR0_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ01.png",
+    )
+    .unwrap();
+    assert_eq!(product_id.instrument(), Instrument::M20MastcamZRight);
+    assert_eq!(
+        product_id.to_string(),
+        "ZR0_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ01"
+    );
+
+    // Suffixes added by processing are ignored
+    let product_id = ProductId::from_file_name("3372MR0176880011204530C00_DXXX-rjcal.png").unwrap();
+    assert_eq!(product_id.to_string(), MSL_MCAM);
+
+    assert!(ProductId::from_file_name("foo.png").is_err());
+    assert!(ProductId::parse("").is_err());
+}
+
+#[test]
+fn test_rank_and_observation_key() {
+    let v1 = ProductId::parse(M20_ZCAM).unwrap();
+    let v2 = ProductId::parse(&M20_ZCAM.replace("J01", "J02")).unwrap();
+    let thumb = ProductId::parse(&M20_ZCAM.replace("ECM_N", "ECM_T")).unwrap();
+
+    assert!(thumb.is_thumbnail());
+    assert!(!v1.is_thumbnail());
+    assert_eq!(v1.observation_key(), v2.observation_key());
+    assert_eq!(v1.observation_key(), thumb.observation_key());
+    assert_eq!(
+        v2.rank(),
+        ProductRank {
+            full_frame: true,
+            version: 2
+        }
+    );
+    assert!(v2.rank() > v1.rank());
+    assert!(v1.rank() > thumb.rank());
+
+    let ecam = ProductId::parse(MSL_ECAM).unwrap();
+    let ecam_thumb = ProductId::parse(&MSL_ECAM.replace("EDR_F", "EDR_T")).unwrap();
+    assert!(ecam_thumb.is_thumbnail());
+    assert_eq!(ecam.observation_key(), ecam_thumb.observation_key());
+    assert_eq!(ecam.rank().version, 0);
+
+    // Another filter is another observation
+    let other = ProductId::parse(&M20_ZCAM.replace("ZL0", "ZL1")).unwrap();
+    assert_ne!(v1.observation_key(), other.observation_key());
+}
+
+#[test]
+fn test_partition_superseded() {
+    let v2 = M20_ZCAM.replace("J01", "J02");
+    let thumb = M20_ZCAM.replace("ECM_N", "ECM_T");
+    let other = M20_ZCAM.replace("ZL0", "ZL1");
+    let files: Vec<String> = vec![
+        format!("{}.png", M20_ZCAM),
+        format!("{}.png", v2),
+        format!("{}.png", thumb),
+        format!("{}.png", other),
+        format!("{}-rjcal.png", M20_ZCAM),
+        "foo.png".to_string(),
+    ];
+
+    let group_of = |file: &String| {
+        let product_id = ProductId::from_file_name(file).ok()?;
+        let suffix = file[product_id.to_string().len()..].to_string();
+        Some((suffix, product_id))
+    };
+    let (kept, superseded) = productid::partition_superseded(files, group_of);
+
+    assert_eq!(
+        kept,
+        vec![
+            format!("{}.png", v2),
+            format!("{}.png", other),
+            format!("{}-rjcal.png", M20_ZCAM),
+            "foo.png".to_string(),
+        ]
+    );
+    assert_eq!(
+        superseded,
+        vec![format!("{}.png", M20_ZCAM), format!("{}.png", thumb)]
+    );
+}
+
+#[test]
+fn test_partition_superseded_msl_thumbnails() {
+    let thumb = MSL_MCAM.replace("C00", "I00");
+    let thumb_id = ProductId::parse(&thumb).unwrap();
+    let full_id = ProductId::parse(MSL_MCAM).unwrap();
+    assert!(thumb_id.is_thumbnail());
+    assert!(!full_id.is_thumbnail());
+    assert_eq!(thumb_id.observation_key(), full_id.observation_key());
+    assert_eq!(thumb_id.to_string(), thumb);
+
+    // Another image in the sequence is another observation
+    let other = MSL_MCAM.replace("0011204530", "0021204540");
+    assert_ne!(
+        ProductId::parse(&other).unwrap().observation_key(),
+        full_id.observation_key()
+    );
+
+    let files: Vec<String> = vec![
+        format!("{}_DXXX.jpg", thumb),
+        format!("{}_DXXX.jpg", MSL_MCAM),
+        format!("{}_DXXX.jpg", other),
+    ];
+    let group_of = |file: &String| {
+        let product_id = ProductId::from_file_name(file).ok()?;
+        let suffix = file[product_id.to_string().len()..].to_string();
+        Some((suffix, product_id))
+    };
+    let (kept, superseded) = productid::partition_superseded(files, group_of);
+    assert_eq!(
+        kept,
+        vec![
+            format!("{}_DXXX.jpg", MSL_MCAM),
+            format!("{}_DXXX.jpg", other)
+        ]
+    );
+    assert_eq!(superseded, vec![format!("{}_DXXX.jpg", thumb)]);
+}
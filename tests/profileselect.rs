@@ -0,0 +1,93 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::profileselect::{InstrumentMatch, ProfileSelector};
+use std::fs;
+use std::path::Path;
+
+fn write_profile(dir: &Path, name: &str, text: &str) -> String {
+    let file_path = dir.join(format!("{}.toml", name));
+    fs::write(&file_path, format!("calfiletype = \"profile\"\n{}", text)).unwrap();
+    file_path.to_str().unwrap().to_string()
+}
+
+fn selector_for(dir: &Path, profiles: &[(&str, &str)]) -> ProfileSelector {
+    let paths: Vec<String> = profiles
+        .iter()
+        .map(|(name, text)| write_profile(dir, name, text))
+        .collect();
+    ProfileSelector::from_profiles(&paths).unwrap()
+}
+
+#[test]
+fn test_select_by_instrument() {
+    let dir = tempfile::tempdir().unwrap();
+    let selector = selector_for(
+        dir.path(),
+        &[
+            ("m20_generic", "mission = \"Mars2020\"\n"),
+            ("zcam", "mission = \"Mars2020\"\ninstrument = \"MASTCAM\"\n"),
+            (
+                "zcam_left",
+                "mission = \"Mars2020\"\ninstrument = \"MCZ_LEFT\"\n",
+            ),
+            ("mcam", "mission = \"MSL\"\ninstrument = \"MASTCAM\"\n"),
+        ],
+    );
+
+    assert_eq!(
+        selector.candidates()[1].instrument_match(Instrument::M20MastcamZRight),
+        Some(InstrumentMatch::CameraGroup)
+    );
+
+    let selection = selector.select(Instrument::M20MastcamZLeft, None).unwrap();
+    assert_eq!(selection.name, "zcam_left");
+    assert!(selection.tied_with.is_empty());
+
+    let selection = selector.select(Instrument::M20MastcamZRight, None).unwrap();
+    assert_eq!(selection.name, "zcam");
+
+    let selection = selector.select(Instrument::M20NavcamLeft, None).unwrap();
+    assert_eq!(selection.name, "m20_generic");
+
+    let selection = selector.select(Instrument::MslMastcamLeft, None).unwrap();
+    assert_eq!(selection.name, "mcam");
+
+    assert!(selector.select(Instrument::NsytIDC, None).is_none());
+}
+
+#[test]
+fn test_select_by_filter_and_priority() {
+    let dir = tempfile::tempdir().unwrap();
+    let selector = selector_for(
+        dir.path(),
+        &[
+            ("zcam_a", "mission = \"Mars2020\"\ninstrument = \"MASTCAM\"\n"),
+            ("zcam_b", "mission = \"Mars2020\"\ninstrument = \"MASTCAM\"\n"),
+            (
+                "zcam_r0",
+                "mission = \"Mars2020\"\ninstrument = \"MASTCAM\"\nfilter_name = \"R0\"\n",
+            ),
+            (
+                "zcam_l0",
+                "mission = \"Mars2020\"\ninstrument = \"MASTCAM\"\nfilter_name = \"L0\"\nauto_priority = -1\n",
+            ),
+        ],
+    )
+    .with_fallback(Some("fallback_profile".to_string()));
+
+    // A declared filter outranks priority, but only when it matches
+    let selection = selector
+        .select(Instrument::M20MastcamZLeft, Some("l0"))
+        .unwrap();
+    assert_eq!(selection.name, "zcam_l0");
+
+    // Equal ranks fall back to name order and are reported
+    let selection = selector
+        .select(Instrument::M20MastcamZRight, Some("R6"))
+        .unwrap();
+    assert_eq!(selection.name, "zcam_a");
+    assert_eq!(selection.tied_with, vec!["zcam_b".to_string()]);
+
+    let selection = selector.select(Instrument::MslMAHLI, None).unwrap();
+    assert_eq!(selection.spec, "fallback_profile");
+    assert_eq!(selection.reason, "fallback");
+}
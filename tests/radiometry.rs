@@ -0,0 +1,194 @@
+use chrono::{TimeZone, Utc};
+use mars_raw_utils::calibfile;
+use mars_raw_utils::floattiff;
+use mars_raw_utils::identify;
+use mars_raw_utils::marsimage::MarsImage;
+use mars_raw_utils::metadata::Metadata;
+use mars_raw_utils::radiometry::{self, RadiometricUnits};
+use mars_raw_utils::time;
+use sciimg::enums::ImageMode;
+use sciimg::image::Image;
+use std::fs;
+use std::str::FromStr;
+
+const CALDATA: &str = r#"
+[msl]
+
+[m20.mastcamz_left]
+responsivity = { L0 = [2000.0, 2500.0, 1000.0], L6 = [400.0] }
+solar_irradiance = { L0 = [1600.0, 1800.0, 1900.0] }
+
+[nsyt]
+"#;
+
+fn assert_close(a: f64, b: f64) {
+    assert!((a - b).abs() < 1.0e-9 * b.abs().max(1.0), "{} != {}", a, b);
+}
+
+#[test]
+fn test_radiometric_units() {
+    assert_eq!(
+        RadiometricUnits::from_str("radiance").unwrap(),
+        RadiometricUnits::Radiance
+    );
+    assert_eq!(
+        RadiometricUnits::from_str("I/F").unwrap(),
+        RadiometricUnits::Iof
+    );
+    assert!(RadiometricUnits::from_str("dn").is_err());
+}
+
+#[test]
+fn test_mars_sun_distance() {
+    // Perihelion (1.381 AU) and aphelion (1.666 AU) bound the distance
+    let r = time::mars_sun_distance_au(&Utc.with_ymd_and_hms(2023, 1, 8, 3, 53, 4).unwrap());
+    assert!(r > 1.38 && r < 1.67);
+
+    let date = radiometry::parse_date_taken_utc("2023-01-08T03:53:04.187").unwrap();
+    assert_eq!(date.timestamp(), 1673149984);
+    assert!(radiometry::parse_date_taken_utc("yesterday").is_err());
+}
+
+#[test]
+fn test_compute_calibration() {
+    let config = calibfile::parse_caldata_from_string(CALDATA).unwrap();
+    let properties = &config.m20.mastcamz_left;
+
+    let mut metadata = Metadata {
+        date_taken_utc: "2023-01-08T03:53:04.187".to_string(),
+        ..Default::default()
+    };
+
+    // Exposure is required
+    assert!(radiometry::compute_calibration(
+        properties,
+        RadiometricUnits::Radiance,
+        "L6",
+        &metadata
+    )
+    .is_err());
+
+    metadata.exposure_duration = Some(50.0);
+    let calibration =
+        radiometry::compute_calibration(properties, RadiometricUnits::Radiance, "l6", &metadata)
+            .unwrap();
+    assert_eq!(calibration.scale.len(), 1);
+    assert_close(calibration.scale[0], 1.0 / (0.05 * 400.0));
+    assert_eq!(calibration.sun_distance_au, None);
+
+    let calibration =
+        radiometry::compute_calibration(properties, RadiometricUnits::Iof, "L0", &metadata)
+            .unwrap();
+    let r = calibration.sun_distance_au.unwrap();
+    assert_close(
+        calibration.scale[1],
+        std::f64::consts::PI * r * r / (0.05 * 2500.0 * 1800.0),
+    );
+
+    // No solar irradiance for L6, no coefficients at all for R0
+    assert!(
+        radiometry::compute_calibration(properties, RadiometricUnits::Iof, "L6", &metadata)
+            .is_err()
+    );
+    assert!(radiometry::compute_calibration(
+        properties,
+        RadiometricUnits::Radiance,
+        "R0",
+        &metadata
+    )
+    .is_err());
+}
+
+#[test]
+fn test_apply_calibration_and_float_output() {
+    let config = calibfile::parse_caldata_from_string(CALDATA).unwrap();
+    let metadata = Metadata {
+        exposure_duration: Some(1000.0),
+        ..Default::default()
+    };
+    let calibration = radiometry::compute_calibration(
+        &config.m20.mastcamz_left,
+        RadiometricUnits::Radiance,
+        "L0",
+        &metadata,
+    )
+    .unwrap();
+
+    let mut image = Image::new_with_bands(3, 2, 3, ImageMode::U16BIT).unwrap();
+    for y in 0..2 {
+        for x in 0..3 {
+            for b in 0..3 {
+                image.put(x, y, 1000.0, b);
+            }
+        }
+    }
+    radiometry::apply_calibration(&mut image, &calibration).unwrap();
+    assert_close(image.get_band(0).get(0, 0) as f64, 0.5);
+    assert!((image.get_band(1).get(2, 1) - 0.4).abs() < 1.0e-6);
+    assert_close(image.get_band(2).get(1, 0) as f64, 1.0);
+
+    let dir = tempfile::tempdir().unwrap();
+    let file_path = dir.path().join("radiance.tif");
+    let file_path = file_path.to_str().unwrap();
+    floattiff::save_image(&image, file_path).unwrap();
+
+    let loaded = floattiff::open_image(file_path).unwrap();
+    assert_eq!(loaded.num_bands(), 3);
+    assert_eq!((loaded.width, loaded.height), (3, 2));
+    assert_eq!(loaded.get_band(1).get(2, 1), image.get_band(1).get(2, 1));
+
+    let mut mono = Image::new_with_bands(3, 2, 1, ImageMode::U16BIT).unwrap();
+    assert!(radiometry::apply_calibration(&mut mono, &calibration).is_err());
+}
+
+#[test]
+fn test_exposure_from_label_of_real_sidecar() {
+    let name = "NRF_0731_0731848568_991ECM_N0361610NCAM12731_04_195J01";
+    let dir = tempfile::tempdir().unwrap();
+    let image_file = dir.path().join(format!("{}.png", name));
+    fs::copy(format!("tests/testdata/{}.png", name), &image_file).unwrap();
+    fs::copy(
+        format!("tests/testdata/{}-metadata.json", name),
+        dir.path().join(format!("{}-metadata.json", name)),
+    )
+    .unwrap();
+    let image_file = image_file.to_str().unwrap();
+
+    // The raw image API doesn't provide an exposure, so the sidecar alone can't be calibrated
+    let metadata = MarsImage::load_image_metadata(image_file).unwrap();
+    assert_eq!(metadata.exposure_duration, None);
+    assert_eq!(metadata.detector_temperature, None);
+
+    let config = calibfile::parse_caldata_from_string(CALDATA).unwrap();
+    let properties = &config.m20.mastcamz_left;
+    assert!(
+        radiometry::compute_calibration(properties, RadiometricUnits::Iof, "L0", &metadata)
+            .is_err()
+    );
+
+    fs::write(
+        dir.path().join(format!("{}.LBL", name)),
+        "PDS_VERSION_ID = PDS3\nINSTRUMENT_ID = \"NAVCAM_RIGHT\"\n\
+         GROUP = INSTRUMENT_STATE_PARMS\n  EXPOSURE_DURATION = 6.4 <ms>\n  \
+         DETECTOR_TEMPERATURE = -12.5 <degC>\nEND_GROUP = INSTRUMENT_STATE_PARMS\n",
+    )
+    .unwrap();
+
+    // Labels are only read on request
+    let mut metadata = MarsImage::load_image_metadata(image_file).unwrap();
+    assert_eq!(metadata.exposure_duration, None);
+    identify::apply_label_metadata(image_file, &mut metadata);
+    assert_eq!(metadata.exposure_duration, Some(6.4));
+    assert_eq!(metadata.detector_temperature, Some(-12.5));
+    assert_eq!(metadata.instrument, "NAVCAM_RIGHT");
+
+    let calibration =
+        radiometry::compute_calibration(properties, RadiometricUnits::Iof, "L0", &metadata)
+            .unwrap();
+    assert_eq!(calibration.exposure_duration, 6.4);
+    let r = calibration.sun_distance_au.unwrap();
+    assert_close(
+        calibration.scale[1],
+        std::f64::consts::PI * r * r / (0.0064 * 2500.0 * 1800.0),
+    );
+}
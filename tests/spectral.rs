@@ -0,0 +1,318 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::marsimage::MarsImage;
+use mars_raw_utils::metadata::Metadata;
+use mars_raw_utils::spectral::{self, CubeFormat, FrameInfo};
+use sciimg::enums::ImageMode;
+use sciimg::image::Image;
+use std::str::FromStr;
+
+const ZCAM_L0: &str = "ZL0_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ01.png";
+const ZCAM_L1: &str = "ZL1_0395_0702017850_081EBY_N0171064ZCAM08419_1100LMJ01.png";
+const ZCAM_L5: &str = "ZL5_0395_0702017870_081EBY_N0171064ZCAM08419_1100LMJ01.png";
+const ZCAM_R6: &str = "ZR6_0395_0702017850_081EBY_N0171064ZCAM08419_1100LMJ01.png";
+
+/// A smooth, non-repeating test pattern so that registration has a single best offset
+fn pattern(x: usize, y: usize) -> f32 {
+    let (x, y) = (x as f32, y as f32);
+    1000.0 + 300.0 * (x * 0.31).sin() * (y * 0.17).cos() + 200.0 * ((x + 2.0 * y) * 0.05).sin()
+}
+
+/// A frame filled from the pattern, offset so that frame pixel (x, y) shows pattern pixel
+/// (x - dx, y - dy), with each band scaled by `gain`
+fn frame_image(width: usize, height: usize, bands: usize, shift: [i32; 2], gain: f32) -> Image {
+    let mut image = Image::new_with_bands(width, height, bands, ImageMode::U16BIT).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            let (px, py) = (x as i32 - shift[0] + 20, y as i32 - shift[1] + 20);
+            for b in 0..bands {
+                image.put(
+                    x,
+                    y,
+                    pattern(px as usize, py as usize) * gain * (b + 1) as f32,
+                    b,
+                );
+            }
+        }
+    }
+    image
+}
+
+fn frame(file_name: &str, image: Image) -> (FrameInfo, MarsImage) {
+    let info = FrameInfo::from_metadata(file_name, &Metadata::default());
+    let raw = MarsImage::from_image(&image, info.instrument);
+    (info, raw)
+}
+
+#[test]
+fn test_filter_wavelengths() {
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::M20MastcamZLeft, "L0"),
+        Some(vec![630.0, 544.0, 480.0])
+    );
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::M20MastcamZRight, "r6"),
+        Some(vec![1022.0])
+    );
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::MslMastcamLeft, "L3"),
+        Some(vec![751.0])
+    );
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::M20MastcamZLeft, "R1"),
+        None
+    );
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::M20NavcamLeft, "L0"),
+        None
+    );
+}
+
+#[test]
+fn test_frame_info_from_file_name() {
+    let info = FrameInfo::from_metadata(ZCAM_R6, &Metadata::default());
+    assert_eq!(info.instrument, Instrument::M20MastcamZRight);
+    assert_eq!(info.filter, Some("R6".to_string()));
+    assert_eq!(info.sequence_id, Some("ZCAM08419".to_string()));
+    assert_eq!(info.wavelengths(), Some(vec![1022.0]));
+
+    let metadata = Metadata {
+        instrument: "MAST_LEFT".to_string(),
+        filter_name: Some("L5".to_string()),
+        imageid: "3372ML0176880011204530C00".to_string(),
+        sol: 3372,
+        ..Default::default()
+    };
+    let info = FrameInfo::from_metadata("3372ML0176880011204530C00_DXXX.png", &metadata);
+    assert_eq!(info.instrument, Instrument::MslMastcamLeft);
+    assert_eq!(info.filter, Some("L5".to_string()));
+    assert_eq!(info.wavelengths(), Some(vec![867.0]));
+}
+
+#[test]
+fn test_group_frames() {
+    let at = |file_name: &str, az: f64| {
+        FrameInfo::from_metadata(
+            file_name,
+            &Metadata {
+                sol: 395,
+                mast_az: Some(az),
+                mast_el: Some(-12.0),
+                ..Default::default()
+            },
+        )
+    };
+
+    let groups = spectral::group_frames(vec![
+        at(ZCAM_L0, 101.02),
+        at(ZCAM_L1, 101.0),
+        at(ZCAM_R6, 101.01),
+        at(ZCAM_L1, 120.0),
+        FrameInfo::from_metadata("foo.png", &Metadata::default()),
+    ]);
+
+    // The eyes share the sequence but are grouped apart
+    assert_eq!(groups.len(), 4);
+    assert_eq!(groups[0].name, "0000_UNKNOWN");
+    assert_eq!(groups[1].name, "0395_ZCAM08419_L_p1");
+    assert_eq!(groups[1].frames.len(), 2);
+    assert_eq!(groups[2].name, "0395_ZCAM08419_L_p2");
+    assert_eq!(groups[2].frames.len(), 1);
+    assert_eq!(groups[3].name, "0395_ZCAM08419_R");
+    assert_eq!(groups[3].frames[0].instrument, Instrument::M20MastcamZRight);
+}
+
+#[test]
+fn test_register_translation() {
+    let reference = frame_image(96, 80, 1, [0, 0], 1.0);
+    let target = frame_image(96, 80, 1, [5, -3], 1.5);
+    assert_eq!(
+        spectral::register_translation(&reference, &target, [0, 0], 8),
+        [5, -3]
+    );
+
+    // Coarse to fine search
+    let target = frame_image(96, 80, 1, [-11, 7], 1.0);
+    assert_eq!(
+        spectral::register_translation(&reference, &target, [0, 0], 16),
+        [-11, 7]
+    );
+}
+
+#[test]
+fn test_assemble_cube() {
+    let frames = vec![
+        frame(ZCAM_L1, frame_image(64, 48, 1, [0, 0], 1.0)),
+        frame(ZCAM_L0, frame_image(64, 48, 3, [4, 2], 0.5)),
+    ];
+
+    // Without registration the frames are stacked as-is
+    let cube = spectral::assemble_cube("test", &frames, None).unwrap();
+    assert_eq!((cube.image.width, cube.image.height), (64, 48));
+    assert_eq!(cube.image.num_bands(), 4);
+    let names: Vec<&str> = cube.bands.iter().map(|b| b.name.as_str()).collect();
+    assert_eq!(names, vec!["L0B", "L0G", "L0R", "L1"]);
+    assert_eq!(cube.bands[3].wavelength, Some(800.0));
+
+    // Registered, the cube covers the shared area and the bands line up
+    let cube = spectral::assemble_cube("test", &frames, Some(8)).unwrap();
+    assert_eq!((cube.image.width, cube.image.height), (60, 46));
+    assert_eq!(cube.bands[0].offset, [4, 2]);
+    assert_eq!(cube.bands[3].offset, [0, 0]);
+    for (x, y) in [(0, 0), (30, 20), (59, 45)] {
+        let l1 = cube.image.get_band(3).get(x, y);
+        let l0r = cube.image.get_band(2).get(x, y);
+        assert!((l0r - l1 * 0.5).abs() < 1.0e-2, "{} {}", l0r, l1);
+    }
+
+    assert!(spectral::assemble_cube("test", &[], None).is_err());
+}
+
+#[test]
+fn test_assemble_cube_scaled_subframes() {
+    let scaled = |file_name: &str, rect: [f64; 4], image: Image| {
+        let info = FrameInfo::from_metadata(
+            file_name,
+            &Metadata {
+                subframe_rect: Some(rect.to_vec()),
+                scale_factor: 2,
+                ..Default::default()
+            },
+        );
+        let raw = MarsImage::from_image(&image, info.instrument);
+        (info, raw)
+    };
+
+    // Offsets on the sensor are halved in the downsampled frames
+    let frames = vec![
+        scaled(
+            ZCAM_L1,
+            [1.0, 1.0, 128.0, 96.0],
+            frame_image(64, 48, 1, [0, 0], 1.0),
+        ),
+        scaled(
+            ZCAM_L5,
+            [9.0, 5.0, 128.0, 96.0],
+            frame_image(64, 48, 1, [-4, -2], 1.0),
+        ),
+    ];
+    let cube = spectral::assemble_cube("test", &frames, None).unwrap();
+    assert_eq!((cube.image.width, cube.image.height), (60, 46));
+    assert_eq!(cube.bands[0].name, "L5");
+    assert_eq!(cube.bands[0].offset, [0, 0]);
+    assert_eq!(cube.bands[1].offset, [4, 2]);
+    for (x, y) in [(0, 0), (30, 20), (59, 45)] {
+        assert_eq!(
+            cube.image.get_band(0).get(x, y),
+            cube.image.get_band(1).get(x, y)
+        );
+    }
+
+    // Frames at different scales can't be stacked
+    let mut mixed = frames.clone();
+    mixed[1].0.scale_factor = 1;
+    assert!(spectral::assemble_cube("test", &mixed, None).is_err());
+}
+
+#[test]
+fn test_envi_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let frames = vec![
+        frame(ZCAM_L1, frame_image(16, 12, 1, [0, 0], 1.0)),
+        frame(ZCAM_L0, frame_image(16, 12, 3, [0, 0], 1.0)),
+    ];
+    let cube = spectral::assemble_cube("0395_ZCAM08419", &frames, None).unwrap();
+
+    let header = spectral::envi_header(&cube);
+    assert!(header.starts_with("ENVI\n"));
+    assert!(header.contains("bands = 4\n"));
+    assert!(header.contains("band names = {L0B, L0G, L0R, L1}\n"));
+    assert!(header.contains("wavelength = {480.0, 544.0, 630.0, 800.0}\n"));
+
+    let path = dir.path().join("cube.hdr");
+    let path = path.to_str().unwrap();
+    spectral::save_cube(&cube, path, CubeFormat::Envi).unwrap();
+    assert!(dir.path().join("cube.img").exists());
+
+    let opened = spectral::open_cube(path).unwrap();
+    assert_eq!(opened.description, "0395_ZCAM08419");
+    assert_eq!(opened.bands.len(), 4);
+    assert_eq!(opened.bands[2].name, "L0R");
+    assert_eq!(opened.bands[3].wavelength, Some(800.0));
+    assert_eq!(
+        opened.image.get_band(1).get(7, 5),
+        cube.image.get_band(1).get(7, 5)
+    );
+}
+
+#[test]
+fn test_tiff_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let frames = vec![frame(ZCAM_L0, frame_image(16, 12, 3, [0, 0], 1.0))];
+    let cube = spectral::assemble_cube("test", &frames, None).unwrap();
+
+    let path = dir.path().join("cube.tif");
+    let path = path.to_str().unwrap();
+    spectral::save_cube(&cube, path, CubeFormat::Tiff).unwrap();
+    assert!(dir.path().join("cube-cube.json").exists());
+
+    let opened = spectral::open_cube(path).unwrap();
+    assert_eq!(opened.bands, cube.bands);
+    assert_eq!(opened.image.num_bands(), 3);
+    assert_eq!(
+        opened.image.get_band(2).get(3, 4),
+        cube.image.get_band(2).get(3, 4)
+    );
+}
+
+#[test]
+fn test_parse_envi_header() {
+    let fields = spectral::parse_envi_header(
+        "ENVI\nsamples = 10\nLines = 5\nband names = {L1,\n L2,\n L3}\nwavelength = {800, 754, 677}\n",
+    )
+    .unwrap();
+    assert_eq!(fields["samples"], "10");
+    assert_eq!(fields["lines"], "5");
+    assert_eq!(fields["band names"], "{L1, L2, L3}");
+
+    assert!(spectral::parse_envi_header("samples = 10\n").is_err());
+    assert!(spectral::parse_envi_header("ENVI\nband names = {L1,\n").is_err());
+}
+
+#[test]
+fn test_extract_spectrum() {
+    let frames = vec![
+        frame(ZCAM_L1, frame_image(16, 12, 1, [0, 0], 1.0)),
+        frame(ZCAM_R6, frame_image(16, 12, 1, [0, 0], 2.0)),
+    ];
+    let mut cube = spectral::assemble_cube("test", &frames, None).unwrap();
+    for y in 0..12 {
+        for x in 0..16 {
+            cube.image.put(x, y, 10.0, 0);
+            cube.image.put(x, y, 20.0, 1);
+        }
+    }
+    cube.image.put(2, 2, 30.0, 1);
+
+    let samples = spectral::extract_spectrum(&cube, [1, 1, 2, 2]).unwrap();
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[0].band, "L1");
+    assert_eq!(samples[0].mean, 10.0);
+    assert_eq!(samples[0].std_dev, 0.0);
+    assert_eq!(samples[1].mean, 22.5);
+    assert_eq!(samples[1].pixels, 4);
+
+    let csv = spectral::spectrum_to_csv(&samples);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "band,filter,wavelength_nm,mean,std_dev,pixels");
+    assert!(lines[1].starts_with("L1,L1,800.0,10,0,4"));
+
+    assert!(spectral::extract_spectrum(&cube, [10, 10, 10, 10]).is_err());
+}
+
+#[test]
+fn test_cube_format() {
+    assert_eq!(CubeFormat::from_str("ENVI").unwrap(), CubeFormat::Envi);
+    assert_eq!(CubeFormat::from_str("tif").unwrap(), CubeFormat::Tiff);
+    assert!(CubeFormat::from_str("fits").is_err());
+    assert_eq!(CubeFormat::Envi.extension(), "hdr");
+}
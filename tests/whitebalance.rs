@@ -0,0 +1,207 @@
+use mars_raw_utils::calprofile::{validate_profile, CalProfile};
+use mars_raw_utils::whitebalance::{self, CalTarget, DerivedProfile, Patch, PatchMeasurement};
+use sciimg::enums::ImageMode;
+use sciimg::image::Image;
+
+const TARGET: &str = r#"
+calfiletype = "caltarget"
+description = "Test target"
+
+[[patch]]
+name = "white"
+rect = [0, 0, 4, 4]
+reflectance = 0.8
+
+[[patch]]
+name = "red"
+rect = [4, 0, 4, 4]
+reference = [0.5, 0.1, 0.1]
+"#;
+
+fn measurement(patch: Patch, mean: [f32; 3]) -> PatchMeasurement {
+    PatchMeasurement {
+        patch,
+        mean,
+        pixels: 1,
+    }
+}
+
+fn colored(name: &str, reference: [f32; 3]) -> Patch {
+    Patch {
+        name: name.to_string(),
+        rect: [0, 0, 1, 1],
+        reflectance: None,
+        reference: Some(reference),
+    }
+}
+
+#[test]
+fn test_parse_target() {
+    let target = CalTarget::parse(TARGET).unwrap();
+    assert_eq!(target.patches.len(), 2);
+    assert!(target.patches[0].is_gray());
+    assert_eq!(target.patches[0].reference(), Some([0.8, 0.8, 0.8]));
+    assert!(!target.patches[1].is_gray());
+
+    assert!(CalTarget::parse(&TARGET.replace("caltarget", "profile")).is_err());
+    assert!(CalTarget::parse(
+        "calfiletype = \"caltarget\"\n[[patch]]\nname = \"x\"\nrect = [0, 0, 1, 1]\n"
+    )
+    .is_err());
+}
+
+#[test]
+fn test_measure_patch() {
+    let mut image = Image::new_with_bands(8, 4, 3, ImageMode::U16BIT).unwrap();
+    for y in 0..4 {
+        for x in 0..8 {
+            image.put(x, y, 200.0, 0);
+            image.put(x, y, 400.0, 1);
+            image.put(x, y, 100.0, 2);
+        }
+    }
+    // Masked pixels are ignored
+    image.put(0, 0, 0.0, 0);
+
+    let patch = Patch::gray("white", [0, 0, 4, 4], 0.8);
+    let measured = whitebalance::measure_patch(&image, &patch).unwrap();
+    assert_eq!(measured.pixels, 15);
+    assert_eq!(measured.mean, [200.0, 400.0, 100.0]);
+
+    let weights = whitebalance::compute_weights(&[measured]).unwrap();
+    [2.0, 1.0, 4.0]
+        .iter()
+        .zip(weights.iter())
+        .for_each(|(expected, w)| assert!((expected - w).abs() < 1.0e-5));
+
+    let outside = Patch::gray("outside", [6, 0, 4, 4], 0.8);
+    assert!(whitebalance::measure_patch(&image, &outside).is_err());
+}
+
+#[test]
+fn test_compute_weights_requires_gray() {
+    let measurements = vec![measurement(
+        colored("red", [0.5, 0.1, 0.1]),
+        [10.0, 2.0, 2.0],
+    )];
+    assert!(whitebalance::compute_weights(&measurements).is_err());
+}
+
+#[test]
+fn test_compute_color_matrix() {
+    // A camera whose red channel picks up a quarter of the green signal
+    let camera = |r: [f32; 3]| [(r[0] + 0.25 * r[1]) * 1000.0, r[1] * 1000.0, r[2] * 1000.0];
+    let patches = vec![
+        Patch::gray("white", [0, 0, 1, 1], 0.8),
+        colored("red", [0.5, 0.1, 0.1]),
+        colored("green", [0.1, 0.4, 0.1]),
+        colored("blue", [0.1, 0.1, 0.3]),
+    ];
+    let measurements: Vec<PatchMeasurement> = patches
+        .into_iter()
+        .map(|p| {
+            let mean = camera(p.reference().unwrap());
+            measurement(p, mean)
+        })
+        .collect();
+
+    let weights = whitebalance::compute_weights(&measurements).unwrap();
+    let color_matrix = whitebalance::compute_color_matrix(&measurements, &weights).unwrap();
+
+    // The matrix undoes the crosstalk once the responses are white balanced
+    for m in measurements.iter() {
+        let balanced = [0, 1, 2].map(|c| m.mean[c] * weights[c] * 0.8 / 800.0);
+        let corrected = color_matrix.transform(balanced, 1.0);
+        let reference = m.patch.reference().unwrap();
+        for c in 0..3 {
+            assert!(
+                (corrected[c] - reference[c]).abs() < 1.0e-3,
+                "{}: {:?} != {:?}",
+                m.patch.name,
+                corrected,
+                reference
+            );
+        }
+    }
+
+    assert!(whitebalance::compute_color_matrix(&measurements[..2], &weights).is_err());
+}
+
+#[test]
+fn test_derived_profile() {
+    let derived = DerivedProfile {
+        description: "White balance from test".to_string(),
+        extends: Some("m20_zcam_rad".to_string()),
+        mission: Some("Mars2020".to_string()),
+        instrument: Some("MCZ_LEFT".to_string()),
+        weights: [1.23456, 1.0, 0.9],
+        ..Default::default()
+    };
+    let table = derived.to_table();
+    assert_eq!(table["extends"].as_str(), Some("m20_zcam_rad"));
+    assert_eq!(table["red_scalar"].as_float(), Some(1.2346));
+    assert!(!table.contains_key("color_matrix"));
+
+    // Everything but `extends` is a profile field
+    let mut table = table;
+    table.remove("extends");
+    let profile: CalProfile = table.try_into().unwrap();
+    assert!(validate_profile(&profile).is_empty());
+    assert_eq!(profile.blue_scalar, 0.9);
+}
+
+#[test]
+fn test_relative_target() {
+    let target = CalTarget::parse(
+        r#"
+calfiletype = "caltarget"
+relative = true
+
+[[patch]]
+name = "gray"
+rect = [470, 170, 60, 60]
+reflectance = 0.5
+
+[[patch]]
+name = "white"
+rect = [900, 900, 100, 100]
+reflectance = 0.9
+"#,
+    )
+    .unwrap();
+
+    // Relative layouts need the target's bounding box
+    assert!(target.place(None).is_err());
+    let patches = target.place(Some([100, 50, 500, 400])).unwrap();
+    assert_eq!(patches[0].rect, [335, 118, 30, 24]);
+    assert_eq!(patches[1].rect, [550, 410, 50, 40]);
+
+    // Absolute layouts are used as given
+    let target = CalTarget::parse(TARGET).unwrap();
+    assert_eq!(target.place(None).unwrap(), target.patches);
+}
+
+#[test]
+fn test_compose_weights() {
+    let source = CalProfile {
+        red_scalar: 1.2,
+        green_scalar: 0.9,
+        blue_scalar: 1.5,
+        ..Default::default()
+    };
+    let composed = whitebalance::compose_weights(&[2.0, 1.0, 0.5], &source);
+    [2.4, 0.9, 0.75]
+        .iter()
+        .zip(composed.iter())
+        .for_each(|(expected, w)| assert!((expected - w).abs() < 1.0e-5));
+
+    // Radiometric products don't carry the source scalars
+    let radiometric = CalProfile {
+        radiometric_units: Some(mars_raw_utils::radiometry::RadiometricUnits::Radiance),
+        ..source
+    };
+    assert_eq!(
+        whitebalance::compose_weights(&[2.0, 1.0, 0.5], &radiometric),
+        [2.0, 1.0, 0.5]
+    );
+}
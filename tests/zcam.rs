@@ -1,4 +1,5 @@
-use mars_raw_utils::m20::zcam;
+use mars_raw_utils::{calibfile, m20::zcam};
+use sciimg::{enums::ImageMode, image::Image};
 
 #[test]
 fn test_focal_length_from_file_name() {
@@ -41,3 +42,148 @@ fn test_focal_length_from_file_name() {
     )
     .is_err());
 }
+
+#[test]
+fn test_filter_from_file_name() {
+    assert_eq!(
+        zcam::filter_from_file_name(
+            "/data/M20/0395/ZCAM/ZR0_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ01.png"
+        ),
+        Some("R0".to_string())
+    );
+    assert_eq!(
+        zcam::filter_from_file_name("ZL6_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ01.png"),
+        Some("L6".to_string())
+    );
+    assert_eq!(zcam::filter_from_file_name("NLF_0670_0726421423.png"), None);
+    assert_eq!(zcam::filter_from_file_name("Z"), None);
+}
+
+#[test]
+fn test_filter_from_metadata_name() {
+    assert_eq!(
+        zcam::filter_from_metadata_name("L1"),
+        Some("L1".to_string())
+    );
+    assert_eq!(
+        zcam::filter_from_metadata_name("ZCAM_R4_939"),
+        Some("R4".to_string())
+    );
+    assert_eq!(
+        zcam::filter_from_metadata_name("ZL0 (RGB)"),
+        Some("L0".to_string())
+    );
+    assert_eq!(
+        zcam::filter_from_metadata_name("l5"),
+        Some("L5".to_string())
+    );
+    assert_eq!(zcam::filter_from_metadata_name("UNK"), None);
+    assert_eq!(zcam::filter_from_metadata_name(""), None);
+}
+
+#[test]
+fn test_is_narrowband_filter() {
+    assert!(!zcam::is_narrowband_filter("L0"));
+    assert!(!zcam::is_narrowband_filter("R0"));
+    assert!(zcam::is_narrowband_filter("L1"));
+    assert!(zcam::is_narrowband_filter("R6"));
+    assert!(zcam::is_narrowband_filter("R7"));
+    assert!(!zcam::is_narrowband_filter("L"));
+}
+
+#[test]
+fn test_filter_flat() {
+    let config = calibfile::parse_caldata_from_string(
+        r#"
+[msl]
+[nsyt]
+[m20.mastcamz_left]
+flat = "M20_MCZ_LEFT_FLATFIELD_-motorcount-_V2.png"
+
+[m20.mastcamz_left.filter_flats]
+L1 = "M20_MCZ_LEFT_L1_FLATFIELD_-motorcount-_V1.png"
+L2 = ""
+"#,
+    )
+    .unwrap();
+    let properties = &config.m20.mastcamz_left;
+
+    assert_eq!(
+        properties.filter_flat("l1"),
+        Some("M20_MCZ_LEFT_L1_FLATFIELD_-motorcount-_V1.png".to_string())
+    );
+    assert_eq!(properties.filter_flat("L2"), None);
+    assert_eq!(properties.filter_flat("L3"), None);
+    assert!(config.m20.mastcamz_right.filter_flats.is_empty());
+}
+
+fn assert_close(a: f32, b: f32) {
+    assert!((a - b).abs() < 1.0e-5, "{} != {}", a, b);
+}
+
+#[test]
+fn test_flat_weights_at_zoom_stops() {
+    for (focal_length, motor_count) in zcam::FOCAL_STOPS.iter().zip(zcam::MOTOR_COUNT_STOPS) {
+        let weights = zcam::flat_weights_for_focal_length(*focal_length);
+        assert_eq!(weights.lower_stop, motor_count);
+        assert_eq!(weights.upper_stop, motor_count);
+        assert!(weights.in_range);
+        assert_eq!(weights.stops(), vec![(motor_count, 1.0)]);
+    }
+
+    // Close enough to a stop to use its flat alone
+    let weights = zcam::flat_weights_for_focal_length(48.3);
+    assert_eq!(weights.stops(), vec![(3834, 1.0)]);
+}
+
+#[test]
+fn test_flat_weights_between_zoom_stops() {
+    let weights = zcam::flat_weights_for_focal_length(40.0);
+    assert_eq!(weights.lower_stop, 2448);
+    assert_eq!(weights.upper_stop, 3834);
+    assert_close(weights.upper_weight, 6.0 / 14.0);
+    assert!(weights.in_range);
+
+    let stops = weights.stops();
+    assert_eq!(stops.len(), 2);
+    assert_close(stops[0].1, 8.0 / 14.0);
+    assert_close(stops[1].1, 6.0 / 14.0);
+
+    let weights = zcam::flat_weights_for_focal_length(105.0);
+    assert_eq!((weights.lower_stop, weights.upper_stop), (8652, 9600));
+    assert_close(weights.upper_weight, 0.5);
+}
+
+#[test]
+fn test_flat_weights_out_of_range() {
+    let weights = zcam::flat_weights_for_focal_length(20.0);
+    assert!(!weights.in_range);
+    assert_eq!(weights.stops(), vec![(0, 1.0)]);
+
+    let weights = zcam::flat_weights_for_focal_length(140.0);
+    assert!(!weights.in_range);
+    assert_eq!(weights.stops(), vec![(9600, 1.0)]);
+
+    assert!(!zcam::flat_weights_for_focal_length(f32::NAN).in_range);
+}
+
+#[test]
+fn test_blend_flats() {
+    let mut lower = Image::new_with_bands(2, 2, 3, ImageMode::U16BIT).unwrap();
+    let mut upper = Image::new_with_bands(2, 2, 3, ImageMode::U16BIT).unwrap();
+    for y in 0..2 {
+        for x in 0..2 {
+            for b in 0..3 {
+                lower.put(x, y, 100.0, b);
+                upper.put(x, y, 200.0 + b as f32, b);
+            }
+        }
+    }
+
+    let blended = zcam::blend_flats(&lower, &upper, 0.25).unwrap();
+    assert_close(blended.get_band(0).get(0, 0), 125.0);
+    assert_close(blended.get_band(2).get(1, 1), 125.5);
+
+    let small = Image::new_with_bands(1, 2, 3, ImageMode::U16BIT).unwrap();
+    assert!(zcam::blend_flats(&lower, &small, 0.5).is_err());
+}